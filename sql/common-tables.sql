@@ -2,7 +2,9 @@ CREATE TABLE levels (
     level INTEGER PRIMARY KEY,
     hash VARCHAR(60),
     prev_hash VARCHAR(60),
-    baked_at TIMESTAMP WITH TIME ZONE);
+    baked_at TIMESTAMP WITH TIME ZONE,
+    protocol TEXT,
+    content_hash TEXT);
 
 CREATE UNIQUE INDEX levels_level ON levels(level);
 CREATE UNIQUE INDEX levels_hash ON levels(hash);
@@ -10,6 +12,7 @@ CREATE UNIQUE INDEX levels_hash ON levels(hash);
 CREATE TABLE contracts (
     name TEXT PRIMARY KEY,
     address VARCHAR(100) NOT NULL,
+    derived_tables BOOLEAN NOT NULL DEFAULT true,
 
     UNIQUE(address)
 );
@@ -24,6 +27,40 @@ CREATE TABLE contract_levels (
 CREATE INDEX ON contract_levels(level);
 CREATE INDEX ON contract_levels(contract, is_origination);
 
+CREATE TABLE contract_balances (
+    contract TEXT NOT NULL REFERENCES contracts(name) ON DELETE CASCADE,
+    level INTEGER NOT NULL,
+    balance NUMERIC NOT NULL,
+    PRIMARY KEY(contract, level)
+);
+
+CREATE INDEX ON contract_balances(level);
+
+CREATE TABLE delegations (
+    contract TEXT NOT NULL REFERENCES contracts(name) ON DELETE CASCADE,
+    level INTEGER NOT NULL,
+    operation_hash VARCHAR(100) NOT NULL,
+    source VARCHAR(100) NOT NULL,
+    -- NULL when the delegation was withdrawn
+    delegate VARCHAR(100)
+);
+
+CREATE INDEX ON delegations(level);
+CREATE INDEX ON delegations(contract);
+
+CREATE TABLE bcd_progress (
+    contract TEXT PRIMARY KEY REFERENCES contracts(name) ON DELETE CASCADE,
+    last_id TEXT NOT NULL
+);
+
+-- contracts that were skipped because their tables could not be derived
+CREATE TABLE failed_contracts (
+    name TEXT PRIMARY KEY,
+    address TEXT NOT NULL,
+    error TEXT NOT NULL,
+    failed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
 CREATE TYPE indexer_mode AS ENUM (
     'Bootstrap',
     'Head'
@@ -31,7 +68,9 @@ CREATE TYPE indexer_mode AS ENUM (
 CREATE TABLE indexer_state (
     quepasa_version TEXT NOT NULL,
     max_id BIGINT NOT NULL,
-    mode indexer_mode NOT NULL
+    mode indexer_mode NOT NULL,
+    -- the chain the db is indexing, set once the node is first asked for it
+    chain_id TEXT
 );
 INSERT INTO indexer_state (
     quepasa_version, max_id, mode
@@ -39,30 +78,14 @@ INSERT INTO indexer_state (
     '{quepasa_version}', 1, 'Bootstrap'
 );
 
-create table tx_contexts (
-    id bigint not null primary key,
-    level integer not null references levels(level) on delete cascade,
-    contract text not null,
-    operation_group_number integer not null,
-    operation_number integer not null,
-    content_number integer not null,
-    internal_number integer
-);
-
-CREATE UNIQUE INDEX ON tx_contexts(
-    level,
-    contract,
-    operation_group_number,
-    operation_number,
-    content_number,
-    coalesce(internal_number, -1)
-);
+{tx_contexts_tables}
 
 CREATE TABLE txs (
     id BIGSERIAL PRIMARY KEY,
-    tx_context_id BIGINT NOT NULL REFERENCES tx_contexts(id) ON DELETE CASCADE,
+    tx_context_id BIGINT NOT NULL REFERENCES {tx_contexts_table}(id) ON DELETE CASCADE,
 
     operation_hash varchar(100) not null,
+    status TEXT NOT NULL DEFAULT 'applied',
     source VARCHAR(100) NOT NULL,
     destination VARCHAR(100),
     entrypoint VARCHAR(100),
@@ -74,10 +97,14 @@ CREATE TABLE txs (
 
     consumed_milligas BIGINT,
     storage_size BIGINT,
-    paid_storage_size_diff BIGINT
+    paid_storage_size_diff BIGINT,
+
+    counter BIGINT,
+    branch VARCHAR(100)
 );
 
 CREATE UNIQUE INDEX ON txs(tx_context_id);
+CREATE INDEX ON txs(operation_hash);
 
 CREATE VIEW txs_ordered AS (
     SELECT
@@ -103,8 +130,9 @@ CREATE VIEW txs_ordered AS (
 CREATE TABLE bigmap_meta_actions (
     id BIGSERIAL PRIMARY KEY,
 
-    tx_context_id BIGINT NOT NULL REFERENCES tx_contexts(id) ON DELETE CASCADE,
+    tx_context_id BIGINT NOT NULL REFERENCES {tx_contexts_table}(id) ON DELETE CASCADE,
     bigmap_id INT NOT NULL,
+    operation_hash VARCHAR(100),
 
     action TEXT NOT NULL,
     value JSONB
@@ -113,16 +141,56 @@ CREATE TABLE bigmap_meta_actions (
 CREATE INDEX ON bigmap_meta_actions(bigmap_id, action, tx_context_id);
 CREATE INDEX ON bigmap_meta_actions(tx_context_id);
 
+-- the alloc actions of bigmap_meta_actions, normalized
+CREATE TABLE bigmap_owners (
+    id BIGSERIAL PRIMARY KEY,
+
+    tx_context_id BIGINT NOT NULL REFERENCES {tx_contexts_table}(id) ON DELETE CASCADE,
+    bigmap_id INT NOT NULL,
+
+    contract_address TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    alloc_level INTEGER NOT NULL
+);
+
+CREATE INDEX ON bigmap_owners(bigmap_id, alloc_level);
+CREATE INDEX ON bigmap_owners(tx_context_id);
+
+CREATE TABLE operation_failures (
+    id BIGSERIAL PRIMARY KEY,
+
+    tx_context_id BIGINT NOT NULL REFERENCES {tx_contexts_table}(id) ON DELETE CASCADE,
+    status TEXT NOT NULL,
+    error_kind TEXT,
+    error_id TEXT
+);
+
+CREATE INDEX ON operation_failures(tx_context_id);
+
+CREATE TABLE raw_storage (
+    tx_context_id BIGINT PRIMARY KEY REFERENCES {tx_contexts_table}(id) ON DELETE CASCADE,
+    contract TEXT NOT NULL,
+    -- the storage is either kept as JSONB, or gzip compressed (see
+    -- --raw-storage-compression)
+    storage JSONB,
+    storage_compressed BYTEA
+);
+
+CREATE INDEX ON raw_storage(contract);
+
 CREATE TABLE contract_deps (
     level INT NOT NULL,
 
     src_contract TEXT NOT NULL,
+    -- the copied bigmap of src_contract (NULL for dependencies recorded
+    -- before the bigmap was tracked)
+    src_bigmap INT,
     dest_schema TEXT NOT NULL,
-    is_deep_copy BOOLEAN NOT NULL DEFAULT true,
-
-    PRIMARY KEY (level, src_contract, dest_schema, is_deep_copy)
+    is_deep_copy BOOLEAN NOT NULL DEFAULT true
 );
 
+CREATE UNIQUE INDEX contract_deps_uniq ON contract_deps(level, src_contract, src_bigmap, dest_schema, is_deep_copy);
+
 CREATE TABLE bigmap_keys(
     id BIGSERIAL PRIMARY KEY,
     bigmap_id INTEGER NOT NULL,
@@ -132,7 +200,7 @@ CREATE TABLE bigmap_keys(
     value JSONB,
 
     UNIQUE(tx_context_id, bigmap_id, keyhash),
-    FOREIGN KEY (tx_context_id) REFERENCES tx_contexts(id) ON DELETE CASCADE
+    FOREIGN KEY (tx_context_id) REFERENCES {tx_contexts_table}(id) ON DELETE CASCADE
 );
 
 
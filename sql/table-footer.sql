@@ -1 +1 @@
-    FOREIGN KEY (tx_context_id) REFERENCES tx_contexts(id) ON DELETE CASCADE);
+    FOREIGN KEY (tx_context_id) REFERENCES {tx_contexts_table}(id) ON DELETE CASCADE);
@@ -43,16 +43,16 @@ INSERT INTO "{{ contract_schema }}"."{{ table }}_live" (
     level, level_timestamp, id, tx_context_id, bigmap_id {% call unfold(columns, "", true) %}
 )
 SELECT
-    level,
-    level_timestamp,
+    ctx_level,
+    ctx_level_timestamp,
     id,
     tx_context_id,
     bigmap_id
     {% call unfold(columns, "", true) %}
 FROM (
     SELECT DISTINCT ON({% call unfold(indices, "t", false) %})
-        ctx.level AS level,
-        level_meta.baked_at AS level_timestamp,
+        ctx.level AS ctx_level,
+        level_meta.baked_at AS ctx_level_timestamp,
         t.*
     FROM "{{ contract_schema }}"."{{ table }}" t
     JOIN "{{ main_schema }}".tx_contexts ctx
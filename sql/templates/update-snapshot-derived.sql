@@ -42,6 +42,116 @@ FROM (
 ) t;
 
 
+{% if ordered_changes -%}
+-- each of the new snapshots of the parent table (holding a single row per
+-- snapshot) and the one preceding it
+WITH snapshots AS (
+    SELECT
+        ctx.id AS tx_context_id,
+        (
+            SELECT
+                prev_ctx.id
+            FROM "{{ contract_schema }}"."{{ parent_table }}" prev
+            JOIN "{{ main_schema }}".tx_contexts prev_ctx
+              ON prev_ctx.id = prev.tx_context_id
+            WHERE ARRAY[
+                  prev_ctx.level,
+                  prev_ctx.operation_group_number,
+                  prev_ctx.operation_number,
+                  prev_ctx.content_number,
+                  COALESCE(prev_ctx.internal_number, -1)]
+                <
+                ARRAY[
+                  ctx.level,
+                  ctx.operation_group_number,
+                  ctx.operation_number,
+                  ctx.content_number,
+                  COALESCE(ctx.internal_number, -1)]
+            ORDER BY
+                prev_ctx.level DESC,
+                prev_ctx.operation_group_number DESC,
+                prev_ctx.operation_number DESC,
+                prev_ctx.content_number DESC,
+                COALESCE(prev_ctx.internal_number, -1) DESC
+            LIMIT 1
+        ) AS prev_tx_context_id
+    FROM "{{ contract_schema }}"."{{ parent_table }}" p
+    JOIN "{{ main_schema }}".tx_contexts ctx
+      ON ctx.id = p.tx_context_id
+    WHERE ctx.id IN ({% call unfold(tx_context_ids, "", false) %})
+)
+INSERT INTO "{{ contract_schema }}"."{{ table }}_ordered" (
+    ordering, level, level_timestamp, id, tx_context_id, deleted {% call unfold(columns, "", true) %}
+)
+SELECT
+    ordering + COALESCE((SELECT max(ordering) FROM "{{ contract_schema }}"."{{ table }}_ordered"), 0) as ordering,
+    level,
+    level_timestamp,
+    id,
+    tx_context_id,
+    deleted
+    {% call unfold(columns, "", true) %}
+FROM (
+    SELECT
+        DENSE_RANK() OVER (
+            ORDER BY
+                ctx.level,
+                ctx.operation_group_number,
+                ctx.operation_number,
+                ctx.content_number,
+                COALESCE(ctx.internal_number, -1)
+        ) AS ordering,
+        ctx.level AS level,
+        level_meta.baked_at AS level_timestamp,
+        t.id,
+        t.tx_context_id,
+        t.deleted
+        {% call unfold(columns, "t", true) %}
+    FROM (
+        -- the elements that were added or changed since the previous snapshot
+        SELECT
+            t.tx_context_id,
+            t.id,
+            FALSE AS deleted
+            {% call unfold(columns, "t", true) %}
+        FROM snapshots s
+        JOIN "{{ contract_schema }}"."{{ table }}" t
+          ON t.tx_context_id = s.tx_context_id
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM "{{ contract_schema }}"."{{ table }}" prev
+            WHERE prev.tx_context_id = s.prev_tx_context_id
+              AND ROW({% call unfold(element_columns, "prev", false) %})
+                  IS NOT DISTINCT FROM
+                  ROW({% call unfold(element_columns, "t", false) %})
+        )
+
+        UNION ALL
+
+        -- the elements that were removed since the previous snapshot
+        SELECT
+            s.tx_context_id,
+            prev.id,
+            TRUE AS deleted
+            {% call unfold(columns, "prev", true) %}
+        FROM snapshots s
+        JOIN "{{ contract_schema }}"."{{ table }}" prev
+          ON prev.tx_context_id = s.prev_tx_context_id
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM "{{ contract_schema }}"."{{ table }}" t
+            WHERE t.tx_context_id = s.tx_context_id
+              AND ROW({% call unfold(element_keys, "t", false) %})
+                  IS NOT DISTINCT FROM
+                  ROW({% call unfold(element_keys, "prev", false) %})
+        )
+    ) t
+    JOIN "{{ main_schema }}".tx_contexts ctx
+      ON ctx.id = t.tx_context_id
+    JOIN "{{ main_schema }}".levels level_meta
+      ON level_meta.level = ctx.level
+) t;
+{%- else %}
 INSERT INTO "{{ contract_schema }}"."{{ table }}_ordered" (
     ordering, level, level_timestamp, id, tx_context_id {% call unfold(columns, "", true) %}
 )
@@ -74,3 +184,4 @@ FROM (
       ON level_meta.level = ctx.level
     WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
 ) t;
+{%- endif %}
@@ -0,0 +1,40 @@
+CREATE TABLE operations (
+    id BIGSERIAL PRIMARY KEY,
+    level INTEGER NOT NULL REFERENCES levels(level) ON DELETE CASCADE,
+    operation_group_number INTEGER NOT NULL,
+    operation_number INTEGER NOT NULL,
+    content_number INTEGER NOT NULL,
+    internal_number INTEGER
+);
+
+CREATE UNIQUE INDEX ON operations(
+    level,
+    operation_group_number,
+    operation_number,
+    content_number,
+    coalesce(internal_number, -1)
+);
+
+CREATE TABLE contract_tx_contexts (
+    id BIGINT NOT NULL PRIMARY KEY,
+    operation_id BIGINT NOT NULL REFERENCES operations(id) ON DELETE CASCADE,
+    contract TEXT NOT NULL,
+
+    UNIQUE(operation_id, contract)
+);
+
+CREATE INDEX ON contract_tx_contexts(contract);
+
+CREATE VIEW tx_contexts AS (
+    SELECT
+        ctx.id,
+        op.level,
+        ctx.contract,
+        op.operation_group_number,
+        op.operation_number,
+        op.content_number,
+        op.internal_number
+    FROM contract_tx_contexts ctx
+    JOIN operations op
+      ON op.id = ctx.operation_id
+);
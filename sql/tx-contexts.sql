@@ -0,0 +1,18 @@
+create table tx_contexts (
+    id bigint not null primary key,
+    level integer not null references levels(level) on delete cascade,
+    contract text not null,
+    operation_group_number integer not null,
+    operation_number integer not null,
+    content_number integer not null,
+    internal_number integer
+);
+
+CREATE UNIQUE INDEX ON tx_contexts(
+    level,
+    contract,
+    operation_group_number,
+    operation_number,
+    content_number,
+    coalesce(internal_number, -1)
+);
@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+
+use crate::config::ContractID;
+use crate::octez::node::{NodeClient, StorageGetter};
+
+// Fetches the block at a level from two nodes and returns what differs
+// between them, as far as indexing the contracts is concerned: the block's
+// hash, predecessor and protocol, and per contract its storage at the level
+// and the results of the operations calling it. Meant for finding out whether
+// a node serves bad data (see --compare-nodes).
+pub(crate) fn compare_nodes(
+    node_a: &NodeClient,
+    node_b: &NodeClient,
+    contracts: &[ContractID],
+    level: u32,
+) -> Result<serde_json::Value> {
+    let a = node_view(node_a, contracts, level)
+        .context("failed to fetch the level from the first node")?;
+    let b = node_view(node_b, contracts, level)
+        .context("failed to fetch the level from the second node")?;
+
+    let mut differences: Vec<serde_json::Value> = vec![];
+    diff_json("", &a, &b, &mut differences);
+    Ok(serde_json::json!({
+        "level": level,
+        "differences": differences,
+    }))
+}
+
+fn node_view(
+    node_cli: &NodeClient,
+    contracts: &[ContractID],
+    level: u32,
+) -> Result<serde_json::Value> {
+    let (meta, block) = node_cli.level_json(level)?;
+
+    let mut contract_views = serde_json::Map::new();
+    for contract in contracts {
+        let operations = block.map_all_tx_contexts(
+            |tx_context, tx, _, operation_result| {
+                if tx_context.contract != contract.address {
+                    return Ok(None);
+                }
+                Ok(Some(serde_json::json!({
+                    "tx_context": tx_context,
+                    "operation_hash": tx.operation_hash,
+                    "result": operation_result,
+                })))
+            },
+        )?;
+        // eg when the contract is not yet originated at this level, which
+        // then is only a difference if the other node does have its storage
+        let storage = node_cli
+            .get_contract_storage(&contract.address, level)
+            .unwrap_or_else(|e| {
+                warn!(
+                    "failed to get the storage of {} at level {}: {:?}",
+                    contract.name, level, e
+                );
+                serde_json::Value::Null
+            });
+        contract_views.insert(
+            contract.name.clone(),
+            serde_json::json!({
+                "storage": storage,
+                "operations": operations,
+            }),
+        );
+    }
+    Ok(serde_json::json!({
+        "hash": meta.hash,
+        "prev_hash": meta.prev_hash,
+        "protocol": meta.protocol,
+        "contracts": contract_views,
+    }))
+}
+
+// Adds a {path, a, b} difference for every value that differs between a and
+// b, descending into objects (by key) and arrays (by index). A value missing
+// on one side is reported as null on that side.
+fn diff_json(
+    path: &str,
+    a: &serde_json::Value,
+    b: &serde_json::Value,
+    res: &mut Vec<serde_json::Value>,
+) {
+    use serde_json::Value;
+
+    match (a, b) {
+        (Value::Object(a_fields), Value::Object(b_fields)) => {
+            let mut keys: Vec<&String> = a_fields
+                .keys()
+                .chain(b_fields.keys())
+                .collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let field_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_json(
+                    &field_path,
+                    a_fields
+                        .get(key)
+                        .unwrap_or(&Value::Null),
+                    b_fields
+                        .get(key)
+                        .unwrap_or(&Value::Null),
+                    res,
+                );
+            }
+        }
+        (Value::Array(a_elems), Value::Array(b_elems)) => {
+            for i in 0..std::cmp::max(a_elems.len(), b_elems.len()) {
+                diff_json(
+                    &format!("{}[{}]", path, i),
+                    a_elems.get(i).unwrap_or(&Value::Null),
+                    b_elems.get(i).unwrap_or(&Value::Null),
+                    res,
+                );
+            }
+        }
+        _ => {
+            if a != b {
+                res.push(serde_json::json!({
+                    "path": path,
+                    "a": a,
+                    "b": b,
+                }));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_diff_json() {
+    use serde_json::json;
+
+    let mut res: Vec<serde_json::Value> = vec![];
+    diff_json(
+        "",
+        &json!({"hash": "BLa", "ops": [1, 2], "same": {"x": [true]}, "only_a": 1}),
+        &json!({"hash": "BLb", "ops": [1, 3, 4], "same": {"x": [true]}}),
+        &mut res,
+    );
+    assert_eq!(
+        vec![
+            json!({"path": "hash", "a": "BLa", "b": "BLb"}),
+            json!({"path": "only_a", "a": 1, "b": null}),
+            json!({"path": "ops[1]", "a": 2, "b": 3}),
+            json!({"path": "ops[2]", "a": null, "b": 4}),
+        ],
+        res
+    );
+}
+
+#[test]
+fn test_compare_nodes() {
+    use crate::octez::node::mock_node;
+
+    let address = "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V";
+    let contracts = vec![ContractID {
+        name: "compared".to_string(),
+        address: address.to_string(),
+    }];
+    // the mock nodes serve the block for every request (the storage
+    // included), which is fine for comparing
+    let node_cli = |file: &str| {
+        let body: &'static str = Box::leak(
+            crate::debug::load_test(&format!("test/{}.{}", address, file))
+                .into_boxed_str(),
+        );
+        NodeClient::new(
+            vec![mock_node("200 OK", body)],
+            "compare".to_string(),
+            0,
+            1,
+        )
+    };
+
+    let res = compare_nodes(
+        &node_cli("level-11.json"),
+        &node_cli("level-11.json"),
+        &contracts,
+        11,
+    )
+    .unwrap();
+    assert_eq!(11, res["level"]);
+    assert_eq!(serde_json::json!([]), res["differences"]);
+
+    let res = compare_nodes(
+        &node_cli("level-11.json"),
+        &node_cli("level-12.json"),
+        &contracts,
+        11,
+    )
+    .unwrap();
+    let differences = res["differences"].as_array().unwrap();
+    assert!(differences
+        .iter()
+        .any(|difference| difference["path"] == "hash"));
+    assert!(differences
+        .iter()
+        .any(|difference| difference["path"]
+            .as_str()
+            .unwrap()
+            .starts_with("contracts.compared.")));
+}
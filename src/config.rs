@@ -1,34 +1,239 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use clap::{App, Arg};
 use serde_yaml;
 use smart_default::SmartDefault;
+use std::collections::HashMap;
 use std::fs;
+use std::path::PathBuf;
+
+use crate::storage_structure::relational::{
+    BigmapFilter, ColumnTypes, LayoutSettings, RenameMap,
+};
 
 #[derive(Clone, SmartDefault, Debug)]
 pub struct Config {
     pub main_schema: String,
 
     pub contracts: Vec<ContractID>,
+    // per contract (by name), the level from which on to index it, when set
+    // above its origination level
+    pub start_levels: HashMap<String, u32>,
+    // per contract (by name), the addresses of contracts it copies bigmaps
+    // from
+    pub contract_deps: HashMap<String, Vec<String>>,
+    // names of the contracts to never fast sync through better-call.dev
+    pub bcd_excluded: Vec<String>,
+    // per contract (by name), the priority with which its missing levels are
+    // indexed (higher first), when set above 0
+    pub priorities: HashMap<String, u32>,
     pub all_contracts: bool,
+    // addresses of contracts to never index, in addition to the built-in
+    // denylist
+    pub deny_contracts: Vec<String>,
+    // addresses of contracts to index even though they are denylisted
+    pub allow_denylisted: Vec<String>,
+    pub record_all_calls: bool,
+    pub index_failures: bool,
+    pub index_delegations: bool,
+    pub shared_tx_contexts: bool,
+    pub track_balance: bool,
+    pub keep_raw_storage: bool,
+    pub raw_storage_compression: StorageCompression,
+    pub empty_string_as_null: bool,
+    // set up new contracts without derived tables (_live, _ordered) and _at
+    // functions
+    pub no_derived_tables: bool,
+    pub latest_origination: bool,
+    pub deterministic_ids: bool,
+    // abort when a contract's tables cannot be derived (eg for an unsupported
+    // type), rather than skipping the contract
+    pub fail_on_contract_errors: bool,
+    pub layout_settings: LayoutSettings,
     pub database_url: String,
+    pub db_conn_retries: i32,
 
     pub reinit: bool,
+    pub drop_contract: Option<String>,
+    pub reprocess_contract: Option<String>,
+    pub reprocess_dependency: Option<(String, i32)>,
+    pub build_derived_tables: bool,
+    pub repair_derived: bool,
+    pub dump_ast: Option<String>,
+    // a block json file to process (without a db) and print the results of
+    pub replay_block: Option<PathBuf>,
+    // print the block's bigmap diffs (as processing sees them) instead
+    pub replay_bigmap_diffs: bool,
+    // the urls of the two nodes to compare, and the level to compare them at
+    pub compare_nodes: Option<(String, String, u32)>,
+    pub dump_format: DumpFormat,
+    // (contract name, (from, to) levels, limit, offset) of --list-calls
+    pub list_calls: Option<(String, (u32, u32), i64, i64)>,
+    pub find_operation: Option<String>,
+    pub bigmap_owner: Option<(i32, Option<u32>)>,
+    // report the gaps in the levels processed per contract, then quit
+    pub audit: bool,
     pub only_migrate: bool,
+    // only check the setup (nodes, db and contracts), then quit
+    pub validate_only: bool,
 
     pub levels: Vec<u32>,
+    pub level_range: Option<(u32, u32)>,
+    pub force: bool,
     pub node_urls: Vec<String>,
     pub node_comm_retries: i32,
+    pub block_source: BlockSource,
+    // (dir, max number of blocks) of the on-disk cache of fetched blocks,
+    // when set
+    pub block_cache: Option<(String, usize)>,
+    pub node_tls: NodeTlsSettings,
+    pub mode: RunMode,
 
     pub bcd_url: Option<String>,
     pub bcd_network: String,
+    // fast sync through better-call.dev only when more levels than this
+    // are missing
+    pub bcd_min_missing_levels: usize,
+
+    // Kafka REST proxy to produce the contracts' data to (instead of
+    // inserting it into the db), when set
+    pub kafka_url: Option<String>,
+    pub kafka_topic: String,
 
     pub getters_cap: usize,
+    pub node_conns_cap: usize,
     pub workers_cap: usize,
+    pub inserter_threads: usize,
+    #[default(10)]
+    pub inserter_batch_size: usize,
+    pub inserter_commit_interval: Option<std::time::Duration>,
+    pub max_buffered_block_bytes: Option<usize>,
+    pub address_cache_size: usize,
+    pub bigmap_copy_chunk_size: Option<usize>,
+    pub metadata_ipfs_gateway: String,
+    pub metadata_timeout: std::time::Duration,
+    pub metadata_fetches_per_sec: u32,
+    pub slow_block_threshold: Option<std::time::Duration>,
+    pub slowest_blocks: usize,
     pub always_yes: bool,
     pub reports_interval: usize,
+    pub report_table_sizes: bool,
+
+    pub healthz_port: Option<u16>,
+    pub healthz_max_lag: i64,
+    pub healthz_levels_feed: bool,
 
     #[default(_code = "chrono::Duration::hours(1)")]
     pub allowed_unbootstrapped_offset: chrono::Duration,
+
+    // the networks to index from this process (see --networks), when not
+    // set the network is set by the settings above
+    pub networks: Vec<NetworkConfig>,
+}
+
+// A network to index, with its own node, main schema and contracts.
+#[derive(Clone, Default, Debug)]
+pub struct NetworkConfig {
+    pub name: String,
+    pub node_urls: Vec<String>,
+    pub main_schema: String,
+
+    pub contracts: Vec<ContractID>,
+    pub start_levels: HashMap<String, u32>,
+    pub contract_deps: HashMap<String, Vec<String>>,
+    pub bcd_excluded: Vec<String>,
+    pub priorities: HashMap<String, u32>,
+    pub layout_settings: LayoutSettings,
+
+    pub healthz_port: Option<u16>,
+}
+
+impl Config {
+    // The networks to index, which is just the one set by the top level
+    // settings unless --networks is set.
+    pub fn networks(&self) -> Vec<NetworkConfig> {
+        if !self.networks.is_empty() {
+            return self.networks.clone();
+        }
+        vec![NetworkConfig {
+            name: "main".to_string(),
+            node_urls: self.node_urls.clone(),
+            main_schema: self.main_schema.clone(),
+            contracts: self.contracts.clone(),
+            start_levels: self.start_levels.clone(),
+            contract_deps: self.contract_deps.clone(),
+            bcd_excluded: self.bcd_excluded.clone(),
+            priorities: self.priorities.clone(),
+            layout_settings: self.layout_settings.clone(),
+            healthz_port: self.healthz_port,
+        }]
+    }
+}
+
+// Where blocks are read from: the node, or a directory with a {level}.json
+// file per block (see --block-archive).
+#[derive(Clone, SmartDefault, Debug, PartialEq)]
+pub enum BlockSource {
+    #[default]
+    Node,
+    Archive(String),
+}
+
+// How to connect to nodes behind mTLS, with a custom CA or with gated RPC
+// endpoints (see NodeClient::set_tls).
+#[derive(Clone, Default, PartialEq)]
+pub struct NodeTlsSettings {
+    // the client certificate and its key (PEM), for mTLS
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    // CA certificates (PEM) to verify the node's certificate with, instead
+    // of the system's
+    pub ca_bundle: Option<PathBuf>,
+    // sent as a bearer token in the Authorization header
+    pub auth_token: Option<String>,
+}
+
+// Keeps the auth token out of the logged config.
+impl std::fmt::Debug for NodeTlsSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeTlsSettings")
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &self.client_key)
+            .field("ca_bundle", &self.ca_bundle)
+            .field(
+                "auth_token",
+                &self
+                    .auth_token
+                    .as_ref()
+                    .map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+// Whether this process indexes, or only serves the indexed data of a db that
+// is indexed by another process (see --read-only).
+#[derive(Clone, Copy, SmartDefault, Debug, PartialEq)]
+pub enum RunMode {
+    #[default]
+    Index,
+    ReadOnly,
+}
+
+// How the raw storage (see --keep-raw-storage) is stored.
+#[derive(Clone, Copy, SmartDefault, Debug, PartialEq)]
+pub enum StorageCompression {
+    #[default]
+    None,
+    Gzip,
+}
+
+// The format in which dumps (eg of --dump-ast) are printed.
+#[derive(Clone, Copy, SmartDefault, Debug, PartialEq)]
+pub enum DumpFormat {
+    #[default]
+    Json,
+    Ron,
+    MessagePack,
 }
 
 #[derive(
@@ -79,6 +284,29 @@ pub fn init_config() -> Result<Config> {
                 .multiple(true)
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("networks")
+                .long("networks")
+                .value_name("NETWORKS")
+                .env("NETWORKS")
+                .help("path to a yaml with multiple networks to index from this process (each with its own node url, main schema and contracts), instead of the single one set by --node-url, --main-schema and the contract settings")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("rename_map")
+                .long("rename-map")
+                .value_name("RENAME_MAP")
+                .env("RENAME_MAP")
+                .help("path to a yaml with, per contract name, overrides of generated table names and column names")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("flatten_ors")
+                .long("flatten-ors")
+                .value_name("FLATTEN_ORS")
+                .help("store ors of which all variants are simple values in a single table (with a column per variant), instead of in a child table per variant")
+                .takes_value(false)
+        )
         .arg(
             Arg::with_name("index_all_contracts")
                 .long("index-all-contracts")
@@ -86,6 +314,87 @@ pub fn init_config() -> Result<Config> {
                 .help("index *all* contracts")
                 .takes_value(false)
         )
+        .arg(
+            Arg::with_name("deny_contracts")
+                .long("deny-contracts")
+                .value_name("DENY_CONTRACTS")
+                .env("DENY_CONTRACTS")
+                .help("comma separated addresses of contracts to never index (eg with --index-all-contracts), in addition to the built-in denylist")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("allow_denylisted")
+                .long("allow-denylisted")
+                .value_name("ALLOW_DENYLISTED")
+                .env("ALLOW_DENYLISTED")
+                .help("comma separated addresses of denylisted contracts to index anyway (overrides both the built-in denylist and --deny-contracts)")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("record_all_calls")
+                .long("record-all-calls")
+                .value_name("RECORD_ALL_CALLS")
+                .help("record the metadata (eg fee and gas) of every call to the indexed contracts in the txs table, including calls that failed or did not change the storage")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("index_failures")
+                .long("index-failures")
+                .value_name("INDEX_FAILURES")
+                .help("record the calls to the indexed contracts that failed or were backtracked, with the errors they failed with, in the operation_failures table")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("index_delegations")
+                .long("index-delegations")
+                .value_name("INDEX_DELEGATIONS")
+                .help("record the delegations with an indexed contract as source or delegate in the delegations table")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("report_table_sizes")
+                .long("report-table-sizes")
+                .value_name("REPORT_TABLE_SIZES")
+                .help("at the end of the run, print the (approximate) number of rows and the size of every table of the indexed contracts, largest first")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("track_balance")
+                .long("track-balance")
+                .value_name("TRACK_BALANCE")
+                .help("record the tez balance of the indexed contracts in the contract_balances table, for every level at which they are active")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("keep_raw_storage")
+                .long("keep-raw-storage")
+                .value_name("KEEP_RAW_STORAGE")
+                .help("store the storage of the indexed contracts after every call (and at origination) as the node returned it (as JSON) in the raw_storage table, in addition to its relational decomposition")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("empty_string_as_null")
+                .long("empty-string-as-null")
+                .value_name("EMPTY_STRING_AS_NULL")
+                .help("store empty strings as NULL (by default they are stored as empty strings, and only absent values, eg of options, as NULL)")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("raw_storage_compression")
+                .long("raw-storage-compression")
+                .value_name("COMPRESSION")
+                .possible_values(&["none", "gzip"])
+                .default_value("none")
+                .help("how --keep-raw-storage stores the storage: as JSONB (none), or as gzip compressed JSON in a BYTEA column (gzip)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shared_tx_contexts")
+                .long("shared-tx-contexts")
+                .value_name("SHARED_TX_CONTEXTS")
+                .help("when setting up the db, store the operation coordinates of tx contexts once (in the operations table) instead of once per contract. has no effect on an already set up db")
+                .takes_value(false)
+        )
         .arg(
             Arg::with_name("database_url")
                 .short("d")
@@ -95,6 +404,11 @@ pub fn init_config() -> Result<Config> {
                 .value_name("DATABASE_URL")
                 .help("The URL of the database")
                 .takes_value(true))
+        .arg(
+            Arg::with_name("expand_database_url")
+                .long("expand-database-url")
+                .help("If set, expand the ${VAR} env var references in the database url (see the node url), eg to keep the password out of it. Write $$ for a literal $")
+                .takes_value(false))
         .arg(
             Arg::with_name("node_url")
                 .short("n")
@@ -104,6 +418,58 @@ pub fn init_config() -> Result<Config> {
                 .value_name("NODE_URL")
                 .help("The URL of the Tezos node, optionally accepts more than 1 (comma separated) for fallback nodes in case of non-transcient communication issues with the primary node")
                 .takes_value(true))
+        .arg(
+            Arg::with_name("block_archive")
+                .long("block-archive")
+                .env("BLOCK_ARCHIVE")
+                .value_name("BLOCK_ARCHIVE")
+                .help("Read the blocks from this directory (with a <level>.json file per block) rather than from the node. The head is the highest level in the directory. All other requests (eg for contract scripts and storage) still go to the node")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("node_client_cert")
+                .long("node-client-cert")
+                .env("NODE_CLIENT_CERT")
+                .value_name("NODE_CLIENT_CERT")
+                .requires("node_client_key")
+                .help("Client certificate file (PEM) to authenticate to the node with, for nodes behind mTLS")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("node_client_key")
+                .long("node-client-key")
+                .env("NODE_CLIENT_KEY")
+                .value_name("NODE_CLIENT_KEY")
+                .requires("node_client_cert")
+                .help("Private key file (PEM) of the --node-client-cert")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("node_ca_bundle")
+                .long("node-ca-bundle")
+                .env("NODE_CA_BUNDLE")
+                .value_name("NODE_CA_BUNDLE")
+                .help("CA certificates file (PEM) to verify the node's certificate with, instead of the system's (eg for nodes with a certificate signed by a private CA)")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("node_auth_token")
+                .long("node-auth-token")
+                .env("NODE_AUTH_TOKEN")
+                .value_name("NODE_AUTH_TOKEN")
+                .help("Token to send to the node as a bearer token (in the Authorization header), for gated RPC endpoints")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("block_cache_dir")
+                .long("block-cache-dir")
+                .env("BLOCK_CACHE_DIR")
+                .value_name("BLOCK_CACHE_DIR")
+                .help("if set, keep the most recently fetched blocks (gzipped) in this directory, to not fetch them again when a level is processed again (eg when reprocessing the levels around a fork)")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("block_cache_size")
+                .long("block-cache-size")
+                .env("BLOCK_CACHE_SIZE")
+                .value_name("BLOCK_CACHE_SIZE")
+                .default_value("100")
+                .help("max number of blocks kept in the --block-cache-dir")
+                .takes_value(true))
         .arg(
             Arg::with_name("node_comm_retries")
                 .long("node-comm-retries")
@@ -112,6 +478,14 @@ pub fn init_config() -> Result<Config> {
                 .value_name("NODE_COMM_RETRIES")
                 .help("The number of times to retry a node RPC call on any error, set to smaller than 0 for infinite")
                 .takes_value(true))
+        .arg(
+            Arg::with_name("db_conn_retries")
+                .long("db-conn-retries")
+                .env("DB_CONN_RETRIES")
+                .default_value("5")
+                .value_name("DB_CONN_RETRIES")
+                .help("The number of times to retry getting a db connection (and inserting a batch of processed levels) on connection errors, set to smaller than 0 for infinite")
+                .takes_value(true))
         .arg(
             Arg::with_name("bcd_enable")
                 .long("bcd-enable")
@@ -133,6 +507,39 @@ pub fn init_config() -> Result<Config> {
                 .default_value("mainnet")
                 .help("For better-call.dev: name of the Tezos network to target")
                 .takes_value(true))
+        .arg(
+            Arg::with_name("bcd_min_missing_levels")
+                .long("bcd-min-missing-levels")
+                .value_name("BCD_MIN_MISSING_LEVELS")
+                .env("BCD_MIN_MISSING_LEVELS")
+                .default_value("1000")
+                .help("For better-call.dev: only fast sync when more than this many levels are missing, fewer missing levels are fetched from the node directly")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("sink")
+                .long("sink")
+                .value_name("SINK")
+                .env("SINK")
+                .possible_values(&["postgres", "kafka"])
+                .default_value("postgres")
+                .help("where to store the contracts' data: in their schemas in the db (postgres), or produced to a Kafka topic (kafka)")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("kafka_url")
+                .long("kafka-url")
+                .value_name("KAFKA_URL")
+                .env("KAFKA_URL")
+                .required_if("sink", "kafka")
+                .help("For --sink kafka: url of the Kafka REST proxy to produce to")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("kafka_topic")
+                .long("kafka-topic")
+                .value_name("KAFKA_TOPIC")
+                .env("KAFKA_TOPIC")
+                .default_value("que-pasa")
+                .help("For --sink kafka: name of the Kafka topic to produce to")
+                .takes_value(true))
         .arg(
             Arg::with_name("getters_cap")
                 .long("getters-cap")
@@ -142,6 +549,14 @@ pub fn init_config() -> Result<Config> {
                 .help("max number of processes used to concurrently fetch block data from the node (for faster bootstrap)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("node_conns_cap")
+                .long("node-conns-cap")
+                .value_name("NODE_CONNS_CAP")
+                .env("NODE_CONNS_CAP")
+                .help("max number of requests in flight to the node, across all getters (defaults to getters_cap). setting it lower than getters_cap allows for more getters without exceeding the node's connection budget")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("workers_cap")
                 .long("workers-cap")
@@ -151,6 +566,101 @@ pub fn init_config() -> Result<Config> {
                 .help("max number of processes used to concurrently process block data (for faster bootstrap)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("inserter_threads")
+                .long("inserter-threads")
+                .value_name("INSERTER_THREADS")
+                .env("INSERTER_THREADS")
+                .default_value("1")
+                .help("number of threads inserting processed levels into the db concurrently (during bootstrap)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("inserter_batch_size")
+                .long("inserter-batch-size")
+                .value_name("INSERTER_BATCH_SIZE")
+                .env("INSERTER_BATCH_SIZE")
+                .default_value("10")
+                .help("max number of processed levels inserted into the db per commit (during bootstrap)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("inserter_commit_interval_ms")
+                .long("inserter-commit-interval-ms")
+                .value_name("INSERTER_COMMIT_INTERVAL_MS")
+                .env("INSERTER_COMMIT_INTERVAL_MS")
+                .help("if set, commit the processed levels at least this many milliseconds after the previous commit, even if there are fewer than the inserter batch size (during bootstrap)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_buffered_blocks_mb")
+                .long("max-buffered-blocks-mb")
+                .value_name("MAX_BUFFERED_BLOCKS_MB")
+                .env("MAX_BUFFERED_BLOCKS_MB")
+                .help("if set, pause fetching blocks while the blocks fetched but not yet processed take up more than this many megabytes (approximately, by the size of their json)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("address_cache_size")
+                .long("address-cache-size")
+                .value_name("ADDRESS_CACHE_SIZE")
+                .env("ADDRESS_CACHE_SIZE")
+                .default_value("100000")
+                .help("max number of decoded addresses to cache (shared by all processes, 0 disables the cache)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bigmap_copy_chunk_size")
+                .long("bigmap-copy-chunk-size")
+                .value_name("BIGMAP_COPY_CHUNK_SIZE")
+                .env("BIGMAP_COPY_CHUNK_SIZE")
+                .help("if set, the rows of a bigmap copy are written to a temporary file every this many copied keys (and inserted from there), rather than kept in memory until the level is inserted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metadata_ipfs_gateway")
+                .long("metadata-ipfs-gateway")
+                .value_name("METADATA_IPFS_GATEWAY")
+                .env("METADATA_IPFS_GATEWAY")
+                .default_value("https://ipfs.io/ipfs")
+                .help("the gateway ipfs metadata uris are fetched through (see the metadata_uris contract setting)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metadata_timeout_ms")
+                .long("metadata-timeout-ms")
+                .value_name("METADATA_TIMEOUT_MS")
+                .env("METADATA_TIMEOUT_MS")
+                .default_value("10000")
+                .help("timeout of fetching the metadata a metadata uri points to, after which the uri is stored as unresolved")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metadata_fetches_per_sec")
+                .long("metadata-fetches-per-sec")
+                .value_name("METADATA_FETCHES_PER_SEC")
+                .env("METADATA_FETCHES_PER_SEC")
+                .default_value("5")
+                .help("the most metadata uris fetched per second (0 disables the limit)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("slow_block_threshold_ms")
+                .long("slow-block-threshold-ms")
+                .value_name("SLOW_BLOCK_THRESHOLD_MS")
+                .env("SLOW_BLOCK_THRESHOLD_MS")
+                .help("if set, log (at warn level) every contract block whose processing takes longer than this many milliseconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("slowest_blocks")
+                .long("slowest-blocks")
+                .value_name("SLOWEST_BLOCKS")
+                .env("SLOWEST_BLOCKS")
+                .default_value("10")
+                .help("number of slowest processed contract blocks to report at the end of the run (0 disables the report)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("levels")
                 .short("l")
@@ -160,6 +670,29 @@ pub fn init_config() -> Result<Config> {
                 .help("command the indexer to process an exact set of levels (format: single number, or a range with format from-to)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("from_level")
+                .long("from-level")
+                .value_name("FROM_LEVEL")
+                .requires("to_level")
+                .help("command the indexer to cleanly re-index the levels from this level on (up to and including --to-level): their indexed data is deleted first")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("to_level")
+                .long("to-level")
+                .value_name("TO_LEVEL")
+                .requires("from_level")
+                .help("see --from-level")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .value_name("FORCE")
+                .help("re-index the levels of --from-level/--to-level even if some of them were never indexed before")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("reports_interval")
                 .short("i")
@@ -170,6 +703,37 @@ pub fn init_config() -> Result<Config> {
                 .help("set the frequency of progress reports during bootstrap (unit: seconds). set to 0 to disable reports.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("healthz_port")
+                .long("healthz-port")
+                .value_name("HEALTHZ_PORT")
+                .env("HEALTHZ_PORT")
+                .help("if set, serve a /healthz endpoint on this port (responds with 200 when the indexer is at most --healthz-max-lag levels behind the chain's head, 503 otherwise)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read_only")
+                .long("read-only")
+                .value_name("READ_ONLY")
+                .help("Run as a read-only follower of a db that is indexed by another process: don't index (nor talk to the node), only serve /healthz (see --healthz-port), which then reports whether the db can be read and has indexed levels. The db is only read, with read-only transactions")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("healthz_max_lag")
+                .long("healthz-max-lag")
+                .value_name("HEALTHZ_MAX_LAG")
+                .env("HEALTHZ_MAX_LAG")
+                .default_value("10")
+                .help("the max number of levels the indexer is allowed to be behind the chain's head for /healthz to report healthy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("healthz_levels_feed")
+                .long("healthz-levels-feed")
+                .value_name("HEALTHZ_LEVELS_FEED")
+                .help("if set (together with --healthz-port), additionally serve a /levels endpoint that streams a server-sent event for every committed level (as JSON: {level, contracts, tx_count})")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("reinit")
                 .long("reinit")
@@ -177,6 +741,177 @@ pub fn init_config() -> Result<Config> {
                 .help("If set, clear the DB out and recreate global tables")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("drop_contract")
+                .long("drop-contract")
+                .value_name("DROP_CONTRACT")
+                .help("If set, remove all indexed data of the contract with this name from the DB (leaving other contracts intact), and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reprocess_contract")
+                .long("reprocess-contract")
+                .value_name("REPROCESS_CONTRACT")
+                .help("If set, delete all indexed data of the contract with this name and process it again at the levels it was indexed for (leaving other contracts intact), and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reprocess_dependency")
+                .long("reprocess-dependency")
+                .value_name("REPROCESS_DEPENDENCY")
+                .help("If set (format: <contract address>:<bigmap id>), delete and process again only the levels at which an indexed contract copied this bigmap of this contract (eg after the contract got indexed, so the copied keys are now known), and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump_ast")
+                .long("dump-ast")
+                .value_name("DUMP_AST")
+                .help("If set, print the relational AST and the derived tables of the contract with this name (or of all contracts, when set to 'all') as JSON (or in the --dump-format), and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replay_block")
+                .long("replay-block")
+                .value_name("REPLAY_BLOCK")
+                .help("If set, process the block in this JSON file (as the node returns it) for the contracts, without a db, and print what would be indexed for each contract (its inserts, tx contexts and bigmap actions) as JSON (or in the --dump-format), and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replay_bigmap_diffs")
+                .long("replay-bigmap-diffs")
+                .value_name("REPLAY_BIGMAP_DIFFS")
+                .requires("replay_block")
+                .help("If set, --replay-block prints the block's bigmap diffs instead: per tx context its bigmap operations, and the bigmaps it owns with their normalized diffs and the bigmaps they were copied from")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("compare_nodes")
+                .long("compare-nodes")
+                .value_names(&["NODE_URL_A", "NODE_URL_B"])
+                .number_of_values(2)
+                .requires("compare_level")
+                .help("If set, fetch the --compare-level from both these nodes and print the differences in the block and, for the contracts, in their storage and operation results as JSON (or in the --dump-format), and exit (with exit code 1 if anything differs)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compare_level")
+                .long("compare-level")
+                .value_name("COMPARE_LEVEL")
+                .requires("compare_nodes")
+                .help("The level to compare the nodes at, see --compare-nodes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump_format")
+                .long("dump-format")
+                .value_name("DUMP_FORMAT")
+                .possible_values(&["json", "ron", "msgpack"])
+                .default_value("json")
+                .help("the format in which --dump-ast and --replay-block print (msgpack is binary)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list_calls")
+                .long("list-calls")
+                .value_name("LIST_CALLS")
+                .requires("list_calls_levels")
+                .help("If set, print the calls to the contract with this name in the --list-calls-levels (with their operation hash, entrypoint, source, amount and the id of their row in the entrypoint's table) as JSON (or in the --dump-format), and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list_calls_levels")
+                .long("list-calls-levels")
+                .value_name("LIST_CALLS_LEVELS")
+                .help("For --list-calls: the range of levels to list the calls of (format: from-to, inclusive)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list_calls_limit")
+                .long("list-calls-limit")
+                .value_name("LIST_CALLS_LIMIT")
+                .default_value("100")
+                .help("For --list-calls: the max number of calls to print")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list_calls_offset")
+                .long("list-calls-offset")
+                .value_name("LIST_CALLS_OFFSET")
+                .default_value("0")
+                .help("For --list-calls: the number of calls to skip (for paginating through the calls)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("skip_derived_tables")
+                .long("skip-derived-tables")
+                .value_name("SKIP_DERIVED_TABLES")
+                .help("set up new contracts without the derived tables (_live, _ordered) and _at functions, which are then not maintained for these contracts either (speeds up bootstrapping when only querying the base tables)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("latest_origination")
+                .long("latest-origination")
+                .value_name("LATEST_ORIGINATION")
+                .help("For contracts that were originated more than once at the same address (eg across a reorg), start indexing at their most recent origination rather than at their first one")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("deterministic_ids")
+                .long("deterministic-ids")
+                .value_name("DETERMINISTIC_IDS")
+                .help("derive the ids of the rows (and tx contexts) from what they identify (the tx context and table), rather than counting them, so that reprocessing a level yields the same ids")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("fail_on_contract_errors")
+                .long("fail-on-contract-errors")
+                .value_name("FAIL_ON_CONTRACT_ERRORS")
+                .help("abort when the tables of a dependency contract (or, with --index-all-contracts, of any contract) cannot be derived, eg because of an unsupported type, rather than skipping it (and recording it in the failed_contracts table)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("build_derived_tables")
+                .long("build-derived-tables")
+                .value_name("BUILD_DERIVED_TABLES")
+                .help("If set, add the derived tables (_live, _ordered) and _at functions to the indexed contracts that were set up without them (with --skip-derived-tables), populate them, and exit")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("repair_derived")
+                .long("repair-derived")
+                .value_name("REPAIR_DERIVED")
+                .help("If set, repopulate the derived tables (_live, _ordered) of the indexed contracts that are stale (that do not cover the latest changes of their table), leaving the others as they are, and exit")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("audit")
+                .long("audit")
+                .value_name("AUDIT")
+                .help("If set, print the gaps in the levels processed for the indexed contracts (the ranges of levels missing between the lowest and highest level processed per contract) as JSON (or in the --dump-format), and exit. Exits with 1 if there are any gaps")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("find_operation")
+                .long("find-operation")
+                .value_name("FIND_OPERATION")
+                .help("If set, print the tx contexts of the calls to indexed contracts made by the operation with this hash (including those of its internal operations) as JSON (or in the --dump-format), and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bigmap_owner")
+                .long("bigmap-owner")
+                .value_name("BIGMAP_OWNER")
+                .help("If set, print the contract and table that owned the bigmap with this id (as of its most recent alloc, or of its most recent alloc at or before --bigmap-owner-level) as JSON (or in the --dump-format), and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bigmap_owner_level")
+                .long("bigmap-owner-level")
+                .value_name("BIGMAP_OWNER_LEVEL")
+                .requires("bigmap_owner")
+                .help("For --bigmap-owner: the level to get the owner at")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("only_migrate")
                 .long("only-migrate")
@@ -184,6 +919,13 @@ pub fn init_config() -> Result<Config> {
                 .help("If set, apply migrations (if any applicable) and then quit without processing levels.")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("validate_config")
+                .long("validate-config")
+                .value_name("VALIDATE_CONFIG")
+                .help("If set, check that the nodes and the db are reachable, that the db was set up by a compatible version, and that the contracts exist, report the outcome and quit without writing anything. Exits nonzero if any check failed.")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("always_yes")
                 .long("always-yes")
@@ -211,7 +953,22 @@ unfortunately.")
 
     if let Some(fpath) = matches.value_of("contract_settings") {
         info!("loading contract settings from {}", fpath);
-        config.contracts = parse_contract_settings_file(fpath).unwrap();
+        let mut network = NetworkConfig::default();
+        add_contract_settings(
+            &mut network,
+            parse_contract_settings_file(fpath)?,
+        );
+        config.contracts = network.contracts;
+        config.start_levels = network.start_levels;
+        config.contract_deps = network.contract_deps;
+        config.bcd_excluded = network.bcd_excluded;
+        config.priorities = network.priorities;
+        config.layout_settings = network.layout_settings;
+    }
+    if let Some(fpath) = matches.value_of("rename_map") {
+        info!("loading table and column renames from {}", fpath);
+        config.layout_settings.rename_maps =
+            parse_rename_map_file(fpath).unwrap();
     }
     if let Some(contracts) = matches.values_of("contracts") {
         config.contracts.extend(
@@ -227,10 +984,14 @@ unfortunately.")
         );
     }
 
-    config.database_url = matches
+    let database_url = matches
         .value_of("database_url")
-        .unwrap()
-        .to_string();
+        .unwrap();
+    config.database_url = if matches.is_present("expand_database_url") {
+        expand_env_vars(database_url).context("bad database url")?
+    } else {
+        database_url.to_string()
+    };
 
     config.allowed_unbootstrapped_offset = duration_str::parse_chrono(
         matches
@@ -239,26 +1000,165 @@ unfortunately.")
     )?;
 
     config.reinit = matches.is_present("reinit");
+    config.drop_contract = matches
+        .value_of("drop_contract")
+        .map(String::from);
+    config.reprocess_contract = matches
+        .value_of("reprocess_contract")
+        .map(String::from);
+    if let Some(dep) = matches.value_of("reprocess_dependency") {
+        let (src_contract, bigmap_id) = dep.split_once(':').ok_or_else(|| {
+            anyhow!("bad --reprocess-dependency format (expected: <contract address>:<bigmap id>, got {})", dep)
+        })?;
+        config.reprocess_dependency =
+            Some((src_contract.to_string(), bigmap_id.parse::<i32>()?));
+    }
+    config.dump_ast = matches
+        .value_of("dump_ast")
+        .map(String::from);
+    config.replay_block = matches
+        .value_of("replay_block")
+        .map(PathBuf::from);
+    config.replay_bigmap_diffs = matches.is_present("replay_bigmap_diffs");
+    if let Some(mut urls) = matches.values_of("compare_nodes") {
+        let level = matches
+            .value_of("compare_level")
+            .unwrap()
+            .parse::<u32>()?;
+        config.compare_nodes = Some((
+            expand_env_vars(urls.next().unwrap())?,
+            expand_env_vars(urls.next().unwrap())?,
+            level,
+        ));
+    }
+    config.dump_format = match matches.value_of("dump_format") {
+        Some("ron") => DumpFormat::Ron,
+        Some("msgpack") => DumpFormat::MessagePack,
+        _ => DumpFormat::Json,
+    };
+    if let Some(name) = matches.value_of("list_calls") {
+        let levels = matches
+            .value_of("list_calls_levels")
+            .unwrap();
+        let (from, to) = levels
+            .split_once('-')
+            .ok_or_else(|| {
+                anyhow!(
+                    "bad --list-calls-levels format (expected: <from>-<to>, got {})",
+                    levels
+                )
+            })?;
+        config.list_calls = Some((
+            name.to_string(),
+            (from.parse::<u32>()?, to.parse::<u32>()?),
+            matches
+                .value_of("list_calls_limit")
+                .unwrap()
+                .parse::<i64>()?,
+            matches
+                .value_of("list_calls_offset")
+                .unwrap()
+                .parse::<i64>()?,
+        ));
+    }
+    config.find_operation = matches
+        .value_of("find_operation")
+        .map(String::from);
+    if let Some(bigmap_id) = matches.value_of("bigmap_owner") {
+        config.bigmap_owner = Some((
+            bigmap_id.parse::<i32>()?,
+            matches
+                .value_of("bigmap_owner_level")
+                .map(|level| level.parse::<u32>())
+                .transpose()?,
+        ));
+    }
     config.only_migrate = matches.is_present("only_migrate");
+    config.validate_only = matches.is_present("validate_config");
     config.all_contracts = matches.is_present("index_all_contracts");
+    config.deny_contracts = matches
+        .value_of("deny_contracts")
+        .map_or_else(Vec::new, addresses);
+    config.allow_denylisted = matches
+        .value_of("allow_denylisted")
+        .map_or_else(Vec::new, addresses);
+    config.record_all_calls = matches.is_present("record_all_calls");
+    config.index_failures = matches.is_present("index_failures");
+    config.index_delegations = matches.is_present("index_delegations");
+    config.shared_tx_contexts = matches.is_present("shared_tx_contexts");
+    config.track_balance = matches.is_present("track_balance");
+    config.keep_raw_storage = matches.is_present("keep_raw_storage");
+    config.empty_string_as_null = matches.is_present("empty_string_as_null");
+    config.raw_storage_compression =
+        match matches.value_of("raw_storage_compression") {
+            Some("gzip") => StorageCompression::Gzip,
+            _ => StorageCompression::None,
+        };
+    config.no_derived_tables = matches.is_present("skip_derived_tables");
+    config.latest_origination = matches.is_present("latest_origination");
+    config.deterministic_ids = matches.is_present("deterministic_ids");
+    config.fail_on_contract_errors =
+        matches.is_present("fail_on_contract_errors");
+    config.build_derived_tables = matches.is_present("build_derived_tables");
+    config.repair_derived = matches.is_present("repair_derived");
+    config.audit = matches.is_present("audit");
+    config.report_table_sizes = matches.is_present("report_table_sizes");
+    config.layout_settings.flatten_ors = matches.is_present("flatten_ors");
     config.always_yes = matches.is_present("always_yes");
 
     config.levels = matches
         .value_of("levels")
         .map_or_else(Vec::new, range);
+    if let (Some(from), Some(to)) =
+        (matches.value_of("from_level"), matches.value_of("to_level"))
+    {
+        config.level_range = Some((from.parse::<u32>()?, to.parse::<u32>()?));
+    }
+    config.force = matches.is_present("force");
 
-    config.node_urls = matches
-        .value_of("node_url")
-        .unwrap()
+    config.node_urls = expand_env_vars(matches.value_of("node_url").unwrap())
+        .context("bad node url")?
         .split(',')
         .map(|s| s.to_string())
         .collect();
 
+    if let Some(dir) = matches.value_of("block_archive") {
+        config.block_source = BlockSource::Archive(dir.to_string());
+    }
+    config.node_tls = NodeTlsSettings {
+        client_cert: matches
+            .value_of("node_client_cert")
+            .map(PathBuf::from),
+        client_key: matches
+            .value_of("node_client_key")
+            .map(PathBuf::from),
+        ca_bundle: matches
+            .value_of("node_ca_bundle")
+            .map(PathBuf::from),
+        auth_token: matches
+            .value_of("node_auth_token")
+            .map(|token| token.to_string()),
+    };
+    if let Some(dir) = matches.value_of("block_cache_dir") {
+        config.block_cache = Some((
+            dir.to_string(),
+            matches
+                .value_of("block_cache_size")
+                .unwrap()
+                .parse::<usize>()?,
+        ));
+    }
+
     config.node_comm_retries = matches
         .value_of("node_comm_retries")
         .unwrap()
         .parse::<i32>()?;
 
+    config.db_conn_retries = matches
+        .value_of("db_conn_retries")
+        .unwrap()
+        .parse::<i32>()?;
+
     if matches.is_present("bcd_enable") {
         config.bcd_url = matches
             .value_of("bcd_url")
@@ -270,6 +1170,23 @@ unfortunately.")
         if config.bcd_network == "ithacanet" {
             config.bcd_network = "ghostnet".to_string();
         }
+        config.bcd_min_missing_levels = matches
+            .value_of("bcd_min_missing_levels")
+            .unwrap()
+            .parse::<usize>()?;
+    }
+
+    if matches.value_of("sink") == Some("kafka") {
+        if config.all_contracts || config.bcd_url.is_some() {
+            return Err(anyhow!("--sink kafka cannot be combined with --index-all-contracts or --bcd-enable (the levels would not be produced in order)"));
+        }
+        config.kafka_url = matches
+            .value_of("kafka_url")
+            .map(String::from);
+        config.kafka_topic = matches
+            .value_of("kafka_topic")
+            .unwrap()
+            .to_string();
     }
 
     config.reports_interval = matches
@@ -277,6 +1194,16 @@ unfortunately.")
         .unwrap()
         .parse::<usize>()?;
 
+    config.healthz_port = matches
+        .value_of("healthz_port")
+        .map(|p| p.parse::<u16>())
+        .transpose()?;
+    config.healthz_max_lag = matches
+        .value_of("healthz_max_lag")
+        .unwrap()
+        .parse::<i64>()?;
+    config.healthz_levels_feed = matches.is_present("healthz_levels_feed");
+
     config.getters_cap = matches
         .value_of("getters_cap")
         .unwrap()
@@ -288,6 +1215,17 @@ unfortunately.")
         );
         config.getters_cap = 1;
     }
+    config.node_conns_cap = match matches.value_of("node_conns_cap") {
+        Some(cap) => cap.parse::<usize>()?,
+        None => config.getters_cap,
+    };
+    if config.node_conns_cap == 0 {
+        warn!(
+            "set node_conns_cap ({}) is invalid. defaulting to 1",
+            config.node_conns_cap
+        );
+        config.node_conns_cap = 1;
+    }
     config.workers_cap = matches
         .value_of("workers_cap")
         .unwrap()
@@ -300,11 +1238,143 @@ unfortunately.")
         );
         config.workers_cap = 1;
     }
+    config.inserter_threads = matches
+        .value_of("inserter_threads")
+        .unwrap()
+        .parse::<usize>()?;
+    if config.inserter_threads == 0 {
+        warn!(
+            "set inserter_threads ({}) is invalid. defaulting to 1",
+            config.inserter_threads
+        );
+        config.inserter_threads = 1;
+    }
+    config.inserter_batch_size = matches
+        .value_of("inserter_batch_size")
+        .unwrap()
+        .parse::<usize>()?;
+    if config.inserter_batch_size == 0 {
+        warn!(
+            "set inserter_batch_size ({}) is invalid. defaulting to 1",
+            config.inserter_batch_size
+        );
+        config.inserter_batch_size = 1;
+    }
+    config.inserter_commit_interval = matches
+        .value_of("inserter_commit_interval_ms")
+        .map(|ms| ms.parse::<u64>())
+        .transpose()?
+        .filter(|ms| *ms > 0)
+        .map(std::time::Duration::from_millis);
+    config.max_buffered_block_bytes = matches
+        .value_of("max_buffered_blocks_mb")
+        .map(|mb| mb.parse::<usize>())
+        .transpose()?
+        .map(|mb| mb * 1024 * 1024);
+    config.address_cache_size = matches
+        .value_of("address_cache_size")
+        .unwrap()
+        .parse::<usize>()?;
+    config.bigmap_copy_chunk_size = matches
+        .value_of("bigmap_copy_chunk_size")
+        .map(|n| n.parse::<usize>())
+        .transpose()?
+        .filter(|n| *n > 0);
+    config.metadata_ipfs_gateway = matches
+        .value_of("metadata_ipfs_gateway")
+        .unwrap()
+        .to_string();
+    config.metadata_timeout = std::time::Duration::from_millis(
+        matches
+            .value_of("metadata_timeout_ms")
+            .unwrap()
+            .parse::<u64>()?,
+    );
+    config.metadata_fetches_per_sec = matches
+        .value_of("metadata_fetches_per_sec")
+        .unwrap()
+        .parse::<u32>()?;
+
+    config.slow_block_threshold = matches
+        .value_of("slow_block_threshold_ms")
+        .map(|ms| ms.parse::<u64>())
+        .transpose()?
+        .map(std::time::Duration::from_millis);
+    config.slowest_blocks = matches
+        .value_of("slowest_blocks")
+        .unwrap()
+        .parse::<usize>()?;
+
+    if matches.is_present("read_only") {
+        config.mode = RunMode::ReadOnly;
+        if config.reinit
+            || config.drop_contract.is_some()
+            || config.reprocess_contract.is_some()
+            || config.reprocess_dependency.is_some()
+            || config.build_derived_tables
+            || config.repair_derived
+            || config.only_migrate
+            || !config.levels.is_empty()
+            || config.level_range.is_some()
+        {
+            return Err(anyhow!("--read-only cannot be combined with --reinit, --drop-contract, --reprocess-contract, --reprocess-dependency, --build-derived-tables, --repair-derived, --only-migrate, --levels or --from-level"));
+        }
+    }
+
+    if config.validate_only
+        && (config.reinit
+            || config.drop_contract.is_some()
+            || config.reprocess_contract.is_some()
+            || config.reprocess_dependency.is_some()
+            || config.build_derived_tables
+            || config.repair_derived
+            || config.only_migrate
+            || config.mode == RunMode::ReadOnly)
+    {
+        return Err(anyhow!("--validate-config cannot be combined with --reinit, --drop-contract, --reprocess-contract, --reprocess-dependency, --build-derived-tables, --repair-derived, --only-migrate or --read-only"));
+    }
+
+    if let Some(fpath) = matches.value_of("networks") {
+        if config.reinit
+            || config.drop_contract.is_some()
+            || config.reprocess_contract.is_some()
+            || config.reprocess_dependency.is_some()
+            || config.build_derived_tables
+            || config.repair_derived
+            || config.dump_ast.is_some()
+            || config.replay_block.is_some()
+            || config.compare_nodes.is_some()
+            || config.list_calls.is_some()
+            || config.find_operation.is_some()
+            || config.bigmap_owner.is_some()
+            || config.audit
+            || config.all_contracts
+            || config.bcd_url.is_some()
+            || !config.levels.is_empty()
+            || config.level_range.is_some()
+            || config.block_source != BlockSource::Node
+        {
+            return Err(anyhow!("--networks cannot be combined with --reinit, --drop-contract, --reprocess-contract, --reprocess-dependency, --build-derived-tables, --repair-derived, --dump-ast, --replay-block, --compare-nodes, --list-calls, --find-operation, --bigmap-owner, --audit, --index-all-contracts, --bcd-enable, --levels, --from-level or --block-archive"));
+        }
+        if !config.contracts.is_empty() {
+            return Err(anyhow!("--networks cannot be combined with --contract-settings or --contracts (set the contracts per network instead)"));
+        }
+        info!("loading networks from {}", fpath);
+        config.networks = parse_networks_file(fpath, &config.layout_settings)?;
+    }
 
     debug!("Config={:#?}", config);
     Ok(config)
 }
 
+// get the addresses of an arg in the form KT1..,KT1..
+fn addresses(arg: &str) -> Vec<String> {
+    arg.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
 // get range of args in the form 1,2,3 or 1-3. All ranges inclusive.
 fn range(arg: &str) -> Vec<u32> {
     let mut result = vec![];
@@ -329,12 +1399,530 @@ fn range(arg: &str) -> Vec<u32> {
     result
 }
 
-fn parse_contract_settings_file(fpath: &str) -> Result<Vec<ContractID>> {
+#[derive(Serialize, Deserialize)]
+struct ContractSettings {
+    #[serde(flatten)]
+    cid: ContractID,
+    #[serde(default)]
+    start_level: Option<u32>,
+    #[serde(default)]
+    table_snapshots: HashMap<String, bool>,
+    #[serde(default)]
+    root_table: Option<String>,
+    #[serde(default)]
+    deps: Vec<String>,
+    // whether to fast sync the contract through better-call.dev (when
+    // enabled), defaults to true
+    #[serde(default)]
+    bcd: Option<bool>,
+    // contracts with a higher priority have their missing levels indexed
+    // first, defaults to 0
+    #[serde(default)]
+    priority: u32,
+    #[serde(default)]
+    column_types: Option<ColumnTypes>,
+    // table => bytes column => michelson type of the packed values
+    #[serde(default)]
+    packed_columns: HashMap<String, HashMap<String, serde_json::Value>>,
+    // table => columns to leave out
+    #[serde(default)]
+    exclude_columns: HashMap<String, Vec<String>>,
+    // the bigmaps to index (by table), defaults to all of them
+    #[serde(default)]
+    bigmaps: Option<BigmapFilter>,
+    // table => column holding the URIs of off-chain metadata to resolve
+    #[serde(default)]
+    metadata_uris: HashMap<String, String>,
+}
+
+fn add_contract_settings(
+    network: &mut NetworkConfig,
+    settings: Vec<ContractSettings>,
+) {
+    for c in settings {
+        if let Some(lvl) = c.start_level {
+            network
+                .start_levels
+                .insert(c.cid.name.clone(), lvl);
+        }
+        if !c.deps.is_empty() {
+            network
+                .contract_deps
+                .insert(c.cid.name.clone(), c.deps);
+        }
+        if c.bcd == Some(false) {
+            network
+                .bcd_excluded
+                .push(c.cid.name.clone());
+        }
+        if c.priority > 0 {
+            network
+                .priorities
+                .insert(c.cid.name.clone(), c.priority);
+        }
+        if let Some(root_table) = c.root_table {
+            network
+                .layout_settings
+                .root_tables
+                .insert(c.cid.name.clone(), root_table);
+        }
+        if !c.table_snapshots.is_empty() {
+            network
+                .layout_settings
+                .table_snapshots
+                .insert(c.cid.name.clone(), c.table_snapshots);
+        }
+        if let Some(column_types) = c.column_types {
+            network
+                .layout_settings
+                .column_types
+                .insert(c.cid.name.clone(), column_types);
+        }
+        if !c.packed_columns.is_empty() {
+            network
+                .layout_settings
+                .packed_columns
+                .insert(c.cid.name.clone(), c.packed_columns);
+        }
+        if !c.exclude_columns.is_empty() {
+            network
+                .layout_settings
+                .excluded_columns
+                .insert(c.cid.name.clone(), c.exclude_columns);
+        }
+        if let Some(bigmaps) = c.bigmaps {
+            network
+                .layout_settings
+                .bigmap_filters
+                .insert(c.cid.name.clone(), bigmaps);
+        }
+        if !c.metadata_uris.is_empty() {
+            network
+                .layout_settings
+                .metadata_uris
+                .insert(c.cid.name.clone(), c.metadata_uris);
+        }
+        network.contracts.push(c.cid);
+    }
+}
+
+#[derive(Deserialize)]
+struct NetworkSettings {
+    name: String,
+    node_url: String,
+    main_schema: String,
+    // path to a contract settings yaml, in addition to the contracts below
+    #[serde(default)]
+    contract_settings: Option<String>,
+    #[serde(default)]
+    contracts: Vec<ContractSettings>,
+    #[serde(default)]
+    healthz_port: Option<u16>,
+}
+
+// Parses the networks yaml. The layout settings that are not set per
+// contract (eg the rename maps) apply to all networks.
+fn parse_networks_file(
+    fpath: &str,
+    layout_settings: &LayoutSettings,
+) -> Result<Vec<NetworkConfig>> {
     let content = fs::read_to_string(fpath)?;
-    #[derive(Serialize, Deserialize)]
+    #[derive(Deserialize)]
     struct ParseType {
-        contracts: Vec<ContractID>,
+        networks: Vec<NetworkSettings>,
     }
     let res: ParseType = serde_yaml::from_str(&content)?;
+
+    let mut networks: Vec<NetworkConfig> = vec![];
+    for mut n in res.networks {
+        expand_contract_addresses(&mut n.contracts)?;
+        let mut network = NetworkConfig {
+            node_urls: expand_env_vars(&n.node_url)
+                .with_context(|| format!("bad node url of network {}", n.name))?
+                .split(',')
+                .map(|s| s.to_string())
+                .collect(),
+            name: n.name,
+            main_schema: n.main_schema,
+            layout_settings: LayoutSettings {
+                rename_maps: layout_settings.rename_maps.clone(),
+                flatten_ors: layout_settings.flatten_ors,
+                ..Default::default()
+            },
+            healthz_port: n.healthz_port,
+            ..Default::default()
+        };
+        if let Some(fpath) = n.contract_settings {
+            add_contract_settings(
+                &mut network,
+                parse_contract_settings_file(&fpath)?,
+            );
+        }
+        add_contract_settings(&mut network, n.contracts);
+        networks.push(network);
+    }
+    assert_networks_ok(&networks)?;
+    Ok(networks)
+}
+
+// Networks must not share anything in the db: their main schemas (holding
+// the common tables) must differ, as must their contracts' names (which name
+// the contracts' schemas).
+fn assert_networks_ok(networks: &[NetworkConfig]) -> Result<()> {
+    if networks.is_empty() {
+        return Err(anyhow!("bad networks settings: no networks set"));
+    }
+    let mut contract_networks: HashMap<&str, &str> = HashMap::new();
+    for (i, network) in networks.iter().enumerate() {
+        for other in &networks[..i] {
+            if other.name == network.name {
+                return Err(anyhow!(
+                    "bad networks settings: multiple networks named '{}'",
+                    network.name
+                ));
+            }
+            if other.main_schema == network.main_schema {
+                return Err(anyhow!(
+                    "bad networks settings: networks {} and {} share main schema '{}'",
+                    other.name,
+                    network.name,
+                    network.main_schema
+                ));
+            }
+            if other.healthz_port.is_some()
+                && other.healthz_port == network.healthz_port
+            {
+                return Err(anyhow!(
+                    "bad networks settings: networks {} and {} share healthz port {}",
+                    other.name,
+                    network.name,
+                    network.healthz_port.unwrap()
+                ));
+            }
+        }
+        for contract_id in &network.contracts {
+            if let Some(other) =
+                contract_networks.insert(&contract_id.name, &network.name)
+            {
+                if other != network.name {
+                    return Err(anyhow!(
+                        "bad networks settings: contract name '{}' is used in networks {} and {} (contract names name their schema, so must be unique across networks)",
+                        contract_id.name,
+                        other,
+                        network.name
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_contract_settings_file(fpath: &str) -> Result<Vec<ContractSettings>> {
+    let content = fs::read_to_string(fpath)?;
+    #[derive(Serialize, Deserialize)]
+    struct ParseType {
+        contracts: Vec<ContractSettings>,
+    }
+    let mut res: ParseType = serde_yaml::from_str(&content)?;
+    expand_contract_addresses(&mut res.contracts)?;
     Ok(res.contracts)
 }
+
+fn expand_contract_addresses(settings: &mut [ContractSettings]) -> Result<()> {
+    for c in settings {
+        c.cid.address = expand_env_vars(&c.cid.address).with_context(|| {
+            format!("bad address of contract {}", c.cid.name)
+        })?;
+    }
+    Ok(())
+}
+
+// Replaces each `${VAR}` with the value of env var VAR, eg to not have to put
+// contract addresses and node urls literally in the settings. `$$` is a
+// literal `$`, as is a `$` followed by anything else. Only applies to the
+// settings that say so (not to the settings yaml as a whole).
+fn expand_env_vars(s: &str) -> Result<String> {
+    expand_vars(s, |var| std::env::var(var).ok())
+}
+
+fn expand_vars<F>(s: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut res = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        res.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            res.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated env var reference"))?;
+            let var = &after[..end];
+            if var.is_empty() {
+                return Err(anyhow!("empty env var reference: ${{}}"));
+            }
+            let value = lookup(var)
+                .ok_or_else(|| anyhow!("env var {} is not set", var))?;
+            res.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            res.push('$');
+        }
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+fn parse_rename_map_file(fpath: &str) -> Result<HashMap<String, RenameMap>> {
+    let content = fs::read_to_string(fpath)?;
+    let res: HashMap<String, RenameMap> = serde_yaml::from_str(&content)?;
+    Ok(res)
+}
+
+#[test]
+fn test_assert_networks_ok() {
+    let network =
+        |name: &str, main_schema: &str, contracts: &[&str]| NetworkConfig {
+            name: name.to_string(),
+            main_schema: main_schema.to_string(),
+            contracts: contracts
+                .iter()
+                .map(|c| ContractID {
+                    name: c.to_string(),
+                    address: format!("KT1{}", c),
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+    assert!(assert_networks_ok(&[
+        network("mainnet", "que_pasa_mainnet", &["a", "b"]),
+        network("ghostnet", "que_pasa_ghostnet", &["c"]),
+    ])
+    .is_ok());
+    assert!(assert_networks_ok(&[]).is_err());
+    assert!(assert_networks_ok(&[
+        network("mainnet", "que_pasa_mainnet", &[]),
+        network("mainnet", "que_pasa_ghostnet", &[]),
+    ])
+    .is_err());
+    assert!(assert_networks_ok(&[
+        network("mainnet", "que_pasa", &[]),
+        network("ghostnet", "que_pasa", &[]),
+    ])
+    .is_err());
+    assert!(assert_networks_ok(&[
+        network("mainnet", "que_pasa_mainnet", &["a", "b"]),
+        network("ghostnet", "que_pasa_ghostnet", &["b"]),
+    ])
+    .is_err());
+
+    let mut with_port = network("ghostnet", "que_pasa_ghostnet", &[]);
+    with_port.healthz_port = Some(8080);
+    let mut other_with_port = network("mainnet", "que_pasa_mainnet", &[]);
+    other_with_port.healthz_port = Some(8080);
+    assert!(assert_networks_ok(&[other_with_port, with_port]).is_err());
+}
+
+#[test]
+fn test_add_contract_settings_bcd() {
+    let settings: Vec<ContractSettings> = serde_yaml::from_str(
+        "
+- name: a
+  address: KT1a
+- name: b
+  address: KT1b
+  bcd: false
+- name: c
+  address: KT1c
+  bcd: true
+",
+    )
+    .unwrap();
+    let mut network = NetworkConfig::default();
+    add_contract_settings(&mut network, settings);
+    assert_eq!(3, network.contracts.len());
+    assert_eq!(vec!["b".to_string()], network.bcd_excluded);
+}
+
+#[test]
+fn test_add_contract_settings_priority() {
+    let settings: Vec<ContractSettings> = serde_yaml::from_str(
+        "
+- name: a
+  address: KT1a
+- name: b
+  address: KT1b
+  priority: 10
+- name: c
+  address: KT1c
+  priority: 0
+",
+    )
+    .unwrap();
+    let mut network = NetworkConfig::default();
+    add_contract_settings(&mut network, settings);
+    assert_eq!(3, network.contracts.len());
+    let mut expected: HashMap<String, u32> = HashMap::new();
+    expected.insert("b".to_string(), 10);
+    assert_eq!(expected, network.priorities);
+}
+
+#[test]
+fn test_add_contract_settings_column_types() {
+    use crate::storage_structure::relational::NumericType;
+    use crate::storage_structure::typing::ExprTy;
+
+    let settings: Vec<ContractSettings> = serde_yaml::from_str(
+        "
+- name: a
+  address: KT1a
+  column_types:
+    types:
+      nat: bigint
+    columns:
+      storage.ledger:
+        amount: numeric
+- name: b
+  address: KT1b
+",
+    )
+    .unwrap();
+    let mut network = NetworkConfig::default();
+    add_contract_settings(&mut network, settings);
+    let column_types = &network.layout_settings.column_types;
+    assert_eq!(1, column_types.len());
+    assert_eq!(
+        Some(&NumericType::Bigint),
+        column_types["a"].types.get("nat")
+    );
+    assert_eq!(
+        NumericType::Bigint,
+        column_types["a"].numeric_type("storage", "counter", &ExprTy::Nat)
+    );
+    assert_eq!(
+        NumericType::Numeric,
+        column_types["a"].numeric_type("storage", "counter", &ExprTy::Int)
+    );
+    assert_eq!(
+        NumericType::Numeric,
+        column_types["a"].numeric_type(
+            "storage.ledger",
+            "amount",
+            &ExprTy::Nat
+        )
+    );
+
+    let settings: Vec<ContractSettings> = serde_yaml::from_str(
+        "
+- name: a
+  address: KT1a
+  column_types:
+    types:
+      mutez: numeric(20)
+    columns:
+      storage.ledger:
+        amount: numeric(30, 2)
+",
+    )
+    .unwrap();
+    let column_types = &settings[0]
+        .column_types
+        .as_ref()
+        .unwrap();
+    assert_eq!(
+        NumericType::BoundedNumeric {
+            precision: 20,
+            scale: 0
+        },
+        column_types.numeric_type("storage", "fee", &ExprTy::Mutez)
+    );
+    assert_eq!(
+        NumericType::BoundedNumeric {
+            precision: 30,
+            scale: 2
+        },
+        column_types.numeric_type("storage.ledger", "amount", &ExprTy::Nat)
+    );
+    assert_eq!(
+        NumericType::Numeric,
+        column_types.numeric_type("storage", "counter", &ExprTy::Nat)
+    );
+    for bad in &[
+        "numeric()",
+        "numeric(0)",
+        "numeric(1001)",
+        "numeric(2, 3)",
+        "numeric(20, 0, 1)",
+        "numeric(-1)",
+        "decimal(20)",
+    ] {
+        assert!(serde_yaml::from_str::<NumericType>(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_expand_vars() {
+    let lookup = |var: &str| match var {
+        "ADDRESS" => Some("KT1abc".to_string()),
+        "EMPTY" => Some("".to_string()),
+        _ => None,
+    };
+    assert_eq!(
+        "address: KT1abc, other: KT1abc.",
+        expand_vars("address: ${ADDRESS}, other: ${ADDRESS}.", lookup).unwrap()
+    );
+    assert_eq!("[]", expand_vars("[${EMPTY}]", lookup).unwrap());
+    assert_eq!("no vars", expand_vars("no vars", lookup).unwrap());
+
+    // escaped, and lone, dollars are kept
+    assert_eq!(
+        "${ADDRESS} costs $5 $",
+        expand_vars("$${ADDRESS} costs $5 $", lookup).unwrap()
+    );
+    assert_eq!("$KT1abc", expand_vars("$$${ADDRESS}", lookup).unwrap());
+
+    let err = expand_vars("address: ${UNSET_VAR}", lookup).unwrap_err();
+    assert!(err.to_string().contains("UNSET_VAR"), "{}", err);
+    assert!(expand_vars("address: ${ADDRESS", lookup).is_err());
+    assert!(expand_vars("address: ${}", lookup).is_err());
+}
+
+#[test]
+fn test_parse_contract_settings_file_env_vars() {
+    let fpath = std::env::temp_dir().join(format!(
+        "quepasa-test-contract-settings-{}.yaml",
+        std::process::id()
+    ));
+    // only the addresses are expanded, not the comments nor other values
+    fs::write(
+        &fpath,
+        "# eg ${QUEPASA_TEST_UNSET}\ncontracts:\n- name: \"fa2\"\n  address: \"${QUEPASA_TEST_FA2_ADDRESS}\"\n  root_table: \"fa2$$\"\n",
+    )
+    .unwrap();
+    let fpath_str = fpath.to_str().unwrap();
+
+    let err = parse_contract_settings_file(fpath_str)
+        .err()
+        .unwrap();
+    assert!(
+        format!("{:?}", err)
+            .contains("env var QUEPASA_TEST_FA2_ADDRESS is not set"),
+        "{:?}",
+        err
+    );
+
+    std::env::set_var("QUEPASA_TEST_FA2_ADDRESS", "KT1abc");
+    let settings = parse_contract_settings_file(fpath_str).unwrap();
+    assert_eq!(1, settings.len());
+    assert_eq!("fa2", settings[0].cid.name);
+    assert_eq!("KT1abc", settings[0].cid.address);
+    assert_eq!(Some("fa2$$".to_string()), settings[0].root_table);
+
+    fs::remove_file(&fpath).unwrap();
+}
@@ -1,15 +1,59 @@
 use std::collections::HashMap;
+use std::sync::RwLock;
 
 pub(crate) fn is_contract_denylisted(address: &str) -> bool {
-    let denylisted = DENYLIST.get(address).is_some();
+    let denylisted = DENYLIST
+        .read()
+        .unwrap()
+        .contains(address);
     if denylisted {
         warn!("ignoring denylisted contract {}", address);
     }
     denylisted
 }
 
+// Extends the built-in denylist with the deny entries, and lifts it for the
+// allow entries (an explicit allow beats any deny).
+pub(crate) fn configure_denylist(deny: &[String], allow: &[String]) {
+    let mut denylist = DENYLIST.write().unwrap();
+    for address in deny {
+        denylist.deny(address);
+    }
+    for address in allow {
+        denylist.allow(address);
+    }
+}
+
 lazy_static! {
-    static ref DENYLIST: HashMap<String, ()> = init_denylist();
+    static ref DENYLIST: RwLock<Denylist> = RwLock::new(Denylist::new());
+}
+
+struct Denylist {
+    denied: HashMap<String, ()>,
+    allowed: HashMap<String, ()>,
+}
+
+impl Denylist {
+    fn new() -> Self {
+        Self {
+            denied: init_denylist(),
+            allowed: HashMap::new(),
+        }
+    }
+
+    fn deny(&mut self, address: &str) {
+        self.denied
+            .insert(address.to_string(), ());
+    }
+
+    fn allow(&mut self, address: &str) {
+        self.allowed
+            .insert(address.to_string(), ());
+    }
+
+    fn contains(&self, address: &str) -> bool {
+        !self.allowed.contains_key(address) && self.denied.contains_key(address)
+    }
 }
 
 fn init_denylist() -> HashMap<String, ()> {
@@ -22,3 +66,31 @@ fn init_denylist() -> HashMap<String, ()> {
     m.insert("KT1FHAtLjG6S6tfjmrDeEySVLeP8a16T4Ngr".to_string(), ());
     m
 }
+
+#[test]
+fn test_denylist_precedence() {
+    let builtin = "KT1FHAtLjG6S6tfjmrDeEySVLeP8a16T4Ngr";
+    let denied = "KT1Denied";
+    let other = "KT1Other";
+
+    let mut denylist = Denylist::new();
+    assert!(denylist.contains(builtin));
+    assert!(!denylist.contains(denied));
+
+    denylist.deny(denied);
+    assert!(denylist.contains(builtin));
+    assert!(denylist.contains(denied));
+    assert!(!denylist.contains(other));
+
+    // an explicit allow beats both built-in and configured denies
+    denylist.allow(builtin);
+    denylist.allow(denied);
+    denylist.allow(other);
+    assert!(!denylist.contains(builtin));
+    assert!(!denylist.contains(denied));
+    assert!(!denylist.contains(other));
+
+    // also when the deny is configured after the allow
+    denylist.deny(other);
+    assert!(!denylist.contains(other));
+}
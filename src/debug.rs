@@ -1,4 +1,8 @@
 use crate::itertools::Itertools;
+use crate::sql::insert;
+use crate::storage_structure::relational::RelationalAST;
+use crate::storage_structure::typing::ExprTy;
+use crate::storage_value::parser;
 
 pub(crate) fn pp_depth<T>(depth: usize, t: T) -> String
 where
@@ -11,9 +15,135 @@ where
         .join("\n")
 }
 
+/// Like pp_depth, but with the leaves decoded as they would be stored:
+/// addresses given as bytes are shown as addresses, and timestamps given as
+/// ints (or strings) are shown as timestamps. The value is matched with the
+/// relational AST only where their structures line up, the rest is shown as
+/// is. Only meant for logging.
+pub(crate) fn pp_human(
+    depth: usize,
+    value: &parser::Value,
+    rel_ast: &RelationalAST,
+) -> String {
+    pp_depth(depth, humanize(value, rel_ast))
+}
+
+fn humanize(value: &parser::Value, rel_ast: &RelationalAST) -> parser::Value {
+    let boxed =
+        |v: &parser::Value, ast: &RelationalAST| Box::new(humanize(v, ast));
+    match (value, rel_ast) {
+        (_, RelationalAST::Option { elem_ast }) => humanize(value, elem_ast),
+        (
+            parser::Value::Pair(l, r),
+            RelationalAST::Pair {
+                left_ast,
+                right_ast,
+            },
+        ) => parser::Value::Pair(boxed(l, left_ast), boxed(r, right_ast)),
+        (
+            parser::Value::Elt(k, v),
+            RelationalAST::Map {
+                key_ast, value_ast, ..
+            },
+        )
+        | (
+            parser::Value::Elt(k, v),
+            RelationalAST::BigMap {
+                key_ast, value_ast, ..
+            },
+        ) => parser::Value::Elt(boxed(k, key_ast), boxed(v, value_ast)),
+        (parser::Value::List(xs), RelationalAST::List { elems_ast, .. }) => {
+            parser::Value::List(
+                xs.iter()
+                    .map(|x| humanize(x, elems_ast))
+                    .collect(),
+            )
+        }
+        (parser::Value::List(xs), RelationalAST::Map { .. })
+        | (parser::Value::List(xs), RelationalAST::BigMap { .. }) => {
+            parser::Value::List(
+                xs.iter()
+                    .map(|x| humanize(x, rel_ast))
+                    .collect(),
+            )
+        }
+        (
+            parser::Value::Left(l),
+            RelationalAST::OrEnumeration { left_ast, .. },
+        ) => parser::Value::Left(boxed(l, left_ast)),
+        (
+            parser::Value::Right(r),
+            RelationalAST::OrEnumeration { right_ast, .. },
+        ) => parser::Value::Right(boxed(r, right_ast)),
+        (_, RelationalAST::Leaf { rel_entry })
+        | (_, RelationalAST::Packed { rel_entry, .. }) => {
+            humanize_leaf(value, &rel_entry.column_type)
+        }
+        _ => value.clone(),
+    }
+}
+
+fn humanize_leaf(value: &parser::Value, column_type: &ExprTy) -> parser::Value {
+    match (column_type, value) {
+        // decode_address expects at least the 22 bytes of the address
+        (ExprTy::Address, parser::Value::Bytes(bs)) if bs.len() >= 44 => {
+            match parser::decode_address(bs) {
+                Ok(addr) => parser::Value::Address(addr),
+                Err(_) => value.clone(),
+            }
+        }
+        (ExprTy::Timestamp, parser::Value::Int(_))
+        | (ExprTy::Timestamp, parser::Value::String(_)) => {
+            match parser::parse_date(value) {
+                Ok(insert::Value::Timestamp(Some(t))) => {
+                    parser::Value::Timestamp(t)
+                }
+                _ => value.clone(),
+            }
+        }
+        _ => value.clone(),
+    }
+}
+
 /// Load from the ../test directory, only for testing
 #[cfg(test)]
 pub(crate) fn load_test(name: &str) -> String {
     println!("loading: {}", name);
     std::fs::read_to_string(std::path::Path::new(name)).unwrap()
 }
+
+#[test]
+fn test_pp_human() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use num::BigInt;
+
+    let type_ast = typing::type_ast_from_json(&serde_json::json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "address", "annots": ["%owner"] },
+            { "prim": "timestamp", "annots": ["%deadline"] },
+        ],
+    }))
+    .unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let value = parser::Value::Pair(
+        Box::new(parser::Value::Bytes(
+            "00006b82198cb179e8306c1bedd08f12dc863f328886".to_string(),
+        )),
+        Box::new(parser::Value::Int(BigInt::from(1_600_000_000))),
+    );
+
+    let got = pp_human(3, &value, &rel_ast);
+    assert!(
+        got.contains("tz1VSUr8wwNhLAzempoch5d6hLRiTh8Cjcjb"),
+        "{}",
+        got
+    );
+    assert!(got.contains("2020-09-13T12:26:40Z"), "{}", got);
+    // the raw form is left as it was
+    assert!(pp_depth(3, &value)
+        .contains("00006b82198cb179e8306c1bedd08f12dc863f328886"));
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use ron::ser::{to_string_pretty, PrettyConfig};
+
+use crate::config::DumpFormat;
+
+// Serializes the value of a dump (eg of --dump-ast) in the given format. The
+// text formats (json, ron) end with a newline.
+pub(crate) fn serialize(
+    value: &serde_json::Value,
+    format: &DumpFormat,
+) -> Result<Vec<u8>> {
+    match format {
+        DumpFormat::Json => {
+            let mut res = serde_json::to_string_pretty(value)?;
+            res.push('\n');
+            Ok(res.into_bytes())
+        }
+        DumpFormat::Ron => {
+            let mut res = to_string_pretty(value, PrettyConfig::new())?;
+            res.push('\n');
+            Ok(res.into_bytes())
+        }
+        DumpFormat::MessagePack => Ok(rmp_serde::to_vec(value)?),
+    }
+}
+
+#[test]
+fn test_serialize_msgpack() {
+    use serde_json::json;
+
+    let cases: Vec<(serde_json::Value, Vec<u8>)> = vec![
+        (json!(null), vec![0xc0]),
+        (json!(true), vec![0xc3]),
+        (json!(5), vec![0x05]),
+        (json!(200), vec![0xcc, 0xc8]),
+        (json!(70000), vec![0xce, 0x00, 0x01, 0x11, 0x70]),
+        (json!(-3), vec![0xfd]),
+        (json!(-200), vec![0xd1, 0xff, 0x38]),
+        (
+            json!(1.5),
+            vec![0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        ),
+        (json!("abc"), vec![0xa3, b'a', b'b', b'c']),
+        (json!([1, "a"]), vec![0x92, 0x01, 0xa1, b'a']),
+        (
+            json!({"a": [], "b": {}}),
+            vec![0x82, 0xa1, b'a', 0x90, 0xa1, b'b', 0x80],
+        ),
+    ];
+    for (value, exp) in cases {
+        assert_eq!(
+            exp,
+            serialize(&value, &DumpFormat::MessagePack).unwrap(),
+            "{}",
+            value
+        );
+    }
+
+    let long_str = "x".repeat(40);
+    let got = serialize(&json!(long_str), &DumpFormat::MessagePack).unwrap();
+    assert_eq!(vec![0xd9, 40], got[..2].to_vec());
+
+    let long_arr = json!(vec![0; 20]);
+    let got = serialize(&long_arr, &DumpFormat::MessagePack).unwrap();
+    assert_eq!(vec![0xdc, 0x00, 20], got[..3].to_vec());
+    assert_eq!(23, got.len());
+}
+
+#[test]
+fn test_serialize_text() {
+    use serde_json::json;
+
+    let value = json!({"storage": {"columns": ["id"]}});
+    assert_eq!(
+        "{\n  \"storage\": {\n    \"columns\": [\n      \"id\"\n    ]\n  }\n}\n",
+        String::from_utf8(serialize(&value, &DumpFormat::Json).unwrap())
+            .unwrap()
+    );
+    let ron = String::from_utf8(serialize(&value, &DumpFormat::Ron).unwrap())
+        .unwrap();
+    assert!(ron.contains("\"storage\""));
+    assert!(ron.ends_with('\n'));
+}
@@ -1,35 +1,47 @@
 use anyhow::{anyhow, ensure, Context, Result};
 use chrono::Duration;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::io;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
 
-use crate::config::ContractID;
+use crate::config::{ContractID, StorageCompression};
 use crate::debug;
+use crate::kafka::KafkaSink;
+use crate::level_feed::LevelFeed;
 use crate::octez::bcd;
 use crate::octez::block::{get_implicit_origination_level, Block, LevelMeta};
-use crate::octez::block_getter::ConcurrentBlockGetter;
+use crate::octez::block_getter::{
+    ByteBudget, ConcurrentBlockGetter, FetchedBlock,
+};
 use crate::octez::node::NodeClient;
 use crate::relational::RelationalAST;
-use crate::sql::db::{DBClient, IndexerMode};
+use crate::shutdown;
+use crate::sql::db::{self, DBClient, IndexerMode};
 use crate::sql::inserter::{
-    insert_processed, DBInserter, ProcessedBlock, ProcessedContractBlock,
+    content_hash, DBInserter, LevelBlock, LevelOrder, ProcessedBlock,
+    ProcessedBlockSink, ProcessedContractBlock,
 };
-use crate::stats::StatsLogger;
+use crate::sql::table::Column;
+use crate::sql::table_builder::TableBuilder;
+use crate::stats::{SlowBlock, StatsLogger};
 use crate::storage_structure::relational;
 use crate::storage_structure::typing;
 use crate::storage_update::bigmap::IntraBlockBigmapDiffsProcessor;
 use crate::storage_update::processor::StorageProcessor;
+use crate::storage_value::address_cache::{AddressCache, SharedAddressCache};
 
 pub struct SaveLevelResult {
     pub level: u32,
-    pub hash: String,
+    // the block's hash, when known (it is not, eg, for levels set up from
+    // better-call.dev)
+    pub hash: Option<String>,
     pub contract_id: ContractID,
     pub is_origination: bool,
     pub tx_count: usize,
@@ -41,11 +53,7 @@ impl SaveLevelResult {
     ) -> Self {
         Self {
             level: processed_block.level.level,
-            hash: processed_block
-                .level
-                .hash
-                .clone()
-                .unwrap(),
+            hash: processed_block.level.hash.clone(),
             contract_id: processed_block.contract.cid.clone(),
             is_origination: processed_block.is_origination,
             tx_count: processed_block.tx_contexts.len(),
@@ -59,14 +67,74 @@ pub struct Executor {
     dbcli: DBClient,
 
     all_contracts: bool,
+    record_all_calls: bool,
+    index_failures: bool,
+    index_delegations: bool,
+    track_balance: bool,
+    keep_raw_storage: bool,
+    empty_string_as_null: bool,
+    deterministic_ids: bool,
+    // abort on contracts whose tables cannot be derived, rather than skipping
+    // them (see skip_failed_contract)
+    fail_on_contract_errors: bool,
+    // the rows of bigmap copies are spilled to disk every this many keys,
+    // when set
+    bigmap_copy_chunk_size: Option<usize>,
+    // (REST proxy url, topic) to produce the contracts' data to, instead of
+    // inserting it into the db
+    kafka: Option<(String, String)>,
+    layout_settings: relational::LayoutSettings,
+    start_levels: HashMap<String, u32>,
+    // per contract (by name), the addresses of contracts it is known to copy
+    // bigmaps from, in addition to the dependencies found while indexing
+    static_deps: HashMap<String, Vec<String>>,
+
+    // fast sync through better-call.dev only when more levels than this are
+    // missing, and never for the excluded contracts (by name)
+    bcd_min_missing_levels: usize,
+    bcd_excluded: Vec<String>,
+    // per contract (by name), the priority with which its missing levels are
+    // indexed, see sort_by_priority
+    priorities: HashMap<String, u32>,
+
+    // decoded addresses, shared by all processors
+    address_cache: SharedAddressCache,
+
+    // the number of threads inserting processed blocks during bootstrap
+    inserter_threads: usize,
+    // during bootstrap processed blocks are inserted in batches of (at
+    // most) this many, and (if set) at least this often
+    inserter_batch_size: usize,
+    inserter_commit_interval: Option<std::time::Duration>,
+
+    // bounds the bytes of the blocks fetched but not yet processed, when set
+    block_budget: Option<Arc<ByteBudget>>,
+
+    // processing a contract's block taking longer than this is logged
+    slow_block_threshold: Option<std::time::Duration>,
+
+    // the only contract to process, while reprocessing it (see
+    // reprocess_contract)
+    reprocessing: Option<ContractID>,
+
+    // the levels processed since switching to bootstrap mode, when the
+    // switch happened in this run
+    bootstrap_levels: Option<BootstrapLevels>,
 
     // Everything below this level has nothing to do with what we are indexing
     mutexed_state: MutexedState,
 
     stats: StatsLogger,
+
+    // (fetched at, level) of the last chain head fetched for indexing_lag
+    chain_head_cache: Arc<Mutex<Option<(Instant, u32)>>>,
 }
 
 impl Executor {
+    const MISSING_LEVELS_CHUNK_SIZE: u32 = 10000;
+    pub const DEFAULT_ADDRESS_CACHE_SIZE: usize = 100000;
+    const DEFAULT_BCD_MIN_MISSING_LEVELS: usize = 1000;
+
     pub fn new(
         node_cli: NodeClient,
         dbcli: DBClient,
@@ -76,11 +144,38 @@ impl Executor {
             node_cli,
             dbcli,
             all_contracts: false,
+            record_all_calls: false,
+            index_failures: false,
+            index_delegations: false,
+            track_balance: false,
+            keep_raw_storage: false,
+            empty_string_as_null: false,
+            bigmap_copy_chunk_size: None,
+            deterministic_ids: false,
+            fail_on_contract_errors: false,
+            kafka: None,
+            layout_settings: relational::LayoutSettings::default(),
+            start_levels: HashMap::new(),
+            static_deps: HashMap::new(),
+            bcd_min_missing_levels: Self::DEFAULT_BCD_MIN_MISSING_LEVELS,
+            bcd_excluded: vec![],
+            priorities: HashMap::new(),
+            address_cache: AddressCache::shared(
+                Self::DEFAULT_ADDRESS_CACHE_SIZE,
+            ),
+            inserter_threads: 1,
+            inserter_batch_size: 10,
+            inserter_commit_interval: None,
+            block_budget: None,
+            slow_block_threshold: None,
+            reprocessing: None,
+            bootstrap_levels: None,
             mutexed_state: MutexedState::new(),
             stats: StatsLogger::new(std::time::Duration::new(
                 reports_interval as u64,
                 0,
             )),
+            chain_head_cache: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -88,16 +183,206 @@ impl Executor {
         self.all_contracts = true
     }
 
+    pub fn record_all_calls(&mut self) {
+        self.record_all_calls = true
+    }
+
+    pub fn index_failures(&mut self) {
+        self.index_failures = true
+    }
+
+    pub fn index_delegations(&mut self) {
+        self.index_delegations = true
+    }
+
+    pub fn track_balance(&mut self) {
+        self.track_balance = true
+    }
+
+    pub fn keep_raw_storage(&mut self) {
+        self.keep_raw_storage = true
+    }
+
+    pub fn store_empty_strings_as_null(&mut self) {
+        self.empty_string_as_null = true
+    }
+
+    pub fn use_deterministic_ids(&mut self) {
+        self.deterministic_ids = true
+    }
+
+    pub fn fail_on_contract_errors(&mut self) {
+        self.fail_on_contract_errors = true
+    }
+
+    pub fn skip_derived_tables(&mut self) {
+        self.dbcli.skip_derived_tables();
+    }
+
+    pub fn use_latest_origination(&mut self) {
+        self.dbcli.use_latest_origination();
+    }
+
+    pub fn set_raw_storage_compression(
+        &mut self,
+        compression: StorageCompression,
+    ) {
+        self.dbcli
+            .set_raw_storage_compression(compression);
+    }
+
+    // Publishes every committed level to the feed.
+    pub(crate) fn publish_levels(&mut self, level_feed: LevelFeed) {
+        self.dbcli.set_level_feed(level_feed);
+    }
+
+    pub fn produce_to_kafka(&mut self, url: &str, topic: &str) {
+        self.kafka = Some((url.to_string(), topic.to_string()));
+        self.dbcli.skip_contract_tables();
+    }
+
+    fn sink(&self, batch_size: usize) -> Box<dyn ProcessedBlockSink> {
+        match &self.kafka {
+            Some((url, topic)) => Box::new(KafkaSink::new(
+                self.dbcli.clone(),
+                url,
+                topic,
+                batch_size,
+            )),
+            None => {
+                let mut inserter = DBInserter::new(
+                    self.dbcli.clone(),
+                    batch_size,
+                    self.inserter_threads,
+                );
+                if let Some(interval) = self.inserter_commit_interval {
+                    inserter.set_commit_interval(interval);
+                }
+                Box::new(inserter)
+            }
+        }
+    }
+
+    pub fn set_layout_settings(
+        &mut self,
+        layout_settings: relational::LayoutSettings,
+    ) {
+        self.layout_settings = layout_settings
+    }
+
+    pub fn set_start_levels(&mut self, start_levels: HashMap<String, u32>) {
+        self.start_levels = start_levels
+    }
+
+    pub fn set_bcd_min_missing_levels(&mut self, min_missing_levels: usize) {
+        self.bcd_min_missing_levels = min_missing_levels
+    }
+
+    pub fn set_bcd_excluded(&mut self, excluded: Vec<String>) {
+        self.bcd_excluded = excluded
+    }
+
+    pub fn set_priorities(&mut self, priorities: HashMap<String, u32>) {
+        self.priorities = priorities
+    }
+
+    // Prefixes the statistics reports, to tell apart the reports of the
+    // executors of different networks.
+    pub fn set_stats_namespace(&mut self, namespace: &str) {
+        self.stats.set_namespace(namespace)
+    }
+
+    pub fn set_address_cache_size(&mut self, size: usize) {
+        self.address_cache = AddressCache::shared(size)
+    }
+
+    pub fn set_bigmap_copy_chunk_size(&mut self, chunk_size: usize) {
+        self.bigmap_copy_chunk_size = Some(chunk_size)
+    }
+
+    pub fn set_inserter_threads(&mut self, threads: usize) {
+        self.inserter_threads = threads
+    }
+
+    pub fn set_inserter_batch_size(&mut self, batch_size: usize) {
+        self.inserter_batch_size = batch_size
+    }
+
+    pub fn set_inserter_commit_interval(
+        &mut self,
+        interval: Option<std::time::Duration>,
+    ) {
+        self.inserter_commit_interval = interval
+    }
+
+    // Caps the (approximate) bytes of the blocks fetched but not yet
+    // processed, the getters pause when exceeding it.
+    pub fn set_max_buffered_block_bytes(&mut self, max_bytes: Option<usize>) {
+        self.block_budget =
+            max_bytes.map(|max_bytes| Arc::new(ByteBudget::new(max_bytes)))
+    }
+
+    pub fn set_slow_block_threshold(
+        &mut self,
+        threshold: Option<std::time::Duration>,
+    ) {
+        self.slow_block_threshold = threshold
+    }
+
+    // Tracks the n slowest processed contract blocks, see log_slowest_blocks.
+    pub fn track_slowest_blocks(&mut self, n: usize) -> Result<()> {
+        self.stats.track_slowest(n)
+    }
+
+    pub fn log_slowest_blocks(&self) -> Result<()> {
+        self.stats.log_slowest()
+    }
+
+    pub fn set_static_deps(
+        &mut self,
+        static_deps: HashMap<String, Vec<String>>,
+    ) {
+        self.static_deps = static_deps
+    }
+
+    fn get_contract_floor(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<Option<u32>> {
+        let origination = self
+            .dbcli
+            .get_origination(contract_id)?;
+        let start_level = self
+            .start_levels
+            .get(&contract_id.name)
+            .copied();
+        Ok(match (origination, start_level) {
+            (Some(origination), Some(start)) => Some(origination.max(start)),
+            (origination, start) => origination.or(start),
+        })
+    }
+
     pub fn add_contract(&mut self, contract_id: &ContractID) -> Result<bool> {
+        ensure!(
+            self.node_cli
+                .contract_exists(&contract_id.address)?,
+            anyhow!(
+                "contract {} ({}) not found on this network, is its address correct?",
+                contract_id.address,
+                contract_id.name
+            )
+        );
         debug!(
             "getting the storage definition for contract={}..",
             contract_id.name
         );
-        let mut contract = get_contract_rel(&self.node_cli, contract_id)?;
+        let mut contract = get_contract_rel(
+            &self.node_cli,
+            contract_id,
+            &self.layout_settings,
+        )?;
 
-        contract.level_floor = self
-            .dbcli
-            .get_origination(contract_id)?;
+        contract.level_floor = self.get_contract_floor(contract_id)?;
 
         debug!("interpreted contract definition: {:#?}", contract);
 
@@ -112,16 +397,24 @@ impl Executor {
         let mut l: Vec<relational::Contract> = vec![];
 
         for contract_id in contracts {
-            l.push(get_contract_rel(&self.node_cli, contract_id)?);
+            match get_contract_rel(
+                &self.node_cli,
+                contract_id,
+                &self.layout_settings,
+            ) {
+                Ok(contract) => l.push(contract),
+                Err(e) if !self.fail_on_contract_errors => {
+                    self.skip_failed_contract(contract_id, &e)?
+                }
+                Err(e) => return Err(e),
+            }
         }
 
         self.dbcli
             .create_contract_schemas(&mut l)?;
 
         for mut contract in l {
-            contract.level_floor = self
-                .dbcli
-                .get_origination(&contract.cid)?;
+            contract.level_floor = self.get_contract_floor(&contract.cid)?;
 
             if self
                 .mutexed_state
@@ -148,6 +441,40 @@ impl Executor {
         Ok(new_contracts)
     }
 
+    // Reports the contracts that are behind the db's head (eg because they
+    // are still catching up), with the number of levels they are behind the
+    // chain's head. Not meant for --index-all-contracts, where contracts only
+    // get their levels of activity recorded.
+    fn report_contracts_behind(
+        &mut self,
+        chain_head: u32,
+        db_head: u32,
+    ) -> Result<()> {
+        let contracts = self.get_config()?;
+        let heads = self
+            .dbcli
+            .get_head_per_contract(&contracts)?;
+        for cid in &contracts {
+            match heads.get(cid) {
+                Some(head) if *head < db_head => {
+                    debug!(
+                        "contract {}: db: {} chain: {}",
+                        cid.name, head, chain_head
+                    );
+                    self.stats.set(
+                        "contracts behind head",
+                        &cid.name,
+                        format!("{} blocks", chain_head as i64 - *head as i64),
+                    )?;
+                }
+                _ => self
+                    .stats
+                    .unset("contracts behind head", &cid.name)?,
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_config(&self) -> Result<Vec<ContractID>> {
         Ok(self
             .mutexed_state
@@ -159,29 +486,95 @@ impl Executor {
 
     pub fn get_config_sorted(&self) -> Result<Vec<ContractID>> {
         let mut res = self.get_config()?;
-        res.sort_by_key(|elem| elem.name.clone());
+        sort_by_priority(&mut res, &self.priorities);
         Ok(res)
     }
 
     pub fn add_dependency_contracts(&mut self) -> Result<()> {
-        let deps = self
+        let config = self.get_config()?;
+        let mut deps = self
             .dbcli
-            .get_config_deps(&self.get_config()?)
+            .get_config_deps(&config)
             .unwrap();
+        for dep in static_deps(&config, &self.static_deps) {
+            if !deps
+                .iter()
+                .any(|d| d.address == dep.address)
+            {
+                deps.push(dep);
+            }
+        }
 
         for dep in &deps {
-            self.add_contract(dep)?;
+            if self.mutexed_state.is_failed(dep)? {
+                continue;
+            }
+            match self.add_contract(dep) {
+                Ok(_) => {}
+                Err(e) if !self.fail_on_contract_errors => {
+                    self.skip_failed_contract(dep, &e)?
+                }
+                Err(e) => return Err(e),
+            }
         }
 
         Ok(())
     }
 
+    // Contracts whose tables cannot be derived (eg because of an unsupported
+    // type, or because the node failed to return their script) are skipped,
+    // and recorded in the failed_contracts table, so that the other contracts
+    // are still indexed. They are not retried in this run.
+    fn skip_failed_contract(
+        &mut self,
+        contract_id: &ContractID,
+        err: &anyhow::Error,
+    ) -> Result<()> {
+        warn!(
+            "skipping contract {} ({}), its tables cannot be derived: {:?}",
+            contract_id.name, contract_id.address, err
+        );
+        self.mutexed_state
+            .add_failed_contract(contract_id)?;
+        self.dbcli
+            .save_failed_contract(contract_id, &format!("{:?}", err))?;
+        self.stats
+            .add("processor", "failed contracts", 1)?;
+        Ok(())
+    }
+
+    // Dependencies are found while indexing (when a contract copies a
+    // bigmap of another contract), so on a fresh db they are unknown for
+    // contracts with bigmaps unless set explicitly. Returns these contracts.
+    pub fn contracts_with_unknown_deps(&mut self) -> Result<Vec<ContractID>> {
+        let mut res: Vec<ContractID> = vec![];
+        for (contract_id, contract) in &self.mutexed_state.get_contracts()? {
+            if contract_id.name == contract_id.address
+                || !has_bigmaps(&contract.storage_ast)
+                || self
+                    .static_deps
+                    .contains_key(&contract_id.name)
+            {
+                continue;
+            }
+            if self
+                .dbcli
+                .get_config_deps(std::slice::from_ref(contract_id))?
+                .is_empty()
+            {
+                res.push(contract_id.clone());
+            }
+        }
+        res.sort_by_key(|contract_id| contract_id.name.clone());
+        Ok(res)
+    }
+
     pub fn exec_continuous(&mut self) -> Result<()> {
         // Executes blocks monotically, from old to new, continues from the heighest block present
         // in the db
         let mode = self.dbcli.get_indexer_mode()?;
         if mode == IndexerMode::Bootstrap {
-            self.repopulate_derived_tables(true)?;
+            self.finalize_bootstrap()?;
         }
 
         fn wait(first_wait: &mut bool) {
@@ -202,6 +595,9 @@ impl Executor {
         }
         let mut first_wait = true;
         loop {
+            if shutdown::requested() {
+                return Ok(());
+            }
             let chain_head = self.node_cli.head()?;
             let db_head = match self.dbcli.get_head()? {
                 Some(head) => Ok(head),
@@ -219,10 +615,24 @@ impl Executor {
                 }
             }?;
             debug!("db: {} chain: {}", db_head.level, chain_head.level);
+            self.stats.set(
+                "indexer",
+                "blocks behind head",
+                (chain_head.level as i64 - db_head.level as i64).to_string(),
+            )?;
             match chain_head.level.cmp(&db_head.level) {
                 Ordering::Greater => {
                     wait_done(&mut first_wait);
+                    if !self.all_contracts {
+                        self.report_contracts_behind(
+                            chain_head.level,
+                            db_head.level,
+                        )?;
+                    }
                     for level in (db_head.level + 1)..=chain_head.level {
+                        if shutdown::requested() {
+                            break;
+                        }
                         Self::print_status(level, &self.exec_level(level)?);
                     }
                     first_wait = true;
@@ -259,6 +669,39 @@ impl Executor {
         }
     }
 
+    /// Returns how many levels the db is behind the chain's head, or None if
+    /// the db has no levels yet. The chain's head is only re-fetched from the
+    /// node every few seconds.
+    pub fn indexing_lag(&self) -> Result<Option<i64>> {
+        let chain_head = self.cached_chain_head()?;
+        let db_head = match self.dbcli.clone().get_head()? {
+            Some(head) => head.level,
+            None => return Ok(None),
+        };
+        let lag = chain_head as i64 - db_head as i64;
+        self.stats
+            .set("indexer", "blocks behind head", lag.to_string())?;
+        Ok(Some(lag))
+    }
+
+    fn cached_chain_head(&self) -> Result<u32> {
+        const CHAIN_HEAD_CACHE_TTL: std::time::Duration =
+            std::time::Duration::from_secs(5);
+
+        let mut cache = self
+            .chain_head_cache
+            .lock()
+            .map_err(|_| anyhow!("failed to lock chain_head_cache mutex"))?;
+        if let Some((fetched_at, level)) = *cache {
+            if fetched_at.elapsed() < CHAIN_HEAD_CACHE_TTL {
+                return Ok(level);
+            }
+        }
+        let level = self.node_cli.head()?.level;
+        *cache = Some((Instant::now(), level));
+        Ok(level)
+    }
+
     pub fn exec_dependents(&mut self) -> Result<Vec<u32>> {
         let mut levels = self
             .dbcli
@@ -272,6 +715,55 @@ impl Executor {
         self.exec_levels(1, 1, levels)
     }
 
+    /// Re-index only the levels at which an indexed contract copied given
+    /// bigmap of src_contract (eg after src_contract got indexed, so that
+    /// the keys of the copied bigmap are now known), after deleting
+    /// everything indexed for them so far.
+    pub fn reprocess_dependency(
+        &mut self,
+        src_contract: &str,
+        bigmap_id: i32,
+    ) -> Result<Vec<u32>> {
+        let levels = self.dbcli.get_bigmap_dependent_levels(
+            &self.get_config()?,
+            src_contract,
+            bigmap_id,
+        )?;
+        if levels.is_empty() {
+            info!(
+                "no indexed contract copied bigmap {} of {}, nothing needs to be reprocessed",
+                bigmap_id, src_contract
+            );
+            return Ok(vec![]);
+        }
+
+        info!(
+            "deleting and reprocessing following levels, they have copies of bigmap {} of {}: {:?}",
+            bigmap_id, src_contract, levels
+        );
+        // (processing switches to bootstrap mode)
+        let mode = self.dbcli.get_indexer_mode()?;
+        let mut conn = self.dbcli.dbconn()?;
+        let mut tx = conn.transaction()?;
+        DBClient::delete_levels(
+            &mut tx,
+            &levels
+                .iter()
+                .map(|lvl| db::level_to_db(*lvl))
+                .collect::<Result<Vec<i32>>>()?,
+        )?;
+        tx.commit()?;
+
+        let processed = self.exec_levels(1, 1, levels)?;
+
+        // deleting the levels cascaded into the derived tables as well
+        if mode == IndexerMode::Head {
+            self.repopulate_derived_tables(false)?;
+            self.bootstrap_levels = None;
+        }
+        Ok(processed)
+    }
+
     pub fn reprocess_forked_levels(
         &mut self,
         num_getters: usize,
@@ -354,8 +846,11 @@ impl Executor {
     ) -> Result<Vec<ContractID>> {
         let mut res: Vec<ContractID> = vec![];
         loop {
-            self.add_dependency_contracts().unwrap();
-            let new_contracts = self.create_contract_schemas().unwrap();
+            if shutdown::requested() {
+                return Ok(res);
+            }
+            self.add_dependency_contracts()?;
+            let new_contracts = self.create_contract_schemas()?;
 
             if new_contracts.is_empty() {
                 break;
@@ -373,11 +868,10 @@ impl Executor {
                 num_processors,
                 acceptable_head_offset,
                 false,
-            )
-            .unwrap();
+            )?;
         }
         if !res.is_empty() {
-            self.exec_dependents().unwrap();
+            self.exec_dependents()?;
         }
         Ok(res)
     }
@@ -408,21 +902,26 @@ impl Executor {
         exec_dependent_levels: bool,
     ) -> Result<()> {
         loop {
+            if shutdown::requested() {
+                return Ok(());
+            }
             let latest_level: LevelMeta = self.node_cli.head()?;
 
-            let mut missing_levels: Vec<u32> = self
-                .dbcli
-                .get_missing_levels(&self.get_config()?, latest_level.level)?;
-            if missing_levels.is_empty() {
-                break;
-            }
-            let has_gaps = missing_levels
-                .windows(2)
-                .any(|w| w[0] != w[1] - 1);
+            let (first_missing, last_missing, num_missing) =
+                match self.dbcli.get_missing_levels_summary(
+                    &self.get_config()?,
+                    &self.start_levels,
+                    latest_level.level,
+                )? {
+                    Some(summary) => summary,
+                    None => break,
+                };
+            let has_gaps =
+                (last_missing - first_missing) as usize + 1 != num_missing;
 
             let first_missing: LevelMeta = self
                 .node_cli
-                .level_json(missing_levels[0])?
+                .level_json(first_missing)?
                 .0;
 
             if !has_gaps
@@ -433,14 +932,18 @@ impl Executor {
                 break;
             }
 
-            if missing_levels.len() > 1000 && bcd_settings.is_some() {
+            let bcd_contracts = match bcd_settings {
+                Some(_) => self.bcd_contracts(latest_level.level)?,
+                None => vec![],
+            };
+            if !bcd_contracts.is_empty() {
                 let (bcd_url, network) = bcd_settings.as_ref().unwrap();
                 let config = &self.get_config_sorted()?;
 
                 let mut exclude_levels: Vec<u32> = self
                     .dbcli
                     .get_fully_processed_levels(config)?;
-                for contract_id in config {
+                for contract_id in &bcd_contracts {
                     info!("Indexing missing levels for {}..", contract_id.name);
                     let bcd_cli = bcd::BCDClient::new(
                         bcd_url.clone(),
@@ -451,23 +954,35 @@ impl Executor {
                     let excl = exclude_levels.clone();
                     let stats = self.stats.clone();
                     let node_cli = self.node_cli.clone();
+                    let mut progress = self.dbcli.with_role("bcd");
+                    // the scan's progress is saved per page, so a failed
+                    // scan is resumed by the next run
                     let processed_levels = self
                         .exec_parallel(
                             num_getters,
                             num_processors,
                             move |height_chan| {
-                                bcd_cli
-                                    .populate_levels_chan(
-                                        || Ok(node_cli.head()?.level),
-                                        &stats,
-                                        &height_chan,
-                                        &excl,
-                                    )
-                                    .unwrap()
+                                bcd_cli.populate_levels_chan(
+                                    || Ok(node_cli.head()?.level),
+                                    &stats,
+                                    &height_chan,
+                                    &excl,
+                                    &mut progress,
+                                )
                             },
                         )
-                        .unwrap();
+                        .with_context(|| {
+                            format!(
+                                "failed to index the missing levels of {} through better-call.dev",
+                                contract_id.name
+                            )
+                        })?;
                     exclude_levels.extend(processed_levels);
+                    if shutdown::requested() {
+                        // the levels not yet processed must not be marked
+                        // empty below
+                        return Ok(());
+                    }
 
                     if let Some(l) =
                         get_implicit_origination_level(&contract_id.address)
@@ -477,22 +992,181 @@ impl Executor {
 
                     self.mark_missing_levels_empty(contract_id)
                         .unwrap();
+                    self.dbcli
+                        .clear_bcd_progress(std::slice::from_ref(
+                            contract_id,
+                        ))?;
 
                     info!("contract {} initialized.", contract_id.name)
                 }
                 self.exec_partially_processed(num_getters, num_processors)?;
             } else {
-                missing_levels.reverse();
-                info!("processing {} missing levels", missing_levels.len());
-                self.exec_levels(num_getters, num_processors, missing_levels)?;
+                info!("processing {} missing levels", num_missing);
+                self.stats
+                    .set_levels_target(Some(num_missing))?;
+                // the levels missing for the contracts of the highest
+                // priority first. as every level is processed for all
+                // contracts, the levels of the later groups shrink
+                // accordingly
+                for contracts in priority_groups(
+                    &self.get_config_sorted()?,
+                    &self.priorities,
+                ) {
+                    if shutdown::requested() {
+                        return Ok(());
+                    }
+                    let missing_levels = self.dbcli.missing_levels_iter(
+                        &contracts,
+                        &self.start_levels,
+                        latest_level.level,
+                        Self::MISSING_LEVELS_CHUNK_SIZE,
+                    )?;
+                    self.exec_levels_iter(
+                        num_getters,
+                        num_processors,
+                        missing_levels,
+                    )?;
+                }
             }
         }
+        self.stats.set_levels_target(None)?;
+        // all contracts are caught up, a better-call.dev scan that was
+        // interrupted (and finished otherwise) must not be resumed later on
+        self.dbcli
+            .clear_bcd_progress(&self.get_config()?)?;
         if exec_dependent_levels {
             self.exec_dependents()?;
         }
         Ok(())
     }
 
+    // The contracts to fast sync through better-call.dev: those not excluded
+    // from it, if more than bcd_min_missing_levels levels are missing for
+    // them. Once these are synced, the levels still missing for the excluded
+    // contracts are fetched from the node directly.
+    fn bcd_contracts(&mut self, head: u32) -> Result<Vec<ContractID>> {
+        let contracts: Vec<ContractID> = self
+            .get_config_sorted()?
+            .into_iter()
+            .filter(|contract_id| {
+                !self
+                    .bcd_excluded
+                    .contains(&contract_id.name)
+            })
+            .collect();
+        match self.dbcli.get_missing_levels_summary(
+            &contracts,
+            &self.start_levels,
+            head,
+        )? {
+            Some((_, _, num_missing))
+                if num_missing > self.bcd_min_missing_levels =>
+            {
+                Ok(contracts)
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    // Brings the derived tables up to date when leaving bootstrap mode. If
+    // the levels processed while bootstrapping directly follow the levels
+    // the derived tables were up to date with (and are few), the derived
+    // tables are only updated for these. Otherwise they are re-populated.
+    fn finalize_bootstrap(&mut self) -> Result<()> {
+        let range = self
+            .bootstrap_levels
+            .take()
+            .and_then(|levels| levels.incremental_range());
+        let (from, to) = match range {
+            Some(range) => range,
+            None => return self.repopulate_derived_tables(true),
+        };
+        self.ensure_sane_input_state()?;
+
+        info!(
+            "updating derived tables (_live, _ordered) for levels {}-{}",
+            from, to
+        );
+        for contract in self
+            .mutexed_state
+            .get_contracts()?
+            .values()
+        {
+            self.dbcli
+                .update_derived_tables_between(contract, from, to)?;
+        }
+        self.dbcli
+            .set_indexer_mode(IndexerMode::Head)
+    }
+
+    fn ensure_sane_input_state(&mut self) -> Result<()> {
+        let latest_level: LevelMeta = self.node_cli.head()?;
+        let missing_levels: Vec<u32> = self.dbcli.get_missing_levels(
+            &self.get_config()?,
+            &self.start_levels,
+            latest_level.level,
+        )?;
+        let has_gaps = missing_levels
+            .windows(2)
+            .any(|w| w[0] != w[1] - 1);
+        ensure!(
+            !has_gaps,
+            anyhow!("cannot re-populate derived tables, there are gaps in the processed levels")
+        );
+        ensure!(
+            self.dbcli
+                .get_partial_processed_levels(&self.get_config()?)?
+                .is_empty(),
+            anyhow!("cannot re-populate derived tables, some levels are only partially processed (not processed for some contracts)")
+        );
+        Ok(())
+    }
+
+    // Adds the derived tables to the contracts set up without them (see
+    // skip_derived_tables), and populates them.
+    pub fn build_derived_tables(&mut self) -> Result<()> {
+        for contract in self
+            .mutexed_state
+            .get_contracts()?
+            .values()
+        {
+            if !self
+                .dbcli
+                .create_derived_tables(contract)?
+            {
+                continue;
+            }
+            info!("building derived tables of {}", contract.cid.name);
+            self.dbcli
+                .repopulate_derived_tables(contract)?;
+        }
+        Ok(())
+    }
+
+    // Repopulates the stale derived tables of the contracts, see
+    // DBClient::repair_derived_tables.
+    pub fn repair_derived_tables(&mut self) -> Result<()> {
+        for contract in self
+            .mutexed_state
+            .get_contracts()?
+            .values()
+        {
+            let repaired = self
+                .dbcli
+                .repair_derived_tables(contract)?;
+            if repaired.is_empty() {
+                info!("derived tables of {} are up to date", contract.cid.name);
+            } else {
+                info!(
+                    "repaired the derived tables of {}: {}",
+                    contract.cid.name,
+                    repaired.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn repopulate_derived_tables(
         &mut self,
         ensure_sane_input_state: bool,
@@ -502,23 +1176,7 @@ impl Executor {
         );
 
         if ensure_sane_input_state {
-            let latest_level: LevelMeta = self.node_cli.head()?;
-            let missing_levels: Vec<u32> = self
-                .dbcli
-                .get_missing_levels(&self.get_config()?, latest_level.level)?;
-            let has_gaps = missing_levels
-                .windows(2)
-                .any(|w| w[0] != w[1] - 1);
-            ensure!(
-                !has_gaps,
-                anyhow!("cannot re-populate derived tables, there are gaps in the processed levels")
-            );
-            ensure!(
-                self.dbcli
-                    .get_partial_processed_levels(&self.get_config()?)?
-                    .is_empty(),
-                anyhow!("cannot re-populate derived tables, some levels are only partially processed (not processed for some contracts)")
-            );
+            self.ensure_sane_input_state()?;
         }
 
         for contract in self
@@ -531,6 +1189,17 @@ impl Executor {
         }
         self.dbcli
             .set_indexer_mode(IndexerMode::Head)?;
+
+        // bootstrapping bulk loaded the contracts' tables, refresh the
+        // planner statistics now that we're switching to head mode
+        for contract in self
+            .mutexed_state
+            .get_contracts()?
+            .values()
+        {
+            self.dbcli
+                .vacuum_analyze_contract(&contract.cid)?;
+        }
         Ok(())
     }
 
@@ -558,21 +1227,173 @@ impl Executor {
             return Ok(vec![]);
         }
 
+        self.exec_levels_iter(
+            num_getters,
+            num_processors,
+            std::iter::once(Ok(levels)),
+        )
+    }
+
+    /// Processes the contract again at every level it was processed for so
+    /// far, after deleting everything indexed for it (eg after fixing how its
+    /// storage is processed). The other contracts' data is left untouched.
+    /// If interrupted, the levels not yet reprocessed are picked up again by
+    /// the next regular run (as levels only partially processed).
+    pub fn reprocess_contract(
+        &mut self,
+        num_getters: usize,
+        num_processors: usize,
+        contract_id: &ContractID,
+    ) -> Result<Vec<u32>> {
+        let contract = self
+            .mutexed_state
+            .get_contract(contract_id)?
+            .ok_or_else(|| {
+                anyhow!(
+                    "cannot reprocess contract {}, it is not set up",
+                    contract_id.name
+                )
+            })?;
+        let mode = self.dbcli.get_indexer_mode()?;
+
+        info!(
+            "deleting the data of contract {} before reprocessing it",
+            contract_id.name
+        );
+        let levels = self
+            .dbcli
+            .delete_contract_data(&contract)?;
+        info!(
+            "reprocessing contract {} at {} levels",
+            contract_id.name,
+            levels.len()
+        );
+
+        self.reprocessing = Some(contract_id.clone());
+        self.dbcli.set_keep_levels(true);
+        let res = self.exec_levels(num_getters, num_processors, levels);
+        self.reprocessing = None;
+        self.dbcli.set_keep_levels(false);
+        let processed = res?;
+
+        // only the contract's derived tables are out of date, so there is no
+        // need to stay in bootstrap mode (which rebuilds all of them)
+        if mode == IndexerMode::Head {
+            self.dbcli
+                .repopulate_derived_tables(&contract)?;
+            self.dbcli
+                .set_indexer_mode(IndexerMode::Head)?;
+            self.bootstrap_levels = None;
+        }
+        Ok(processed)
+    }
+
+    /// Re-index the levels from..=to, after deleting everything indexed for
+    /// them so far. Levels below every contract's floor are skipped. Refuses
+    /// to run (unless forced) if some of the levels were never indexed, as
+    /// re-indexing those out of order leaves the levels before them missing.
+    pub fn exec_level_range(
+        &mut self,
+        num_getters: usize,
+        num_processors: usize,
+        (from, to): (u32, u32),
+        force: bool,
+    ) -> Result<Vec<u32>> {
+        ensure!(
+            from <= to,
+            anyhow!("bad level range {}-{}: from is above to", from, to)
+        );
+        let from = if self.all_contracts {
+            from
+        } else {
+            from.max(self.mutexed_state.get_level_floor()?)
+        };
+        if from > to {
+            info!("level range is below the floor of every contract, nothing to re-index");
+            return Ok(vec![]);
+        }
+
+        let missing = self.dbcli.get_missing_levels_between(
+            &self.get_config()?,
+            &self.start_levels,
+            from,
+            to,
+        )?;
+        if !missing.is_empty() {
+            if !force {
+                return Err(anyhow!(
+                    "level range {}-{} contains {} levels that were never indexed (first: {}, last: {}), pass --force to re-index the range anyway",
+                    from,
+                    to,
+                    missing.len(),
+                    missing[0],
+                    missing[missing.len() - 1],
+                ));
+            }
+            warn!(
+                "level range {}-{} contains {} levels that were never indexed, re-indexing anyway (forced)",
+                from,
+                to,
+                missing.len(),
+            );
+        }
+
+        let levels: Vec<u32> = (from..=to).collect();
+        // (processing switches to bootstrap mode)
+        let mode = self.dbcli.get_indexer_mode()?;
+        info!("deleting levels {}-{} before re-indexing them", from, to);
+        let mut conn = self.dbcli.dbconn()?;
+        let mut tx = conn.transaction()?;
+        DBClient::delete_levels(
+            &mut tx,
+            &levels
+                .iter()
+                .map(|lvl| db::level_to_db(*lvl))
+                .collect::<Result<Vec<i32>>>()?,
+        )?;
+        tx.commit()?;
+
+        let processed =
+            self.exec_levels(num_getters, num_processors, levels)?;
+
+        // deleting the levels cascaded into the derived tables as well
+        if mode == IndexerMode::Head {
+            self.repopulate_derived_tables(false)?;
+            self.bootstrap_levels = None;
+        }
+        Ok(processed)
+    }
+
+    // Like exec_levels, but for levels that are streamed in chunks (eg from
+    // DBClient::missing_levels_iter) rather than known upfront.
+    fn exec_levels_iter<I>(
+        &mut self,
+        num_getters: usize,
+        num_processors: usize,
+        levels: I,
+    ) -> Result<Vec<u32>>
+    where
+        I: Iterator<Item = Result<Vec<u32>>> + Send + 'static,
+    {
         let st = self.mutexed_state.clone();
         let have_floor = !self.all_contracts;
-        let processed_levels = self.exec_parallel(
-            num_getters,
-            num_processors,
-            move |height_chan| {
-                for l in levels {
-                    if have_floor && l < st.get_level_floor().unwrap() {
+        self.exec_parallel(num_getters, num_processors, move |height_chan| {
+            for chunk in levels {
+                if shutdown::requested() {
+                    break;
+                }
+                for l in chunk? {
+                    if shutdown::requested() {
+                        break;
+                    }
+                    if have_floor && l < st.get_level_floor()? {
                         continue;
                     }
-                    height_chan.send(l).unwrap();
+                    height_chan.send(l)?;
                 }
-            },
-        )?;
-        Ok(processed_levels)
+            }
+            Ok(())
+        })
     }
 
     pub fn exec_parallel<F>(
@@ -582,33 +1403,72 @@ impl Executor {
         levels_selector: F,
     ) -> Result<Vec<u32>>
     where
-        F: FnOnce(flume::Sender<u32>) + Send + 'static,
+        F: FnOnce(flume::Sender<u32>) -> Result<()> + Send + 'static,
     {
         // a parallel exec has the consequence that we need to re-derive the
         // _live and _ordered tables when done. therefore we change the mode to
         // "bootstrap" here
+        if self.dbcli.get_indexer_mode()? == IndexerMode::Head {
+            let derived_until = self
+                .dbcli
+                .get_head()?
+                .map(|head| head.level);
+            self.bootstrap_levels = Some(BootstrapLevels::new(derived_until));
+        }
         self.dbcli
             .set_indexer_mode(IndexerMode::Bootstrap)?;
 
         let (height_send, height_recv) = flume::bounded::<u32>(num_getters);
         let (block_send, block_recv) =
-            flume::bounded::<Box<(LevelMeta, Block)>>(num_getters * 5);
+            flume::bounded::<Box<FetchedBlock>>(num_getters * 5);
 
-        let block_getter =
+        let mut block_getter =
             ConcurrentBlockGetter::new(self.node_cli.clone(), num_getters);
+        block_getter.set_byte_budget(self.block_budget.clone());
         let mut threads = block_getter.run(height_recv, block_send);
 
-        threads.push(thread::spawn(|| levels_selector(height_send)));
+        let batch_size = self.inserter_batch_size;
+        let sink = self.sink(batch_size);
+
+        let level_order: Option<LevelOrder> = if sink.needs_level_order() {
+            Some(Arc::new(Mutex::new(VecDeque::new())))
+        } else {
+            None
+        };
+        // when the selector fails, the levels it selected so far are still
+        // processed before the failure is returned
+        let (selector, order_recorder) = match &level_order {
+            Some(order) => {
+                // record the levels in the order they are handed out, before
+                // handing them out
+                let (selected_send, selected_recv) =
+                    flume::bounded::<u32>(num_getters);
+                let order = order.clone();
+                let order_recorder = thread::spawn(move || -> Result<()> {
+                    for level in selected_recv {
+                        order
+                            .lock()
+                            .map_err(|_| anyhow!("failed to lock level order"))?
+                            .push_back(level);
+                        height_send.send(level)?;
+                    }
+                    Ok(())
+                });
+                (
+                    thread::spawn(|| levels_selector(selected_send)),
+                    Some(order_recorder),
+                )
+            }
+            None => (thread::spawn(|| levels_selector(height_send)), None),
+        };
 
         self.stats.reset()?;
         let stats_thread = self.stats.run();
 
-        let batch_size = 10;
-        let inserter = DBInserter::new(self.dbcli.clone(), batch_size);
         let (processed_send, processed_recv) =
-            flume::bounded::<Box<ProcessedBlock>>(batch_size * 10);
+            flume::bounded::<Box<LevelBlock>>(batch_size * 10);
 
-        threads.push(inserter.run(&self.stats, processed_recv)?);
+        let sink_thread = sink.run(&self.stats, processed_recv, level_order)?;
 
         let processed_results: Arc<Mutex<(Vec<u32>, Vec<u32>)>> =
             Arc::new(Mutex::new((vec![], vec![])));
@@ -621,8 +1481,11 @@ impl Executor {
             res.1.extend(reprocess);
         } else {
             info!("starting {} concurrent processors", num_processors);
-            for _ in 0..num_processors {
+            for i in 0..num_processors {
                 let mut exec = self.clone();
+                exec.dbcli = exec
+                    .dbcli
+                    .with_role(&format!("processor-{}", i));
                 let w_recv_ch = block_recv.clone();
                 let w_send_ch = processed_send.clone();
                 let res_arc = processed_results.clone();
@@ -639,11 +1502,26 @@ impl Executor {
             drop(processed_send);
         }
 
+        // (the sink first: when it fails, the processors fail on sending to
+        // it)
+        sink_thread
+            .join()
+            .map_err(|e| anyhow!("inserter thread failed with err: {:?}", e))?
+            .context("failed to insert the processed levels")?;
         for t in threads {
             t.join().map_err(|e| {
                 anyhow!("parallel execution thread failed with err: {:?}", e)
             })?;
         }
+        let selected = selector.join().map_err(|e| {
+            anyhow!("levels selector thread failed with err: {:?}", e)
+        })?;
+        let recorded = match order_recorder {
+            Some(order_recorder) => order_recorder.join().map_err(|e| {
+                anyhow!("level order thread failed with err: {:?}", e)
+            })?,
+            None => Ok(()),
+        };
         self.stats.stop();
         stats_thread.thread().unpark();
         stats_thread.join().map_err(|e| {
@@ -654,8 +1532,13 @@ impl Executor {
             Arc::try_unwrap(processed_results)
                 .map_err(|e| anyhow!("{:?}", e))?
                 .into_inner()?;
+        if let Some(bootstrap_levels) = &mut self.bootstrap_levels {
+            bootstrap_levels.add(&processed_levels);
+        }
+        selected.context("failed to select the levels to process")?;
+        recorded.context("failed to hand out the levels to process")?;
 
-        if !reprocess_levels.is_empty() {
+        if !reprocess_levels.is_empty() && !shutdown::requested() {
             warn!(
                 "reprocessing following forked levels: {:?}",
                 reprocess_levels
@@ -673,15 +1556,21 @@ impl Executor {
 
     fn read_block_chan(
         &mut self,
-        block_ch: flume::Receiver<Box<(LevelMeta, Block)>>,
-        processed_ch: flume::Sender<Box<ProcessedBlock>>,
+        block_ch: flume::Receiver<Box<FetchedBlock>>,
+        processed_ch: flume::Sender<Box<LevelBlock>>,
     ) -> Result<(Vec<u32>, Vec<u32>)> {
         let in_ch = block_ch.clone();
 
         let mut processed_levels: Vec<u32> = vec![];
         let mut reprocess_levels: Vec<u32> = vec![];
         for b in block_ch {
-            let (meta, block) = *b;
+            let (meta, block, size) = *b;
+            let _release = BudgetRelease::new(self.block_budget.clone(), size);
+            if shutdown::requested() {
+                // keep draining the channel, so that the block getters
+                // can finish the levels already handed to them
+                continue;
+            }
 
             let (processed_block, forked_lvls) = self
                 .exec_for_block(&meta, &block)
@@ -719,14 +1608,34 @@ impl Executor {
                     processed_ch.capacity().unwrap()
                 ),
             )?;
-            processed_ch.send(Box::new(processed_block))?;
+            processed_ch.send(Box::new((meta.level, processed_block)))?;
             self.stats
                 .add("processor", "levels", 1)?;
+            self.stats.add_levels_processed(1)?;
             self.stats.set(
                 "processor",
                 "last processed level",
                 format!("{} ({:?})", meta.level, meta.baked_at.unwrap()),
             )?;
+            let address_hit_rate = self
+                .address_cache
+                .lock()
+                .map_err(|_| anyhow!("failed to lock address_cache mutex"))?
+                .hit_rate();
+            if let Some(budget) = &self.block_budget {
+                self.stats.set(
+                    "processor",
+                    "buffered block bytes",
+                    format!("{}/{}", budget.used()?, budget.cap()),
+                )?;
+            }
+            if let Some(hit_rate) = address_hit_rate {
+                self.stats.set(
+                    "processor",
+                    "address cache hit rate",
+                    format!("{:.1}%", hit_rate),
+                )?;
+            }
 
             processed_levels.push(meta.level);
         }
@@ -747,6 +1656,13 @@ impl Executor {
                     level_height
                 )
             })?;
+        if let Some(hit_rate) = self.node_cli.block_cache_hit_rate()? {
+            self.stats.set(
+                "processor",
+                "block cache hit rate",
+                format!("{:.1}%", hit_rate),
+            )?;
+        }
 
         let mut res: Vec<SaveLevelResult> = vec![];
         let (processed_block, forked_lvls) = self
@@ -785,11 +1701,8 @@ impl Executor {
         #[cfg(feature = "regression_force_update_derived")]
         let update_derived_tables = true | update_derived_tables;
 
-        insert_processed(
-            &mut self.dbcli.clone(),
-            update_derived_tables,
-            processed_block,
-        )?;
+        self.sink(1)
+            .insert_processed(update_derived_tables, processed_block)?;
 
         Ok(res)
     }
@@ -806,7 +1719,9 @@ impl Executor {
             level.prev_hash.as_ref().unwrap(),
         )?;
 
-        let process_contracts = if self.all_contracts {
+        let process_contracts = if let Some(contract_id) = &self.reprocessing {
+            vec![contract_id.clone()]
+        } else if self.all_contracts {
             let active_contracts: Vec<ContractID> = block
                 .active_contracts()
                 .iter()
@@ -830,7 +1745,16 @@ impl Executor {
                 );
                 self.add_missing_contracts(&new_contracts)?;
             }
-            active_contracts
+            let mut res: Vec<ContractID> = vec![];
+            for contract_id in active_contracts {
+                if !self
+                    .mutexed_state
+                    .is_failed(&contract_id)?
+                {
+                    res.push(contract_id);
+                }
+            }
+            res
         } else {
             self.get_config()?
         };
@@ -842,15 +1766,14 @@ impl Executor {
                 .mutexed_state
                 .get_contract(contract_id)?
                 .unwrap();
-            contract_results.push(
-                self.exec_for_block_contract(level, block, &diffs, &contract)
-                    .with_context(|| {
-                        anyhow!(
-                            "err on processing contract={}",
-                            contract_id.name
-                        )
-                    })?,
-            );
+            let begin = Instant::now();
+            let cres = self
+                .exec_for_block_contract(level, block, &diffs, &contract)
+                .with_context(|| {
+                    anyhow!("err on processing contract={}", contract_id.name)
+                })?;
+            self.record_block_time(&cres, begin.elapsed())?;
+            contract_results.push(cres);
         }
         for cres in &contract_results {
             if cres.is_origination {
@@ -860,9 +1783,38 @@ impl Executor {
                 )?;
             }
         }
+
+        let hash = content_hash(&contract_results)?;
+        for cres in contract_results.iter_mut() {
+            cres.level.content_hash = Some(hash.clone());
+        }
         Ok((contract_results, forked_lvls))
     }
 
+    fn record_block_time(
+        &self,
+        cres: &ProcessedContractBlock,
+        elapsed: std::time::Duration,
+    ) -> Result<()> {
+        if let Some(threshold) = self.slow_block_threshold {
+            if elapsed > threshold {
+                warn!(
+                    "slow block: processing level {} of contract {} ({} txs) took {:?}",
+                    cres.level.level,
+                    cres.contract.cid.name,
+                    cres.tx_contexts.len(),
+                    elapsed
+                );
+            }
+        }
+        self.stats.add_block_time(SlowBlock {
+            level: cres.level.level,
+            contract: cres.contract.cid.name.clone(),
+            tx_count: cres.tx_contexts.len(),
+            duration: elapsed,
+        })
+    }
+
     fn exec_for_block_contract(
         &self,
         meta: &LevelMeta,
@@ -873,23 +1825,55 @@ impl Executor {
         let is_origination =
             block.has_contract_origination(&contract.cid.address);
 
-        if !is_origination && !block.is_contract_active(&contract.cid.address) {
+        let is_active = if self.record_all_calls || self.index_failures {
+            block.is_contract_called(&contract.cid.address)
+        } else {
+            block.is_contract_active(&contract.cid.address)
+        };
+        // levels below the floor (eg a configured start level) may still be
+        // processed for other contracts, but not for this one
+        let below_floor = matches!(
+            contract.level_floor,
+            Some(floor) if meta.level < floor
+        );
+        // delegations need not change the storage, so these are recorded
+        // for inactive contracts as well
+        let delegations = if self.index_delegations && !below_floor {
+            block.delegations(&contract.cid.address)
+        } else {
+            vec![]
+        };
+        if below_floor || (!is_origination && !is_active) {
             return Ok(ProcessedContractBlock {
                 level: meta.clone(),
                 contract: contract.clone(),
 
                 inserts: vec![],
+                spilled_inserts: None,
                 tx_contexts: vec![],
                 txs: vec![],
                 bigmap_contract_deps: vec![],
                 bigmap_keyhashes: HashMap::new(),
                 bigmap_meta_actions: vec![],
+                operation_failures: vec![],
+                raw_storage: vec![],
+                delegations,
                 is_origination: false,
+                balance: None,
+                deterministic_ids: self.deterministic_ids,
             });
         }
 
         let mut storage_processor = self.get_storage_processor()?;
         storage_processor.set_stats_logger(self.stats.clone());
+        storage_processor.set_record_all_calls(self.record_all_calls);
+        storage_processor.set_index_failures(self.index_failures);
+        storage_processor.set_keep_raw_storage(self.keep_raw_storage);
+        storage_processor.set_empty_string_as_null(self.empty_string_as_null);
+        storage_processor
+            .set_bigmap_copy_chunk_size(self.bigmap_copy_chunk_size);
+        storage_processor.set_deterministic_ids(self.deterministic_ids);
+        storage_processor.set_address_cache(self.address_cache.clone());
         storage_processor
             .process_block(block, diffs, contract)
             .with_context(|| {
@@ -904,18 +1888,35 @@ impl Executor {
         let bigmap_contract_deps =
             storage_processor.drain_bigmap_contract_dependencies();
         let bigmap_meta_actions = storage_processor.drain_bigmap_meta_actions();
+        let operation_failures = storage_processor.drain_operation_failures();
+        let raw_storage = storage_processor.drain_raw_storage();
+
+        let balance = if self.track_balance {
+            Some(
+                self.node_cli
+                    .get_contract_balance(&contract.cid.address, meta.level)?,
+            )
+        } else {
+            None
+        };
 
         Ok(ProcessedContractBlock {
             contract: contract.clone(),
             level: meta.clone(),
 
             inserts: inserts.values().cloned().collect(),
+            spilled_inserts: storage_processor.drain_spilled_inserts(),
             tx_contexts,
             txs,
             bigmap_contract_deps,
             bigmap_keyhashes: storage_processor.get_bigmap_keyhashes(),
             is_origination,
             bigmap_meta_actions,
+            operation_failures,
+            raw_storage,
+            delegations,
+            balance,
+            deterministic_ids: self.deterministic_ids,
         })
     }
 
@@ -1004,10 +2005,12 @@ impl Executor {
             })
             .collect::<Vec<String>>()
             .join(",");
-        let hash_msg = if contract_results.is_empty() {
-            "".to_string()
-        } else {
-            format!("\nblock has hash: {}", contract_results[0].hash)
+        let hash_msg = match contract_results
+            .iter()
+            .find_map(|c| c.hash.as_ref())
+        {
+            Some(hash) => format!("\nblock has hash: {}", hash),
+            None => "".to_string(),
         };
 
         if contract_statuses.is_empty() {
@@ -1017,11 +2020,91 @@ impl Executor {
     }
 }
 
+// Releases a fetched block's bytes from the budget once it is done with (ie
+// processed, skipped, or failed to process).
+struct BudgetRelease {
+    budget: Option<Arc<ByteBudget>>,
+    bytes: usize,
+}
+
+impl BudgetRelease {
+    fn new(budget: Option<Arc<ByteBudget>>, bytes: usize) -> Self {
+        Self { budget, bytes }
+    }
+}
+
+impl Drop for BudgetRelease {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            if let Err(e) = budget.release(self.bytes) {
+                warn!("failed to release buffered block bytes: {}", e);
+            }
+        }
+    }
+}
+
+// The levels processed while bootstrapping, to tell whether the derived
+// tables can be updated for just these levels when leaving bootstrap mode.
+#[derive(Clone, Debug)]
+struct BootstrapLevels {
+    // the db's head when bootstrapping started, the derived tables are up to
+    // date until here
+    derived_until: Option<u32>,
+    levels: BTreeSet<u32>,
+    // set once more levels were processed than are updated incrementally
+    exceeded: bool,
+}
+
+impl BootstrapLevels {
+    const MAX_INCREMENTAL_LEVELS: usize = 1000;
+
+    fn new(derived_until: Option<u32>) -> Self {
+        Self {
+            derived_until,
+            levels: BTreeSet::new(),
+            exceeded: false,
+        }
+    }
+
+    fn add(&mut self, levels: &[u32]) {
+        if self.exceeded {
+            return;
+        }
+        self.levels.extend(levels);
+        if self.levels.len() > Self::MAX_INCREMENTAL_LEVELS {
+            self.exceeded = true;
+            self.levels.clear();
+        }
+    }
+
+    // The range of levels to update the derived tables for, or None if they
+    // must be re-populated instead: the derived tables can only be updated
+    // with the levels directly following those they contain, and only when
+    // these have no gaps (levels that are filled in later would be out of
+    // order).
+    fn incremental_range(&self) -> Option<(u32, u32)> {
+        if self.exceeded {
+            return None;
+        }
+        let from = *self.levels.iter().next()?;
+        let to = *self.levels.iter().next_back()?;
+        if (to - from) as usize + 1 != self.levels.len() {
+            return None;
+        }
+        match self.derived_until {
+            Some(derived_until) if from != derived_until + 1 => None,
+            _ => Some((from, to)),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct MutexedState {
     #[allow(clippy::type_complexity)]
     contracts: Arc<Mutex<HashMap<ContractID, relational::Contract>>>,
     level_floor: Arc<Mutex<u32>>,
+    // the contracts that were skipped, see Executor::skip_failed_contract
+    failed_contracts: Arc<Mutex<HashSet<ContractID>>>,
 }
 
 impl MutexedState {
@@ -1029,6 +2112,7 @@ impl MutexedState {
         Self {
             contracts: Arc::new(Mutex::new(HashMap::new())),
             level_floor: Arc::new(Mutex::new(0)),
+            failed_contracts: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
@@ -1082,8 +2166,13 @@ impl MutexedState {
             .lock()
             .map_err(|_| anyhow!("failed to lock contracts mutex"))?;
 
+        // the floor may already be set higher than the origination, by a
+        // configured start level
         let mut v = contracts.get_mut(contract_id).unwrap();
-        v.level_floor = Some(level);
+        v.level_floor = Some(
+            v.level_floor
+                .map_or(level, |floor| floor.max(level)),
+        );
         Ok(())
     }
 
@@ -1108,6 +2197,7 @@ impl MutexedState {
         Ok(contracts.clone())
     }
 
+    // The contracts that are neither known nor were skipped before.
     pub fn get_missing_contracts(
         &self,
         l: &[ContractID],
@@ -1116,20 +2206,100 @@ impl MutexedState {
             .contracts
             .lock()
             .map_err(|_| anyhow!("failed to lock contracts mutex"))?;
+        let failed_contracts = self
+            .failed_contracts
+            .lock()
+            .map_err(|_| anyhow!("failed to lock failed contracts mutex"))?;
 
         Ok(l.iter()
-            .filter(|contract_id| !contracts.contains_key(contract_id))
+            .filter(|contract_id| {
+                !contracts.contains_key(contract_id)
+                    && !failed_contracts.contains(contract_id)
+            })
             .cloned()
             .collect::<Vec<ContractID>>())
     }
+
+    pub fn add_failed_contract(&self, contract_id: &ContractID) -> Result<()> {
+        self.failed_contracts
+            .lock()
+            .map_err(|_| anyhow!("failed to lock failed contracts mutex"))?
+            .insert(contract_id.clone());
+        Ok(())
+    }
+
+    pub fn is_failed(&self, contract_id: &ContractID) -> Result<bool> {
+        Ok(self
+            .failed_contracts
+            .lock()
+            .map_err(|_| anyhow!("failed to lock failed contracts mutex"))?
+            .contains(contract_id))
+    }
 }
 
 pub(crate) fn get_contract_rel(
     node_cli: &NodeClient,
     cid: &ContractID,
+    layout_settings: &relational::LayoutSettings,
 ) -> Result<relational::Contract> {
+    let rename_map = layout_settings
+        .rename_maps
+        .get(&cid.name)
+        .cloned()
+        .unwrap_or_default();
+    let mut packed_columns: HashMap<String, HashMap<String, typing::Ele>> =
+        HashMap::new();
+    if let Some(tables) = layout_settings
+        .packed_columns
+        .get(&cid.name)
+    {
+        for (table, columns) in tables {
+            for (column, packed_type) in columns {
+                let packed_type = typing::type_ast_from_json(packed_type)
+                    .with_context(|| {
+                        anyhow!(
+                            "bad packed type for column {} (table {})",
+                            column,
+                            table
+                        )
+                    })
+                    .with_context(|| {
+                        anyhow!("contract address={}", cid.address)
+                    })?;
+                packed_columns
+                    .entry(table.clone())
+                    .or_default()
+                    .insert(column.clone(), packed_type);
+            }
+        }
+    }
+    let excluded_columns = layout_settings
+        .excluded_columns
+        .get(&cid.name)
+        .cloned()
+        .unwrap_or_default();
+    let builder = |root_table: &str| {
+        let mut builder = relational::ASTBuilder::new(root_table);
+        builder.with_rename_map(rename_map.clone());
+        builder.with_packed_columns(packed_columns.clone());
+        builder.with_excluded_columns(excluded_columns.clone());
+        if layout_settings.flatten_ors {
+            builder.flatten_ors();
+        }
+        builder
+    };
+
+    let mut get_constant = |hash: &str| node_cli.get_global_constant(hash);
     let (storage_def, _) =
         &node_cli.get_contract_storage_definition(&cid.address, None)?;
+    let storage_def = &typing::expand_global_constants(
+        storage_def,
+        &mut get_constant,
+    )
+    .with_context(|| {
+        "failed to expand the global constants of the storage definition"
+    })
+    .with_context(|| anyhow!("contract address={}", cid.address))?;
     let type_ast = typing::type_ast_from_json(storage_def)
         .with_context(|| {
             "failed to derive a storage type from the storage definition"
@@ -1145,7 +2315,9 @@ pub(crate) fn get_contract_rel(
     );
 
     // Build the internal representation from the storage defition
-    let storage_ast = relational::ASTBuilder::new("storage")
+    let root_table = layout_settings.root_table(&cid.name)?;
+    let mut storage_builder = builder(&root_table);
+    let storage_ast = storage_builder
         .build_relational_ast(&type_ast)
         .with_context(|| {
             "failed to build a relational AST from the storage type"
@@ -1156,8 +2328,23 @@ pub(crate) fn get_contract_rel(
     let entrypoint_defs =
         &node_cli.get_contract_entrypoint_definitions(&cid.address, None)?;
 
+    let mut table_origins = storage_builder.table_origins().clone();
     let mut entrypoint_asts: HashMap<String, RelationalAST> = HashMap::new();
     for (entrypoint, entrypoint_def) in entrypoint_defs {
+        let entrypoint_def = &typing::expand_global_constants(
+            entrypoint_def,
+            &mut get_constant,
+        )
+        .with_context(|| {
+            "failed to expand the global constants of the entrypoint type"
+        })
+        .with_context(|| {
+            anyhow!(
+                "contract address={}, entrypoint={}",
+                cid.address,
+                entrypoint
+            )
+        })?;
         let type_ast = typing::type_ast_from_json(entrypoint_def)
             .with_context(|| "failed to derive an entrypoint type ast")
             .with_context(|| {
@@ -1169,32 +2356,253 @@ pub(crate) fn get_contract_rel(
             })?;
 
         // Build the internal representation from the storage defition
-        let rel_ast = relational::ASTBuilder::new(
-            format!("entry.{}", entrypoint).as_str(),
-        )
-        .memoryless_bigmaps()
-        .build_relational_ast(&type_ast)
-        .with_context(|| {
-            "failed to build a relational AST from the entrypoint type"
-        })
-        .with_context(|| {
-            anyhow!(
-                "contract address={}, entrypoint={}",
-                cid.address,
-                entrypoint
-            )
-        })?;
+        let mut entrypoint_builder =
+            builder(format!("entry.{}", entrypoint).as_str());
+        let rel_ast = entrypoint_builder
+            .memoryless_bigmaps()
+            .build_relational_ast(&type_ast)
+            .with_context(|| {
+                "failed to build a relational AST from the entrypoint type"
+            })
+            .with_context(|| {
+                anyhow!(
+                    "contract address={}, entrypoint={}",
+                    cid.address,
+                    entrypoint
+                )
+            })?;
 
+        table_origins.extend(
+            entrypoint_builder
+                .table_origins()
+                .clone(),
+        );
         entrypoint_asts.insert(entrypoint.clone(), rel_ast);
     }
 
-    Ok(relational::Contract {
+    let mut found_packed: Vec<(String, String)> =
+        relational::packed_columns(&storage_ast);
+    for rel_ast in entrypoint_asts.values() {
+        found_packed.extend(relational::packed_columns(rel_ast));
+    }
+    for (table, columns) in &packed_columns {
+        for column in columns.keys() {
+            if !found_packed.contains(&(table.clone(), column.clone())) {
+                return Err(anyhow!(
+                    "packed column {} (table {}) is not a bytes column of the contract",
+                    column,
+                    table
+                ))
+                .with_context(|| anyhow!("contract address={}", cid.address));
+            }
+        }
+    }
+
+    let mut found_skipped: Vec<(String, String)> =
+        relational::skipped_columns(&storage_ast);
+    for rel_ast in entrypoint_asts.values() {
+        found_skipped.extend(relational::skipped_columns(rel_ast));
+    }
+    for (table, columns) in &excluded_columns {
+        for column in columns {
+            if !found_skipped.contains(&(table.clone(), column.clone())) {
+                return Err(anyhow!(
+                    "excluded column {} (table {}) is not a (non-key) column of the contract",
+                    column,
+                    table
+                ))
+                .with_context(|| anyhow!("contract address={}", cid.address));
+            }
+        }
+    }
+
+    let bigmap_filter = layout_settings
+        .bigmap_filters
+        .get(&cid.name)
+        .cloned()
+        .unwrap_or_default();
+    if !bigmap_filter.include.is_empty() && !bigmap_filter.exclude.is_empty() {
+        return Err(anyhow!(
+            "bigmaps can either be included or excluded, not both"
+        ))
+        .with_context(|| anyhow!("contract address={}", cid.address));
+    }
+    let bigmap_tables = relational::bigmap_tables(&storage_ast);
+    for table in bigmap_filter
+        .include
+        .iter()
+        .chain(bigmap_filter.exclude.iter())
+    {
+        if !bigmap_tables.contains(table) {
+            return Err(anyhow!(
+                "{} is not a bigmap table of the contract (its bigmap tables: {})",
+                table,
+                bigmap_tables.join(", ")
+            ))
+            .with_context(|| anyhow!("contract address={}", cid.address));
+        }
+    }
+
+    let metadata_uris = layout_settings
+        .metadata_uris
+        .get(&cid.name)
+        .cloned()
+        .unwrap_or_default();
+
+    let contract = relational::Contract {
         cid: cid.clone(),
         level_floor: None,
+        table_snapshots: layout_settings
+            .table_snapshots
+            .get(&cid.name)
+            .cloned()
+            .unwrap_or_default(),
+        table_origins,
+        root_table,
+        column_types: layout_settings
+            .column_types
+            .get(&cid.name)
+            .cloned()
+            .unwrap_or_default(),
+        bigmap_filter,
+        metadata_uris,
 
         storage_ast,
         entrypoint_asts,
-    })
+    };
+    TableBuilder::tables_from_contract(&contract)
+        .with_context(|| anyhow!("contract address={}", cid.address))?;
+    Ok(contract)
+}
+
+/// The contract's relational ASTs together with the tables derived from
+/// them, for external tooling that needs to map storage paths to columns.
+pub(crate) fn contract_layout_json(
+    contract: &relational::Contract,
+) -> Result<serde_json::Value> {
+    #[derive(Serialize)]
+    struct TableLayout<'a> {
+        name: &'a str,
+        columns: Vec<&'a Column>,
+        indices: &'a [String],
+        snapshots: bool,
+    }
+
+    let (mut tables, _, _) = TableBuilder::tables_from_contract(contract)?;
+    tables.sort_by(|a, b| a.name.cmp(&b.name));
+    let tables: Vec<TableLayout> = tables
+        .iter()
+        .map(|t| TableLayout {
+            name: &t.name,
+            columns: t.get_columns(),
+            indices: &t.indices,
+            snapshots: t.contains_snapshots(),
+        })
+        .collect();
+
+    Ok(serde_json::json!({
+        "contract": contract.cid,
+        "root_table": contract.root_table,
+        "storage_ast": contract.storage_ast,
+        "entrypoint_asts": contract.entrypoint_asts,
+        "tables": tables,
+    }))
+}
+
+// The explicitly set dependencies of the contracts in config, that are not
+// part of config themselves. Like the dependencies found while indexing, they
+// are named after their address.
+fn static_deps(
+    config: &[ContractID],
+    static_deps: &HashMap<String, Vec<String>>,
+) -> Vec<ContractID> {
+    let mut res: Vec<ContractID> = vec![];
+    for contract_id in config {
+        for address in static_deps
+            .get(&contract_id.name)
+            .map_or(&[] as &[String], |deps| deps.as_slice())
+        {
+            if config
+                .iter()
+                .any(|c| &c.address == address)
+                || res
+                    .iter()
+                    .any(|c| &c.address == address)
+            {
+                continue;
+            }
+            res.push(ContractID {
+                name: address.clone(),
+                address: address.clone(),
+            });
+        }
+    }
+    res
+}
+
+// Sorts the contracts by priority (highest first, contracts without one
+// have priority 0), and by name within the same priority.
+fn sort_by_priority(
+    contracts: &mut [ContractID],
+    priorities: &HashMap<String, u32>,
+) {
+    contracts.sort_by_key(|contract_id| {
+        (
+            std::cmp::Reverse(
+                priorities
+                    .get(&contract_id.name)
+                    .copied()
+                    .unwrap_or(0),
+            ),
+            contract_id.name.clone(),
+        )
+    });
+}
+
+// Splits the contracts, sorted with sort_by_priority, into groups of the
+// same priority.
+fn priority_groups(
+    contracts: &[ContractID],
+    priorities: &HashMap<String, u32>,
+) -> Vec<Vec<ContractID>> {
+    let priority = |contract_id: &ContractID| {
+        priorities
+            .get(&contract_id.name)
+            .copied()
+            .unwrap_or(0)
+    };
+    let mut res: Vec<Vec<ContractID>> = vec![];
+    for contract_id in contracts {
+        match res.last_mut() {
+            Some(group) if priority(&group[0]) == priority(contract_id) => {
+                group.push(contract_id.clone())
+            }
+            _ => res.push(vec![contract_id.clone()]),
+        }
+    }
+    res
+}
+
+fn has_bigmaps(ast: &RelationalAST) -> bool {
+    match ast {
+        RelationalAST::BigMap { .. } => true,
+        RelationalAST::Option { elem_ast } => has_bigmaps(elem_ast),
+        RelationalAST::Pair {
+            left_ast,
+            right_ast,
+        }
+        | RelationalAST::OrEnumeration {
+            left_ast,
+            right_ast,
+            ..
+        } => has_bigmaps(left_ast) || has_bigmaps(right_ast),
+        RelationalAST::Map {
+            key_ast, value_ast, ..
+        } => has_bigmaps(key_ast) || has_bigmaps(value_ast),
+        RelationalAST::List { elems_ast, .. } => has_bigmaps(elems_ast),
+        RelationalAST::Packed { packed_ast, .. } => has_bigmaps(packed_ast),
+        RelationalAST::Leaf { .. } => false,
+    }
 }
 
 #[test]
@@ -1269,6 +2677,52 @@ fn test_generate() {
     }
 }
 
+#[test]
+fn test_generate_composite_key_indices() {
+    use crate::sql::postgresql_generator::PostgresqlGenerator;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+
+    // the derived tables' templates select the latest row per key by all
+    // of the table's indices, so each part of a composite key must be one
+    for (prim, exp_indices) in &[
+        (
+            "map",
+            vec![r#""idx_address""#, r#""idx_nat""#, r#""storage_id""#],
+        ),
+        ("big_map", vec![r#""idx_address""#, r#""idx_nat""#]),
+    ] {
+        let storage_definition = serde_json::json!({
+            "prim": prim,
+            "args": [
+                {
+                    "prim": "pair",
+                    "args": [{"prim": "address"}, {"prim": "nat"}],
+                },
+                {"prim": "mutez"},
+            ],
+        });
+        let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+        let rel_ast = ASTBuilder::new("storage")
+            .build_relational_ast(&type_ast)
+            .unwrap();
+        let mut builder =
+            crate::sql::table_builder::TableBuilder::new("storage");
+        builder.populate(&rel_ast);
+
+        let table = &builder.tables["storage.noname"];
+        assert_eq!(
+            PostgresqlGenerator::table_sql_indices(table, false),
+            exp_indices
+                .iter()
+                .map(|idx| idx.to_string())
+                .collect::<Vec<String>>(),
+            "{}",
+            prim,
+        );
+    }
+}
+
 #[test]
 fn test_get_origination_operations_from_block() {
     use crate::octez::block::Block;
@@ -1298,3 +2752,286 @@ fn test_get_origination_operations_from_block() {
 
 #[test]
 fn test_storage() {}
+
+#[test]
+fn test_contract_layout_json() {
+    use crate::storage_structure::relational::ASTBuilder;
+
+    let storage_definition = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {
+                "prim": "address",
+                "annots": ["%admin"],
+            },
+            {
+                "prim": "big_map",
+                "args": [{"prim": "nat"}, {"prim": "mutez"}],
+                "annots": ["%ledger"],
+            },
+        ],
+    });
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let contract = relational::Contract {
+        cid: ContractID {
+            name: "testcontract".to_string(),
+            address: "KT1".to_string(),
+        },
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: Default::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        storage_ast: ASTBuilder::new("storage")
+            .build_relational_ast(&type_ast)
+            .unwrap(),
+        entrypoint_asts: HashMap::new(),
+    };
+
+    let got = contract_layout_json(&contract).unwrap();
+    assert_eq!("testcontract", got["contract"]["name"]);
+    assert_eq!(
+        "admin",
+        got["storage_ast"]["Pair"]["left_ast"]["Leaf"]["rel_entry"]
+            ["column_name"]
+    );
+
+    let tables = got["tables"].as_array().unwrap();
+    assert_eq!(
+        vec!["storage", "storage.ledger"],
+        tables
+            .iter()
+            .map(|t| t["name"].as_str().unwrap())
+            .collect::<Vec<&str>>()
+    );
+    assert_eq!(true, tables[0]["snapshots"]);
+    assert_eq!(false, tables[1]["snapshots"]);
+    assert_eq!(
+        vec![
+            "tx_context_id",
+            "id",
+            "level",
+            "level_timestamp",
+            "idx_nat",
+            "mutez",
+            "bigmap_id",
+            "deleted"
+        ],
+        tables[1]["columns"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["name"].as_str().unwrap())
+            .collect::<Vec<&str>>()
+    );
+}
+
+#[test]
+fn test_static_deps() {
+    let contract = |name: &str, address: &str| ContractID {
+        name: name.to_string(),
+        address: address.to_string(),
+    };
+    let config = vec![contract("a", "KT1a"), contract("b", "KT1b")];
+    let mut deps: HashMap<String, Vec<String>> = HashMap::new();
+    deps.insert(
+        "a".to_string(),
+        vec!["KT1x".to_string(), "KT1b".to_string()],
+    );
+    deps.insert(
+        "b".to_string(),
+        vec!["KT1x".to_string(), "KT1y".to_string()],
+    );
+    deps.insert("c".to_string(), vec!["KT1z".to_string()]);
+
+    assert_eq!(
+        vec![contract("KT1x", "KT1x"), contract("KT1y", "KT1y")],
+        static_deps(&config, &deps)
+    );
+}
+
+#[test]
+fn test_priority_groups() {
+    let contract = |name: &str| ContractID {
+        name: name.to_string(),
+        address: format!("KT1{}", name),
+    };
+    let mut priorities: HashMap<String, u32> = HashMap::new();
+    priorities.insert("minor".to_string(), 1);
+    priorities.insert("critical".to_string(), 10);
+
+    let mut contracts = vec![
+        contract("b"),
+        contract("minor"),
+        contract("critical"),
+        contract("a"),
+    ];
+    sort_by_priority(&mut contracts, &priorities);
+    assert_eq!(
+        vec![
+            contract("critical"),
+            contract("minor"),
+            contract("a"),
+            contract("b"),
+        ],
+        contracts
+    );
+    assert_eq!(
+        vec![
+            vec![contract("critical")],
+            vec![contract("minor")],
+            vec![contract("a"), contract("b")],
+        ],
+        priority_groups(&contracts, &priorities)
+    );
+
+    // without priorities, all contracts are indexed together (by name)
+    sort_by_priority(&mut contracts, &HashMap::new());
+    assert_eq!(
+        vec![vec![
+            contract("a"),
+            contract("b"),
+            contract("critical"),
+            contract("minor"),
+        ]],
+        priority_groups(&contracts, &HashMap::new())
+    );
+}
+
+#[test]
+fn test_has_bigmaps() {
+    use crate::storage_structure::relational::ASTBuilder;
+
+    let ast = |storage_definition: serde_json::Value| {
+        ASTBuilder::new("storage")
+            .build_relational_ast(
+                &typing::type_ast_from_json(&storage_definition).unwrap(),
+            )
+            .unwrap()
+    };
+    assert!(has_bigmaps(&ast(serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {"prim": "address"},
+            {"prim": "option", "args": [{
+                "prim": "big_map",
+                "args": [{"prim": "nat"}, {"prim": "mutez"}],
+            }]},
+        ],
+    }))));
+    assert!(!has_bigmaps(&ast(serde_json::json!({
+        "prim": "map",
+        "args": [{"prim": "nat"}, {"prim": "mutez"}],
+    }))));
+}
+
+#[test]
+fn test_bootstrap_levels() {
+    let mut levels = BootstrapLevels::new(Some(100));
+    assert_eq!(None, levels.incremental_range());
+    levels.add(&[103, 101]);
+    levels.add(&[102]);
+    assert_eq!(Some((101, 103)), levels.incremental_range());
+
+    // gap
+    levels.add(&[105]);
+    assert_eq!(None, levels.incremental_range());
+
+    // not newer than the derived tables
+    let mut levels = BootstrapLevels::new(Some(100));
+    levels.add(&[100, 101]);
+    assert_eq!(None, levels.incremental_range());
+
+    // not directly following the derived tables
+    let mut levels = BootstrapLevels::new(Some(100));
+    levels.add(&[102, 103]);
+    assert_eq!(None, levels.incremental_range());
+
+    let mut levels = BootstrapLevels::new(None);
+    levels.add(&[5, 6]);
+    assert_eq!(Some((5, 6)), levels.incremental_range());
+
+    // too many
+    let mut levels = BootstrapLevels::new(Some(0));
+    levels.add(
+        &(1..=BootstrapLevels::MAX_INCREMENTAL_LEVELS as u32 + 1)
+            .collect::<Vec<u32>>(),
+    );
+    assert_eq!(None, levels.incremental_range());
+    levels.add(&[1]);
+    assert_eq!(None, levels.incremental_range());
+}
+
+#[test]
+fn test_save_level_result() {
+    let processed_block = crate::test_support::test_block(42, &[("a", true)]);
+    let res = SaveLevelResult::from_processed_block(&processed_block[0]);
+    assert_eq!(42, res.level);
+    assert_eq!(Some("hash42".to_string()), res.hash);
+    assert_eq!("a", res.contract_id.name);
+
+    let mut processed_block = processed_block;
+    processed_block[0].level.hash = None;
+    let res = SaveLevelResult::from_processed_block(&processed_block[0]);
+    assert_eq!(42, res.level);
+    assert_eq!(None, res.hash);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_skip_failed_contracts() {
+    use crate::octez::node::mock_node;
+    use crate::sql::db::{drop_test_db, test_db};
+
+    let schema = "test_skip_failed_contracts";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    // the node returns a malformed script for every contract
+    let node_cli = NodeClient::new(
+        vec![mock_node("200 OK", "{}\n")],
+        "main".to_string(),
+        0,
+        1,
+    );
+    let contract_id = ContractID {
+        name: "KT1failing".to_string(),
+        address: "KT1failing".to_string(),
+    };
+
+    let mut executor = Executor::new(node_cli.clone(), dbcli.clone(), 0);
+    executor.index_all_contracts();
+    executor
+        .add_missing_contracts(std::slice::from_ref(&contract_id))
+        .unwrap();
+    assert!(executor
+        .mutexed_state
+        .get_contract(&contract_id)
+        .unwrap()
+        .is_none());
+    // and is not tried again
+    assert!(executor
+        .mutexed_state
+        .get_missing_contracts(std::slice::from_ref(&contract_id))
+        .unwrap()
+        .is_empty());
+    let failed = dbcli.get_failed_contracts().unwrap();
+    assert_eq!(1, failed.len());
+    assert_eq!(contract_id, failed[0].0);
+    assert!(failed[0]
+        .1
+        .contains("malformed script response"));
+
+    let mut executor = Executor::new(node_cli, dbcli.clone(), 0);
+    executor.index_all_contracts();
+    executor.fail_on_contract_errors();
+    assert!(executor
+        .add_missing_contracts(&[contract_id])
+        .is_err());
+
+    drop_test_db(&dbcli, schema);
+}
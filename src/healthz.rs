@@ -0,0 +1,221 @@
+use anyhow::Result;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::executor::Executor;
+use crate::level_feed::LevelFeed;
+use crate::sql::db::DBClient;
+
+// Minimal http server for readiness probes: GET /healthz responds with 200
+// when the db is at most max_lag levels behind the chain's head, and with 503
+// otherwise.
+//
+// With a level feed, GET /levels streams the committed levels as server-sent
+// events (see LevelFeed).
+pub(crate) fn serve(
+    port: u16,
+    executor: Executor,
+    max_lag: i64,
+    level_feed: Option<LevelFeed>,
+) -> Result<thread::JoinHandle<()>> {
+    info!("serving /healthz on port {}", port);
+    if level_feed.is_some() {
+        info!("serving /levels on port {}", port);
+    }
+    serve_http(port, level_feed, move |path| {
+        if path != "/healthz" {
+            return ("404 Not Found", "not found".to_string());
+        }
+        match executor.indexing_lag() {
+            Ok(Some(lag)) if lag <= max_lag => {
+                ("200 OK", format!("{} blocks behind head", lag))
+            }
+            Ok(Some(lag)) => (
+                "503 Service Unavailable",
+                format!("{} blocks behind head", lag),
+            ),
+            Ok(None) => {
+                ("503 Service Unavailable", "no levels indexed".to_string())
+            }
+            Err(e) => (
+                "503 Service Unavailable",
+                format!("failed to determine indexing lag: {}", e),
+            ),
+        }
+    })
+}
+
+// The /healthz of a read-only follower, which has no node to compare the db
+// against: responds with 200 when the db can be read and has indexed levels,
+// and with 503 otherwise.
+pub(crate) fn serve_read_only(
+    port: u16,
+    dbcli: DBClient,
+) -> Result<thread::JoinHandle<()>> {
+    info!("serving /healthz (read-only) on port {}", port);
+    serve_http(port, None, move |path| {
+        if path != "/healthz" {
+            return ("404 Not Found", "not found".to_string());
+        }
+        match dbcli.clone().get_head() {
+            Ok(Some(head)) => {
+                ("200 OK", format!("level {} indexed", head.level))
+            }
+            Ok(None) => {
+                ("503 Service Unavailable", "no levels indexed".to_string())
+            }
+            Err(e) => (
+                "503 Service Unavailable",
+                format!("failed to read the indexed head: {}", e),
+            ),
+        }
+    })
+}
+
+// Serves each request with the (status, body) that respond returns for the
+// requested path, except for requests of /levels when there is a level feed.
+fn serve_http<F>(
+    port: u16,
+    level_feed: Option<LevelFeed>,
+    respond: F,
+) -> Result<thread::JoinHandle<()>>
+where
+    F: Fn(&str) -> (&'static str, String) + Send + 'static,
+{
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) =
+                        handle(stream, level_feed.as_ref(), &respond)
+                    {
+                        warn!("failed to serve http request: {}", e);
+                    }
+                }
+                Err(e) => warn!("failed to accept http connection: {}", e),
+            }
+        }
+    }))
+}
+
+// Requests are served one at a time, so a client that connects without
+// sending its request must not hold up the next ones for long.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn handle<F>(
+    mut stream: TcpStream,
+    level_feed: Option<&LevelFeed>,
+    respond: &F,
+) -> Result<()>
+where
+    F: Fn(&str) -> (&'static str, String),
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("");
+    if let (LEVELS_PATH, Some(level_feed)) = (path, level_feed) {
+        return stream_levels(stream, level_feed);
+    }
+    let (status, body) = respond(path);
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+const LEVELS_PATH: &str = "/levels";
+
+// Comments are sent when there are no events for this long, so clients (and
+// proxies in between) don't time out the stream, and clients that went away
+// are noticed.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Streams the levels of the feed to the client as server-sent events, from
+// a thread of its own (the stream stays open until the client goes away).
+fn stream_levels(mut stream: TcpStream, level_feed: &LevelFeed) -> Result<()> {
+    let events = level_feed.subscribe()?;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n"
+    )?;
+    stream.flush()?;
+
+    thread::spawn(move || {
+        if let Err(e) = write_events(&mut stream, &events) {
+            debug!("stopped streaming levels to a client: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn write_events<W: Write>(
+    out: &mut W,
+    events: &flume::Receiver<String>,
+) -> Result<()> {
+    loop {
+        match events.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(event) => write!(out, "data: {}\n\n", event)?,
+            Err(flume::RecvTimeoutError::Timeout) => {
+                write!(out, ": keepalive\n\n")?
+            }
+            Err(flume::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        out.flush()?;
+    }
+}
+
+#[test]
+fn test_write_events() {
+    let (send_ch, recv_ch) = flume::unbounded::<String>();
+    send_ch
+        .send(r#"{"level":1}"#.to_string())
+        .unwrap();
+    send_ch
+        .send(r#"{"level":2}"#.to_string())
+        .unwrap();
+    drop(send_ch);
+
+    let mut out: Vec<u8> = vec![];
+    write_events(&mut out, &recv_ch).unwrap();
+    assert_eq!(
+        "data: {\"level\":1}\n\ndata: {\"level\":2}\n\n",
+        String::from_utf8(out).unwrap()
+    );
+}
+
+#[test]
+fn test_serve_http_idle_client() {
+    use std::io::Read;
+
+    let port = 38091;
+    serve_http(port, None, |path| ("200 OK", path.to_string())).unwrap();
+
+    // a client that never sends its request
+    let _idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    client
+        .set_read_timeout(Some(READ_TIMEOUT * 3))
+        .unwrap();
+    write!(client, "GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    client
+        .read_to_string(&mut response)
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("/healthz"), "{}", response);
+}
@@ -0,0 +1,441 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::octez::block::TxContext;
+use crate::sql::db;
+use crate::sql::db::DBClient;
+use crate::sql::insert::Insert;
+use crate::sql::inserter::{
+    insert_batch, LevelBlock, LevelOrder, ProcessedBatch, ProcessedBlock,
+    ProcessedBlockSink, ProcessedContractBlock, SPILLED_INSERTS_CHUNK_SIZE,
+};
+use crate::sql::types::BigmapMetaAction;
+use crate::stats::StatsLogger;
+#[cfg(test)]
+use crate::test_support::test_block;
+
+// Produces the contracts' data to a Kafka topic (through a Kafka REST proxy),
+// instead of inserting it into the contracts' schemas. Everything else (the
+// processed levels, tx contexts, bigmap keys, ..) is still inserted into the
+// db, as it is needed to continue indexing.
+//
+// One message is produced per contract and level at which the contract is
+// active, keyed by the contract's name, in level order. A level's data is
+// produced before the level is saved in the db, so after a crash (or a fork)
+// levels may be produced again.
+#[derive(Clone)]
+pub(crate) struct KafkaSink {
+    dbcli: DBClient,
+
+    // url of the Kafka REST proxy
+    url: String,
+    topic: String,
+
+    // the number of processed blocks to collect before producing them
+    batch_size: usize,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    contract: &'a str,
+    address: &'a str,
+    level: u32,
+    level_hash: Option<&'a str>,
+    baked_at: Option<DateTime<Utc>>,
+    is_origination: bool,
+    inserts: &'a [Insert],
+    tx_contexts: &'a [TxContext],
+    bigmap_actions: &'a [BigmapMetaAction],
+}
+
+impl KafkaSink {
+    const PRODUCE_TIMEOUT: Duration = Duration::from_secs(60);
+    // how long processed levels may wait on an earlier level (see InOrder)
+    const MAX_HELD_BACK: Duration = Duration::from_secs(60 * 60);
+
+    pub(crate) fn new(
+        dbcli: DBClient,
+        url: &str,
+        topic: &str,
+        batch_size: usize,
+    ) -> Self {
+        Self {
+            dbcli,
+            url: url.trim_end_matches('/').to_string(),
+            topic: topic.to_string(),
+            batch_size,
+        }
+    }
+
+    fn exec(
+        &mut self,
+        stats: &StatsLogger,
+        recv_ch: flume::Receiver<Box<LevelBlock>>,
+        level_order: LevelOrder,
+    ) -> Result<()> {
+        let mut in_order = InOrder::new(level_order, Self::MAX_HELD_BACK);
+        let mut batch = ProcessedBatch::new(self.dbcli.get_max_id()?);
+        let mut records: Vec<serde_json::Value> = vec![];
+
+        loop {
+            let (level, processed_block) =
+                match recv_ch.recv_timeout(Duration::from_secs(1)) {
+                    Ok(received) => *received,
+                    Err(flume::RecvTimeoutError::Timeout) => {
+                        in_order.check_held_back()?;
+                        continue;
+                    }
+                    Err(flume::RecvTimeoutError::Disconnected) => break,
+                };
+            for mut block in in_order.add(level, processed_block)? {
+                batch.offset_ids(&mut block);
+                records.extend(records_for_block(&block)?);
+                batch.add_offset(block);
+
+                if batch.len() >= self.batch_size {
+                    self.flush(Some(stats), &batch, &records)?;
+                    batch.clear();
+                    records.clear();
+                }
+            }
+        }
+        // processed blocks that are still held back wait on a level that
+        // was not processed (eg on shutdown), they are processed again on the
+        // next run
+        self.flush(Some(stats), &batch, &records)
+    }
+
+    fn flush(
+        &mut self,
+        stats: Option<&StatsLogger>,
+        batch: &ProcessedBatch,
+        records: &[serde_json::Value],
+    ) -> Result<()> {
+        if batch.len() == 0 {
+            return Ok(());
+        }
+        self.produce(records)?;
+        if let Some(stats) = stats {
+            stats.add("inserter", "kafka messages", records.len())?;
+        }
+
+        let retries = self.dbcli.get_conn_retries();
+        let dbcli = &mut self.dbcli;
+        db::retry_with_backoff(
+            "insert a batch of processed levels",
+            retries,
+            db::is_conn_err,
+            || insert_batch(dbcli, stats, false, batch),
+        )
+    }
+
+    fn produce(&self, records: &[serde_json::Value]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let uri = format!("{}/topics/{}", self.url, self.topic);
+        debug!("POST {} ({} records)..", uri, records.len());
+
+        let resp = reqwest::blocking::Client::new()
+            .post(&uri)
+            .header("Content-Type", "application/vnd.kafka.json.v2+json")
+            .header("Accept", "application/vnd.kafka.v2+json")
+            .timeout(Self::PRODUCE_TIMEOUT)
+            .body(serde_json::to_string(
+                &serde_json::json!({ "records": records }),
+            )?)
+            .send()?;
+        let status = resp.status();
+        let body = resp.text()?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "failed to produce to Kafka topic {}: {} {}",
+                self.topic,
+                status,
+                body
+            ));
+        }
+        produce_error(&serde_json::from_str(&body)?).map_or(Ok(()), |err| {
+            Err(anyhow!(
+                "failed to produce to Kafka topic {}: {}",
+                self.topic,
+                err
+            ))
+        })
+    }
+}
+
+impl ProcessedBlockSink for KafkaSink {
+    fn run(
+        &self,
+        stats: &StatsLogger,
+        recv_ch: flume::Receiver<Box<LevelBlock>>,
+        level_order: Option<LevelOrder>,
+    ) -> Result<thread::JoinHandle<Result<()>>> {
+        let level_order = level_order.ok_or_else(|| {
+            anyhow!("producing to Kafka requires the order of the levels")
+        })?;
+        let mut sink = self.clone();
+        sink.dbcli = sink.dbcli.with_role("inserter");
+        let stats_cl = stats.clone();
+
+        let thread_handle =
+            thread::spawn(move || sink.exec(&stats_cl, recv_ch, level_order));
+        Ok(thread_handle)
+    }
+
+    fn insert_processed(
+        &mut self,
+        _update_derived_tables: bool,
+        mut processed: ProcessedBlock,
+    ) -> Result<()> {
+        let mut batch = ProcessedBatch::new(self.dbcli.get_max_id()?);
+        batch.offset_ids(&mut processed);
+        let records = records_for_block(&processed)?;
+        batch.add_offset(processed);
+
+        self.flush(None, &batch, &records)
+    }
+
+    fn needs_level_order(&self) -> bool {
+        true
+    }
+}
+
+// Holds back processed blocks until all levels handed out before theirs are
+// processed, so that they are released in the order the levels were handed
+// out.
+//
+// A level that was handed out but never arrives (eg as processing it failed)
+// would hold back all later levels for good, so blocks are held back for at
+// most max_held_back, after which adding or checking fails.
+struct InOrder {
+    level_order: LevelOrder,
+    pending: HashMap<u32, ProcessedBlock>,
+    max_held_back: Duration,
+    // since when pending blocks are held back without any being released
+    held_back_since: Option<Instant>,
+}
+
+impl InOrder {
+    fn new(level_order: LevelOrder, max_held_back: Duration) -> Self {
+        Self {
+            level_order,
+            pending: HashMap::new(),
+            max_held_back,
+            held_back_since: None,
+        }
+    }
+
+    fn add(
+        &mut self,
+        level: u32,
+        block: ProcessedBlock,
+    ) -> Result<Vec<ProcessedBlock>> {
+        self.pending.insert(level, block);
+
+        let mut released: Vec<ProcessedBlock> = vec![];
+        {
+            let mut level_order = self
+                .level_order
+                .lock()
+                .map_err(|_| anyhow!("failed to lock level_order mutex"))?;
+            while let Some(next) = level_order.front() {
+                match self.pending.remove(next) {
+                    Some(block) => released.push(block),
+                    None => break,
+                }
+                level_order.pop_front();
+            }
+        }
+        if self.pending.is_empty() || !released.is_empty() {
+            self.held_back_since = None;
+        }
+        if !self.pending.is_empty() && self.held_back_since.is_none() {
+            self.held_back_since = Some(Instant::now());
+        }
+        self.check_held_back()?;
+        Ok(released)
+    }
+
+    fn check_held_back(&self) -> Result<()> {
+        let since = match self.held_back_since {
+            Some(since) => since,
+            None => return Ok(()),
+        };
+        if since.elapsed() <= self.max_held_back {
+            return Ok(());
+        }
+        let waiting_for = self
+            .level_order
+            .lock()
+            .map_err(|_| anyhow!("failed to lock level_order mutex"))?
+            .front()
+            .cloned();
+        Err(anyhow!(
+            "level {:?} was not processed within {:?}, holding back {} processed levels",
+            waiting_for,
+            self.max_held_back,
+            self.pending.len()
+        ))
+    }
+}
+
+// One record per contract that is active in the block, in the format of the
+// Kafka REST proxy's produce request.
+fn records_for_block(
+    block: &[ProcessedContractBlock],
+) -> Result<Vec<serde_json::Value>> {
+    let mut res: Vec<serde_json::Value> = vec![];
+    for cres in block {
+        if !cres.is_origination && cres.tx_contexts.is_empty() {
+            continue;
+        }
+        // spilled inserts are read back into memory, as the message holds
+        // all of the contract's data of the level
+        let mut with_spilled: Vec<Insert> = vec![];
+        let inserts: &[Insert] = match &cres.spilled_inserts {
+            Some(spilled) => {
+                with_spilled.extend(cres.inserts.iter().cloned());
+                spilled.for_each_chunk(
+                    SPILLED_INSERTS_CHUNK_SIZE,
+                    |chunk| {
+                        with_spilled.extend(chunk);
+                        Ok(())
+                    },
+                )?;
+                &with_spilled
+            }
+            None => &cres.inserts,
+        };
+        let msg = Message {
+            contract: &cres.contract.cid.name,
+            address: &cres.contract.cid.address,
+            level: cres.level.level,
+            level_hash: cres.level.hash.as_deref(),
+            baked_at: cres.level.baked_at,
+            is_origination: cres.is_origination,
+            inserts,
+            tx_contexts: &cres.tx_contexts,
+            bigmap_actions: &cres.bigmap_meta_actions,
+        };
+        res.push(serde_json::json!({
+            "key": cres.contract.cid.name,
+            "value": serde_json::to_value(&msg)?,
+        }));
+    }
+    Ok(res)
+}
+
+// The REST proxy responds with an offset per record, which holds an error
+// instead if the record could not be produced.
+fn produce_error(resp: &serde_json::Value) -> Option<String> {
+    resp["offsets"]
+        .as_array()?
+        .iter()
+        .find(|offset| !offset["error"].is_null())
+        .map(|offset| offset["error"].to_string())
+}
+
+#[test]
+fn test_in_order() {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    let level_order: LevelOrder =
+        Arc::new(Mutex::new(VecDeque::from(vec![10, 11, 12, 13, 14])));
+    let mut in_order =
+        InOrder::new(level_order.clone(), Duration::from_secs(60));
+
+    let mut add = |level: u32, contracts: &[(&str, bool)]| -> Vec<usize> {
+        in_order
+            .add(level, test_block(level, contracts))
+            .unwrap()
+            .iter()
+            .map(|b| b.len())
+            .collect()
+    };
+    // (the number of contracts of the released blocks)
+    assert_eq!(add(11, &[("a", true)]), Vec::<usize>::new());
+    assert_eq!(add(13, &[("a", true), ("b", true)]), Vec::<usize>::new());
+    assert_eq!(add(10, &[("a", true)]), vec![1, 1]);
+    level_order
+        .lock()
+        .unwrap()
+        .push_back(15);
+    // a block without contracts is ordered by its level all the same
+    assert_eq!(add(12, &[]), vec![0, 2]);
+    assert_eq!(add(14, &[("a", true)]), vec![1]);
+    assert_eq!(level_order.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_in_order_held_back() {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    let level_order: LevelOrder =
+        Arc::new(Mutex::new(VecDeque::from(vec![10, 11, 12])));
+    let mut in_order =
+        InOrder::new(level_order.clone(), Duration::from_millis(100));
+
+    // level 10 never arrives
+    in_order.check_held_back().unwrap();
+    assert!(in_order
+        .add(11, test_block(11, &[("a", true)]))
+        .unwrap()
+        .is_empty());
+    in_order.check_held_back().unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let err = in_order.check_held_back().unwrap_err();
+    assert_eq!(
+        "level Some(10) was not processed within 100ms, holding back 1 processed levels",
+        err.to_string()
+    );
+    assert!(in_order
+        .add(12, test_block(12, &[("a", true)]))
+        .is_err());
+}
+
+#[test]
+fn test_records_for_block() {
+    let records =
+        records_for_block(&test_block(5, &[("a", true), ("b", false)]))
+            .unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["key"], serde_json::json!("a"));
+    assert_eq!(records[0]["value"]["contract"], serde_json::json!("a"));
+    assert_eq!(records[0]["value"]["level"], serde_json::json!(5));
+    assert_eq!(
+        records[0]["value"]["level_hash"],
+        serde_json::json!("hash5")
+    );
+    assert_eq!(
+        records[0]["value"]["tx_contexts"][0]["contract"],
+        serde_json::json!("KT1a")
+    );
+}
+
+#[test]
+fn test_produce_error() {
+    assert_eq!(
+        produce_error(&serde_json::json!({
+            "offsets": [
+                {"partition": 0, "offset": 1, "error_code": null, "error": null},
+            ]
+        })),
+        None
+    );
+    assert_eq!(
+        produce_error(&serde_json::json!({
+            "offsets": [
+                {"partition": 0, "offset": 1, "error_code": null, "error": null},
+                {"partition": null, "offset": null, "error_code": 50003, "error": "timeout"},
+            ]
+        })),
+        Some("\"timeout\"".to_string())
+    );
+}
@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+use crate::sql::db::level_to_db;
+use crate::sql::inserter::ProcessedBatch;
+
+// Pushes an event per committed level to the subscribers (the clients of the
+// /levels feed, see healthz::serve): {level, contracts, tx_count}, where the
+// contracts are the names of the contracts that were called (or originated)
+// at the level.
+//
+// Every subscriber has its own bounded buffer of events. Events for a
+// subscriber whose buffer is full are dropped, so a slow client never holds
+// up the inserter.
+#[derive(Clone, Default)]
+pub(crate) struct LevelFeed {
+    subscribers: Arc<Mutex<Vec<flume::Sender<String>>>>,
+}
+
+impl LevelFeed {
+    const BUFFERED_EVENTS: usize = 100;
+
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn subscribe(&self) -> Result<flume::Receiver<String>> {
+        let (send_ch, recv_ch) =
+            flume::bounded::<String>(Self::BUFFERED_EVENTS);
+        self.subscribers
+            .lock()
+            .map_err(|_| anyhow!("failed to lock level feed subscribers"))?
+            .push(send_ch);
+        Ok(recv_ch)
+    }
+
+    // Publishes the levels of a committed batch, in level order. Subscribers
+    // that went away are removed.
+    pub(crate) fn publish(&self, batch: &ProcessedBatch) -> Result<()> {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .map_err(|_| anyhow!("failed to lock level feed subscribers"))?;
+        if subscribers.is_empty() {
+            return Ok(());
+        }
+        let events = level_events(batch)?
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<serde_json::Result<Vec<String>>>()?;
+
+        let mut dropped = 0;
+        subscribers.retain(|send_ch| {
+            for event in &events {
+                match send_ch.try_send(event.clone()) {
+                    Ok(()) => {}
+                    Err(flume::TrySendError::Full(_)) => dropped += 1,
+                    Err(flume::TrySendError::Disconnected(_)) => return false,
+                }
+            }
+            true
+        });
+        if dropped > 0 {
+            debug!("level feed: dropped {} events for slow clients", dropped);
+        }
+        Ok(())
+    }
+}
+
+fn level_events(batch: &ProcessedBatch) -> Result<Vec<serde_json::Value>> {
+    let mut contracts: BTreeMap<i32, BTreeSet<&str>> = batch
+        .levels
+        .keys()
+        .map(|level| (*level, BTreeSet::new()))
+        .collect();
+    for (contract_id, (_, tx_contexts)) in &batch.contract_tx_contexts {
+        for tx_context in tx_contexts {
+            if let Some(names) =
+                contracts.get_mut(&level_to_db(tx_context.level)?)
+            {
+                names.insert(&contract_id.name);
+            }
+        }
+    }
+    for (contract_id, level, is_origination) in &batch.contract_levels {
+        if !*is_origination {
+            continue;
+        }
+        if let Some(names) = contracts.get_mut(level) {
+            names.insert(&contract_id.name);
+        }
+    }
+
+    let mut tx_counts: BTreeMap<i32, usize> = BTreeMap::new();
+    for tx_context in &batch.tx_contexts {
+        *tx_counts
+            .entry(level_to_db(tx_context.level)?)
+            .or_default() += 1;
+    }
+
+    Ok(contracts
+        .into_iter()
+        .map(|(level, names)| {
+            serde_json::json!({
+                "level": level,
+                "contracts": names,
+                "tx_count": tx_counts.get(&level).copied().unwrap_or(0),
+            })
+        })
+        .collect())
+}
+
+#[test]
+fn test_level_feed() {
+    use crate::test_support::test_block;
+    use serde_json::json;
+
+    let feed = LevelFeed::new();
+    let mut batch = ProcessedBatch::new(0);
+    // publishing without subscribers is a no-op
+    batch.add(test_block(1, &[("a", true)]));
+    feed.publish(&batch).unwrap();
+
+    let recv_ch = feed.subscribe().unwrap();
+    let gone = feed.subscribe().unwrap();
+    drop(gone);
+
+    batch.clear();
+    batch.add(test_block(3, &[("a", false), ("b", true)]));
+    batch.add(test_block(2, &[("a", true), ("b", true)]));
+    feed.publish(&batch).unwrap();
+    assert_eq!(1, feed.subscribers.lock().unwrap().len());
+
+    let events: Vec<serde_json::Value> = recv_ch
+        .try_iter()
+        .map(|event| serde_json::from_str(&event).unwrap())
+        .collect();
+    assert_eq!(
+        vec![
+            json!({"level": 2, "contracts": ["a", "b"], "tx_count": 2}),
+            json!({"level": 3, "contracts": ["b"], "tx_count": 1}),
+        ],
+        events
+    );
+
+    // events for a slow client are dropped rather than blocking
+    for level in 4..(4 + LevelFeed::BUFFERED_EVENTS as u32 + 10) {
+        batch.clear();
+        batch.add(test_block(level, &[("a", true)]));
+        feed.publish(&batch).unwrap();
+    }
+    assert_eq!(LevelFeed::BUFFERED_EVENTS, recv_ch.len());
+}
@@ -6,29 +6,44 @@ extern crate log;
 #[macro_use]
 extern crate serde;
 
+pub mod compare;
 pub mod config;
 pub mod contract_denylist;
 pub mod debug;
+pub mod dump;
 pub mod executor;
+pub mod healthz;
+pub mod kafka;
+pub mod level_feed;
+pub mod metadata;
 pub mod octez;
+pub mod replay;
+pub mod shutdown;
 pub mod sql;
 pub mod stats;
 pub mod storage_structure;
 pub mod storage_update;
 pub mod storage_value;
+#[cfg(test)]
+mod test_support;
 
-use anyhow::Context;
-use config::CONFIG;
+use anyhow::{anyhow, Context, Result};
+use config::{NetworkConfig, RunMode, CONFIG};
 use env_logger::Env;
+use octez::block_cache::BlockCache;
 use octez::node;
 use sql::db::DBClient;
 use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
 use std::panic;
+use std::path::Path;
 use std::process;
 use std::thread;
 
 use config::ContractID;
 use contract_denylist::is_contract_denylisted;
+use metadata::MetadataResolver;
 use storage_structure::relational;
 
 fn main() {
@@ -46,22 +61,106 @@ fn main() {
     env_logger::init_from_env(env);
 
     let config = CONFIG.as_ref().unwrap();
+    contract_denylist::configure_denylist(
+        &config.deny_contracts,
+        &config.allow_denylisted,
+    );
+
+    if config.validate_only {
+        let mut ok = true;
+        for network in config.networks() {
+            ok &= validate_config(config, &network);
+        }
+        if !ok {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if config.networks.is_empty() {
+        run_network(config, &config.networks()[0]);
+        return;
+    }
 
-    let node_cli = &node::NodeClient::new(
-        config.node_urls.clone(),
+    shutdown::install_handler().unwrap();
+    let threads: Vec<thread::JoinHandle<()>> = config
+        .networks
+        .iter()
+        .cloned()
+        .map(|network| {
+            thread::Builder::new()
+                .name(network.name.clone())
+                .spawn(move || {
+                    info!("indexing network {}", network.name);
+                    run_network(config, &network)
+                })
+                .unwrap()
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+fn run_network(config: &config::Config, network: &NetworkConfig) {
+    if config.mode == RunMode::ReadOnly {
+        follow_read_only(config, network);
+        return;
+    }
+
+    let mut node_cli = node::NodeClient::new(
+        network.node_urls.clone(),
         "main".to_string(),
         config.node_comm_retries,
+        config.node_conns_cap,
     );
+    node_cli.set_block_source(config.block_source.clone());
+    node_cli
+        .set_tls(config.node_tls.clone())
+        .with_context(|| "failed to set up the node tls")
+        .unwrap();
+    if let Some((dir, size)) = &config.block_cache {
+        // networks never share cached blocks
+        let mut dir = std::path::PathBuf::from(dir);
+        if !config.networks.is_empty() {
+            dir.push(&network.name);
+        }
+        node_cli.set_block_cache(
+            BlockCache::new(&dir, *size)
+                .with_context(|| "failed to set up the block cache")
+                .unwrap(),
+        );
+    }
+    let node_cli = &node_cli;
+
+    if let Some(target) = &config.dump_ast {
+        dump_ast(node_cli, config, network, target);
+        return;
+    }
+    if let Some(path) = &config.replay_block {
+        replay_block(node_cli, config, network, path);
+        return;
+    }
+    if let Some((url_a, url_b, level)) = &config.compare_nodes {
+        compare_nodes(config, network, url_a, url_b, *level);
+        return;
+    }
 
     let mut dbcli = DBClient::connect(
         &config.database_url,
-        &config.main_schema,
+        &network.main_schema,
         std::time::Duration::from_millis(5 * 60 * 1000),
         10,
+        config.db_conn_retries,
     )
     .with_context(|| "failed to connect to the db")
     .unwrap();
 
+    let chain_id = node_cli
+        .chain_id()
+        .with_context(|| "failed to get the node's chain")
+        .unwrap();
+
     let setup_db = config.reinit || !dbcli.common_tables_exist().unwrap();
     if config.reinit {
         assert_sane_db(&mut dbcli);
@@ -70,15 +169,61 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
             process::exit(1);
         }
         dbcli
-            .delete_everything(node_cli, executor::get_contract_rel)
+            .delete_everything(node_cli, |node_cli, contract_id| {
+                executor::get_contract_rel(
+                    node_cli,
+                    contract_id,
+                    &network.layout_settings,
+                )
+            })
             .with_context(|| "failed to delete the db's content")
             .unwrap();
     }
     if setup_db {
-        dbcli.create_common_tables().unwrap();
+        dbcli
+            .create_common_tables(config.shared_tx_contexts)
+            .unwrap();
+        dbcli.set_chain_id(&chain_id).unwrap();
         info!("Common tables set up in db");
     } else {
         assert_sane_db(&mut dbcli);
+        dbcli
+            .add_missing_common_columns()
+            .unwrap();
+        assert_same_chain(&mut dbcli, &chain_id);
+        let shared_tx_contexts = dbcli.load_shared_tx_contexts().unwrap();
+        if shared_tx_contexts != config.shared_tx_contexts {
+            warn!(
+                "ignoring --shared-tx-contexts={}: the db was set up with shared tx contexts={}",
+                config.shared_tx_contexts, shared_tx_contexts
+            );
+        }
+    }
+
+    if let Some(name) = &config.drop_contract {
+        drop_contract(&mut dbcli, node_cli, network, name);
+        return;
+    }
+    if let Some((name, levels, limit, offset)) = &config.list_calls {
+        list_calls(&mut dbcli, config, name, *levels, *limit, *offset);
+        return;
+    }
+    if let Some(hash) = &config.find_operation {
+        find_operation(&mut dbcli, config, hash);
+        return;
+    }
+    if let Some((bigmap_id, level)) = &config.bigmap_owner {
+        bigmap_owner(&mut dbcli, config, *bigmap_id, *level);
+        return;
+    }
+    if config.audit {
+        audit(&mut dbcli, config, network);
+        return;
+    }
+
+    // when indexing multiple networks, the handler is installed once for all
+    if config.networks.is_empty() {
+        shutdown::install_handler().unwrap();
     }
 
     let bcd_settings = config
@@ -88,15 +233,161 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
 
     let mut executor = executor::Executor::new(
         node_cli.clone(),
-        dbcli,
+        dbcli.clone(),
         config.reports_interval,
     );
+    if !config.networks.is_empty() {
+        executor.set_stats_namespace(&network.name);
+    }
+    executor.set_layout_settings(network.layout_settings.clone());
+    executor.set_start_levels(network.start_levels.clone());
+    executor.set_bcd_min_missing_levels(config.bcd_min_missing_levels);
+    executor.set_bcd_excluded(network.bcd_excluded.clone());
+    executor.set_priorities(network.priorities.clone());
+    executor.set_static_deps(network.contract_deps.clone());
+    executor.set_address_cache_size(config.address_cache_size);
+    if let Some(chunk_size) = config.bigmap_copy_chunk_size {
+        executor.set_bigmap_copy_chunk_size(chunk_size);
+    }
+    executor.set_inserter_threads(config.inserter_threads);
+    executor.set_inserter_batch_size(config.inserter_batch_size);
+    executor.set_inserter_commit_interval(config.inserter_commit_interval);
+    executor.set_max_buffered_block_bytes(config.max_buffered_block_bytes);
+    executor.set_slow_block_threshold(config.slow_block_threshold);
+    executor
+        .track_slowest_blocks(config.slowest_blocks)
+        .unwrap();
+    if config.record_all_calls {
+        executor.record_all_calls();
+    }
+    if config.index_failures {
+        executor.index_failures();
+    }
+    if config.index_delegations {
+        executor.index_delegations();
+    }
+    if config.track_balance {
+        executor.track_balance();
+    }
+    if config.keep_raw_storage {
+        executor.keep_raw_storage();
+        executor.set_raw_storage_compression(config.raw_storage_compression);
+    }
+    if config.empty_string_as_null {
+        executor.store_empty_strings_as_null();
+    }
+    if config.no_derived_tables {
+        executor.skip_derived_tables();
+    }
+    if config.latest_origination {
+        executor.use_latest_origination();
+    }
+    if config.deterministic_ids {
+        executor.use_deterministic_ids();
+    }
+    if config.fail_on_contract_errors {
+        executor.fail_on_contract_errors();
+    }
+    if let Some(url) = &config.kafka_url {
+        executor.produce_to_kafka(url, &config.kafka_topic);
+    }
+    if let Some(port) = network.healthz_port {
+        let level_feed = if config.healthz_levels_feed {
+            let level_feed = level_feed::LevelFeed::new();
+            executor.publish_levels(level_feed.clone());
+            Some(level_feed)
+        } else {
+            None
+        };
+        healthz::serve(
+            port,
+            executor.clone(),
+            config.healthz_max_lag,
+            level_feed,
+        )
+        .with_context(|| "failed to start the healthz server")
+        .unwrap();
+    }
+    if !network
+        .layout_settings
+        .metadata_uris
+        .is_empty()
+        && config.kafka_url.is_none()
+    {
+        let resolver = MetadataResolver::new(
+            &config.metadata_ipfs_gateway,
+            config.metadata_timeout,
+            config.metadata_fetches_per_sec,
+        )
+        .with_context(|| "failed to set up the metadata resolver")
+        .unwrap();
+        metadata::resolve_in_background(
+            dbcli.clone(),
+            network
+                .layout_settings
+                .metadata_uris
+                .clone(),
+            resolver,
+        );
+    }
     if config.all_contracts {
-        index_all_contracts(config, &bcd_settings, executor);
-        return;
+        index_all_contracts(config, &bcd_settings, &mut executor);
+    } else {
+        index_contracts(config, network, &bcd_settings, &mut executor);
     }
+    executor.log_slowest_blocks().unwrap();
+    if shutdown::requested() {
+        info!("shut down gracefully");
+    }
+    if config.report_table_sizes {
+        report_table_sizes(&mut dbcli, &executor.get_config().unwrap());
+    }
+}
 
-    for contract_id in &config.contracts {
+// Serves the db that another process indexes, without touching the node or
+// writing anything.
+fn follow_read_only(config: &config::Config, network: &NetworkConfig) {
+    let port = match network.healthz_port {
+        Some(port) => port,
+        None => {
+            exit_with_err("--read-only requires --healthz-port (there is nothing to serve otherwise)");
+            return;
+        }
+    };
+    let mut dbcli = DBClient::connect(
+        &config.database_url,
+        &network.main_schema,
+        std::time::Duration::from_millis(5 * 60 * 1000),
+        2,
+        config.db_conn_retries,
+    )
+    .with_context(|| "failed to connect to the db")
+    .unwrap();
+    dbcli.set_read_only();
+    if !dbcli.common_tables_exist().unwrap() {
+        exit_with_err(&format!(
+            "the db has not been set up (yet) in schema {}",
+            network.main_schema
+        ));
+    }
+    assert_sane_db(&mut dbcli);
+
+    healthz::serve_read_only(port, dbcli)
+        .with_context(|| "failed to start the healthz server")
+        .unwrap();
+    while !shutdown::requested() {
+        thread::sleep(std::time::Duration::from_millis(500));
+    }
+    info!("shut down gracefully");
+}
+
+fn index_contracts(
+    config: &config::Config,
+    network: &NetworkConfig,
+    bcd_settings: &Option<(String, String)>,
+    executor: &mut executor::Executor,
+) {
+    for contract_id in &network.contracts {
         executor
             .add_contract(contract_id)
             .unwrap();
@@ -106,6 +397,15 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
         executor
             .add_dependency_contracts()
             .unwrap();
+        for contract_id in executor
+            .contracts_with_unknown_deps()
+            .unwrap()
+        {
+            info!(
+                "contract {} has bigmaps, schemas of contracts it copies bigmaps from are only created once these are known (set them under `deps` in the contract settings to create them now)",
+                contract_id.name
+            );
+        }
         executor
             .create_contract_schemas()
             .unwrap();
@@ -129,15 +429,89 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
             .unwrap();
         return;
     }
+    if let Some(name) = &config.reprocess_contract {
+        let contract_id = match contracts
+            .iter()
+            .find(|contract_id| &contract_id.name == name)
+        {
+            Some(contract_id) => contract_id,
+            None => {
+                exit_with_err(
+                    format!("Cannot reprocess contract {}, it is not in the contract settings.", name)
+                        .as_str(),
+                );
+                return;
+            }
+        };
+        executor
+            .reprocess_contract(num_getters, num_processors, contract_id)
+            .unwrap();
+        return;
+    }
+    if let Some((src_contract, bigmap_id)) = &config.reprocess_dependency {
+        executor
+            .add_dependency_contracts()
+            .unwrap();
+        executor
+            .create_contract_schemas()
+            .unwrap();
+        executor
+            .reprocess_dependency(src_contract, *bigmap_id)
+            .unwrap();
+        return;
+    }
+    if config.build_derived_tables {
+        executor
+            .add_dependency_contracts()
+            .unwrap();
+        executor.build_derived_tables().unwrap();
+        return;
+    }
+    if config.repair_derived {
+        executor
+            .add_dependency_contracts()
+            .unwrap();
+        executor
+            .repair_derived_tables()
+            .unwrap();
+        return;
+    }
+    if let Some(level_range) = config.level_range {
+        executor
+            .add_dependency_contracts()
+            .unwrap();
+        executor
+            .create_contract_schemas()
+            .unwrap();
+        executor
+            .exec_level_range(
+                num_getters,
+                num_processors,
+                level_range,
+                config.force,
+            )
+            .unwrap();
+        return;
+    }
 
-    let new_initialized = executor
-        .exec_new_contracts_historically(
-            &bcd_settings,
-            num_getters,
-            num_processors,
-            config.allowed_unbootstrapped_offset,
-        )
-        .unwrap();
+    let new_initialized = match executor.exec_new_contracts_historically(
+        bcd_settings,
+        num_getters,
+        num_processors,
+        config.allowed_unbootstrapped_offset,
+    ) {
+        Ok(new_initialized) => new_initialized,
+        Err(e) => {
+            exit_with_err(&format!(
+                "failed to index the new contracts historically: {:?}",
+                e
+            ));
+            return;
+        }
+    };
+    if shutdown::requested() {
+        return;
+    }
     if !new_initialized.is_empty() {
         info!("all contracts historically bootstrapped.");
     }
@@ -152,19 +526,25 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
     }
 
     // We will first load missing levels (if any)
-    executor
-        .exec_missing_levels(
-            &bcd_settings,
-            num_getters,
-            num_processors,
-            config.allowed_unbootstrapped_offset,
-            true,
-        )
-        .unwrap();
+    if let Err(e) = executor.exec_missing_levels(
+        bcd_settings,
+        num_getters,
+        num_processors,
+        config.allowed_unbootstrapped_offset,
+        true,
+    ) {
+        exit_with_err(&format!("failed to index the missing levels: {:?}", e));
+    }
+    if shutdown::requested() {
+        return;
+    }
 
     executor
         .reprocess_forked_levels(num_getters, num_processors)
         .unwrap();
+    if shutdown::requested() {
+        return;
+    }
 
     // At last, normal operation.
     info!("processing blocks at the chain head");
@@ -174,9 +554,20 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
 fn index_all_contracts(
     config: &config::Config,
     bcd_settings: &Option<(String, String)>,
-    mut executor: executor::Executor,
+    executor: &mut executor::Executor,
 ) {
     executor.index_all_contracts();
+    if let Some(level_range) = config.level_range {
+        executor
+            .exec_level_range(
+                config.getters_cap,
+                config.workers_cap,
+                level_range,
+                config.force,
+            )
+            .unwrap();
+        return;
+    }
     if !config.levels.is_empty() {
         executor
             .exec_levels(
@@ -185,6 +576,9 @@ fn index_all_contracts(
                 config.levels.clone(),
             )
             .unwrap();
+        if shutdown::requested() {
+            return;
+        }
         #[cfg(feature = "regression_force_update_derived")]
         if true {
             info!("skipping re-populating of derived tables, always_update_derived enabled");
@@ -195,21 +589,129 @@ fn index_all_contracts(
             .unwrap();
     } else {
         info!("processing missing levels");
-        executor
-            .exec_missing_levels(
-                bcd_settings,
-                config.getters_cap,
-                config.workers_cap,
-                config.allowed_unbootstrapped_offset,
-                false,
-            )
-            .unwrap();
+        if let Err(e) = executor.exec_missing_levels(
+            bcd_settings,
+            config.getters_cap,
+            config.workers_cap,
+            config.allowed_unbootstrapped_offset,
+            false,
+        ) {
+            exit_with_err(&format!(
+                "failed to index the missing levels: {:?}",
+                e
+            ));
+        }
+        if shutdown::requested() {
+            return;
+        }
 
         info!("processing blocks at the chain head");
         executor.exec_continuous().unwrap();
     }
 }
 
+// Checks the setup without writing anything: the nodes and the db are
+// reachable, the db was set up by a compatible version (if at all), and the
+// contracts exist (with settings that fit them). Reports every check on
+// stdout, and returns whether all of them passed.
+fn validate_config(config: &config::Config, network: &NetworkConfig) -> bool {
+    if !config.networks.is_empty() {
+        println!("network {}:", network.name);
+    }
+    let colored = atty::is(atty::Stream::Stdout);
+    let mut ok = true;
+    let mut report = |what: String, res: Result<String>| {
+        let (passed, detail) = match res {
+            Ok(detail) => (true, detail),
+            Err(err) => (false, format!("{:#}", err)),
+        };
+        ok &= passed;
+        let status = if passed { "ok" } else { "FAILED" };
+        if colored {
+            let color = if passed { 32 } else { 31 };
+            println!(
+                "\x1b[{}m{:>6}\x1b[0m {}: {}",
+                color, status, what, detail
+            );
+        } else {
+            println!("{:>6} {}: {}", status, what, detail);
+        }
+    };
+
+    let mut node_cli = node::NodeClient::new(
+        network.node_urls.clone(),
+        "main".to_string(),
+        0,
+        config.node_conns_cap,
+    );
+    node_cli.set_block_source(config.block_source.clone());
+    if config.node_tls != config::NodeTlsSettings::default() {
+        report(
+            "node tls".to_string(),
+            node_cli
+                .set_tls(config.node_tls.clone())
+                .map(|_| "files readable".to_string()),
+        );
+    }
+    let mut node_reachable = false;
+    for url in &network.node_urls {
+        let res = node_cli.ping(url);
+        node_reachable |= res.is_ok();
+        report(
+            format!("node {}", url),
+            res.map(|level| format!("reachable, head at level {}", level)),
+        );
+    }
+
+    report(
+        format!("database (schema {})", network.main_schema),
+        DBClient::connect(
+            &config.database_url,
+            &network.main_schema,
+            std::time::Duration::from_secs(10),
+            1,
+            0,
+        )
+        .and_then(|mut dbcli| {
+            if !dbcli.common_tables_exist()? {
+                return Ok("reachable, not set up yet".to_string());
+            }
+            let db_version = dbcli.get_quepasa_version()?;
+            if schema_version(&db_version)
+                != schema_version(crate::config::QUEPASA_VERSION)
+            {
+                return Err(anyhow!(
+                    "set up by Que Pasa {}, which is incompatible with Que Pasa {}",
+                    db_version,
+                    crate::config::QUEPASA_VERSION
+                ));
+            }
+            Ok(format!("reachable, set up by Que Pasa {}", db_version))
+        }),
+    );
+
+    for contract_id in &network.contracts {
+        // the node would be retried for as long as it is unreachable
+        if !node_reachable {
+            report(
+                format!("contract {}", contract_id.name),
+                Err(anyhow!("cannot be checked, no node is reachable")),
+            );
+            continue;
+        }
+        report(
+            format!("contract {}", contract_id.name),
+            executor::get_contract_rel(
+                &node_cli,
+                contract_id,
+                &network.layout_settings,
+            )
+            .map(|_| format!("{} exists", contract_id.address)),
+        );
+    }
+    ok
+}
+
 fn assert_contracts_ok(contracts: &[ContractID]) {
     if contracts.is_empty() {
         exit_with_err("zero contracts to index..");
@@ -244,6 +746,344 @@ fn schema_version(v: &str) -> String {
     }
 }
 
+fn drop_contract(
+    dbcli: &mut DBClient,
+    node_cli: &node::NodeClient,
+    network: &NetworkConfig,
+    name: &str,
+) {
+    let contract_id = match dbcli.get_contract_id(name).unwrap() {
+        Some(contract_id) => contract_id,
+        None => {
+            exit_with_err(
+                format!("Cannot drop contract {}, it is not indexed.", name)
+                    .as_str(),
+            );
+            return;
+        }
+    };
+    if !confirm_request(
+        format!(
+            "
+Dropping contract {} -- all its indexed data will be destroyed. Continue?",
+            name
+        )
+        .as_str(),
+    ) {
+        process::exit(1);
+    }
+    let contract = executor::get_contract_rel(
+        node_cli,
+        &contract_id,
+        &network.layout_settings,
+    )
+    .unwrap();
+    dbcli
+        .delete_contract(&contract)
+        .with_context(|| format!("failed to drop contract {}", name))
+        .unwrap();
+    info!("contract {} dropped", name);
+}
+
+fn dump_ast(
+    node_cli: &node::NodeClient,
+    config: &config::Config,
+    network: &NetworkConfig,
+    target: &str,
+) {
+    let contracts: Vec<&ContractID> = network
+        .contracts
+        .iter()
+        .filter(|contract_id| target == "all" || contract_id.name == target)
+        .collect();
+    if contracts.is_empty() {
+        exit_with_err(
+            format!("Cannot dump the AST of contract {}, it is not in the contract settings.", target)
+                .as_str(),
+        );
+    }
+
+    let mut res = serde_json::Map::new();
+    for contract_id in contracts {
+        let contract = executor::get_contract_rel(
+            node_cli,
+            contract_id,
+            &network.layout_settings,
+        )
+        .unwrap();
+        res.insert(
+            contract_id.name.clone(),
+            executor::contract_layout_json(&contract).unwrap(),
+        );
+    }
+    let out =
+        dump::serialize(&serde_json::Value::Object(res), &config.dump_format)
+            .unwrap();
+    io::stdout().write_all(&out).unwrap();
+}
+
+fn replay_block(
+    node_cli: &node::NodeClient,
+    config: &config::Config,
+    network: &NetworkConfig,
+    path: &Path,
+) {
+    let block_json = fs::read_to_string(path)
+        .with_context(|| {
+            format!("failed to read block json from {}", path.display())
+        })
+        .unwrap();
+    if config.replay_bigmap_diffs {
+        let res = replay::replay_bigmap_diffs(&block_json)
+            .with_context(|| {
+                format!(
+                    "failed to replay the bigmap diffs of {}",
+                    path.display()
+                )
+            })
+            .unwrap();
+        let out = dump::serialize(&res, &config.dump_format).unwrap();
+        io::stdout().write_all(&out).unwrap();
+        return;
+    }
+    // the contracts' scripts are still taken from the node (or the cache)
+    let contracts: Vec<relational::Contract> = network
+        .contracts
+        .iter()
+        .map(|contract_id| {
+            executor::get_contract_rel(
+                node_cli,
+                contract_id,
+                &network.layout_settings,
+            )
+        })
+        .collect::<Result<Vec<relational::Contract>>>()
+        .unwrap();
+    let res = replay::replay_block(node_cli, config, &contracts, &block_json)
+        .with_context(|| format!("failed to replay {}", path.display()))
+        .unwrap();
+    let out = dump::serialize(&res, &config.dump_format).unwrap();
+    io::stdout().write_all(&out).unwrap();
+}
+
+fn compare_nodes(
+    config: &config::Config,
+    network: &NetworkConfig,
+    url_a: &str,
+    url_b: &str,
+    level: u32,
+) {
+    let node_cli = |url: &str| {
+        let mut node_cli = node::NodeClient::new(
+            vec![url.to_string()],
+            "compare".to_string(),
+            config.node_comm_retries,
+            config.node_conns_cap,
+        );
+        node_cli
+            .set_tls(config.node_tls.clone())
+            .with_context(|| "failed to set up the node tls")
+            .unwrap();
+        node_cli
+    };
+    let res = compare::compare_nodes(
+        &node_cli(url_a),
+        &node_cli(url_b),
+        &network.contracts,
+        level,
+    )
+    .with_context(|| format!("failed to compare the nodes at level {}", level))
+    .unwrap();
+    let out = dump::serialize(&res, &config.dump_format).unwrap();
+    io::stdout().write_all(&out).unwrap();
+    if res["differences"] != serde_json::json!([]) {
+        process::exit(1);
+    }
+}
+
+fn list_calls(
+    dbcli: &mut DBClient,
+    config: &config::Config,
+    name: &str,
+    (from, to): (u32, u32),
+    limit: i64,
+    offset: i64,
+) {
+    let contract_id = match dbcli.get_contract_id(name).unwrap() {
+        Some(contract_id) => contract_id,
+        None => {
+            exit_with_err(
+                format!(
+                    "Cannot list the calls of contract {}, it is not indexed.",
+                    name
+                )
+                .as_str(),
+            );
+            return;
+        }
+    };
+    let calls = dbcli
+        .get_calls(&contract_id, from, to, limit, offset)
+        .with_context(|| format!("failed to get the calls of {}", name))
+        .unwrap();
+    let out = dump::serialize(
+        &serde_json::to_value(&calls).unwrap(),
+        &config.dump_format,
+    )
+    .unwrap();
+    io::stdout().write_all(&out).unwrap();
+}
+
+fn find_operation(dbcli: &mut DBClient, config: &config::Config, hash: &str) {
+    let tx_contexts = dbcli
+        .find_by_operation_hash(hash)
+        .with_context(|| format!("failed to find operation {}", hash))
+        .unwrap();
+    if tx_contexts.is_empty() {
+        warn!("operation {} made no calls to indexed contracts", hash);
+    }
+    let out = dump::serialize(
+        &serde_json::to_value(&tx_contexts).unwrap(),
+        &config.dump_format,
+    )
+    .unwrap();
+    io::stdout().write_all(&out).unwrap();
+}
+
+fn bigmap_owner(
+    dbcli: &mut DBClient,
+    config: &config::Config,
+    bigmap_id: i32,
+    level: Option<u32>,
+) {
+    let owner = dbcli
+        .get_bigmap_owner(bigmap_id, level.unwrap_or(i32::MAX as u32))
+        .with_context(|| {
+            format!("failed to get the owner of bigmap {}", bigmap_id)
+        })
+        .unwrap();
+    if owner.is_none() {
+        warn!("no alloc of bigmap {} was indexed", bigmap_id);
+    }
+    let out = dump::serialize(
+        &serde_json::to_value(&owner).unwrap(),
+        &config.dump_format,
+    )
+    .unwrap();
+    io::stdout().write_all(&out).unwrap();
+}
+
+fn audit(
+    dbcli: &mut DBClient,
+    config: &config::Config,
+    network: &NetworkConfig,
+) {
+    let mut res: Vec<serde_json::Value> = vec![];
+    let mut any_gaps = false;
+    for contract_id in &network.contracts {
+        let gaps = dbcli
+            .find_level_gaps(contract_id)
+            .with_context(|| {
+                format!(
+                    "failed to find the level gaps of contract {}",
+                    contract_id.name
+                )
+            })
+            .unwrap();
+        let missing: u32 = gaps
+            .iter()
+            .map(|(from, to)| to - from + 1)
+            .sum();
+        if !gaps.is_empty() {
+            warn!(
+                "contract {} is missing {} levels in {} gaps",
+                contract_id.name,
+                missing,
+                gaps.len()
+            );
+            any_gaps = true;
+        }
+        res.push(serde_json::json!({
+            "contract": contract_id.name,
+            "gaps": gaps,
+            "missing": missing,
+        }));
+    }
+    let out =
+        dump::serialize(&serde_json::Value::Array(res), &config.dump_format)
+            .unwrap();
+    io::stdout().write_all(&out).unwrap();
+    if any_gaps {
+        process::exit(1);
+    }
+}
+
+fn report_table_sizes(dbcli: &mut DBClient, contracts: &[ContractID]) {
+    let mut rows: Vec<(String, String, i64, i64)> = vec![];
+    for contract_id in contracts {
+        for (table, num_rows, size) in dbcli
+            .table_stats(contract_id)
+            .with_context(|| {
+                format!("failed to get table sizes of {}", contract_id.name)
+            })
+            .unwrap()
+        {
+            rows.push((contract_id.name.clone(), table, num_rows, size));
+        }
+    }
+    rows.sort_by(|a, b| {
+        b.3.cmp(&a.3)
+            .then_with(|| a.1.cmp(&b.1))
+    });
+
+    let contract_width = rows
+        .iter()
+        .map(|row| row.0.len())
+        .chain(std::iter::once("contract".len()))
+        .max()
+        .unwrap();
+    let table_width = rows
+        .iter()
+        .map(|row| row.1.len())
+        .chain(std::iter::once("table".len()))
+        .max()
+        .unwrap();
+    println!(
+        "{:<cw$}  {:<tw$}  {:>14}  {:>10}",
+        "contract",
+        "table",
+        "rows (approx)",
+        "size",
+        cw = contract_width,
+        tw = table_width,
+    );
+    for (contract, table, num_rows, size) in &rows {
+        println!(
+            "{:<cw$}  {:<tw$}  {:>14}  {:>10}",
+            contract,
+            table,
+            num_rows,
+            pretty_size(*size),
+            cw = contract_width,
+            tw = table_width,
+        );
+    }
+}
+
+fn pretty_size(bytes: i64) -> String {
+    let units = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        return format!("{} {}", bytes, units[unit]);
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
 fn assert_sane_db(dbcli: &mut DBClient) {
     let db_version = dbcli.get_quepasa_version().unwrap();
     if schema_version(&db_version)
@@ -263,6 +1103,20 @@ Either drop the old database namespace or keep it and target a different one.",
     }
 }
 
+fn assert_same_chain(dbcli: &mut DBClient, chain_id: &str) {
+    if let Err(e) = dbcli.check_chain_id(chain_id) {
+        exit_with_err(
+            format!(
+                "
+Cannot target a database that was initialized for another chain: {}.
+Either drop the old database namespace or keep it and target a different one.",
+                e
+            )
+            .as_str(),
+        );
+    }
+}
+
 fn confirm_request(msg: &str) -> bool {
     // returns true if user confirmed, otherwise false.
 
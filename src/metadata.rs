@@ -0,0 +1,605 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::shutdown;
+use crate::sql::db::DBClient;
+use crate::sql::insert;
+
+// The schemes of the URIs that point to metadata (see TZIP-16). Column values
+// that do not start with one of these are not taken for URIs.
+const URI_SCHEMES: &[&str] = &[
+    "ipfs://",
+    "https://",
+    "http://",
+    "tezos-storage:",
+    "sha256://",
+];
+
+// The table the resolved metadata of a table's URIs go to. It is a child
+// table of the table, so every row of it refers to the row holding the URI.
+pub(crate) fn resolved_metadata_table(table: &str) -> String {
+    format!("{}.resolved_metadata", table)
+}
+
+// The URI a column value holds. Bytes (which are stored as hex) are decoded
+// as text first. Values that are not URIs (eg the other fields of a TZIP-12
+// token_info) give None.
+pub(crate) fn uri_value(value: &insert::Value) -> Option<String> {
+    let s = match value {
+        insert::Value::String(s) => s,
+        _ => return None,
+    };
+    let text = hex::decode(s)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .unwrap_or_else(|| s.clone());
+    let uri = text.trim();
+    if URI_SCHEMES
+        .iter()
+        .any(|scheme| uri.starts_with(scheme))
+    {
+        Some(uri.to_string())
+    } else {
+        None
+    }
+}
+
+// The metadata a URI points to, or the error resolving it failed with.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Resolved {
+    pub metadata: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+// Resolves the metadata URIs of the rows indexed so far, for the columns set
+// up for this (contract name => table => column, see the metadata_uris
+// contract setting), in a thread of its own until shutdown is requested.
+// Fetching is kept out of processing, so that slow or failing fetches do not
+// hold up indexing, and what is indexed does not depend on them. Rows that
+// are deleted (eg when their level is reprocessed) take their resolved
+// metadata with them, the rows that replace them are resolved anew.
+pub(crate) fn resolve_in_background(
+    dbcli: DBClient,
+    uri_columns: HashMap<String, HashMap<String, String>>,
+    resolver: MetadataResolver,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut dbcli = dbcli;
+        let mut resolver = resolver;
+        while !shutdown::requested() {
+            match resolve_pending(&mut dbcli, &uri_columns, &mut resolver) {
+                Ok(0) => {}
+                Ok(n) => {
+                    info!("resolved {} metadata uris", n);
+                    continue;
+                }
+                Err(e) => warn!("failed to resolve metadata uris: {:?}", e),
+            }
+            for _ in 0..100 {
+                if shutdown::requested() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    })
+}
+
+// Resolves (a batch of) the metadata URIs that have not been resolved yet,
+// returning how many rows were resolved. Rows holding something else than a
+// URI get a resolved metadata row without URI, so they are not looked at
+// again.
+pub(crate) fn resolve_pending(
+    dbcli: &mut DBClient,
+    uri_columns: &HashMap<String, HashMap<String, String>>,
+    resolver: &mut MetadataResolver,
+) -> Result<usize> {
+    const BATCH_SIZE: i64 = 100;
+
+    let mut res = 0;
+    for (contract_schema, columns) in uri_columns {
+        for (table, column) in columns {
+            let pending = match dbcli.get_unresolved_metadata_uris(
+                contract_schema,
+                table,
+                column,
+                BATCH_SIZE,
+            )? {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let resolved: Vec<(i64, Option<String>, Resolved)> = pending
+                .into_iter()
+                .map(|(id, value)| {
+                    match uri_value(&insert::Value::String(value)) {
+                        Some(uri) => {
+                            let resolved = resolver.resolve(&uri);
+                            (id, Some(uri), resolved)
+                        }
+                        None => (
+                            id,
+                            None,
+                            Resolved {
+                                metadata: None,
+                                error: None,
+                            },
+                        ),
+                    }
+                })
+                .collect();
+            dbcli.save_resolved_metadata(contract_schema, table, &resolved)?;
+            res += resolved.len();
+        }
+    }
+    Ok(res)
+}
+
+// Fetches the off-chain metadata that contracts point to from their storage
+// (eg the "" entry of a TZIP-12 token_info). ipfs URIs are fetched through
+// the ipfs gateway, http(s) URIs directly. Other URIs (eg tezos-storage ones,
+// which point into the contract's own storage) are not resolved.
+//
+// The URIs come from the chain, so anyone can make us fetch them: http(s)
+// URIs of hosts that resolve to a non-public address (eg loopback, private
+// networks, link-local) are not fetched, and redirects are not followed.
+//
+// Fetches are rate limited, and successfully fetched metadata is cached by
+// URL.
+pub(crate) struct MetadataResolver {
+    client: reqwest::blocking::Client,
+    ipfs_gateway: String,
+    // the least time between the starts of two fetches
+    min_interval: Duration,
+    next_fetch: Option<Instant>,
+    cache: HashMap<String, serde_json::Value>,
+}
+
+impl MetadataResolver {
+    const CACHE_SIZE: usize = 10000;
+
+    // fetches_per_sec of 0 disables the rate limit.
+    pub(crate) fn new(
+        ipfs_gateway: &str,
+        timeout: Duration,
+        fetches_per_sec: u32,
+    ) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::blocking::Client::builder()
+                .timeout(timeout)
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+            ipfs_gateway: ipfs_gateway
+                .trim_end_matches('/')
+                .to_string(),
+            min_interval: if fetches_per_sec == 0 {
+                Duration::from_secs(0)
+            } else {
+                Duration::from_secs(1) / fetches_per_sec
+            },
+            next_fetch: None,
+            cache: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn resolve(&mut self, uri: &str) -> Resolved {
+        match self.fetch(uri) {
+            Ok(metadata) => Resolved {
+                metadata: Some(metadata),
+                error: None,
+            },
+            Err(e) => {
+                debug!("failed to resolve metadata uri {}: {}", uri, e);
+                Resolved {
+                    metadata: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        }
+    }
+
+    fn fetch(&mut self, uri: &str) -> Result<serde_json::Value> {
+        let url = self.url(uri)?;
+        if let Some(metadata) = self.cache.get(&url) {
+            return Ok(metadata.clone());
+        }
+        // (the gateway is ours to choose, the other hosts are not)
+        if !uri.starts_with("ipfs://") {
+            check_public_host(&url)?;
+        }
+
+        self.wait_turn();
+        debug!("GET {}..", url);
+        let resp = self.client.get(&url).send()?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(anyhow!("GET {} failed: {}", url, status));
+        }
+        let metadata: serde_json::Value =
+            serde_json::from_str(&resp.text()?)
+                .map_err(|e| anyhow!("GET {} returned no json: {}", url, e))?;
+
+        if self.cache.len() >= Self::CACHE_SIZE {
+            self.cache.clear();
+        }
+        self.cache.insert(url, metadata.clone());
+        Ok(metadata)
+    }
+
+    fn url(&self, uri: &str) -> Result<String> {
+        if let Some(path) = uri.strip_prefix("ipfs://") {
+            return Ok(format!("{}/{}", self.ipfs_gateway, path));
+        }
+        if uri.starts_with("https://") || uri.starts_with("http://") {
+            return Ok(uri.to_string());
+        }
+        Err(anyhow!("unsupported uri: {}", uri))
+    }
+
+    // Waits until the rate limit allows for the next fetch.
+    fn wait_turn(&mut self) {
+        let now = Instant::now();
+        let at = match self.next_fetch {
+            Some(at) if at > now => at,
+            _ => now,
+        };
+        self.next_fetch = Some(at + self.min_interval);
+        if at > now {
+            thread::sleep(at - now);
+        }
+    }
+}
+
+// Errors if the host of the url resolves to an address that is not public.
+// (The host is resolved again when fetching, so this does not hold off a host
+// that changes its addresses in between.)
+fn check_public_host(url: &str) -> Result<()> {
+    let parsed = reqwest::Url::parse(url)?;
+    for addr in parsed.socket_addrs(|| None)? {
+        if !is_public(&addr.ip()) {
+            return Err(anyhow!(
+                "refusing to fetch {}: it resolves to non-public address {}",
+                url,
+                addr.ip()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+                // an ipv4 address mapped to ipv6
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public_v4(&Ipv4Addr::new(a, b, c, d));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local (fc00::/7)
+                || segments[0] & 0xfe00 == 0xfc00
+                // link-local (fe80::/10)
+                || segments[0] & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "this" network (0.0.0.0/8)
+        || octets[0] == 0
+        // shared address space (100.64.0.0/10)
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+}
+
+#[test]
+fn test_uri_value() {
+    let string = |s: &str| insert::Value::String(s.to_string());
+    let bytes = |s: &str| string(&hex::encode(s));
+
+    assert_eq!(
+        Some("ipfs://QmHash/metadata.json".to_string()),
+        uri_value(&bytes("ipfs://QmHash/metadata.json"))
+    );
+    assert_eq!(
+        Some("https://example.com/token/1".to_string()),
+        uri_value(&string("https://example.com/token/1"))
+    );
+    assert_eq!(
+        Some("tezos-storage:content".to_string()),
+        uri_value(&bytes("tezos-storage:content"))
+    );
+    // other token_info fields
+    assert_eq!(None, uri_value(&bytes("Some Token")));
+    assert_eq!(None, uri_value(&bytes("6")));
+    assert_eq!(None, uri_value(&insert::Value::Null));
+}
+
+#[test]
+fn test_resolve() {
+    let mut resolver = MetadataResolver::new(
+        "https://gateway.test/ipfs/",
+        Duration::from_secs(1),
+        0,
+    )
+    .unwrap();
+    assert_eq!(
+        "https://gateway.test/ipfs/QmHash/metadata.json",
+        resolver
+            .url("ipfs://QmHash/metadata.json")
+            .unwrap()
+    );
+    assert_eq!(
+        "http://example.com/1",
+        resolver
+            .url("http://example.com/1")
+            .unwrap()
+    );
+
+    // uris that are not fetched are marked unresolved, with the reason
+    let resolved = resolver.resolve("tezos-storage:content");
+    assert_eq!(None, resolved.metadata);
+    assert_eq!(
+        Some("unsupported uri: tezos-storage:content".to_string()),
+        resolved.error
+    );
+    for uri in &[
+        "http://127.0.0.1/metadata.json",
+        "http://10.0.0.1/metadata.json",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]:8080/metadata.json",
+        "http://[::ffff:192.168.1.1]/metadata.json",
+    ] {
+        let resolved = resolver.resolve(uri);
+        assert_eq!(None, resolved.metadata);
+        assert!(
+            resolved
+                .error
+                .as_ref()
+                .unwrap()
+                .contains("non-public address"),
+            "{}: {:?}",
+            uri,
+            resolved.error
+        );
+    }
+
+    // cached metadata is not fetched again
+    let metadata = serde_json::json!({"name": "token"});
+    resolver
+        .cache
+        .insert("https://example.com/1".to_string(), metadata.clone());
+    assert_eq!(
+        Resolved {
+            metadata: Some(metadata),
+            error: None,
+        },
+        resolver.resolve("https://example.com/1")
+    );
+}
+
+#[test]
+fn test_is_public() {
+    for ip in &[
+        "1.1.1.1",
+        "100.128.0.1",
+        "2606:4700:4700::1111",
+        "::ffff:8.8.8.8",
+    ] {
+        assert!(is_public(&ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in &[
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.0.1",
+        "169.254.169.254",
+        "100.64.0.1",
+        "0.0.0.0",
+        "::1",
+        "::",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:127.0.0.1",
+    ] {
+        assert!(!is_public(&ip.parse().unwrap()), "{}", ip);
+    }
+}
+
+#[test]
+fn test_rate_limit() {
+    let mut resolver =
+        MetadataResolver::new("", Duration::from_secs(1), 20).unwrap();
+    let start = Instant::now();
+    for _ in 0..5 {
+        resolver.wait_turn();
+    }
+    // the first fetch goes right away, the others 50ms apart
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_resolve_pending() {
+    use crate::octez::block::{LevelMeta, TxContext};
+    use crate::sql::db::{drop_test_db, test_contract, test_db};
+    use crate::sql::insert::{Column, Insert, Value};
+
+    let schema = "test_resolve_pending";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let type_json = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "string", "annots": ["%name"] },
+            { "prim": "bytes", "annots": ["%uri"] },
+        ],
+    });
+    let mut contract = test_contract(schema, type_json);
+    contract
+        .metadata_uris
+        .insert("storage".to_string(), "uri".to_string());
+    dbcli.skip_derived_tables();
+    dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap();
+
+    // a storage row per tx context, (some of them) with a uri
+    let values: Vec<Value> = vec![
+        Value::String(hex::encode("ipfs://QmHash")),
+        Value::String(hex::encode("http://127.0.0.1/metadata.json")),
+        Value::String(hex::encode("Some Token")),
+    ];
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    DBClient::save_levels(
+        &mut db_tx,
+        &[&LevelMeta {
+            level: 10,
+            hash: None,
+            prev_hash: None,
+            baked_at: None,
+            protocol: None,
+            content_hash: None,
+        }],
+        false,
+    )
+    .unwrap();
+    for (i, value) in values.into_iter().enumerate() {
+        let id = i as i64 + 1;
+        DBClient::save_tx_contexts(
+            &mut db_tx,
+            &[TxContext {
+                id: Some(id),
+                contract: contract.cid.address.clone(),
+                level: 10,
+                operation_group_number: id as usize,
+                operation_number: 0,
+                content_number: 0,
+                internal_number: None,
+            }],
+            false,
+        )
+        .unwrap();
+        let column = |name: &str, value: Value| Column {
+            name: name.to_string(),
+            value,
+        };
+        DBClient::apply_inserts(
+            &mut db_tx,
+            &contract.cid,
+            &[Insert {
+                table_name: "storage".to_string(),
+                id,
+                fk_id: None,
+                parent_table: None,
+                columns: vec![
+                    column("tx_context_id", Value::BigInt(id)),
+                    column("name", Value::String("token".to_string())),
+                    column("uri", value),
+                ],
+            }],
+        )
+        .unwrap();
+    }
+    db_tx.commit().unwrap();
+    drop(conn);
+
+    let mut resolver = MetadataResolver::new(
+        "https://gateway.test/ipfs",
+        Duration::from_secs(1),
+        0,
+    )
+    .unwrap();
+    let metadata = serde_json::json!({"name": "token"});
+    resolver.cache.insert(
+        "https://gateway.test/ipfs/QmHash".to_string(),
+        metadata.clone(),
+    );
+    let mut uri_columns: HashMap<String, HashMap<String, String>> =
+        HashMap::new();
+    uri_columns
+        .insert(contract.cid.name.clone(), contract.metadata_uris.clone());
+
+    // every row is resolved once
+    assert_eq!(
+        3,
+        resolve_pending(&mut dbcli, &uri_columns, &mut resolver).unwrap()
+    );
+    assert_eq!(
+        0,
+        resolve_pending(&mut dbcli, &uri_columns, &mut resolver).unwrap()
+    );
+    let resolved =
+        |dbcli: &DBClient| -> Vec<(i64, Option<String>, bool, Option<String>)> {
+            dbcli
+            .dbconn()
+            .unwrap()
+            .query(
+                format!(
+                    r#"SELECT storage_id, uri, resolved, metadata FROM "{}"."storage.resolved_metadata" ORDER BY storage_id"#,
+                    contract.cid.name
+                )
+                .as_str(),
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+            .collect()
+        };
+    assert_eq!(
+        vec![
+            (
+                1,
+                Some("ipfs://QmHash".to_string()),
+                true,
+                Some(metadata.to_string())
+            ),
+            (
+                2,
+                Some("http://127.0.0.1/metadata.json".to_string()),
+                false,
+                None
+            ),
+            (3, None, false, None),
+        ],
+        resolved(&dbcli)
+    );
+
+    // resolved metadata go with the rows they belong to
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(&format!(
+            r#"DELETE FROM "{}"."storage" WHERE id = 1"#,
+            contract.cid.name
+        ))
+        .unwrap();
+    assert_eq!(
+        vec![2, 3],
+        resolved(&dbcli)
+            .iter()
+            .map(|(id, _, _, _)| *id)
+            .collect::<Vec<i64>>()
+    );
+
+    drop_test_db(&dbcli, schema);
+}
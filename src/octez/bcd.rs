@@ -1,16 +1,20 @@
 // bcd => better-call.dev
 use crate::config::ContractID;
+use crate::shutdown;
+use crate::sql::db::BcdProgressStore;
 use crate::stats::StatsLogger;
 use anyhow::{anyhow, Result};
 use backoff::{retry, Error, ExponentialBackoff};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::time::Duration;
 
 pub struct BCDClient {
     api_url: String,
     network: String,
     timeout: Duration,
+    // for how long to retry transient failures of a request
+    max_retry_time: Duration,
     contract_id: ContractID,
 }
 
@@ -24,19 +28,22 @@ impl BCDClient {
             api_url,
             network,
             timeout: Duration::from_secs(20),
+            max_retry_time: Duration::from_secs(15 * 60),
             contract_id,
         }
     }
 
-    pub(crate) fn populate_levels_chan<F>(
+    pub(crate) fn populate_levels_chan<F, S>(
         &self,
         node_at_height: F,
         stats: &StatsLogger,
         height_send: &flume::Sender<u32>,
         exclude_levels: &[u32],
+        progress: &mut S,
     ) -> Result<()>
     where
         F: Fn() -> Result<u32>,
+        S: BcdProgressStore,
     {
         let mut exclude: HashMap<u32, ()> = HashMap::new();
         for l in exclude_levels {
@@ -54,6 +61,9 @@ impl BCDClient {
 
         let latest_level = self.get_latest_level()?;
         loop {
+            if shutdown::requested() {
+                return Ok(());
+            }
             let node_height = node_at_height()?;
             if node_height >= latest_level {
                 break;
@@ -66,8 +76,18 @@ impl BCDClient {
         let report_name =
             &format!("better-call.dev '{}'", &self.contract_id.name);
 
-        let mut last_id = None;
+        let mut last_id = progress.get_bcd_last_id(&self.contract_id)?;
+        if let Some(last_id) = &last_id {
+            info!(
+                "resuming the better-call.dev scan of {} (last_id={})",
+                self.contract_id.name, last_id
+            );
+        }
+        let mut checkpoints = PageCheckpoints::new(self.contract_id.clone());
         loop {
+            if shutdown::requested() {
+                break;
+            }
             let (levels, new_last_id) = self.get_levels_page_with_contract(
                 &self.contract_id.address,
                 last_id,
@@ -76,9 +96,11 @@ impl BCDClient {
                 break;
             }
 
-            for level in levels {
-                send_level(level)?;
+            for level in &levels {
+                send_level(*level)?;
             }
+            checkpoints.push(new_last_id.clone(), levels);
+            checkpoints.advance(progress)?;
 
             stats.add(report_name, "pages", 1)?;
             stats.set(report_name, "last_id", new_last_id.clone())?;
@@ -163,23 +185,199 @@ impl BCDClient {
             warn!("transient better-call.dev communication error, retrying.. err={}", e);
             Error::Transient(e)
         }
-        let op = || -> Result<O> {
+        // failing to connect, a timeout, a server error or being rate
+        // limited is worth retrying, other error responses (eg a 404 for an
+        // unknown network) are not
+        let op = || -> Result<O, Error<anyhow::Error>> {
             let uri = format!("{}/{}", self.api_url, endpoint);
             debug!("GET {}..", uri);
 
             let cli = reqwest::blocking::Client::new();
-            let body = cli
-                .get(uri)
+            let resp = cli
+                .get(&uri)
                 .query(query_params)
                 .timeout(self.timeout)
-                .send()?
-                .text()?;
-            let parsed: O = parse_func(&body)?;
-            Ok(parsed)
+                .send()
+                .map_err(|e| transient_err(anyhow!(e)))?;
+            let status = resp.status();
+            let body = resp
+                .text()
+                .map_err(|e| transient_err(anyhow!(e)))?;
+            if !status.is_success() {
+                let e = anyhow!(
+                    "better-call.dev responded to GET {} with status {}: {}",
+                    uri,
+                    status,
+                    body
+                );
+                if status.is_server_error()
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                {
+                    return Err(transient_err(e));
+                }
+                return Err(Error::Permanent(e));
+            }
+            parse_func(&body).map_err(transient_err)
         };
-        retry(ExponentialBackoff::default(), || {
-            op().map_err(transient_err)
+        let backoff = ExponentialBackoff {
+            max_elapsed_time: Some(self.max_retry_time),
+            ..ExponentialBackoff::default()
+        };
+        retry(backoff, op).map_err(|e| match e {
+            Error::Permanent(e) | Error::Transient(e) => e,
         })
-        .map_err(|e| anyhow!(e))
     }
 }
+
+// The pages of which the levels have been sent, but not yet all confirmed to
+// be processed. A page's last_id is only saved once its levels and those of
+// all pages before it are processed, so that resuming from it skips no level.
+struct PageCheckpoints {
+    contract_id: ContractID,
+    pending: VecDeque<(String, Vec<u32>)>,
+}
+
+impl PageCheckpoints {
+    fn new(contract_id: ContractID) -> Self {
+        Self {
+            contract_id,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, last_id: String, levels: Vec<u32>) {
+        self.pending
+            .push_back((last_id, levels));
+    }
+
+    fn advance<S: BcdProgressStore>(&mut self, store: &mut S) -> Result<()> {
+        let mut confirmed: Option<String> = None;
+        while let Some((_, levels)) = self.pending.front() {
+            if !store.levels_processed(&self.contract_id, levels)? {
+                break;
+            }
+            confirmed = self
+                .pending
+                .pop_front()
+                .map(|(last_id, _)| last_id);
+        }
+        if let Some(last_id) = confirmed {
+            store.set_bcd_last_id(&self.contract_id, &last_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct TestStore {
+    processed: Vec<u32>,
+    last_id: Option<String>,
+}
+
+#[cfg(test)]
+impl BcdProgressStore for TestStore {
+    fn get_bcd_last_id(
+        &mut self,
+        _contract_id: &ContractID,
+    ) -> Result<Option<String>> {
+        Ok(self.last_id.clone())
+    }
+
+    fn set_bcd_last_id(
+        &mut self,
+        _contract_id: &ContractID,
+        last_id: &str,
+    ) -> Result<()> {
+        self.last_id = Some(last_id.to_string());
+        Ok(())
+    }
+
+    fn levels_processed(
+        &mut self,
+        _contract_id: &ContractID,
+        levels: &[u32],
+    ) -> Result<bool> {
+        Ok(levels
+            .iter()
+            .all(|l| self.processed.contains(l)))
+    }
+}
+
+#[test]
+fn test_page_checkpoints() {
+    let mut store = TestStore {
+        processed: vec![],
+        last_id: None,
+    };
+    let mut checkpoints = PageCheckpoints::new(ContractID::default());
+    checkpoints.push("page1".to_string(), vec![30, 29]);
+    checkpoints.push("page2".to_string(), vec![20, 15]);
+    checkpoints.push("page3".to_string(), vec![10]);
+
+    checkpoints.advance(&mut store).unwrap();
+    assert_eq!(None, store.last_id);
+
+    // a later page being processed is not enough
+    store.processed = vec![20, 15, 10];
+    checkpoints.advance(&mut store).unwrap();
+    assert_eq!(None, store.last_id);
+
+    store.processed.push(30);
+    checkpoints.advance(&mut store).unwrap();
+    assert_eq!(None, store.last_id);
+
+    store.processed.push(29);
+    checkpoints.advance(&mut store).unwrap();
+    assert_eq!(Some("page3".to_string()), store.last_id);
+    assert!(checkpoints.pending.is_empty());
+}
+
+#[test]
+fn test_populate_levels_chan_http_failures() {
+    use crate::octez::node::mock_node_responses;
+
+    let bcd_cli = |url: String| {
+        let mut cli =
+            BCDClient::new(url, "mainnet".to_string(), ContractID::default());
+        cli.max_retry_time = Duration::from_secs(2);
+        cli
+    };
+    let head = "[{\"network\":\"mainnet\",\"level\":100}]";
+    let stats = StatsLogger::new(Duration::from_secs(60));
+
+    // the server error is retried, the 404 for the second page is not
+    let cli = bcd_cli(mock_node_responses(vec![
+        ("500 Internal Server Error", ""),
+        ("200 OK", head),
+        (
+            "200 OK",
+            "{\"operations\":[{\"level\":100},{\"level\":90}],\"last_id\":\"page1\"}",
+        ),
+        ("404 Not Found", "not found"),
+    ]));
+    let mut store = TestStore {
+        processed: vec![100, 90],
+        last_id: None,
+    };
+    let (height_send, height_recv) = flume::unbounded::<u32>();
+    let err = cli
+        .populate_levels_chan(|| Ok(100), &stats, &height_send, &[], &mut store)
+        .unwrap_err();
+    assert!(err.to_string().contains("404"), "{}", err);
+    assert_eq!(
+        vec![100, 90],
+        height_recv
+            .try_iter()
+            .collect::<Vec<u32>>()
+    );
+    // the next run resumes after the first page
+    assert_eq!(Some("page1".to_string()), store.last_id);
+
+    // persistent server errors are given up on eventually
+    let cli =
+        bcd_cli(mock_node_responses(vec![("503 Service Unavailable", "")]));
+    let err = cli
+        .populate_levels_chan(|| Ok(100), &stats, &height_send, &[], &mut store)
+        .unwrap_err();
+    assert!(err.to_string().contains("503"), "{}", err);
+}
@@ -2,8 +2,10 @@ use crate::itertools::Itertools;
 use chrono::{DateTime, Utc};
 use pg_bigdecimal::{BigDecimal, PgNumeric};
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::sync::Mutex;
 
 use crate::contract_denylist::is_contract_denylisted;
 
@@ -11,6 +13,89 @@ const LIQUIDITY_BAKING_LEVEL: u32 = 1589247;
 const LIQUIDITY_BAKING: &str = "KT1TxqZ8QtKvLu3V3JH7Gx58n7Co8pgtpQU5";
 const LIQUIDITY_BAKING_TOKEN: &str = "KT1AafHA1C1vk959wvHWBispY9Y2f3fxBUUo";
 
+// Operation kinds up to the latest protocol we know of. Operations of any
+// other kind are still deserialized (and ignored unless they carry a
+// contract call or origination in the usual place), but we warn about them
+// once so that a new protocol doesn't go unnoticed.
+const KNOWN_OPERATION_KINDS: &[&str] = &[
+    "endorsement",
+    "endorsement_with_slot",
+    "preendorsement",
+    "attestation",
+    "attestation_with_dal",
+    "preattestation",
+    "dal_attestation",
+    "seed_nonce_revelation",
+    "vdf_revelation",
+    "double_endorsement_evidence",
+    "double_preendorsement_evidence",
+    "double_attestation_evidence",
+    "double_preattestation_evidence",
+    "double_baking_evidence",
+    "activate_account",
+    "proposals",
+    "ballot",
+    "reveal",
+    "transaction",
+    "origination",
+    "delegation",
+    "event",
+    "set_deposits_limit",
+    "increase_paid_storage",
+    "update_consensus_key",
+    "drain_delegate",
+    "failing_noop",
+    "register_global_constant",
+    "transfer_ticket",
+    "dal_publish_slot_header",
+    "dal_publish_commitment",
+    "tx_rollup_origination",
+    "tx_rollup_submit_batch",
+    "tx_rollup_commit",
+    "tx_rollup_return_bond",
+    "tx_rollup_finalize_commitment",
+    "tx_rollup_remove_commitment",
+    "tx_rollup_rejection",
+    "tx_rollup_dispatch_tickets",
+    "sc_rollup_originate",
+    "sc_rollup_add_messages",
+    "sc_rollup_cement",
+    "sc_rollup_publish",
+    "sc_rollup_refute",
+    "sc_rollup_timeout",
+    "sc_rollup_execute_outbox_message",
+    "sc_rollup_recover_bond",
+    "smart_rollup_originate",
+    "smart_rollup_add_messages",
+    "smart_rollup_cement",
+    "smart_rollup_publish",
+    "smart_rollup_refute",
+    "smart_rollup_timeout",
+    "smart_rollup_execute_outbox_message",
+    "smart_rollup_recover_bond",
+    "zk_rollup_origination",
+    "zk_rollup_publish",
+    "zk_rollup_update",
+];
+
+lazy_static! {
+    static ref WARNED_OPERATION_KINDS: Mutex<HashSet<String>> =
+        Mutex::new(HashSet::new());
+}
+
+fn check_operation_kind(kind: &str) {
+    if kind.is_empty() || KNOWN_OPERATION_KINDS.contains(&kind) {
+        return;
+    }
+    let mut warned = WARNED_OPERATION_KINDS.lock().unwrap();
+    if warned.insert(kind.to_string()) {
+        warn!(
+            "encountered unrecognized operation kind '{}', ignoring it unless it calls or originates a contract",
+            kind
+        );
+    }
+}
+
 pub(crate) fn get_implicit_origination_level(contract: &str) -> Option<u32> {
     if contract == LIQUIDITY_BAKING || contract == LIQUIDITY_BAKING_TOKEN {
         return Some(LIQUIDITY_BAKING_LEVEL);
@@ -25,6 +110,10 @@ pub struct LevelMeta {
     pub hash: Option<String>,
     pub prev_hash: Option<String>,
     pub baked_at: Option<DateTime<Utc>>,
+    pub protocol: Option<String>,
+    // hash over the content indexed for the level, see
+    // inserter::content_hash
+    pub content_hash: Option<String>,
 }
 
 #[derive(
@@ -39,16 +128,16 @@ pub struct Block {
     pub hash: String,
     pub header: Header,
     pub operations: Vec<Vec<Operation>>,
+    #[serde(default)]
+    pub protocol: String,
 
-    #[serde(skip)]
-    protocol: String,
     #[serde(skip)]
     chain_id: String,
     #[serde(skip)]
     metadata: Metadata,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde_derive::Serialize)]
 pub(crate) struct TxContext {
     pub id: Option<i64>,
     pub contract: String,
@@ -64,6 +153,7 @@ pub(crate) struct Tx {
     pub tx_context_id: i64,
 
     pub operation_hash: String,
+    pub status: String,
     pub source: Option<String>,
     pub destination: Option<String>,
 
@@ -78,6 +168,21 @@ pub(crate) struct Tx {
     pub consumed_milligas: Option<i64>,
     pub storage_size: Option<i64>,
     pub paid_storage_size_diff: Option<i64>,
+
+    // of the operation that was signed (so internal operations get those of
+    // the operation that emitted them)
+    pub counter: Option<i64>,
+    pub branch: Option<String>,
+}
+
+// An applied delegation (or withdrawal of the delegate, when there is none),
+// see Block::delegations.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Delegation {
+    pub level: u32,
+    pub operation_hash: String,
+    pub source: String,
+    pub delegate: Option<String>,
 }
 
 impl Hash for TxContext {
@@ -163,9 +268,39 @@ impl Block {
         Ok(None)
     }
 
-    pub(crate) fn map_tx_contexts<F, O>(
+    pub(crate) fn map_tx_contexts<F, O>(&self, f: F) -> anyhow::Result<Vec<O>>
+    where
+        F: FnMut(
+            TxContext,
+            Tx,
+            bool,
+            &OperationResult,
+        ) -> anyhow::Result<Option<O>>,
+    {
+        self.map_tx_contexts_internal(f, false)
+    }
+
+    // Same as map_tx_contexts, but also visits operations that did not get
+    // applied (eg failed or backtracked operations).
+    pub(crate) fn map_all_tx_contexts<F, O>(
+        &self,
+        f: F,
+    ) -> anyhow::Result<Vec<O>>
+    where
+        F: FnMut(
+            TxContext,
+            Tx,
+            bool,
+            &OperationResult,
+        ) -> anyhow::Result<Option<O>>,
+    {
+        self.map_tx_contexts_internal(f, true)
+    }
+
+    fn map_tx_contexts_internal<F, O>(
         &self,
         mut f: F,
+        include_unapplied: bool,
     ) -> anyhow::Result<Vec<O>>
     where
         F: FnMut(
@@ -185,10 +320,13 @@ impl Block {
                 for (content_number, content) in
                     operation.contents.iter().enumerate()
                 {
+                    check_operation_kind(&content.kind);
                     if let Some(operation_result) =
                         &content.metadata.operation_result
                     {
-                        if operation_result.status != "applied" {
+                        if !include_unapplied
+                            && operation_result.status != "applied"
+                        {
                             continue;
                         }
                         if let Some(dest_addr) = &content.destination {
@@ -207,6 +345,7 @@ impl Block {
                                         tx_context_id: -1,
 
                                         operation_hash: operation.hash.clone(),
+                                        status: operation_result.status.clone(),
                                         source: content.source.clone(),
                                         destination: content
                                             .destination
@@ -251,6 +390,10 @@ impl Block {
                                                     .paid_storage_size_diff
                                                     .as_ref(),
                                             )?,
+                                        counter: Self::parse_option_i64(
+                                            content.counter.as_ref(),
+                                        )?,
+                                        branch: operation.branch.clone(),
                                     },
                                     false,
                                     operation_result,
@@ -265,7 +408,11 @@ impl Block {
                                     .iter()
                                     .enumerate()
                                 {
-                                    if internal_op.result.status != "applied" {
+                                    check_operation_kind(&internal_op.kind);
+                                    if !include_unapplied
+                                        && internal_op.result.status
+                                            != "applied"
+                                    {
                                         continue;
                                     }
                                     if let Some(internal_dest_addr) =
@@ -292,6 +439,10 @@ impl Block {
                                                     operation_hash: operation
                                                         .hash
                                                         .clone(),
+                                                    status: internal_op
+                                                        .result
+                                                        .status
+                                                        .clone(),
                                                     source: Some(
                                                         internal_op
                                                             .source
@@ -334,6 +485,10 @@ impl Block {
                                                                 .paid_storage_size_diff
                                                                 .as_ref(),
                                                     )?,
+                                                    counter: Self::parse_option_i64(
+                                                        content.counter.as_ref(),
+                                                    )?,
+                                                    branch: operation.branch.clone(),
                                                 },
                                                 false,
                                                 &internal_op.result,
@@ -365,6 +520,10 @@ impl Block {
                                                 operation_hash: operation
                                                     .hash
                                                     .clone(),
+                                                status: internal_op
+                                                    .result
+                                                    .status
+                                                    .clone(),
                                                 source: Some(
                                                     internal_op.source.clone(),
                                                 ),
@@ -385,6 +544,10 @@ impl Block {
                                                 consumed_milligas: None,
                                                 storage_size: None,
                                                 paid_storage_size_diff: None,
+                                                counter: Self::parse_option_i64(
+                                                    content.counter.as_ref(),
+                                                )?,
+                                                branch: operation.branch.clone(),
                                             },
                                             true,
                                             &internal_op.result,
@@ -412,6 +575,7 @@ impl Block {
                                     tx_context_id: -1,
 
                                     operation_hash: operation.hash.clone(),
+                                    status: operation_result.status.clone(),
                                     source: content.source.clone(),
                                     destination: Some(contract.clone()),
 
@@ -445,6 +609,10 @@ impl Block {
                                                 .paid_storage_size_diff
                                                 .as_ref(),
                                         )?,
+                                    counter: Self::parse_option_i64(
+                                        content.counter.as_ref(),
+                                    )?,
+                                    branch: operation.branch.clone(),
                                 },
                                 true,
                                 operation_result,
@@ -461,6 +629,20 @@ impl Block {
     }
 
     pub(crate) fn is_contract_active(&self, contract_address: &str) -> bool {
+        self.is_contract_active_internal(contract_address, false)
+    }
+
+    // Same as is_contract_active, but also considers calls to the contract
+    // that did not get applied.
+    pub(crate) fn is_contract_called(&self, contract_address: &str) -> bool {
+        self.is_contract_active_internal(contract_address, true)
+    }
+
+    fn is_contract_active_internal(
+        &self,
+        contract_address: &str,
+        include_unapplied: bool,
+    ) -> bool {
         if is_contract_denylisted(contract_address) {
             return false;
         }
@@ -472,7 +654,9 @@ impl Block {
                     if let Some(operation_result) =
                         &content.metadata.operation_result
                     {
-                        if operation_result.status != "applied" {
+                        if !include_unapplied
+                            && operation_result.status != "applied"
+                        {
                             continue;
                         }
                         if content.destination == destination {
@@ -493,6 +677,68 @@ impl Block {
         is_implicit_active(self.header.level, contract_address)
     }
 
+    /// The applied delegations (including those made by contracts, as
+    /// internal operations) with the contract as source or delegate.
+    pub(crate) fn delegations(
+        &self,
+        contract_address: &str,
+    ) -> Vec<Delegation> {
+        let is_match = |source: &str, delegate: &Option<String>| {
+            source == contract_address
+                || delegate.as_deref() == Some(contract_address)
+        };
+        let mut res: Vec<Delegation> = vec![];
+        for operations in &self.operations {
+            for operation in operations {
+                for content in &operation.contents {
+                    let applied = content
+                        .metadata
+                        .operation_result
+                        .as_ref()
+                        .map(|op_res| op_res.status == "applied")
+                        .unwrap_or(false);
+                    if !applied {
+                        continue;
+                    }
+                    if content.kind == "delegation" {
+                        let source = content
+                            .source
+                            .clone()
+                            .unwrap_or_default();
+                        if is_match(&source, &content.delegate) {
+                            res.push(Delegation {
+                                level: self.header.level,
+                                operation_hash: operation.hash.clone(),
+                                source,
+                                delegate: content.delegate.clone(),
+                            });
+                        }
+                    }
+                    for internal_op in &content
+                        .metadata
+                        .internal_operation_results
+                    {
+                        if internal_op.kind == "delegation"
+                            && internal_op.result.status == "applied"
+                            && is_match(
+                                &internal_op.source,
+                                &internal_op.delegate,
+                            )
+                        {
+                            res.push(Delegation {
+                                level: self.header.level,
+                                operation_hash: operation.hash.clone(),
+                                source: internal_op.source.clone(),
+                                delegate: internal_op.delegate.clone(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        res
+    }
+
     pub(crate) fn has_contract_origination(
         &self,
         contract_address: &str,
@@ -746,8 +992,8 @@ pub struct Operation {
     signature: Option<String>,
     #[serde(skip)]
     chain_id: String,
-    #[serde(skip)]
-    branch: String,
+    // the hash of the block the operation was signed against
+    pub branch: Option<String>,
 }
 
 #[derive(
@@ -759,7 +1005,12 @@ pub struct Operation {
     serde_derive::Deserialize,
 )]
 pub struct Content {
+    #[serde(default)]
+    pub kind: String,
     pub slot: Option<i64>,
+    // operations of kinds we know nothing about may come without (or with
+    // differently shaped) metadata, those are of no interest to the indexer
+    #[serde(default)]
     pub metadata: OperationMetadata,
     pub destination: Option<String>,
     pub source: Option<String>,
@@ -769,13 +1020,13 @@ pub struct Content {
     pub storage_limit: Option<String>,
     pub amount: Option<String>,
     pub balance: Option<String>,
+    // set on delegations, unless the delegation is withdrawn
+    pub delegate: Option<String>,
 
-    #[serde(skip)]
-    kind: String,
     #[serde(skip)]
     endorsement: Option<Endorsement>,
-    #[serde(skip)]
-    counter: Option<String>,
+    // set on manager operations (eg transactions and originations)
+    pub counter: Option<String>,
     #[serde(skip)]
     script: Option<Script>,
 }
@@ -840,6 +1091,7 @@ pub struct OperationMetadata {
 pub struct OperationResult {
     #[serde(default)]
     pub originated_contracts: Vec<String>,
+    #[serde(default)]
     pub status: String,
     pub storage: Option<::serde_json::Value>,
     pub big_map_diff: Option<Vec<BigMapDiff>>,
@@ -852,6 +1104,11 @@ pub struct OperationResult {
     #[serde(default)]
     pub paid_storage_size_diff: Option<String>,
 
+    // set when the operation failed (status "failed"), operations that were
+    // backtracked (or skipped) have no errors of their own
+    #[serde(default)]
+    pub errors: Vec<OperationError>,
+
     #[serde(skip)]
     balance_updates: Option<Vec<BalanceUpdate>>,
     #[serde(skip)]
@@ -859,6 +1116,21 @@ pub struct OperationResult {
     //    pub lazy_storage_diff: Option<Vec<LazyStorageDiff>>,
 }
 
+#[derive(
+    Default,
+    Debug,
+    Clone,
+    PartialEq,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+pub struct OperationError {
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default)]
+    pub id: String,
+}
+
 #[derive(
     Default,
     Debug,
@@ -1029,13 +1301,19 @@ pub struct ValueType2 {
     serde_derive::Deserialize,
 )]
 pub struct InternalOperationResult {
+    #[serde(default)]
     pub kind: String,
+    #[serde(default)]
     pub source: String,
+    #[serde(default)]
     pub nonce: i64,
     pub amount: Option<String>, // todo, is this possible?
     pub balance: Option<String>,
     pub destination: Option<String>,
     pub parameters: Option<Parameters>,
+    // set on delegations, unless the delegation is withdrawn
+    pub delegate: Option<String>,
+    #[serde(default)]
     pub result: OperationResult,
 }
 
@@ -1092,3 +1370,53 @@ pub struct Code {
     pub prim: Option<String>,
     pub args: Option<Vec<::serde_json::Value>>,
 }
+
+#[test]
+fn test_delegations() {
+    // level 11, with the contract setting its delegate during the call, and
+    // an unrelated delegation in another operation
+    let block: Block = serde_json::from_str(&crate::debug::load_test(
+        "test/KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V.delegation-level-11.json",
+    ))
+    .unwrap();
+
+    assert_eq!(
+        vec![Delegation {
+            level: 11,
+            operation_hash:
+                "ooUGU6LZJjFUqDuW32oupsRTRmNRHr1VBxPpswXTpn5rbs8tsmZ"
+                    .to_string(),
+            source: "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V".to_string(),
+            delegate: Some("tz1YPSCGWXwBdTncK2aCctSZAXWvGsGwVJqU".to_string()),
+        }],
+        block.delegations("KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V")
+    );
+    // the baker is the delegate of both
+    assert_eq!(
+        2,
+        block
+            .delegations("tz1YPSCGWXwBdTncK2aCctSZAXWvGsGwVJqU")
+            .len()
+    );
+    assert!(block
+        .delegations("KT1GER1yY5GyJgCn5aH7emhGec16ArrJxsiN")
+        .is_empty());
+}
+
+#[test]
+fn test_tx_counter_and_branch() {
+    let block: Block = serde_json::from_str(&crate::debug::load_test(
+        "test/KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V.level-11.json",
+    ))
+    .unwrap();
+
+    let txs = block
+        .map_tx_contexts(|_, tx, _, _| Ok(Some(tx)))
+        .unwrap();
+    assert_eq!(1, txs.len());
+    assert_eq!(Some(9), txs[0].counter);
+    assert_eq!(
+        Some("BM17jKHEtLYZpHVF1ZXb6L8UAw8LV294AhGLJtv2NvbnU8e2abS".to_string()),
+        txs[0].branch
+    );
+}
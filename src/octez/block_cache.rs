@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Context, Result};
+use linked_hash_map::LinkedHashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::sql::compression::{gunzip, gzip};
+
+// Keeps the most recently fetched blocks on disk (gzipped, the way the node
+// returned them), keyed by level and hash, evicting the least recently used
+// block once the capacity is reached. Meant to avoid fetching a level's block
+// again shortly after, eg when reprocessing the levels around a fork: the
+// blocks that were not forked out are served from here, while the new block
+// of a forked level has another hash and is fetched from the node.
+//
+// Blocks cached in an earlier run are picked up again, oldest levels first in
+// line for eviction.
+pub(crate) struct BlockCache {
+    dir: PathBuf,
+    capacity: usize,
+    state: Mutex<BlockCacheState>,
+}
+
+struct BlockCacheState {
+    entries: LinkedHashMap<(u32, String), ()>,
+
+    hits: u64,
+    lookups: u64,
+}
+
+impl BlockCache {
+    pub(crate) fn new(dir: &Path, capacity: usize) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| {
+            format!("failed to create block cache dir {}", dir.display())
+        })?;
+        let mut cached: Vec<(u32, String)> = vec![];
+        for entry in fs::read_dir(dir).with_context(|| {
+            format!("failed to read block cache dir {}", dir.display())
+        })? {
+            let name = entry?.file_name();
+            if let Some(key) = name
+                .to_str()
+                .and_then(Self::parse_file_name)
+            {
+                cached.push(key);
+            }
+        }
+        cached.sort();
+
+        let cache = Self {
+            dir: dir.to_path_buf(),
+            capacity: capacity.max(1),
+            state: Mutex::new(BlockCacheState {
+                entries: LinkedHashMap::new(),
+                hits: 0,
+                lookups: 0,
+            }),
+        };
+        {
+            let mut state = cache.lock()?;
+            for key in cached {
+                cache.insert(&mut state, key);
+            }
+        }
+        Ok(cache)
+    }
+
+    // The block's json, if it is cached.
+    pub(crate) fn get(&self, level: u32, hash: &str) -> Result<Option<String>> {
+        let key = (level, hash.to_string());
+        {
+            let mut state = self.lock()?;
+            state.lookups += 1;
+            if state
+                .entries
+                .get_refresh(&key)
+                .is_none()
+            {
+                return Ok(None);
+            }
+        }
+        let path = self.path(&key);
+        let body = fs::read(&path)
+            .map_err(|e| anyhow!(e))
+            .and_then(|compressed| gunzip(&compressed))
+            .and_then(|body| Ok(String::from_utf8(body)?));
+        let mut state = self.lock()?;
+        match body {
+            Ok(body) => {
+                state.hits += 1;
+                Ok(Some(body))
+            }
+            Err(e) => {
+                warn!(
+                    "failed to read cached block {}, fetching it instead: {:?}",
+                    path.display(),
+                    e
+                );
+                state.entries.remove(&key);
+                Ok(None)
+            }
+        }
+    }
+
+    pub(crate) fn put(&self, level: u32, hash: &str, body: &str) -> Result<()> {
+        let key = (level, hash.to_string());
+        if self.lock()?.entries.contains_key(&key) {
+            return Ok(());
+        }
+        let path = self.path(&key);
+        fs::write(&path, gzip(body.as_bytes())?).with_context(|| {
+            format!("failed to write cached block {}", path.display())
+        })?;
+        let mut state = self.lock()?;
+        self.insert(&mut state, key);
+        Ok(())
+    }
+
+    // The percentage of lookups that were served from the cache.
+    pub(crate) fn hit_rate(&self) -> Result<Option<f64>> {
+        let state = self.lock()?;
+        if state.lookups == 0 {
+            return Ok(None);
+        }
+        Ok(Some(state.hits as f64 / state.lookups as f64 * 100.0))
+    }
+
+    fn insert(&self, state: &mut BlockCacheState, key: (u32, String)) {
+        state.entries.insert(key, ());
+        while state.entries.len() > self.capacity {
+            if let Some((evicted, _)) = state.entries.pop_front() {
+                let path = self.path(&evicted);
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!(
+                        "failed to remove cached block {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, BlockCacheState>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow!("failed to lock block cache"))
+    }
+
+    fn path(&self, key: &(u32, String)) -> PathBuf {
+        self.dir
+            .join(format!("{}-{}.json.gz", key.0, key.1))
+    }
+
+    fn parse_file_name(name: &str) -> Option<(u32, String)> {
+        let (level, hash) = name
+            .strip_suffix(".json.gz")?
+            .split_once('-')?;
+        Some((level.parse::<u32>().ok()?, hash.to_string()))
+    }
+}
+
+#[test]
+fn test_block_cache() {
+    let dir = std::env::temp_dir()
+        .join(format!("quepasa-test-block-cache-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let cache = BlockCache::new(&dir, 2).unwrap();
+    assert_eq!(None, cache.hit_rate().unwrap());
+    cache
+        .put(10, "BLa", "{\"level\":10}")
+        .unwrap();
+    cache
+        .put(11, "BLb", "{\"level\":11}")
+        .unwrap();
+    assert_eq!(
+        Some("{\"level\":10}".to_string()),
+        cache.get(10, "BLa").unwrap()
+    );
+    // a forked level has another hash
+    assert_eq!(None, cache.get(11, "BLc").unwrap());
+    // 11 is the least recently used
+    cache
+        .put(12, "BLd", "{\"level\":12}")
+        .unwrap();
+    assert_eq!(None, cache.get(11, "BLb").unwrap());
+    assert!(!dir.join("11-BLb.json.gz").exists());
+    assert_eq!(Some(1.0 / 3.0 * 100.0), cache.hit_rate().unwrap());
+
+    // picked up again by a later run
+    drop(cache);
+    fs::write(dir.join("unrelated.txt"), "").unwrap();
+    let cache = BlockCache::new(&dir, 1).unwrap();
+    assert_eq!(None, cache.get(10, "BLa").unwrap());
+    assert_eq!(
+        Some("{\"level\":12}".to_string()),
+        cache.get(12, "BLd").unwrap()
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,23 +1,38 @@
 use crate::octez::block::{Block, LevelMeta};
 use crate::octez::node;
 use anyhow::{anyhow, Context, Result};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
+// A fetched block, with its approximate size in bytes (see ByteBudget).
+pub type FetchedBlock = (LevelMeta, Block, usize);
+
 #[derive(Clone)]
 pub struct ConcurrentBlockGetter {
     node_cli: node::NodeClient,
     workers: usize,
+    // when set, the getters pause while the blocks fetched (but not yet
+    // released by whoever consumes them) exceed the budget
+    byte_budget: Option<Arc<ByteBudget>>,
 }
 
 impl ConcurrentBlockGetter {
     pub fn new(node_cli: node::NodeClient, workers: usize) -> Self {
-        Self { node_cli, workers }
+        Self {
+            node_cli,
+            workers,
+            byte_budget: None,
+        }
+    }
+
+    pub fn set_byte_budget(&mut self, byte_budget: Option<Arc<ByteBudget>>) {
+        self.byte_budget = byte_budget;
     }
 
     pub fn run(
         &self,
         recv_ch: flume::Receiver<u32>,
-        send_ch: flume::Sender<Box<(LevelMeta, Block)>>,
+        send_ch: flume::Sender<Box<FetchedBlock>>,
     ) -> Vec<thread::JoinHandle<()>> {
         let mut threads = vec![];
 
@@ -25,8 +40,15 @@ impl ConcurrentBlockGetter {
             let w_node_cli = self.node_cli.clone();
             let w_recv_ch = recv_ch.clone();
             let w_send_ch = send_ch.clone();
+            let w_byte_budget = self.byte_budget.clone();
             threads.push(thread::spawn(move || {
-                Self::worker_fn(w_node_cli, w_recv_ch, w_send_ch).unwrap();
+                Self::worker_fn(
+                    w_node_cli,
+                    w_recv_ch,
+                    w_send_ch,
+                    w_byte_budget,
+                )
+                .unwrap();
             }));
         }
 
@@ -36,16 +58,104 @@ impl ConcurrentBlockGetter {
     fn worker_fn(
         node_cli: node::NodeClient,
         recv_ch: flume::Receiver<u32>,
-        send_ch: flume::Sender<Box<(LevelMeta, Block)>>,
+        send_ch: flume::Sender<Box<FetchedBlock>>,
+        byte_budget: Option<Arc<ByteBudget>>,
     ) -> Result<()> {
         for level_height in recv_ch {
-            let (level, block) = node_cli
-                .level_json(level_height)
+            let (level, block, size) = node_cli
+                .level_json_sized(level_height)
                 .with_context(|| {
                     anyhow!("failed to get json for block {}", level_height)
                 })?;
-            send_ch.send(Box::new((level, block)))?;
+            if let Some(byte_budget) = &byte_budget {
+                byte_budget.acquire(size)?;
+            }
+            send_ch.send(Box::new((level, block, size)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Bounds the (approximate) number of bytes of the blocks that are buffered
+/// between being fetched and being processed. Blocks are acquired by the
+/// getters once fetched, and released by the processors once processed.
+pub struct ByteBudget {
+    cap: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ByteBudget {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            cap,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    // Waits until the bytes fit in the budget. A block larger than the budget
+    // is let through when nothing else is buffered, so it cannot block the
+    // getters forever.
+    pub fn acquire(&self, bytes: usize) -> Result<()> {
+        let mut used = self
+            .used
+            .lock()
+            .map_err(|_| anyhow!("failed to lock byte budget mutex"))?;
+        while *used > 0 && *used + bytes > self.cap {
+            used = self
+                .released
+                .wait(used)
+                .map_err(|_| anyhow!("failed to lock byte budget mutex"))?;
         }
+        *used += bytes;
+        Ok(())
+    }
+
+    pub fn release(&self, bytes: usize) -> Result<()> {
+        let mut used = self
+            .used
+            .lock()
+            .map_err(|_| anyhow!("failed to lock byte budget mutex"))?;
+        *used = used.saturating_sub(bytes);
+        self.released.notify_all();
         Ok(())
     }
+
+    pub fn used(&self) -> Result<usize> {
+        Ok(*self
+            .used
+            .lock()
+            .map_err(|_| anyhow!("failed to lock byte budget mutex"))?)
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+}
+
+#[test]
+fn test_byte_budget() {
+    use std::time::Duration;
+
+    let budget = Arc::new(ByteBudget::new(100));
+    budget.acquire(60).unwrap();
+    budget.acquire(40).unwrap();
+    assert_eq!(100, budget.used().unwrap());
+
+    // over budget: waits until enough is released
+    let acquirer = {
+        let budget = budget.clone();
+        thread::spawn(move || budget.acquire(30).unwrap())
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(100, budget.used().unwrap());
+    budget.release(60).unwrap();
+    acquirer.join().unwrap();
+    assert_eq!(70, budget.used().unwrap());
+
+    // a block larger than the budget passes once nothing else is buffered
+    budget.release(70).unwrap();
+    budget.acquire(500).unwrap();
+    assert_eq!(500, budget.used().unwrap());
 }
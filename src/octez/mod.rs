@@ -1,4 +1,5 @@
 pub mod bcd;
 pub mod block;
+pub mod block_cache;
 pub mod block_getter;
 pub mod node;
@@ -1,11 +1,17 @@
+use crate::config::{BlockSource, NodeTlsSettings};
 use crate::octez::block::{Block, LevelMeta};
+use crate::octez::block_cache::BlockCache;
 use anyhow::{anyhow, Context, Result};
 use backoff::{retry, Error, ExponentialBackoff};
 use chrono::{DateTime, Utc};
-use curl::easy::Easy;
+use curl::easy::{Easy, List};
+use pg_bigdecimal::{BigDecimal, PgNumeric};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs;
 use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::Duration;
 use thiserror::Error;
 
@@ -15,6 +21,66 @@ pub struct NodeClient {
     chain: String,
     timeout: Duration,
     comm_retries: i32,
+    // blocks are read from here, all other requests (eg for storage) always
+    // go to the node
+    block_source: BlockSource,
+    // recently fetched blocks, when set (see set_block_cache)
+    block_cache: Option<Arc<BlockCache>>,
+    // applied to every request (see set_tls)
+    tls: NodeTlsSettings,
+
+    // shared by all clones, so it caps the requests in flight across all
+    // threads that use this node client
+    requests_limiter: Arc<RequestsLimiter>,
+
+    // the expansions of the global constants fetched so far (by hash), these
+    // never change once registered
+    global_constants: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+}
+
+/// A counting semaphore on the number of requests in flight to the node.
+struct RequestsLimiter {
+    max_in_flight: usize,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+struct RequestPermit<'a> {
+    limiter: &'a RequestsLimiter,
+}
+
+impl RequestsLimiter {
+    fn new(max_in_flight: usize) -> Self {
+        Self {
+            max_in_flight: max_in_flight.max(1),
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Result<RequestPermit<'_>> {
+        let mut in_flight = self
+            .in_flight
+            .lock()
+            .map_err(|_| anyhow!("failed to lock node requests limiter"))?;
+        while *in_flight >= self.max_in_flight {
+            in_flight = self
+                .released
+                .wait(in_flight)
+                .map_err(|_| anyhow!("failed to lock node requests limiter"))?;
+        }
+        *in_flight += 1;
+        Ok(RequestPermit { limiter: self })
+    }
+}
+
+impl Drop for RequestPermit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.limiter.in_flight.lock() {
+            *in_flight -= 1;
+        }
+        self.limiter.released.notify_one();
+    }
 }
 
 #[derive(Error, Debug)]
@@ -31,54 +97,258 @@ struct HttpError {
     status_code: u32,
 }
 
+// The node no longer has the context (ie the state) of the requested block:
+// nodes that are not archive nodes prune the context of old blocks, so eg
+// the storage of contracts cannot be requested at those levels anymore.
+#[derive(Error, Debug)]
+#[error("the node no longer has the context of the block (it was pruned, the node is not an archive node)")]
+pub(crate) struct ContextPrunedError;
+
+impl ContextPrunedError {
+    // Whether the body of a failed request is the error with which the node
+    // reports a pruned context.
+    fn is_pruned_response(body: &str) -> bool {
+        let body = body.to_lowercase();
+        body.contains("cannot_checkout_context")
+            || body.contains("failed to checkout the context")
+            || body.contains("missing_context")
+    }
+
+    pub(crate) fn caused(e: &anyhow::Error) -> bool {
+        e.chain()
+            .any(|cause| cause.is::<ContextPrunedError>())
+    }
+}
+
 impl NodeClient {
     pub fn new(
         node_urls: Vec<String>,
         chain: String,
         comm_retries: i32,
+        max_conns: usize,
     ) -> Self {
         Self {
             node_urls,
             chain,
             timeout: Duration::from_secs(20),
             comm_retries,
+            block_source: BlockSource::Node,
+            block_cache: None,
+            tls: NodeTlsSettings::default(),
+            requests_limiter: Arc::new(RequestsLimiter::new(max_conns)),
+            global_constants: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn set_block_source(&mut self, block_source: BlockSource) {
+        self.block_source = block_source;
+    }
+
+    /// Keep the blocks fetched from the node in the cache, and have
+    /// level_json (and level_json_sized) serve blocks from it (if the block
+    /// the node has at the level is cached).
+    pub(crate) fn set_block_cache(&mut self, block_cache: BlockCache) {
+        self.block_cache = Some(Arc::new(block_cache));
+    }
+
+    /// Connect to the node with a client certificate, a custom CA bundle
+    /// and/or an auth token. The files are checked up front, so that a typo
+    /// in a path is reported here rather than as a failing request.
+    pub(crate) fn set_tls(&mut self, tls: NodeTlsSettings) -> Result<()> {
+        if tls.client_cert.is_some() != tls.client_key.is_some() {
+            return Err(anyhow!(
+                "a node client certificate needs a key (and vice versa)"
+            ));
+        }
+        for path in [&tls.client_cert, &tls.client_key, &tls.ca_bundle]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            fs::File::open(path).with_context(|| {
+                format!("failed to open node tls file {}", path.display())
+            })?;
+        }
+        self.tls = tls;
+        Ok(())
+    }
+
+    pub(crate) fn block_cache_hit_rate(&self) -> Result<Option<f64>> {
+        match &self.block_cache {
+            Some(block_cache) => block_cache.hit_rate(),
+            None => Ok(None),
         }
     }
 
-    /// Return the highest level on the chain
+    /// Return the highest level on the chain (or in the block archive)
     pub(crate) fn head(&self) -> Result<LevelMeta> {
-        let (meta, _) = self.level_json_internal("head")?;
+        let level = match &self.block_source {
+            BlockSource::Node => "head".to_string(),
+            BlockSource::Archive(dir) => {
+                format!("{}", Self::archive_head(dir)?)
+            }
+        };
+        let (meta, _, _) = self.level_json_internal(&level)?;
         Ok(meta)
     }
 
+    /// The level of the head of the node at node_url, in a single attempt
+    /// (ie without retrying on any error).
+    pub(crate) fn ping(&self, node_url: &str) -> Result<u32> {
+        let body = self.load_from_node("blocks/head/header", node_url)?;
+        let header: serde_json::Value = serde_json::from_str(&body)?;
+        let level = header["level"]
+            .as_u64()
+            .ok_or_else(|| {
+                anyhow!("no level in the head's header: {}", body)
+            })?;
+        u32::try_from(level)
+            .map_err(|_| anyhow!("bad level {} in the head's header", level))
+    }
+
+    /// The id of the chain the node is on (eg NetXdQprcVkpaWU for mainnet)
+    pub(crate) fn chain_id(&self) -> Result<String> {
+        let body = self
+            .load("chain_id", Self::load_from_node_retry_on_transient_err)
+            .with_context(|| "failed to get the chain id")?;
+        Self::deserialize(&body)?
+            .as_str()
+            .map(|chain_id| chain_id.to_string())
+            .ok_or_else(|| {
+                anyhow!("malformed chain id response (not a json string)")
+            })
+    }
+
     pub(crate) fn level_json(&self, level: u32) -> Result<(LevelMeta, Block)> {
-        self.level_json_internal(&format!("{}", level))
+        let (meta, block, _) = self.level_json_sized(level)?;
+        Ok((meta, block))
     }
 
-    fn level_json_internal(&self, level: &str) -> Result<(LevelMeta, Block)> {
+    fn level_hash(&self, level: u32) -> Result<String> {
         let body = self
             .load(
-                &format!("blocks/{}", level),
+                &format!("blocks/{}/hash", level),
                 Self::load_from_node_retry_on_transient_err,
             )
             .with_context(|| {
-                format!("failed to get level_json for level={}", level)
+                format!("failed to get the block hash for level={}", level)
             })?;
+        Self::deserialize(&body)?
+            .as_str()
+            .map(|hash| hash.to_string())
+            .ok_or_else(|| {
+                anyhow!("malformed block hash response (not a json string)")
+            })
+    }
+
+    // Like level_json, but also returns the size of the block's json (a
+    // rough estimate of the memory the block takes up once deserialized).
+    pub(crate) fn level_json_sized(
+        &self,
+        level: u32,
+    ) -> Result<(LevelMeta, Block, usize)> {
+        if let (BlockSource::Node, Some(block_cache)) =
+            (&self.block_source, &self.block_cache)
+        {
+            // only the hash is fetched to find out whether the cached block
+            // (if any) is still the one at this level
+            let hash = self.level_hash(level)?;
+            if let Some(body) = block_cache.get(level, &hash)? {
+                let (meta, block) = Self::parse_level_json(&body)?;
+                return Ok((meta, block, body.len()));
+            }
+        }
+        self.level_json_internal(&format!("{}", level))
+    }
+
+    fn level_json_internal(
+        &self,
+        level: &str,
+    ) -> Result<(LevelMeta, Block, usize)> {
+        let body = match &self.block_source {
+            BlockSource::Node => self.load(
+                &format!("blocks/{}", level),
+                Self::load_from_node_retry_on_transient_err,
+            ),
+            BlockSource::Archive(dir) => self.load_from_archive(dir, level),
+        }
+        .with_context(|| {
+            format!("failed to get level_json for level={}", level)
+        })?;
 
-        let mut deserializer = serde_json::Deserializer::from_str(&body);
+        let (meta, block) = Self::parse_level_json(&body)?;
+        if let (BlockSource::Node, Some(block_cache)) =
+            (&self.block_source, &self.block_cache)
+        {
+            if let Err(e) = block_cache.put(meta.level, &block.hash, &body) {
+                warn!("failed to cache block {}: {:?}", meta.level, e);
+            }
+        }
+        Ok((meta, block, body.len()))
+    }
+
+    fn parse_level_json(body: &str) -> Result<(LevelMeta, Block)> {
+        let mut deserializer = serde_json::Deserializer::from_str(body);
         deserializer.disable_recursion_limit();
         let block: Block = Block::deserialize(&mut deserializer)
             .with_context(|| anyhow!("failed to deserialize block json"))?;
 
         let meta = LevelMeta {
-            level: block.header.level as u32,
+            level: block.header.level,
             hash: Some(block.hash.clone()),
             prev_hash: Some(block.header.predecessor.clone()),
             baked_at: Some(Self::timestamp_from_block(&block)?),
+            protocol: Some(block.protocol.clone())
+                .filter(|protocol| !protocol.is_empty()),
+            content_hash: None,
         };
         Ok((meta, block))
     }
 
+    // Reads the block from {dir}/{level}.json. A missing file means the level
+    // is not available yet, it is retried as often as failed node requests
+    // are.
+    fn load_from_archive(&self, dir: &str, level: &str) -> Result<String> {
+        let path = format!("{}/{}.json", dir, level);
+        let mut i = 0;
+        loop {
+            if Self::file_exists(&path)? {
+                return Ok(fs::read_to_string(&path)?);
+            }
+            if self.comm_retries >= 0 && i >= self.comm_retries {
+                return Err(anyhow!(
+                    "level {} is not available in block archive {}",
+                    level,
+                    dir
+                ));
+            }
+            warn!(
+                "level {} is not available in block archive {} (yet), retrying..",
+                level, dir
+            );
+            std::thread::sleep(std::time::Duration::from_millis(1000));
+            i += 1;
+        }
+    }
+
+    // The highest level that has a file in the block archive.
+    fn archive_head(dir: &str) -> Result<u32> {
+        let mut head: Option<u32> = None;
+        for entry in fs::read_dir(dir)
+            .with_context(|| format!("failed to read block archive {}", dir))?
+        {
+            let name = entry?.file_name();
+            let level = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+                .and_then(|level| level.parse::<u32>().ok());
+            if let Some(level) = level {
+                head = Some(head.unwrap_or(0).max(level));
+            }
+        }
+        head.ok_or_else(|| anyhow!("block archive {} has no blocks", dir))
+    }
+
     fn file_exists(path: &str) -> Result<bool> {
         let metadata = fs::metadata(&path);
         match metadata {
@@ -204,6 +474,93 @@ impl NodeClient {
         Ok(res)
     }
 
+    /// Return the expansion of a global constant (the Micheline expression
+    /// registered under the hash), cached once fetched
+    pub(crate) fn get_global_constant(
+        &self,
+        hash: &str,
+    ) -> Result<serde_json::Value> {
+        if let Some(expansion) = self
+            .global_constants
+            .lock()
+            .map_err(|_| anyhow!("failed to lock global constants cache"))?
+            .get(hash)
+        {
+            return Ok(expansion.clone());
+        }
+        let body = self
+            .load(
+                &format!("blocks/head/context/global_constants/{}", hash),
+                Self::load_from_node_retry_on_transient_err,
+            )
+            .with_context(|| {
+                format!("failed to get global constant='{}'", hash)
+            })?;
+        let expansion = Self::deserialize(&body)?;
+        self.global_constants
+            .lock()
+            .map_err(|_| anyhow!("failed to lock global constants cache"))?
+            .insert(hash.to_string(), expansion.clone());
+        Ok(expansion)
+    }
+
+    /// Return whether the contract exists (at the chain's head). Only a
+    /// response of the node that the contract is not found results in false,
+    /// other failures (eg the node being unreachable) are errors.
+    pub(crate) fn contract_exists(&self, contract_id: &str) -> Result<bool> {
+        let body = self
+            .load(
+                &format!(
+                    "blocks/head/context/contracts/{}/balance",
+                    contract_id
+                ),
+                Self::load_from_node_optional,
+            )
+            .with_context(|| {
+                format!(
+                    "failed to check whether contract='{}' exists",
+                    contract_id
+                )
+            })?;
+        Ok(body.is_some())
+    }
+
+    /// Return the contract's balance (in mutez) at given level
+    pub(crate) fn get_contract_balance(
+        &self,
+        contract_id: &str,
+        level: u32,
+    ) -> Result<PgNumeric> {
+        let body = self
+            .load(
+                &format!(
+                    "blocks/{}/context/contracts/{}/balance",
+                    level, contract_id
+                ),
+                Self::load_from_node_retry_on_transient_err,
+            )
+            .with_context(|| {
+                format!(
+                    "failed to get balance for contract='{}', level={}",
+                    contract_id, level
+                )
+            })?;
+        Self::parse_balance(&body).with_context(|| {
+            format!(
+                "failed to parse balance for contract='{}', level={}, balance body={}",
+                contract_id, level, body,
+            )
+        })
+    }
+
+    fn parse_balance(body: &str) -> Result<PgNumeric> {
+        let json = Self::deserialize(body)?;
+        let mutez = json.as_str().ok_or_else(|| {
+            anyhow!("malformed balance response (not a json string)")
+        })?;
+        Ok(PgNumeric::new(Some(BigDecimal::from_str(mutez)?)))
+    }
+
     fn parse_rfc3339(rfc3339: &str) -> Result<DateTime<Utc>> {
         let fixedoffset = chrono::DateTime::parse_from_rfc3339(rfc3339)?;
         Ok(fixedoffset.with_timezone(&Utc))
@@ -229,104 +586,150 @@ impl NodeClient {
             if self.comm_retries >= 0 && i > self.comm_retries {
                 break;
             }
+            let mut pruned = 0;
             for node_url in &self.node_urls {
                 let res = from_node_func(self, endpoint, node_url);
                 if res.is_ok() {
                     return res;
                 }
-                warn!("failed to call tezos node RPC endpoint on node_url {} (attempt {}/{}) (endpoint={}), err: {:?}", node_url, i+1, max_retries, endpoint, res.unwrap_err());
+                let err = res.unwrap_err();
+                if ContextPrunedError::caused(&err) {
+                    // retrying does not bring the context back, though
+                    // another node may still have it
+                    warn!("tezos node on node_url {} no longer has the context for endpoint={}", node_url, endpoint);
+                    pruned += 1;
+                    continue;
+                }
+                warn!("failed to call tezos node RPC endpoint on node_url {} (attempt {}/{}) (endpoint={}), err: {:?}", node_url, i+1, max_retries, endpoint, err);
                 std::thread::sleep(std::time::Duration::from_millis(1000));
             }
+            if pruned == self.node_urls.len() {
+                return Err(anyhow!(ContextPrunedError)).with_context(|| {
+                    format!(
+                        "failed to call tezos node RPC endpoint on all node_urls (endpoint={})",
+                        endpoint
+                    )
+                });
+            }
             i += 1;
         }
         Err(anyhow!("failed to call tezos node RPC endpoint on all node_urls (endpoint={}", endpoint))
     }
 
-    fn load_from_node_retry_on_transient_err(
-        &self,
-        endpoint: &str,
-        node_url: &str,
-    ) -> Result<String> {
-        fn transient_err(e: anyhow::Error) -> Error<anyhow::Error> {
-            if e.is::<curl::Error>() {
-                let curl_err = e.downcast::<curl::Error>();
-                if curl_err.is_err() {
-                    let downcast_err = curl_err.err().unwrap();
-                    error!("unexpected err on possibly transcient err downcast: {}", downcast_err);
-                    return Error::Permanent(downcast_err);
+    fn transient_err(e: anyhow::Error) -> Error<anyhow::Error> {
+        if e.is::<curl::Error>() {
+            let curl_err = e.downcast::<curl::Error>();
+            if curl_err.is_err() {
+                let downcast_err = curl_err.err().unwrap();
+                error!(
+                    "unexpected err on possibly transcient err downcast: {}",
+                    downcast_err
+                );
+                return Error::Permanent(downcast_err);
+            }
+
+            match curl_err.as_ref().ok().unwrap().code() {
+                // 7: CONNECTION REFUSED
+                // 28: TIMEOUT
+                // 56: RECEIVE ERROR
+                7 | 28 | 56 => {
+                    warn!("transient node communication error, retrying.. err={:?}", curl_err);
+                    return Error::Transient(anyhow!("{:?}", curl_err));
                 }
+                _ => {}
+            };
 
-                match curl_err.as_ref().ok().unwrap().code() {
-                    // 7: CONNECTION REFUSED
-                    // 28: TIMEOUT
-                    // 56: RECEIVE ERROR
-                    7 | 28 | 56 => {
-                        warn!("transient node communication error, retrying.. err={:?}", curl_err);
-                        return Error::Transient(anyhow!("{:?}", curl_err));
-                    }
-                    _ => {}
-                };
-
-                let curl_err_val = curl_err.ok().unwrap();
-                return Error::Permanent(anyhow!(
-                    "{} {} (curl status code: {})",
-                    curl_err_val.description(),
-                    curl_err_val
-                        .extra_description()
-                        .map(|descr| format!("(verbose: {})", descr))
-                        .unwrap_or_else(|| "".to_string()),
-                    curl_err_val.code(),
-                ));
+            let curl_err_val = curl_err.ok().unwrap();
+            return Error::Permanent(anyhow!(
+                "{} {} (curl status code: {})",
+                curl_err_val.description(),
+                curl_err_val
+                    .extra_description()
+                    .map(|descr| format!("(verbose: {})", descr))
+                    .unwrap_or_else(|| "".to_string()),
+                curl_err_val.code(),
+            ));
+        }
+        if e.is::<HttpError>() {
+            let http_err = e.downcast::<HttpError>();
+            if http_err.as_ref().is_err() {
+                let downcast_err = http_err.err().unwrap();
+                error!(
+                    "unexpected err on possibly transcient err downcast: {}",
+                    downcast_err
+                );
+                return Error::Permanent(downcast_err);
             }
-            if e.is::<HttpError>() {
-                let http_err = e.downcast::<HttpError>();
-                if http_err.as_ref().is_err() {
-                    let downcast_err = http_err.err().unwrap();
-                    error!("unexpected err on possibly transcient err downcast: {}", downcast_err);
-                    return Error::Permanent(downcast_err);
-                }
 
-                let err = http_err.unwrap();
-                if err.status_code == 429 {
-                    warn!("transient node communication error, retrying.. err={:?}", err);
-                    return Error::Transient(anyhow!("{:?}", err));
-                }
-                return Error::Permanent(anyhow!(
-                    "bad http status code {}, not retrying..",
-                    err.status_code
-                ));
+            let err = http_err.unwrap();
+            if err.status_code == 429 {
+                warn!(
+                    "transient node communication error, retrying.. err={:?}",
+                    err
+                );
+                return Error::Transient(anyhow!("{:?}", err));
             }
-            warn!(
-                "permanent node communication error, not retrying.. err={:?}",
-                e
-            );
-            Error::Permanent(e)
+            return Error::Permanent(anyhow!(
+                "bad http status code {}, not retrying..",
+                err.status_code
+            ));
+        }
+        if e.is::<ContextPrunedError>() {
+            return Error::Permanent(e);
         }
+        warn!(
+            "permanent node communication error, not retrying.. err={:?}",
+            e
+        );
+        Error::Permanent(e)
+    }
+
+    fn load_from_node_retry_on_transient_err(
+        &self,
+        endpoint: &str,
+        node_url: &str,
+    ) -> Result<String> {
         retry(ExponentialBackoff::default(), || {
             self.load_from_node(endpoint, node_url)
-                .map_err(transient_err)
+                .map_err(Self::transient_err)
+        })
+        .map_err(|e| match e {
+            Error::Permanent(e) if e.is::<ContextPrunedError>() => e,
+            e => anyhow!(e),
+        })
+    }
+
+    // Like load_from_node_retry_on_transient_err, except that a 404 response
+    // (ie the requested resource does not exist) results in None.
+    fn load_from_node_optional(
+        &self,
+        endpoint: &str,
+        node_url: &str,
+    ) -> Result<Option<String>> {
+        retry(ExponentialBackoff::default(), || {
+            match self.load_from_node(endpoint, node_url) {
+                Ok(body) => Ok(Some(body)),
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<HttpError>(),
+                        Some(HttpError { status_code: 404 })
+                    ) =>
+                {
+                    Ok(None)
+                }
+                Err(e) => Err(Self::transient_err(e)),
+            }
         })
         .map_err(|e| anyhow!(e))
     }
 
     fn load_from_node(&self, endpoint: &str, node_url: &str) -> Result<String> {
         let uri = format!("{}/chains/{}/{}", node_url, self.chain, endpoint);
+        let _permit = self.requests_limiter.acquire()?;
         debug!("loading: {}", uri);
 
         let mut resp_data = Vec::new();
-        let mut handle = Easy::new();
-
-        handle
-            .timeout(self.timeout)
-            .with_context(|| {
-                format!(
-                    "failed to set timeout to curl handle for uri='{}'",
-                    uri
-                )
-            })?;
-        handle.url(&uri).with_context(|| {
-            format!("failed to call endpoint, uri='{}'", uri)
-        })?;
+        let mut handle = self.new_handle(&uri)?;
         {
             let mut transfer = handle.transfer();
             transfer.write_function(|new_data| {
@@ -340,6 +743,11 @@ impl NodeClient {
 
         let status_code = handle.response_code()?;
         if status_code != 200 {
+            if ContextPrunedError::is_pruned_response(&String::from_utf8_lossy(
+                &resp_data,
+            )) {
+                return Err(ContextPrunedError.into());
+            }
             return Err(HttpError { status_code }.into());
         }
 
@@ -350,6 +758,47 @@ impl NodeClient {
         Ok(body.to_string())
     }
 
+    fn new_handle(&self, uri: &str) -> Result<Easy> {
+        let mut handle = Easy::new();
+
+        handle
+            .timeout(self.timeout)
+            .with_context(|| {
+                format!(
+                    "failed to set timeout to curl handle for uri='{}'",
+                    uri
+                )
+            })?;
+        handle.url(uri).with_context(|| {
+            format!("failed to call endpoint, uri='{}'", uri)
+        })?;
+        if let Some(path) = &self.tls.client_cert {
+            handle.ssl_cert(path).with_context(|| {
+                format!("failed to set client certificate for uri='{}'", uri)
+            })?;
+        }
+        if let Some(path) = &self.tls.client_key {
+            handle.ssl_key(path).with_context(|| {
+                format!("failed to set client key for uri='{}'", uri)
+            })?;
+        }
+        if let Some(path) = &self.tls.ca_bundle {
+            handle.cainfo(path).with_context(|| {
+                format!("failed to set ca bundle for uri='{}'", uri)
+            })?;
+        }
+        if let Some(token) = &self.tls.auth_token {
+            let mut headers = List::new();
+            headers.append(&format!("Authorization: Bearer {}", token))?;
+            handle
+                .http_headers(headers)
+                .with_context(|| {
+                    format!("failed to set auth header for uri='{}'", uri)
+                })?;
+        }
+        Ok(handle)
+    }
+
     fn deserialize(body: &str) -> Result<serde_json::Value> {
         let mut deserializer = serde_json::Deserializer::from_str(body);
         deserializer.disable_recursion_limit();
@@ -422,3 +871,340 @@ impl StorageGetter for NodeClient {
         Ok(serde_json::Value::from_str(&body).ok())
     }
 }
+
+#[test]
+fn test_requests_limiter() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    let limiter = Arc::new(RequestsLimiter::new(2));
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let max_seen = Arc::new(AtomicUsize::new(0));
+
+    let threads: Vec<thread::JoinHandle<()>> = (0..8)
+        .map(|_| {
+            let limiter = limiter.clone();
+            let in_flight = in_flight.clone();
+            let max_seen = max_seen.clone();
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    let _permit = limiter.acquire().unwrap();
+                    let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_seen.fetch_max(n, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(1));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(2, max_seen.load(Ordering::SeqCst));
+    assert_eq!(0, *limiter.in_flight.lock().unwrap());
+}
+
+#[test]
+fn test_parse_balance() {
+    assert_eq!(
+        PgNumeric::new(Some(BigDecimal::from_str("1234567").unwrap())),
+        NodeClient::parse_balance("\"1234567\"\n").unwrap()
+    );
+    assert!(NodeClient::parse_balance("1234567").is_err());
+    assert!(NodeClient::parse_balance("\"12tez\"").is_err());
+}
+
+#[cfg(test)]
+pub(crate) fn mock_node(status: &'static str, body: &'static str) -> String {
+    mock_node_responses(vec![(status, body)])
+}
+
+// Like mock_node, but responds to the requests with the responses in turn
+// (and keeps responding with the last one).
+#[cfg(test)]
+pub(crate) fn mock_node_responses(
+    responses: Vec<(&'static str, &'static str)>,
+) -> String {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let (status, body) = responses[i.min(responses.len() - 1)];
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+    url
+}
+
+#[test]
+fn test_contract_exists() {
+    let node_cli =
+        |url: String| NodeClient::new(vec![url], "main".to_string(), 0, 1);
+
+    let cli = node_cli(mock_node("200 OK", "\"1000\"\n"));
+    assert!(cli
+        .contract_exists("KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq")
+        .unwrap());
+
+    let cli = node_cli(mock_node("404 Not Found", ""));
+    assert!(!cli
+        .contract_exists("KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq")
+        .unwrap());
+
+    let cli = node_cli(mock_node("500 Internal Server Error", ""));
+    assert!(cli
+        .contract_exists("KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq")
+        .is_err());
+}
+
+#[test]
+fn test_context_pruned() {
+    let cli = NodeClient::new(
+        vec![mock_node(
+            "500 Internal Server Error",
+            "[{\"kind\":\"permanent\",\"id\":\"store.cannot_checkout_context\",\"block_hash\":\"BLockGenesisGenesisGenesisGenesisGenesisf79b5d1CoW2\",\"context_hash\":\"CoV8SQumiVU9saiu3FVNeDNewJaJH8yWdsGF3WLdsRr2P9S7MzCj\"}]\n",
+        )],
+        "main".to_string(),
+        // the context does not come back by retrying
+        -1,
+        1,
+    );
+    let err = cli
+        .get_contract_storage("KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq", 1000)
+        .unwrap_err();
+    assert!(ContextPrunedError::caused(&err), "{:?}", err);
+
+    let cli = NodeClient::new(
+        vec![mock_node("500 Internal Server Error", "[]\n")],
+        "main".to_string(),
+        0,
+        1,
+    );
+    let err = cli
+        .get_contract_storage("KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq", 1000)
+        .unwrap_err();
+    assert!(!ContextPrunedError::caused(&err), "{:?}", err);
+}
+
+#[test]
+fn test_chain_id() {
+    let node_cli =
+        |url: String| NodeClient::new(vec![url], "main".to_string(), 0, 1);
+
+    let cli = node_cli(mock_node("200 OK", "\"NetXdQprcVkpaWU\"\n"));
+    assert_eq!("NetXdQprcVkpaWU", cli.chain_id().unwrap());
+
+    let cli = node_cli(mock_node("200 OK", "{}\n"));
+    assert!(cli.chain_id().is_err());
+}
+
+#[test]
+fn test_global_constants() {
+    use crate::storage_structure::typing;
+
+    let cli = NodeClient::new(
+        vec![mock_node(
+            "200 OK",
+            "{\"prim\":\"pair\",\"args\":[{\"prim\":\"nat\",\"annots\":[\"%token_id\"]},{\"prim\":\"address\",\"annots\":[\"%owner\"]}]}\n",
+        )],
+        "main".to_string(),
+        0,
+        1,
+    );
+    let hash = "exprteHSeKpfY8PC1b3nqcTrrkmVGVgUTSj8yJxxstgewVxzZCJJmv";
+    let storage_def = serde_json::json!({
+        "prim": "big_map",
+        "args": [
+            { "prim": "constant", "args": [{ "string": hash }] },
+            { "prim": "nat" },
+        ],
+        "annots": ["%ledger"],
+    });
+    let expanded = typing::expand_global_constants(&storage_def, &mut |hash| {
+        cli.get_global_constant(hash)
+    })
+    .unwrap();
+    assert_eq!(
+        typing::type_ast_from_json(&serde_json::json!({
+            "prim": "big_map",
+            "args": [
+                {
+                    "prim": "pair",
+                    "args": [
+                        { "prim": "nat", "annots": ["%token_id"] },
+                        { "prim": "address", "annots": ["%owner"] },
+                    ],
+                },
+                { "prim": "nat" },
+            ],
+            "annots": ["%ledger"],
+        }))
+        .unwrap(),
+        typing::type_ast_from_json(&expanded).unwrap()
+    );
+    assert_eq!(
+        1,
+        cli.global_constants
+            .lock()
+            .unwrap()
+            .len()
+    );
+    // served from the cache from now on
+    let cached = cli.global_constants.lock().unwrap()[hash].clone();
+    assert_eq!(cached, cli.get_global_constant(hash).unwrap());
+}
+
+#[test]
+fn test_block_archive() {
+    let dir = std::env::temp_dir()
+        .join(format!("quepasa-test-block-archive-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for level in &[5, 7] {
+        fs::copy(
+            format!(
+                "test/KT1FEwAhVSNH5gpUGXZCti2K8aU5kg9uqt2v.level-{}.json",
+                level
+            ),
+            dir.join(format!("{}.json", level)),
+        )
+        .unwrap();
+    }
+    fs::write(dir.join("notes.txt"), "not a block").unwrap();
+
+    let mut node_cli = NodeClient::new(vec![], "main".to_string(), 0, 1);
+    node_cli.set_block_source(BlockSource::Archive(
+        dir.to_str().unwrap().to_string(),
+    ));
+
+    assert_eq!(7, node_cli.head().unwrap().level);
+    let (meta, block) = node_cli.level_json(5).unwrap();
+    assert_eq!(5, meta.level);
+    assert_eq!(5, block.header.level);
+    // a level without a file is not available (yet)
+    assert!(node_cli.level_json(6).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_level_json_cached() {
+    let dir = std::env::temp_dir().join(format!(
+        "quepasa-test-level-json-cached-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    let body = fs::read_to_string(
+        "test/KT1FEwAhVSNH5gpUGXZCti2K8aU5kg9uqt2v.level-5.json",
+    )
+    .unwrap();
+    let hash = "BLETkzShYywKedz5eH4kGNBE7SzofsZZu58pfDwWBsVJREdADZ5";
+    let block_cache = BlockCache::new(&dir, 2).unwrap();
+    block_cache.put(5, hash, &body).unwrap();
+
+    // the node is only asked for the hash of the block at the level
+    let mut node_cli = NodeClient::new(
+        vec![mock_node(
+            "200 OK",
+            "\"BLETkzShYywKedz5eH4kGNBE7SzofsZZu58pfDwWBsVJREdADZ5\"\n",
+        )],
+        "main".to_string(),
+        0,
+        1,
+    );
+    node_cli.set_block_cache(block_cache);
+    let (meta, block, size) = node_cli.level_json_sized(5).unwrap();
+    assert_eq!(5, meta.level);
+    assert_eq!(hash, block.hash);
+    assert_eq!(body.len(), size);
+    let (meta, _) = node_cli.level_json(5).unwrap();
+    assert_eq!(5, meta.level);
+    assert_eq!(Some(100.0), node_cli.block_cache_hit_rate().unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_node_tls() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    let dir = std::env::temp_dir()
+        .join(format!("quepasa-test-node-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // curl only reads the files once it connects over https, so they need
+    // not be valid pem here
+    for file in &["client.pem", "client.key", "ca.pem"] {
+        fs::write(dir.join(file), "").unwrap();
+    }
+    let tls = NodeTlsSettings {
+        client_cert: Some(dir.join("client.pem")),
+        client_key: Some(dir.join("client.key")),
+        ca_bundle: Some(dir.join("ca.pem")),
+        auth_token: Some("s3cret".to_string()),
+    };
+
+    let mut cli = NodeClient::new(vec![], "main".to_string(), 0, 1);
+    assert!(cli
+        .set_tls(NodeTlsSettings {
+            client_key: None,
+            ..tls.clone()
+        })
+        .is_err());
+    assert!(cli
+        .set_tls(NodeTlsSettings {
+            ca_bundle: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..tls.clone()
+        })
+        .is_err());
+    assert!(!format!("{:?}", tls).contains("s3cret"));
+
+    // a node that responds with the request's Authorization header
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let mut stream = listener
+            .incoming()
+            .next()
+            .unwrap()
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut auth = String::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+            if let Some(value) = line.strip_prefix("Authorization: ") {
+                auth = value.trim().to_string();
+            }
+            line.clear();
+        }
+        let body = format!("\"{}\"", auth);
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+    });
+    let mut cli = NodeClient::new(vec![url], "main".to_string(), 0, 1);
+    cli.set_tls(tls).unwrap();
+    assert_eq!("Bearer s3cret", cli.chain_id().unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
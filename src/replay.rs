@@ -0,0 +1,332 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::octez::block::{Block, TxContext};
+use crate::octez::node::{NodeClient, StorageGetter};
+use crate::sql::db::{BigmapEntry, BigmapKeysGetter};
+use crate::sql::insert::Insert;
+use crate::sql::types::BigmapMetaAction;
+use crate::storage_structure::relational::Contract;
+use crate::storage_update::bigmap::IntraBlockBigmapDiffsProcessor;
+use crate::storage_update::processor::StorageProcessor;
+
+// Processes a block (as the node returns it) for the contracts, without a db,
+// and returns what would be indexed for each contract active in it: its
+// inserts, tx contexts and bigmap actions. Meant for debugging the processing
+// of a single block (see --replay-block).
+//
+// There is no db to get the keys bigmaps had before the block from, so
+// bigmap copies and clears only see the keys that were set in the block
+// itself.
+pub(crate) fn replay_block(
+    node_cli: &NodeClient,
+    config: &Config,
+    contracts: &[Contract],
+    block_json: &str,
+) -> Result<serde_json::Value> {
+    let mut deserializer = serde_json::Deserializer::from_str(block_json);
+    deserializer.disable_recursion_limit();
+    let raw_block = serde_json::Value::deserialize(&mut deserializer)
+        .context("failed to parse block json")?;
+    let block: Block = Block::deserialize(&raw_block)
+        .context("failed to deserialize block json")?;
+    let diffs = IntraBlockBigmapDiffsProcessor::from_block(&block)?;
+
+    let storage_getter = BlockStorageGetter {
+        originated: originated_storage(&raw_block),
+        node_cli: node_cli.clone(),
+    };
+
+    let mut res: Vec<serde_json::Value> = vec![];
+    for contract in contracts {
+        let is_origination =
+            block.has_contract_origination(&contract.cid.address);
+        let is_active = if config.record_all_calls || config.index_failures {
+            block.is_contract_called(&contract.cid.address)
+        } else {
+            block.is_contract_active(&contract.cid.address)
+        };
+        if !is_origination && !is_active {
+            continue;
+        }
+
+        let mut storage_processor =
+            StorageProcessor::new(1, storage_getter.clone(), NoBigmapKeys {});
+        storage_processor.set_record_all_calls(config.record_all_calls);
+        storage_processor.set_index_failures(config.index_failures);
+        storage_processor.set_empty_string_as_null(config.empty_string_as_null);
+        storage_processor.set_deterministic_ids(config.deterministic_ids);
+        storage_processor
+            .process_block(&block, &diffs, contract)
+            .with_context(|| {
+                format!(
+                    "replay failed (level={}, contract={}): could not process block",
+                    block.header.level, contract.cid.name,
+                )
+            })?;
+
+        let mut inserts: Vec<Insert> = storage_processor
+            .drain_inserts()
+            .into_values()
+            .collect();
+        inserts
+            .sort_by(|a, b| (a.id, &a.table_name).cmp(&(b.id, &b.table_name)));
+        let (mut tx_contexts, _) = storage_processor.drain_txs();
+        tx_contexts.sort_by_key(|tx_context| tx_context.id);
+        let bigmap_actions = storage_processor.drain_bigmap_meta_actions();
+
+        res.push(serde_json::to_value(&Replayed {
+            contract: &contract.cid.name,
+            address: &contract.cid.address,
+            level: block.header.level,
+            is_origination,
+            inserts: &inserts,
+            tx_contexts: &tx_contexts,
+            bigmap_actions: &bigmap_actions,
+        })?);
+    }
+    Ok(serde_json::Value::Array(res))
+}
+
+// The bigmap diffs of a block as processing sees them (see
+// IntraBlockBigmapDiffsProcessor::to_json), for debugging bigmap copies (see
+// --replay-bigmap-diffs). Needs neither a db nor a node.
+pub(crate) fn replay_bigmap_diffs(
+    block_json: &str,
+) -> Result<serde_json::Value> {
+    let mut deserializer = serde_json::Deserializer::from_str(block_json);
+    deserializer.disable_recursion_limit();
+    let block: Block = Block::deserialize(&mut deserializer)
+        .context("failed to deserialize block json")?;
+    Ok(IntraBlockBigmapDiffsProcessor::from_block(&block)?.to_json())
+}
+
+#[derive(Serialize)]
+struct Replayed<'a> {
+    contract: &'a str,
+    address: &'a str,
+    level: u32,
+    is_origination: bool,
+    inserts: &'a [Insert],
+    tx_contexts: &'a [TxContext],
+    bigmap_actions: &'a [BigmapMetaAction],
+}
+
+// Takes the storage of contracts originated in the block from the block
+// itself (so no node is needed to replay originations), other storage is
+// taken from the node.
+//
+// Note that bigmaps in the storage of an origination are literal lists of
+// elements rather than bigmap ids, so for these their keys are inserted as
+// part of the origination's storage.
+#[derive(Clone)]
+struct BlockStorageGetter {
+    originated: HashMap<String, serde_json::Value>,
+    node_cli: NodeClient,
+}
+
+impl StorageGetter for BlockStorageGetter {
+    fn get_contract_storage(
+        &self,
+        contract_id: &str,
+        level: u32,
+    ) -> Result<serde_json::Value> {
+        match self.originated.get(contract_id) {
+            Some(storage) => Ok(storage.clone()),
+            None => self
+                .node_cli
+                .get_contract_storage(contract_id, level),
+        }
+    }
+
+    fn get_bigmap_value(
+        &self,
+        level: u32,
+        bigmap_id: i32,
+        keyhash: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        self.node_cli
+            .get_bigmap_value(level, bigmap_id, keyhash)
+    }
+}
+
+struct NoBigmapKeys {}
+
+impl BigmapKeysGetter for NoBigmapKeys {
+    fn get(&mut self, level: u32, bigmap_id: i32) -> Result<Vec<BigmapEntry>> {
+        warn!(
+            "replay: the keys of bigmap {} at level {} are unknown, copies and clears of it are incomplete",
+            bigmap_id, level
+        );
+        Ok(vec![])
+    }
+
+    fn get_table(&mut self, _bigmap_id: i32) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+// The storage of the contracts originated in the block (directly, or by
+// internal operations), by address.
+fn originated_storage(
+    block: &serde_json::Value,
+) -> HashMap<String, serde_json::Value> {
+    let mut res: HashMap<String, serde_json::Value> = HashMap::new();
+    let mut add = |content: &serde_json::Value, result: &serde_json::Value| {
+        let storage = &content["script"]["storage"];
+        if storage.is_null() {
+            return;
+        }
+        for address in result["originated_contracts"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|address| address.as_str())
+        {
+            res.insert(address.to_string(), storage.clone());
+        }
+    };
+
+    let contents = block["operations"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|group| group.as_array())
+        .flatten()
+        .filter_map(|operation| operation["contents"].as_array())
+        .flatten();
+    for content in contents {
+        add(content, &content["metadata"]["operation_result"]);
+        for internal in content["metadata"]["internal_operation_results"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            add(internal, &internal["result"]);
+        }
+    }
+    res
+}
+
+#[test]
+fn test_originated_storage() {
+    use std::str::FromStr;
+
+    let block = serde_json::Value::from_str(&crate::debug::load_test(
+        "test/KT1GT5sQWfK4f8x1DqqEfKvKoZg4sZciio7k.level-50503.json",
+    ))
+    .unwrap();
+    let originated = originated_storage(&block);
+    let mut addresses: Vec<&String> = originated.keys().collect();
+    addresses.sort();
+    assert_eq!(
+        vec![
+            "KT18ymrGAUCF6WUsjhKPpM28xGNKNoZHM3Sy",
+            "KT1P1L9Wy1Zyo7xGVvedqYWa4FEsV6crtVkY",
+            "KT1Xrm4db2jadUqHq163MpFBqG8johBcQeL1",
+        ],
+        addresses
+    );
+    assert!(originated
+        .values()
+        .all(|storage| !storage.is_null()));
+
+    assert!(originated_storage(&serde_json::json!({})).is_empty());
+}
+
+#[test]
+fn test_replay_block() {
+    use crate::storage_structure::relational::{ASTBuilder, ColumnTypes};
+    use crate::storage_structure::typing;
+    use std::str::FromStr;
+
+    let address = "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V";
+    let script_json = serde_json::Value::from_str(&crate::debug::load_test(
+        &format!("test/{}.script", address),
+    ))
+    .unwrap();
+    let storage_definition = script_json["code"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["prim"] == "storage")
+        .unwrap()["args"][0]
+        .clone();
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: "replayed".to_string(),
+            address: address.to_string(),
+        },
+        storage_ast: ASTBuilder::new("storage")
+            .build_relational_ast(&type_ast)
+            .unwrap(),
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+
+    // the block has no originations, so the node is never asked for storage
+    let node_cli = NodeClient::new(
+        vec!["http://localhost:1".to_string()],
+        "replay".to_string(),
+        0,
+        1,
+    );
+    let block_json =
+        crate::debug::load_test(&format!("test/{}.level-11.json", address));
+    let res =
+        replay_block(&node_cli, &Config::default(), &[contract], &block_json)
+            .unwrap();
+
+    let replayed = res.as_array().unwrap();
+    assert_eq!(1, replayed.len());
+    assert_eq!("replayed", replayed[0]["contract"]);
+    assert_eq!(11, replayed[0]["level"]);
+    assert_eq!(false, replayed[0]["is_origination"]);
+    assert_eq!(
+        1,
+        replayed[0]["tx_contexts"]
+            .as_array()
+            .unwrap()
+            .len()
+    );
+    let inserts = replayed[0]["inserts"]
+        .as_array()
+        .unwrap();
+    assert!(!inserts.is_empty());
+    assert!(inserts
+        .iter()
+        .all(|insert| insert["table_name"].is_string()));
+}
+
+#[test]
+fn test_replay_bigmap_diffs() {
+    let res = replay_bigmap_diffs(&crate::debug::load_test(
+        "test/KT1GT5sQWfK4f8x1DqqEfKvKoZg4sZciio7k.level-50503.json",
+    ))
+    .unwrap();
+    let tx_contexts = res.as_array().unwrap();
+    assert!(!tx_contexts.is_empty());
+    // the originations allocate bigmaps, which their tx contexts own
+    assert!(tx_contexts.iter().any(|tx_context| {
+        tx_context["ops"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|op| op["op"] == "alloc")
+            && !tx_context["owned_bigmaps"]
+                .as_array()
+                .unwrap()
+                .is_empty()
+    }));
+
+    assert!(replay_bigmap_diffs("{}").is_err());
+}
@@ -0,0 +1,46 @@
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Set from the signal handler, so nothing else than an atomic store may
+// happen there. Everything that loops over levels checks this flag and stops
+// picking up new ones once it is set, leaving the levels already processed to
+// be inserted (and committed) before exiting.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+    // a second signal kills the process right away
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+    }
+}
+
+/// Install the handler for SIGINT and SIGTERM that requests a graceful
+/// shutdown.
+pub fn install_handler() -> Result<()> {
+    for sig in &[libc::SIGINT, libc::SIGTERM] {
+        let handler = on_signal as extern "C" fn(libc::c_int);
+        let prev = unsafe { libc::signal(*sig, handler as libc::sighandler_t) };
+        if prev == libc::SIG_ERR {
+            return Err(anyhow!(
+                "failed to install handler for signal {}",
+                sig
+            ));
+        }
+    }
+
+    // the signal handler itself cannot log
+    thread::spawn(|| {
+        while !requested() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        warn!("shutdown requested, finishing the levels in progress (signal again to exit immediately)..");
+    });
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+const COMPRESSION_LEVEL: u32 = 6;
+
+/// Compresses data into the gzip format (so that it can be read back with
+/// any gzip tool).
+pub(crate) fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder =
+        GzEncoder::new(Vec::new(), Compression::new(COMPRESSION_LEVEL));
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompresses gzip data (a single member), checking its crc and size.
+pub(crate) fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut res = vec![];
+    GzDecoder::new(data)
+        .read_to_end(&mut res)
+        .context("failed to decompress gzip data")?;
+    Ok(res)
+}
+
+/// Reads back a raw_storage row's storage: either as it is (the storage
+/// column), or decompressed (the storage_compressed column).
+pub(crate) fn raw_storage_value(
+    storage: Option<serde_json::Value>,
+    storage_compressed: Option<Vec<u8>>,
+) -> Result<Option<serde_json::Value>> {
+    match (storage, storage_compressed) {
+        (Some(storage), _) => Ok(Some(storage)),
+        (None, Some(compressed)) => {
+            let json = gunzip(&compressed)
+                .context("failed to decompress raw storage")?;
+            Ok(Some(serde_json::from_slice(&json)?))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+#[test]
+fn test_gzip_roundtrip() {
+    let storage = serde_json::json!({
+        "prim": "Pair",
+        "args": [
+            { "int": "42" },
+            [{ "prim": "Elt", "args": [{ "string": "a" }, { "bytes": "00" }] }],
+        ],
+    });
+    let json = serde_json::to_vec(&storage).unwrap();
+    let compressed = gzip(&json).unwrap();
+    assert_eq!([0x1f, 0x8b], compressed[..2]);
+    assert_eq!(json, gunzip(&compressed).unwrap());
+    assert_eq!(
+        Some(storage.clone()),
+        raw_storage_value(None, Some(compressed.clone())).unwrap()
+    );
+    assert_eq!(
+        Some(storage.clone()),
+        raw_storage_value(Some(storage), None).unwrap()
+    );
+    assert_eq!(None, raw_storage_value(None, None).unwrap());
+
+    assert_eq!(Vec::<u8>::new(), gunzip(&gzip(&[]).unwrap()).unwrap());
+    // "hello\n" as compressed by another gzip implementation, with a file name
+    let gzipped =
+        hex::decode("1f8b08080000000002ff6100cb48cdc9c9e7020020303a3606000000")
+            .unwrap();
+    assert_eq!(b"hello\n".to_vec(), gunzip(&gzipped).unwrap());
+
+    let mut corrupted = compressed;
+    let n = corrupted.len();
+    corrupted[n - 5] ^= 0xff;
+    assert!(gunzip(&corrupted).is_err());
+    assert!(gunzip(b"not gzip data").is_err());
+}
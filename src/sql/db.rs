@@ -1,23 +1,34 @@
 use anyhow::{anyhow, Result};
 use askama::Template;
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use postgres::fallible_iterator::FallibleIterator;
 use postgres::types::{BorrowToSql, FromSql, ToSql};
-use postgres::Transaction;
+use postgres::{GenericClient, Transaction};
 
 use chrono::{DateTime, Utc};
+use pg_bigdecimal::PgNumeric;
 
-use crate::config::ContractID;
-use crate::octez::block::{LevelMeta, Tx, TxContext};
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use crate::config::{ContractID, StorageCompression};
+use crate::level_feed::LevelFeed;
+use crate::metadata;
+use crate::octez::block::{Delegation, LevelMeta, Tx, TxContext};
 use crate::octez::node::NodeClient;
+use crate::sql::compression;
 use crate::sql::insert::{Column, Insert, Value};
 use crate::sql::postgresql_generator::PostgresqlGenerator;
 use crate::sql::table::Table;
 use crate::sql::table_builder::TableBuilder;
-use crate::sql::types::BigmapMetaAction;
+use crate::sql::types::{
+    BigmapMetaAction, BigmapOwner, CallRecord, OperationFailure, RawStorage,
+};
 use crate::storage_structure::relational;
 
 use r2d2_postgres::{postgres::NoTls, PostgresConnectionManager};
@@ -37,6 +48,9 @@ struct RepopulateSnapshotDerivedTmpl<'a> {
     table: &'a str,
     parent_table: &'a str,
     columns: &'a [String],
+    ordered_changes: bool,
+    element_columns: &'a [String],
+    element_keys: &'a [String],
 }
 #[derive(Template)]
 #[template(path = "repopulate-changes-derived.sql", escape = "none")]
@@ -55,6 +69,9 @@ struct UpdateSnapshotDerivedTmpl<'a> {
     table: &'a str,
     parent_table: &'a str,
     columns: &'a [String],
+    ordered_changes: bool,
+    element_columns: &'a [String],
+    element_keys: &'a [String],
     tx_context_ids: &'a [i64],
 }
 #[derive(Template)]
@@ -71,10 +88,95 @@ struct UpdateChangesDerivedTmpl<'a> {
 type DBPool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
 type DBPooledConn = r2d2::PooledConnection<PostgresConnectionManager<NoTls>>;
 
+// Keeps the last error the pool failed to connect with, if retrying won't
+// resolve it (see is_fatal_conn_err). The pool itself only reports that it
+// timed out waiting for a connection.
+#[derive(Clone, Debug, Default)]
+struct ConnErrorHandler {
+    fatal: Arc<Mutex<Option<String>>>,
+}
+
+impl ConnErrorHandler {
+    fn last_fatal(&self) -> Option<String> {
+        self.fatal
+            .lock()
+            .ok()
+            .and_then(|fatal| fatal.clone())
+    }
+
+    fn clear(&self) {
+        if let Ok(mut fatal) = self.fatal.lock() {
+            *fatal = None;
+        }
+    }
+}
+
+impl r2d2::HandleError<postgres::Error> for ConnErrorHandler {
+    fn handle_error(&self, err: postgres::Error) {
+        if let Ok(mut fatal) = self.fatal.lock() {
+            *fatal = if is_fatal_conn_err(&err) {
+                Some(err.to_string())
+            } else {
+                None
+            };
+        }
+        r2d2::LoggingErrorHandler.handle_error(err);
+    }
+}
+
 #[derive(Clone)]
 pub struct DBClient {
     dbpool: DBPool,
+    conn_errors: ConnErrorHandler,
     main_schema: String,
+
+    // the number of times to retry getting a connection, smaller than 0 for
+    // infinite
+    conn_retries: i32,
+
+    // whether tx contexts share their operation coordinates (in the
+    // operations table) across contracts
+    shared_tx_contexts: bool,
+
+    // whether the contracts' data is stored in their own schema (false when
+    // it is produced to Kafka instead, see KafkaSink)
+    contract_tables: bool,
+
+    // whether contracts set up by this client get derived tables (_live,
+    // _ordered) and _at functions. whether a contract has them is recorded
+    // in the contracts table, derived tables are only maintained for the
+    // contracts that have them
+    derived_tables: bool,
+
+    // whether inserting levels leaves the levels' existing rows (and the
+    // rows of all contracts at these levels) in place, rather than replacing
+    // them (see Executor::reprocess_contract)
+    keep_levels: bool,
+
+    // whether a contract that was originated more than once at the same
+    // address (eg across a reorg) starts at its most recent origination,
+    // rather than at its first one (see get_origination)
+    latest_origination: bool,
+
+    // whether the connections only read (see --read-only), their transactions
+    // are then read-only as well
+    read_only: bool,
+
+    // how the raw storage is stored (see --raw-storage-compression)
+    raw_storage_compression: StorageCompression,
+
+    // the feed committed levels are published to (see --healthz-levels-feed),
+    // when set
+    level_feed: Option<LevelFeed>,
+
+    // the role of the thread(s) using this client (eg "inserter"), set as
+    // part of the connections' application_name so they can be told apart
+    // in pg_stat_activity
+    role: String,
+
+    // the contracts of which the schema is up to date (see
+    // create_contract_schemas), which only has to be checked once per run
+    checked_schemas: Arc<Mutex<HashSet<String>>>,
 }
 
 impl DBClient {
@@ -85,28 +187,79 @@ impl DBClient {
         main_schema: &str,
         conn_timeout: Duration,
         max_conn: u32,
+        conn_retries: i32,
     ) -> Result<Self> {
         let manager = PostgresConnectionManager::new(url.parse()?, NoTls);
+        let conn_errors = ConnErrorHandler::default();
         let dbpool = r2d2::Builder::new()
             .max_size(max_conn)
             .connection_timeout(conn_timeout)
+            .error_handler(Box::new(conn_errors.clone()))
             .build(manager)?;
 
         Ok(DBClient {
             dbpool,
+            conn_errors,
             main_schema: main_schema.to_string(),
+            conn_retries,
+            shared_tx_contexts: false,
+            contract_tables: true,
+            derived_tables: true,
+            keep_levels: false,
+            latest_origination: false,
+            read_only: false,
+            raw_storage_compression: StorageCompression::None,
+            level_feed: None,
+            role: "main".to_string(),
+            checked_schemas: Arc::new(Mutex::new(HashSet::new())),
         })
     }
 
+    // A client sharing this client's connection pool, whose connections are
+    // tagged with the given role.
+    pub(crate) fn with_role(&self, role: &str) -> Self {
+        let mut res = self.clone();
+        res.role = role.to_string();
+        res
+    }
+
+    // Connections are retried on losing the connection to the db (eg while
+    // it restarts), not when the db refuses them (eg a wrong password).
     pub(crate) fn dbconn(&self) -> Result<DBPooledConn> {
-        let mut conn = self
-            .dbpool
-            .get()
-            .map_err(|err| anyhow!("err: {}", err))?;
-        conn.simple_query(
-            format!(r#"SET SCHEMA '{}'"#, self.main_schema).as_str(),
-        )?;
-        Ok(conn)
+        retry_with_backoff(
+            "get a db connection",
+            self.conn_retries,
+            is_conn_err,
+            || {
+                let mut conn = self.dbpool.get().map_err(|err| {
+                    match self.conn_errors.last_fatal() {
+                        Some(fatal) => {
+                            anyhow!("{} (last error: {})", err, fatal)
+                        }
+                        None => anyhow::Error::from(err),
+                    }
+                })?;
+                self.conn_errors.clear();
+                conn.simple_query(
+                    format!(
+                        r#"SET SCHEMA '{}'; SET application_name = '{}'"#,
+                        self.main_schema,
+                        application_name(&self.role),
+                    )
+                    .as_str(),
+                )?;
+                if self.read_only {
+                    conn.simple_query(
+                        "SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY",
+                    )?;
+                }
+                Ok(conn)
+            },
+        )
+    }
+
+    pub(crate) fn get_conn_retries(&self) -> i32 {
+        self.conn_retries
     }
 
     pub(crate) fn get_quepasa_version(&mut self) -> Result<String> {
@@ -125,7 +278,10 @@ FROM indexer_state
         Ok(version)
     }
 
-    pub(crate) fn create_common_tables(&mut self) -> Result<()> {
+    pub(crate) fn create_common_tables(
+        &mut self,
+        shared_tx_contexts: bool,
+    ) -> Result<()> {
         let mut conn = self.dbconn()?;
 
         conn.simple_query(
@@ -133,9 +289,310 @@ FROM indexer_state
                 .as_str(),
         )?;
         conn.simple_query(
-            PostgresqlGenerator::create_common_tables(&self.main_schema)
-                .as_str(),
+            PostgresqlGenerator::create_common_tables(
+                &self.main_schema,
+                shared_tx_contexts,
+            )
+            .as_str(),
+        )?;
+        self.shared_tx_contexts = shared_tx_contexts;
+        Ok(())
+    }
+
+    /// Whether tx contexts are shared across contracts is decided when the
+    /// common tables are created, here it is read back from an existing db.
+    pub(crate) fn load_shared_tx_contexts(&mut self) -> Result<bool> {
+        let mut conn = self.dbconn()?;
+        self.shared_tx_contexts =
+            Self::has_operations_table(&mut *conn, &self.main_schema)?;
+        Ok(self.shared_tx_contexts)
+    }
+
+    pub(crate) fn shares_tx_contexts(&self) -> bool {
+        self.shared_tx_contexts
+    }
+
+    pub(crate) fn skip_contract_tables(&mut self) {
+        self.contract_tables = false
+    }
+
+    pub(crate) fn has_contract_tables(&self) -> bool {
+        self.contract_tables
+    }
+
+    pub(crate) fn skip_derived_tables(&mut self) {
+        self.derived_tables = false;
+    }
+
+    // Whether the contract was set up with derived tables (see
+    // skip_derived_tables).
+    fn has_derived_tables<C: GenericClient>(
+        conn: &mut C,
+        contract: &ContractID,
+    ) -> Result<bool> {
+        match conn.query_opt(
+            "SELECT derived_tables FROM contracts WHERE name = $1",
+            &[&contract.name],
+        )? {
+            Some(row) => Ok(row.get(0)),
+            None => Ok(true),
+        }
+    }
+
+    pub(crate) fn set_keep_levels(&mut self, keep_levels: bool) {
+        self.keep_levels = keep_levels
+    }
+
+    pub(crate) fn keeps_levels(&self) -> bool {
+        self.keep_levels
+    }
+
+    pub(crate) fn use_latest_origination(&mut self) {
+        self.latest_origination = true;
+    }
+
+    pub(crate) fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    pub(crate) fn set_raw_storage_compression(
+        &mut self,
+        compression: StorageCompression,
+    ) {
+        self.raw_storage_compression = compression
+    }
+
+    pub(crate) fn raw_storage_compression(&self) -> StorageCompression {
+        self.raw_storage_compression
+    }
+
+    pub(crate) fn set_level_feed(&mut self, level_feed: LevelFeed) {
+        self.level_feed = Some(level_feed)
+    }
+
+    pub(crate) fn level_feed(&self) -> Option<&LevelFeed> {
+        self.level_feed.as_ref()
+    }
+
+    fn has_operations_table<C: GenericClient>(
+        conn: &mut C,
+        main_schema: &str,
+    ) -> Result<bool> {
+        Ok(conn
+            .query_opt(
+                "
+SELECT
+    1
+FROM information_schema.tables
+WHERE table_schema = $1
+  AND table_name = 'operations'
+",
+                &[&main_schema],
+            )?
+            .is_some())
+    }
+
+    pub(crate) fn add_missing_common_columns(&mut self) -> Result<()> {
+        // databases set up before the protocol was tracked per level get the
+        // column added, with NULL for all levels processed so far
+        let mut conn = self.dbconn()?;
+        conn.simple_query(
+            "ALTER TABLE levels ADD COLUMN IF NOT EXISTS protocol TEXT",
+        )?;
+        // same for the content hash of levels
+        conn.simple_query(
+            "ALTER TABLE levels ADD COLUMN IF NOT EXISTS content_hash TEXT",
+        )?;
+        // same for whether contracts have derived tables
+        conn.simple_query(
+            "ALTER TABLE contracts ADD COLUMN IF NOT EXISTS derived_tables BOOLEAN NOT NULL DEFAULT true",
         )?;
+        // same for the chain the db is indexing, which is then taken from
+        // the node (see check_chain_id)
+        conn.simple_query(
+            "ALTER TABLE indexer_state ADD COLUMN IF NOT EXISTS chain_id TEXT",
+        )?;
+        // same for the operation hash of bigmap meta actions
+        conn.simple_query(
+            "ALTER TABLE bigmap_meta_actions ADD COLUMN IF NOT EXISTS operation_hash VARCHAR(100)",
+        )?;
+        // same for the bigmap of contract dependencies, which also becomes
+        // part of what makes them unique
+        conn.simple_query(
+            "
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1
+        FROM information_schema.columns
+        WHERE table_schema = current_schema()
+          AND table_name = 'contract_deps'
+          AND column_name = 'src_bigmap'
+    ) THEN
+        ALTER TABLE contract_deps ADD COLUMN src_bigmap INT;
+        ALTER TABLE contract_deps DROP CONSTRAINT IF EXISTS contract_deps_pkey;
+        CREATE UNIQUE INDEX contract_deps_uniq ON contract_deps(level, src_contract, src_bigmap, dest_schema, is_deep_copy);
+    END IF;
+END
+$$;
+",
+        )?;
+        // same for the counter, branch and status of txs (txs recorded so
+        // far were all applied), the txs_ordered view is replaced to pick
+        // them up (tx.* was expanded when it was created)
+        conn.simple_query(
+            "
+ALTER TABLE txs ADD COLUMN IF NOT EXISTS counter BIGINT;
+ALTER TABLE txs ADD COLUMN IF NOT EXISTS branch VARCHAR(100);
+ALTER TABLE txs ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'applied';
+CREATE OR REPLACE VIEW txs_ordered AS (
+    SELECT
+        DENSE_RANK() OVER (
+            ORDER BY
+                ctx.level,
+                ctx.operation_group_number,
+                ctx.operation_number,
+                ctx.content_number,
+                coalesce(ctx.internal_number, -1)
+        ) ordering,
+        ctx.level,
+        meta.baked_at as level_timestamp,
+        tx.*
+    FROM txs tx
+    JOIN tx_contexts ctx
+      ON ctx.id = tx.tx_context_id
+    JOIN levels meta
+      ON meta.level = ctx.level
+    ORDER BY ordering
+);
+",
+        )?;
+        // and for the index of txs by operation hash
+        conn.simple_query(
+            "CREATE INDEX IF NOT EXISTS txs_operation_hash_idx ON txs(operation_hash)",
+        )?;
+        // and for the balances table, which is only filled when tracking
+        // balances
+        conn.simple_query(
+            "
+CREATE TABLE IF NOT EXISTS contract_balances (
+    contract TEXT NOT NULL REFERENCES contracts(name) ON DELETE CASCADE,
+    level INTEGER NOT NULL,
+    balance NUMERIC NOT NULL,
+    PRIMARY KEY(contract, level)
+);
+CREATE INDEX IF NOT EXISTS contract_balances_level_idx ON contract_balances(level);
+",
+        )?;
+        // and for the delegations, which are only recorded when indexing
+        // delegations
+        conn.simple_query(
+            "
+CREATE TABLE IF NOT EXISTS delegations (
+    contract TEXT NOT NULL REFERENCES contracts(name) ON DELETE CASCADE,
+    level INTEGER NOT NULL,
+    operation_hash VARCHAR(100) NOT NULL,
+    source VARCHAR(100) NOT NULL,
+    delegate VARCHAR(100)
+);
+CREATE INDEX IF NOT EXISTS delegations_level_idx ON delegations(level);
+CREATE INDEX IF NOT EXISTS delegations_contract_idx ON delegations(contract);
+",
+        )?;
+        // and for the better-call.dev scan progress
+        conn.simple_query(
+            "
+CREATE TABLE IF NOT EXISTS bcd_progress (
+    contract TEXT PRIMARY KEY REFERENCES contracts(name) ON DELETE CASCADE,
+    last_id TEXT NOT NULL
+);
+",
+        )?;
+        // and for the contracts that were skipped
+        conn.simple_query(
+            "
+CREATE TABLE IF NOT EXISTS failed_contracts (
+    name TEXT PRIMARY KEY,
+    address TEXT NOT NULL,
+    error TEXT NOT NULL,
+    failed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+",
+        )?;
+        // and for the failed operations, which are only recorded when
+        // indexing failures
+        let tx_contexts_table =
+            if Self::has_operations_table(&mut *conn, &self.main_schema)? {
+                "contract_tx_contexts"
+            } else {
+                "tx_contexts"
+            };
+        conn.simple_query(&format!(
+            "
+CREATE TABLE IF NOT EXISTS operation_failures (
+    id BIGSERIAL PRIMARY KEY,
+
+    tx_context_id BIGINT NOT NULL REFERENCES {}(id) ON DELETE CASCADE,
+    status TEXT NOT NULL,
+    error_kind TEXT,
+    error_id TEXT
+);
+CREATE INDEX IF NOT EXISTS operation_failures_tx_context_id_idx ON operation_failures(tx_context_id);
+",
+            tx_contexts_table
+        ))?;
+        // and for the raw storage, which is only kept when asked for
+        conn.simple_query(&format!(
+            "
+CREATE TABLE IF NOT EXISTS raw_storage (
+    tx_context_id BIGINT PRIMARY KEY REFERENCES {}(id) ON DELETE CASCADE,
+    contract TEXT NOT NULL,
+    storage JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS raw_storage_contract_idx ON raw_storage(contract);
+-- the storage is either kept as JSONB, or compressed (see
+-- --raw-storage-compression)
+ALTER TABLE raw_storage ADD COLUMN IF NOT EXISTS storage_compressed BYTEA;
+ALTER TABLE raw_storage ALTER COLUMN storage DROP NOT NULL;
+",
+            tx_contexts_table
+        ))?;
+        // and for the bigmap owners, which are backfilled from the alloc
+        // actions recorded so far (those recorded without their table leave
+        // the owner of their bigmap unknown)
+        conn.simple_query(&format!(
+            "
+CREATE TABLE IF NOT EXISTS bigmap_owners (
+    id BIGSERIAL PRIMARY KEY,
+
+    tx_context_id BIGINT NOT NULL REFERENCES {}(id) ON DELETE CASCADE,
+    bigmap_id INT NOT NULL,
+
+    contract_address TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    alloc_level INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS bigmap_owners_bigmap_id_alloc_level_idx ON bigmap_owners(bigmap_id, alloc_level);
+CREATE INDEX IF NOT EXISTS bigmap_owners_tx_context_id_idx ON bigmap_owners(tx_context_id);
+
+INSERT INTO bigmap_owners (
+    tx_context_id, bigmap_id, contract_address, table_name, alloc_level
+)
+SELECT
+    action.tx_context_id,
+    action.bigmap_id,
+    ctx.contract,
+    action.value->>'table',
+    ctx.level
+FROM bigmap_meta_actions action
+JOIN tx_contexts ctx
+  ON ctx.id = action.tx_context_id
+WHERE action.action = 'alloc'
+  AND action.value->>'table' IS NOT NULL
+  AND NOT EXISTS (SELECT 1 FROM bigmap_owners);
+",
+            tx_contexts_table
+        ))?;
         Ok(())
     }
 
@@ -158,21 +615,19 @@ WHERE table_schema = $1
         &mut self,
         contract: &relational::Contract,
     ) -> Result<()> {
-        let (mut tables, noview_prefixes, _): (
-            Vec<Table>,
-            Vec<String>,
-            Vec<String>,
-        ) = TableBuilder::tables_from_contract(contract);
-
-        tables.sort_by_key(|t| t.name.clone());
-
+        if !self.contract_tables {
+            return Ok(());
+        }
         let mut conn = self.dbconn()?;
+        if !Self::has_derived_tables(&mut *conn, &contract.cid)? {
+            return Ok(());
+        }
+        let (tables, noview_tables, _): (Vec<Table>, Vec<String>, Vec<String>) =
+            TableBuilder::tables_from_contract(contract)?;
+
         let mut tx = conn.transaction()?;
         for (i, table) in tables.iter().enumerate() {
-            if !noview_prefixes
-                .iter()
-                .any(|prefix| table.name.starts_with(prefix))
-            {
+            if !noview_tables.contains(&table.name) {
                 info!(
                     "repopulating {table} _live and _ordered ({contract} table {table_i}/~{table_total})",
                     contract = contract.cid.name,
@@ -187,6 +642,280 @@ WHERE table_schema = $1
         Ok(())
     }
 
+    // Repopulates the derived tables (_live and _ordered) of the contract's
+    // tables that are stale, leaving the others as they are. Returns the
+    // tables that were repopulated.
+    //
+    // A table's derived tables are stale when they do not cover its latest
+    // tx context: _ordered has fewer rows than the table, or its newest tx
+    // context is older than the table's, or _live does not hold exactly the
+    // rows of the table's newest tx context that are live. This is a cheap
+    // check, not a full comparison, meant to catch derived tables that were
+    // left behind (eg by an interrupted repopulate, or by manual edits). The
+    // _ordered of a table holding ordered changes leaves out the elements
+    // that did not change, so only its _live is checked.
+    pub(crate) fn repair_derived_tables(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<Vec<String>> {
+        if !self.contract_tables {
+            return Ok(vec![]);
+        }
+        let mut conn = self.dbconn()?;
+        if !Self::has_derived_tables(&mut *conn, &contract.cid)? {
+            return Ok(vec![]);
+        }
+        let (tables, noview_tables, _): (Vec<Table>, Vec<String>, Vec<String>) =
+            TableBuilder::tables_from_contract(contract)?;
+
+        let mut repaired: Vec<String> = vec![];
+        let mut tx = conn.transaction()?;
+        for table in &tables {
+            if noview_tables.contains(&table.name) {
+                continue;
+            }
+            if !self.derived_tables_are_stale(&mut tx, &contract.cid, table)? {
+                continue;
+            }
+            info!(
+                "repairing stale {table} _live and _ordered ({contract})",
+                contract = contract.cid.name,
+                table = table.name,
+            );
+            self.repopulate_derived_table(&mut tx, &contract.cid, table)?;
+            repaired.push(table.name.clone());
+        }
+        tx.commit()?;
+        Ok(repaired)
+    }
+
+    fn derived_tables_are_stale(
+        &self,
+        tx: &mut Transaction,
+        contract_id: &ContractID,
+        table: &Table,
+    ) -> Result<bool> {
+        // which rows of the newest tx context are live mirrors the
+        // repopulate templates. the _live of a snapshot table holds nothing
+        // but those rows, and its newest tx context is that of its parent
+        let (ctx_table, latest_live, only_latest_live) = if table
+            .contains_snapshots()
+        {
+            (
+                PostgresqlGenerator::table_parent_name(table)
+                    .unwrap_or_else(|| table.name.clone()),
+                "TRUE".to_string(),
+                true,
+            )
+        } else {
+            (
+                table.name.clone(),
+                format!(
+                    r#"NOT t.deleted
+      AND t.bigmap_id NOT IN (
+        SELECT bigmap_id FROM "{main_schema}".bigmap_meta_actions WHERE action = 'clear'
+      )"#,
+                    main_schema = self.main_schema,
+                ),
+                false,
+            )
+        };
+        let latest_ctx = format!(
+            r#"
+    SELECT ctx.id
+    FROM "{contract_schema}"."{ctx_table}" t
+    JOIN "{main_schema}".tx_contexts ctx
+      ON ctx.id = t.tx_context_id
+    ORDER BY
+        ctx.level DESC,
+        ctx.operation_group_number DESC,
+        ctx.operation_number DESC,
+        ctx.content_number DESC,
+        COALESCE(ctx.internal_number, -1) DESC
+    LIMIT 1"#,
+            main_schema = self.main_schema,
+            contract_schema = contract_id.name,
+            ctx_table = ctx_table,
+        );
+        let row = tx.query_one(
+            format!(
+                r#"
+WITH latest_ctx AS ({latest_ctx}
+), base AS (
+    SELECT COUNT(*) AS n, MAX(tx_context_id) AS max_ctx
+    FROM "{contract_schema}"."{table}"
+), ordered AS (
+    SELECT COUNT(*) AS n, MAX(tx_context_id) AS max_ctx
+    FROM "{contract_schema}"."{table}_ordered"
+), live AS (
+    SELECT
+        COUNT(*) AS n,
+        COUNT(*) FILTER (WHERE tx_context_id IN (SELECT * FROM latest_ctx)) AS n_latest
+    FROM "{contract_schema}"."{table}_live"
+), latest AS (
+    SELECT COUNT(*) AS n
+    FROM "{contract_schema}"."{table}" t
+    WHERE t.tx_context_id IN (SELECT * FROM latest_ctx)
+      AND {latest_live}
+)
+SELECT
+    ($2 AND (
+        ordered.n < base.n
+        OR COALESCE(ordered.max_ctx, -1) < COALESCE(base.max_ctx, -1)
+    ))
+    OR live.n_latest <> latest.n
+    OR ($1 AND live.n <> live.n_latest)
+FROM base, ordered, live, latest"#,
+                latest_ctx = latest_ctx,
+                latest_live = latest_live,
+                contract_schema = contract_id.name,
+                table = table.name,
+            )
+            .as_str(),
+            &[&only_latest_live, &!table.contains_ordered_changes()],
+        )?;
+        Ok(row.get(0))
+    }
+
+    pub(crate) fn vacuum_analyze_contract(
+        &mut self,
+        contract: &ContractID,
+    ) -> Result<()> {
+        if !self.contract_tables {
+            return Ok(());
+        }
+        // VACUUM cannot run inside a transaction block, so run it on a
+        // connection that is not inside one (ie in autocommit mode)
+        let mut conn = self.dbconn()?;
+        let tables: Vec<String> = conn
+            .query(
+                "
+SELECT table_name
+FROM information_schema.tables
+WHERE table_schema = $1
+  AND table_type = 'BASE TABLE'
+ORDER BY table_name",
+                &[&contract.name],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        for table in &tables {
+            info!(
+                "vacuum analyzing {contract}.{table}",
+                contract = contract.name,
+                table = table,
+            );
+            conn.simple_query(&format!(
+                r#"VACUUM ANALYZE "{contract_schema}"."{table}""#,
+                contract_schema = contract.name,
+                table = table,
+            ))?;
+        }
+        Ok(())
+    }
+
+    // The (id, value) of (at most limit of) the rows of the table with a
+    // value in its metadata uri column that have no resolved metadata yet,
+    // or None while the contract's resolved metadata table is not set up.
+    pub(crate) fn get_unresolved_metadata_uris(
+        &mut self,
+        contract_schema: &str,
+        table: &str,
+        column: &str,
+        limit: i64,
+    ) -> Result<Option<Vec<(i64, String)>>> {
+        let resolved_table = metadata::resolved_metadata_table(table);
+        let mut conn = self.dbconn()?;
+        let exists = conn
+            .query_opt(
+                "
+SELECT 1
+FROM information_schema.tables
+WHERE table_schema = $1
+  AND table_name = $2",
+                &[&contract_schema, &resolved_table],
+            )?
+            .is_some();
+        if !exists {
+            return Ok(None);
+        }
+        let rows = conn.query(
+            format!(
+                r#"
+SELECT
+    t.id,
+    t.{column}::TEXT
+FROM "{contract_schema}"."{table}" t
+LEFT JOIN "{contract_schema}"."{resolved_table}" resolved
+  ON resolved."{parent_ref}" = t.id
+WHERE resolved."{parent_ref}" IS NULL
+  AND t.{column} IS NOT NULL
+ORDER BY t.id
+LIMIT $1"#,
+                contract_schema = contract_schema,
+                table = table,
+                resolved_table = resolved_table,
+                parent_ref = PostgresqlGenerator::parent_ref(table),
+                column = PostgresqlGenerator::quote_id(column),
+            )
+            .as_str(),
+            &[&limit],
+        )?;
+        Ok(Some(
+            rows.iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect(),
+        ))
+    }
+
+    // Saves the (id, uri, resolved metadata) of rows of the table. Rows that
+    // were deleted in the meantime (eg by reprocessing their level) are
+    // skipped.
+    pub(crate) fn save_resolved_metadata(
+        &mut self,
+        contract_schema: &str,
+        table: &str,
+        resolved: &[(i64, Option<String>, metadata::Resolved)],
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+        let stmt = tx.prepare(
+            format!(
+                r#"
+INSERT INTO "{contract_schema}"."{resolved_table}" (
+    "{parent_ref}", uri, resolved, metadata, error
+)
+SELECT $1, $2, $3, $4, $5
+WHERE EXISTS (SELECT 1 FROM "{contract_schema}"."{table}" WHERE id = $1)
+ON CONFLICT DO NOTHING"#,
+                contract_schema = contract_schema,
+                table = table,
+                resolved_table = metadata::resolved_metadata_table(table),
+                parent_ref = PostgresqlGenerator::parent_ref(table),
+            )
+            .as_str(),
+        )?;
+        for (id, uri, resolved) in resolved {
+            let metadata_json: Option<String> = resolved
+                .metadata
+                .as_ref()
+                .map(|m| m.to_string());
+            tx.execute(
+                &stmt,
+                &[
+                    id,
+                    uri,
+                    &resolved.metadata.is_some(),
+                    &metadata_json,
+                    &resolved.error,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
     fn repopulate_derived_table(
         &self,
         tx: &mut Transaction,
@@ -199,12 +928,17 @@ WHERE table_schema = $1
             let parent_table: String =
                 PostgresqlGenerator::table_parent_name(table)
                     .unwrap_or_else(|| table.name.clone());
+            let (element_columns, element_keys) =
+                PostgresqlGenerator::table_sql_element_columns(table);
             let tmpl = RepopulateSnapshotDerivedTmpl {
                 main_schema: &self.main_schema,
                 contract_schema: &contract_id.name,
                 table: &table.name,
                 parent_table: &parent_table,
                 columns: &columns,
+                ordered_changes: table.contains_ordered_changes(),
+                element_columns: &element_columns,
+                element_keys: &element_keys,
             };
             tx.simple_query(&tmpl.render()?)?;
         } else {
@@ -227,23 +961,17 @@ WHERE table_schema = $1
         contract: &relational::Contract,
         tx_contexts: &[TxContext],
     ) -> Result<()> {
-        if tx_contexts.is_empty() {
+        if tx_contexts.is_empty()
+            || !Self::has_derived_tables(tx, &contract.cid)?
+        {
             return Ok(());
         }
 
-        let (mut tables, noview_prefixes, _): (
-            Vec<Table>,
-            Vec<String>,
-            Vec<String>,
-        ) = TableBuilder::tables_from_contract(contract);
-
-        tables.sort_by_key(|t| t.name.clone());
+        let (tables, noview_tables, _): (Vec<Table>, Vec<String>, Vec<String>) =
+            TableBuilder::tables_from_contract(contract)?;
 
         for table in &tables {
-            if !noview_prefixes
-                .iter()
-                .any(|prefix| table.name.starts_with(prefix))
-            {
+            if !noview_tables.contains(&table.name) {
                 self.update_derived_table(
                     tx,
                     &contract.cid,
@@ -255,6 +983,215 @@ WHERE table_schema = $1
         Ok(())
     }
 
+    // Updates the derived tables with the contract's tx contexts in the given
+    // range of levels, which must all be newer than those the derived tables
+    // were derived from so far.
+    pub(crate) fn update_derived_tables_between(
+        &mut self,
+        contract: &relational::Contract,
+        from: u32,
+        to: u32,
+    ) -> Result<()> {
+        if !self.contract_tables {
+            return Ok(());
+        }
+        let mut conn = self.dbconn()?;
+        let tx_contexts: Vec<TxContext> = conn
+            .query(
+                "
+SELECT
+    id,
+    level,
+    operation_group_number,
+    operation_number,
+    content_number,
+    internal_number
+FROM tx_contexts
+WHERE contract = $1
+  AND level BETWEEN $2 AND $3",
+                &[
+                    &contract.cid.address,
+                    &level_to_db(from)?,
+                    &level_to_db(to)?,
+                ],
+            )?
+            .iter()
+            .map(|row| tx_context_from_row(row, 0, &contract.cid.address))
+            .collect::<Result<Vec<TxContext>>>()?;
+
+        let mut tx = conn.transaction()?;
+        self.update_derived_tables(&mut tx, contract, &tx_contexts)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // The calls to the contract in the levels from..=to (inclusive), in
+    // execution order, paginated by limit and offset. Calls are those
+    // recorded in the txs table, so they include calls that failed when
+    // indexing with --record-all-calls or --index-failures.
+    pub(crate) fn get_calls(
+        &mut self,
+        contract: &ContractID,
+        from: u32,
+        to: u32,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<CallRecord>> {
+        let mut conn = self.dbconn()?;
+        let mut calls: Vec<CallRecord> = conn
+            .query(
+                "
+SELECT
+    ctx.id,
+    ctx.level,
+    ctx.operation_group_number,
+    ctx.operation_number,
+    ctx.content_number,
+    ctx.internal_number,
+    tx.operation_hash,
+    tx.status,
+    tx.entrypoint,
+    tx.source,
+    tx.amount,
+    raw.storage,
+    raw.storage_compressed
+FROM tx_contexts ctx
+JOIN txs tx
+  ON tx.tx_context_id = ctx.id
+LEFT JOIN raw_storage raw
+  ON raw.tx_context_id = ctx.id
+WHERE ctx.contract = $1
+  AND ctx.level BETWEEN $2 AND $3
+ORDER BY
+    ctx.level,
+    ctx.operation_group_number,
+    ctx.operation_number,
+    ctx.content_number,
+    COALESCE(ctx.internal_number, -1)
+LIMIT $4
+OFFSET $5",
+                &[
+                    &contract.address,
+                    &level_to_db(from)?,
+                    &level_to_db(to)?,
+                    &limit,
+                    &offset,
+                ],
+            )?
+            .iter()
+            .map(|row| {
+                Ok(CallRecord {
+                    tx_context: tx_context_from_row(row, 0, &contract.address)?,
+                    operation_hash: row.get(6),
+                    status: row.get(7),
+                    entrypoint: row.get(8),
+                    source: row.get(9),
+                    amount: row.get(10),
+                    entrypoint_row_id: None,
+                    raw_storage: compression::raw_storage_value(
+                        row.get(11),
+                        row.get(12),
+                    )?,
+                })
+            })
+            .collect::<Result<Vec<CallRecord>>>()?;
+
+        // the entrypoint tables only exist for the contract's entrypoints
+        // (not eg for calls to "default" when it is not one of them), and not
+        // at all when the contract has no tables of its own
+        let entry_tables: Vec<String> = conn
+            .query(
+                "
+SELECT table_name
+FROM information_schema.tables
+WHERE table_schema = $1
+  AND table_name LIKE 'entry.%'",
+                &[&contract.name],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        let entrypoints: Vec<String> = calls
+            .iter()
+            .filter_map(|call| call.entrypoint.clone())
+            .unique()
+            .filter(|entrypoint| {
+                entry_tables.contains(&format!("entry.{}", entrypoint))
+            })
+            .collect();
+        for entrypoint in &entrypoints {
+            let tx_context_ids: Vec<i64> = calls
+                .iter()
+                .filter(|call| call.entrypoint.as_ref() == Some(entrypoint))
+                .map(|call| call.tx_context.id.unwrap())
+                .collect();
+            let row_ids: HashMap<i64, i64> = conn
+                .query(
+                    format!(
+                        r#"
+SELECT tx_context_id, id
+FROM "{contract_schema}"."entry.{entrypoint}"
+WHERE tx_context_id = ANY($1)"#,
+                        contract_schema = contract.name,
+                        entrypoint = entrypoint,
+                    )
+                    .as_str(),
+                    &[&tx_context_ids],
+                )?
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect();
+            for call in calls
+                .iter_mut()
+                .filter(|call| call.entrypoint.as_ref() == Some(entrypoint))
+            {
+                call.entrypoint_row_id = row_ids
+                    .get(&call.tx_context.id.unwrap())
+                    .copied();
+            }
+        }
+        Ok(calls)
+    }
+
+    // The tx contexts of the contract calls made by the operation with the
+    // given hash, in execution order. These can be many: an operation's
+    // contents and their internal operations can call several contracts
+    // (and the same contract multiple times).
+    pub(crate) fn find_by_operation_hash(
+        &mut self,
+        hash: &str,
+    ) -> Result<Vec<TxContext>> {
+        let mut conn = self.dbconn()?;
+        conn.query(
+            "
+SELECT
+    ctx.id,
+    ctx.level,
+    ctx.operation_group_number,
+    ctx.operation_number,
+    ctx.content_number,
+    ctx.internal_number,
+    ctx.contract
+FROM txs tx
+JOIN tx_contexts ctx
+  ON ctx.id = tx.tx_context_id
+WHERE tx.operation_hash = $1
+ORDER BY
+    ctx.level,
+    ctx.operation_group_number,
+    ctx.operation_number,
+    ctx.content_number,
+    COALESCE(ctx.internal_number, -1)",
+            &[&hash],
+        )?
+        .iter()
+        .map(|row| {
+            let contract: String = row.get(6);
+            tx_context_from_row(row, 0, &contract)
+        })
+        .collect()
+    }
+
     fn update_derived_table(
         &self,
         tx: &mut Transaction,
@@ -273,12 +1210,17 @@ WHERE table_schema = $1
             let parent_table: String =
                 PostgresqlGenerator::table_parent_name(table)
                     .unwrap_or_else(|| table.name.clone());
+            let (element_columns, element_keys) =
+                PostgresqlGenerator::table_sql_element_columns(table);
             let tmpl = UpdateSnapshotDerivedTmpl {
                 main_schema: &self.main_schema,
                 contract_schema: &contract_id.name,
                 table: &table.name,
                 parent_table: &parent_table,
                 columns: &columns,
+                ordered_changes: table.contains_ordered_changes(),
+                element_columns: &element_columns,
+                element_keys: &element_keys,
                 tx_context_ids: &tx_context_ids,
             };
             tx.simple_query(&tmpl.render()?)?;
@@ -297,16 +1239,23 @@ WHERE table_schema = $1
         Ok(())
     }
 
+    // Sets up the schemas of the contracts that have none yet, returning
+    // whether there were any. The schemas of the other contracts get the
+    // columns and tables they miss (eg when set up by an older version)
+    // added, which is only checked the first time per run.
     pub(crate) fn create_contract_schemas(
         &mut self,
         contracts: &mut Vec<relational::Contract>,
     ) -> Result<bool> {
+        if contracts.is_empty() {
+            return Ok(false);
+        }
         let mut conn = self.dbconn()?;
         let mut tx = conn.transaction()?;
 
         contracts.sort_by_key(|c| c.cid.name.clone());
 
-        let num_columns = 2;
+        let num_columns = 3;
         let v_refs = (1..(num_columns * contracts.len()) + 1)
             .map(|i| format!("${}", i))
             .collect::<Vec<String>>()
@@ -315,7 +1264,7 @@ WHERE table_schema = $1
             .join("), (");
         let stmt = tx.prepare(&format!(
             "
-INSERT INTO contracts (name, address)
+INSERT INTO contracts (name, address, derived_tables)
 VALUES ({})
 ON CONFLICT DO NOTHING
 RETURNING name",
@@ -325,7 +1274,11 @@ RETURNING name",
         let values: Vec<&dyn postgres::types::ToSql> = contracts
             .iter()
             .flat_map(|c| {
-                [c.cid.name.borrow_to_sql(), c.cid.address.borrow_to_sql()]
+                [
+                    c.cid.name.borrow_to_sql(),
+                    c.cid.address.borrow_to_sql(),
+                    self.derived_tables.borrow_to_sql(),
+                ]
             })
             .collect();
 
@@ -333,10 +1286,31 @@ RETURNING name",
             .query_raw(&stmt, values)?
             .map(|x| x.try_get(0))
             .collect::<Vec<String>>()?;
+        if self.contract_tables {
+            let checked = self
+                .checked_schemas
+                .lock()
+                .map_err(|_| anyhow!("failed to lock checked_schemas"))?
+                .clone();
+            for contract in contracts.iter().filter(|c| {
+                !new_contracts.contains(&c.cid.name)
+                    && !checked.contains(&c.cid.name)
+            }) {
+                Self::add_missing_contract_columns(&mut tx, contract)?;
+                self.add_missing_tables(&mut tx, contract)?;
+                self.switch_ordered_derivations(&mut tx, contract)?;
+            }
+        }
         if new_contracts.is_empty() {
-            tx.rollback()?;
+            tx.commit()?;
+            self.set_schemas_checked(contracts)?;
             return Ok(false);
         }
+        if !self.contract_tables {
+            tx.commit()?;
+            self.set_schemas_checked(contracts)?;
+            return Ok(true);
+        }
         let mut stmnts: Vec<String> = vec![];
         for name in &new_contracts {
             let contract = contracts
@@ -344,13 +1318,11 @@ RETURNING name",
                 .find(|c| &c.cid.name == name)
                 .unwrap();
 
-            let (mut tables, noview_prefixes, nofunctions_prefixes): (
+            let (tables, noview_tables, nofunctions_tables): (
                 Vec<Table>,
                 Vec<String>,
                 Vec<String>,
-            ) = TableBuilder::tables_from_contract(contract);
-
-            tables.sort_by_key(|t| t.name.clone());
+            ) = TableBuilder::tables_from_contract(contract)?;
 
             stmnts.push(format!(
                 r#"
@@ -359,62 +1331,343 @@ CREATE SCHEMA IF NOT EXISTS "{contract_schema}";
                 contract_schema = contract.cid.name
             ));
 
-            let generator = PostgresqlGenerator::new(
+            let mut generator = PostgresqlGenerator::new(
                 self.main_schema.clone(),
                 &contract.cid,
             );
+            if self.shared_tx_contexts {
+                generator.share_tx_contexts();
+            }
 
             for table in &tables {
                 let table_def = generator.create_table_definition(table)?;
                 stmnts.push(table_def);
+            }
+            for table in contract.metadata_uris.keys() {
+                stmnts.push(generator.create_resolved_metadata_table(table));
+            }
+            if self.derived_tables {
+                stmnts.extend(Self::derived_table_stmnts(
+                    &generator,
+                    contract,
+                    &tables,
+                    &noview_tables,
+                    &nofunctions_tables,
+                )?);
+            }
+        }
+        for stmnt in stmnts {
+            tx.simple_query(stmnt.as_str())?;
+        }
+        tx.commit()?;
+        self.set_schemas_checked(contracts)?;
+
+        Ok(true)
+    }
 
-                if !noview_prefixes
+    fn set_schemas_checked(
+        &self,
+        contracts: &[relational::Contract],
+    ) -> Result<()> {
+        self.checked_schemas
+            .lock()
+            .map_err(|_| anyhow!("failed to lock checked_schemas"))?
+            .extend(
+                contracts
                     .iter()
-                    .any(|prefix| table.name.starts_with(prefix))
-                {
-                    for derived_table_def in
-                        generator.create_derived_table_definitions(table)?
-                    {
-                        stmnts.push(derived_table_def);
-                    }
-                }
+                    .map(|c| c.cid.name.clone()),
+            );
+        Ok(())
+    }
 
-                if !nofunctions_prefixes
+    // Contracts set up before storage rows carried their level get the level
+    // columns added to their tables, and those set up before list elements
+    // carried their position get the ordinal column added to their list
+    // tables (and their derived tables), with NULL for the rows indexed so
+    // far.
+    fn add_missing_contract_columns(
+        tx: &mut Transaction,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let with_column =
+            |tx: &mut Transaction, column: &str| -> Result<Vec<String>> {
+                Ok(tx
+                    .query(
+                        "
+SELECT
+    table_name::TEXT
+FROM information_schema.columns
+WHERE table_schema = $1
+  AND column_name = $2
+",
+                        &[&contract.cid.name, &column],
+                    )?
                     .iter()
-                    .any(|prefix| table.name.starts_with(prefix))
-                {
-                    let function_def = generator
-                        .create_table_functions(&contract.cid.name, table)?;
-                    stmnts.extend(function_def);
-                }
+                    .map(|row| row.get(0))
+                    .collect())
+            };
+        let with_level = with_column(tx, "level_timestamp")?;
+        let with_ordinal = with_column(tx, "ordinal")?;
+        let (tables, _, _) = TableBuilder::tables_from_contract(contract)?;
+        for table in tables
+            .iter()
+            .filter(|t| !with_level.contains(&t.name))
+        {
+            tx.simple_query(&format!(
+                r#"
+ALTER TABLE IF EXISTS "{contract_schema}"."{table}"
+    ADD COLUMN IF NOT EXISTS level INTEGER,
+    ADD COLUMN IF NOT EXISTS level_timestamp TIMESTAMP WITH TIME ZONE"#,
+                contract_schema = contract.cid.name,
+                table = table.name,
+            ))?;
+        }
+        for table in tables
+            .iter()
+            .filter(|t| !with_ordinal.contains(&t.name))
+        {
+            let ordinal = match table.columns.get("ordinal") {
+                Some(column) => column,
+                None => continue,
+            };
+            let column = PostgresqlGenerator::create_sql(ordinal)
+                .ok_or_else(|| anyhow!("no sql type for the ordinal column"))?;
+            for derived in &["", "_live", "_ordered"] {
+                tx.simple_query(&format!(
+                    r#"
+ALTER TABLE IF EXISTS "{contract_schema}"."{table}{derived}"
+    ADD COLUMN IF NOT EXISTS {column}"#,
+                    contract_schema = contract.cid.name,
+                    table = table.name,
+                    derived = derived,
+                    column = column,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Contracts that gained tables after they were set up get these added,
+    // with their derived tables and functions when the contract has these:
+    // the tables of entrypoints that were added to the contract (eg by a
+    // lazy upgrade). So do the tables the resolved metadata go to of metadata
+    // uri columns that were configured later on.
+    fn add_missing_tables(
+        &self,
+        tx: &mut Transaction,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let existing: Vec<String> = tx
+            .query(
+                "
+SELECT
+    table_name
+FROM information_schema.tables
+WHERE table_schema = $1
+",
+                &[&contract.cid.name],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        let (tables, noview_tables, nofunctions_tables) =
+            TableBuilder::tables_from_contract(contract)?;
+        let missing: Vec<Table> = tables
+            .into_iter()
+            .filter(|t| t.name.starts_with("entry."))
+            .filter(|t| !existing.contains(&t.name))
+            .collect();
+        // (in the order of tables_from_contract: parents before their
+        // children, which refer to them)
+
+        let mut generator =
+            PostgresqlGenerator::new(self.main_schema.clone(), &contract.cid);
+        if self.shared_tx_contexts {
+            generator.share_tx_contexts();
+        }
+        let mut stmnts: Vec<String> = vec![];
+        for table in contract.metadata_uris.keys() {
+            if !existing.contains(&metadata::resolved_metadata_table(table)) {
+                info!(
+                    "adding the resolved metadata table of {} to contract {}",
+                    table, contract.cid.name
+                );
+                stmnts.push(generator.create_resolved_metadata_table(table));
             }
         }
+        for table in &missing {
+            info!(
+                "adding table {} to contract {}",
+                table.name, contract.cid.name
+            );
+            stmnts.push(generator.create_table_definition(table)?);
+        }
+        if !missing.is_empty()
+            && existing.contains(&format!("{}_live", contract.root_table))
+        {
+            stmnts.extend(Self::derived_table_stmnts(
+                &generator,
+                contract,
+                &missing,
+                &noview_tables,
+                &nofunctions_tables,
+            )?);
+        }
         for stmnt in stmnts {
             tx.simple_query(stmnt.as_str())?;
         }
-        tx.commit()?;
+        Ok(())
+    }
+
+    // Repopulates the _ordered tables whose derivation was switched between
+    // snapshots and changes (see TableBuilder::override_snapshots) since the
+    // contract was set up, adding or dropping the column that marks the
+    // removed elements.
+    fn switch_ordered_derivations(
+        &self,
+        tx: &mut Transaction,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        if !Self::has_derived_tables(tx, &contract.cid)? {
+            return Ok(());
+        }
+        // table => whether it has a deleted column
+        let existing: HashMap<String, bool> = tx
+            .query(
+                "
+SELECT
+    table_name::TEXT,
+    bool_or(column_name = 'deleted')
+FROM information_schema.columns
+WHERE table_schema = $1
+GROUP BY table_name
+",
+                &[&contract.cid.name],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        let (tables, noview_tables, _) =
+            TableBuilder::tables_from_contract(contract)?;
+        for table in tables.iter().filter(|t| {
+            t.contains_snapshots() && !noview_tables.contains(&t.name)
+        }) {
+            let ordered_changes = table.contains_ordered_changes();
+            match existing.get(&format!("{}_ordered", table.name)) {
+                Some(has_deleted) if *has_deleted != ordered_changes => {}
+                _ => continue,
+            }
+            info!(
+                "repopulating {table}_ordered with the {derivation} of {table} ({contract})",
+                contract = contract.cid.name,
+                table = table.name,
+                derivation = if ordered_changes { "changes" } else { "snapshots" },
+            );
+            tx.simple_query(&format!(
+                r#"
+ALTER TABLE "{contract_schema}"."{table}_ordered"
+    {alter}"#,
+                contract_schema = contract.cid.name,
+                table = table.name,
+                alter = if ordered_changes {
+                    "ADD COLUMN deleted BOOLEAN NOT NULL DEFAULT 'false'"
+                } else {
+                    "DROP COLUMN deleted"
+                },
+            ))?;
+            self.repopulate_derived_table(tx, &contract.cid, table)?;
+        }
+        Ok(())
+    }
+
+    // Adds the derived tables and _at functions to a contract set up
+    // without them (see skip_derived_tables). They are left empty, to be
+    // populated with repopulate_derived_tables. Returns false if the
+    // contract already had them.
+    pub(crate) fn create_derived_tables(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<bool> {
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+        if Self::has_derived_tables(&mut tx, &contract.cid)? {
+            return Ok(false);
+        }
+        let (tables, noview_tables, nofunctions_tables): (
+            Vec<Table>,
+            Vec<String>,
+            Vec<String>,
+        ) = TableBuilder::tables_from_contract(contract)?;
 
+        let mut generator =
+            PostgresqlGenerator::new(self.main_schema.clone(), &contract.cid);
+        if self.shared_tx_contexts {
+            generator.share_tx_contexts();
+        }
+        for stmnt in Self::derived_table_stmnts(
+            &generator,
+            contract,
+            &tables,
+            &noview_tables,
+            &nofunctions_tables,
+        )? {
+            tx.simple_query(stmnt.as_str())?;
+        }
+        tx.execute(
+            "UPDATE contracts SET derived_tables = true WHERE name = $1",
+            &[&contract.cid.name],
+        )?;
+        tx.commit()?;
         Ok(true)
     }
 
+    fn derived_table_stmnts(
+        generator: &PostgresqlGenerator,
+        contract: &relational::Contract,
+        tables: &[Table],
+        noview_tables: &[String],
+        nofunctions_tables: &[String],
+    ) -> Result<Vec<String>> {
+        let mut stmnts: Vec<String> = vec![];
+        for table in tables {
+            if !noview_tables.contains(&table.name) {
+                stmnts
+                    .extend(generator.create_derived_table_definitions(table)?);
+            }
+
+            if !nofunctions_tables.contains(&table.name) {
+                stmnts.extend(
+                    generator
+                        .create_table_functions(&contract.cid.name, table)?,
+                );
+            }
+        }
+        Ok(stmnts)
+    }
+
     pub(crate) fn delete_contract_schema(
         tx: &mut Transaction,
         contract: &relational::Contract,
     ) -> Result<()> {
         info!("deleting schema for contract {}", contract.cid.name);
-        let (mut tables, noview_prefixes, nofunctions_prefixes): (
+        let (mut tables, noview_tables, nofunctions_tables): (
             Vec<Table>,
             Vec<String>,
             Vec<String>,
-        ) = TableBuilder::tables_from_contract(contract);
-        tables.sort_by_key(|t| t.name.clone());
+        ) = TableBuilder::tables_from_contract(contract)?;
+        // children before their parents, which they refer to
         tables.reverse();
 
+        for table in contract.metadata_uris.keys() {
+            tx.simple_query(&format!(
+                r#"DROP TABLE IF EXISTS "{contract_schema}"."{table}""#,
+                contract_schema = contract.cid.name,
+                table = metadata::resolved_metadata_table(table),
+            ))?;
+        }
+
         for table in &tables {
-            if !nofunctions_prefixes
-                .iter()
-                .any(|prefix| table.name.starts_with(prefix))
-            {
+            if !nofunctions_tables.contains(&table.name) {
                 tx.simple_query(
                     format!(
                         r#"
@@ -437,15 +1690,12 @@ DROP FUNCTION IF EXISTS "{contract_schema}"."{table}_at(INT)";
                 )?;
             }
 
-            if !noview_prefixes
-                .iter()
-                .any(|prefix| table.name.starts_with(prefix))
-            {
+            if !noview_tables.contains(&table.name) {
                 tx.simple_query(
                     format!(
                         r#"
-DROP TABLE "{contract_schema}"."{table}_ordered";
-DROP TABLE "{contract_schema}"."{table}_live";
+DROP TABLE IF EXISTS "{contract_schema}"."{table}_ordered";
+DROP TABLE IF EXISTS "{contract_schema}"."{table}_live";
 "#,
                         contract_schema = contract.cid.name,
                         table = table.name,
@@ -473,7 +1723,7 @@ DROP TABLE "{contract_schema}"."{table}";
         actions: &[BigmapMetaAction],
     ) -> Result<()> {
         for chunk in actions.chunks(Self::INSERT_BATCH_SIZE) {
-            let num_columns = 4;
+            let num_columns = 5;
             let v_refs = (1..(num_columns * chunk.len()) + 1)
                 .map(|i| format!("${}", i))
                 .collect::<Vec<String>>()
@@ -483,7 +1733,7 @@ DROP TABLE "{contract_schema}"."{table}";
             let stmt = tx.prepare(&format!(
                 "
     INSERT INTO bigmap_meta_actions (
-        tx_context_id, bigmap_id, action, value
+        tx_context_id, bigmap_id, operation_hash, action, value
     )
     Values ({})",
                 v_refs
@@ -495,6 +1745,7 @@ DROP TABLE "{contract_schema}"."{table}";
                     [
                         x.tx_context_id.borrow_to_sql(),
                         x.bigmap_id.borrow_to_sql(),
+                        x.operation_hash.borrow_to_sql(),
                         x.action.borrow_to_sql(),
                         x.value.borrow_to_sql(),
                     ]
@@ -503,46 +1754,69 @@ DROP TABLE "{contract_schema}"."{table}";
 
             tx.query_raw(&stmt, values)?;
         }
-        Ok(())
+        Self::save_bigmap_owners(tx, actions)
     }
 
-    pub(crate) fn save_bigmap_keyhashes(
+    fn save_bigmap_owners(
         tx: &mut Transaction,
-        bigmap_keyhashes: BigmapEntries,
+        actions: &[BigmapMetaAction],
     ) -> Result<()> {
-        for chunk in bigmap_keyhashes
-            .into_iter()
-            .collect::<Vec<(
-                (i32, TxContext, String),
-                (serde_json::Value, Option<serde_json::Value>),
-            )>>()
-            .chunks(Self::INSERT_BATCH_SIZE)
-        {
-            let num_columns = 5;
-            let v_refs = (1..(num_columns * chunk.len()) + 1)
-                .map(|i| format!("${}", i))
-                .collect::<Vec<String>>()
-                .chunks(num_columns)
-                .map(|x| x.join(", "))
+        let allocs: Vec<(i64, i32, String)> = actions
+            .iter()
+            .filter(|x| x.action == "alloc")
+            .map(|x| {
+                let table = x
+                    .value
+                    .as_ref()
+                    .and_then(|value| value["table"].as_str())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "alloc of bigmap {} has no table: {:?}",
+                            x.bigmap_id,
+                            x.value
+                        )
+                    })?;
+                Ok((x.tx_context_id, x.bigmap_id, table.to_string()))
+            })
+            .collect::<Result<Vec<(i64, i32, String)>>>()?;
+        for chunk in allocs.chunks(Self::INSERT_BATCH_SIZE) {
+            let num_columns = 3;
+            let v_refs = (0..chunk.len())
+                .map(|i| {
+                    format!(
+                        "${}::BIGINT, ${}::INT, ${}::TEXT",
+                        num_columns * i + 1,
+                        num_columns * i + 2,
+                        num_columns * i + 3
+                    )
+                })
                 .join("), (");
+            // the owning contract and the alloc level are those of the
+            // alloc's tx context
             let stmt = tx.prepare(&format!(
                 "
-INSERT INTO bigmap_keys (
-    tx_context_id, bigmap_id, keyhash, key, value
-)
-Values ({})",
+    INSERT INTO bigmap_owners (
+        tx_context_id, bigmap_id, contract_address, table_name, alloc_level
+    )
+    SELECT
+        alloc.tx_context_id,
+        alloc.bigmap_id,
+        ctx.contract,
+        alloc.table_name,
+        ctx.level
+    FROM (VALUES ({})) AS alloc(tx_context_id, bigmap_id, table_name)
+    JOIN tx_contexts ctx
+      ON ctx.id = alloc.tx_context_id",
                 v_refs
             ))?;
 
             let values: Vec<&dyn postgres::types::ToSql> = chunk
                 .iter()
-                .flat_map(|((bigmap_id, tx_context, keyhash), (key, value))| {
+                .flat_map(|x| {
                     [
-                        tx_context.id.borrow_to_sql(),
-                        bigmap_id.borrow_to_sql(),
-                        keyhash.borrow_to_sql(),
-                        key.borrow_to_sql(),
-                        value.borrow_to_sql(),
+                        x.0.borrow_to_sql(),
+                        x.1.borrow_to_sql(),
+                        x.2.borrow_to_sql(),
                     ]
                 })
                 .collect();
@@ -552,26 +1826,205 @@ Values ({})",
         Ok(())
     }
 
-    pub(crate) fn save_tx_contexts(
-        tx: &mut Transaction,
-        tx_contexts: &[TxContext],
-    ) -> Result<()> {
-        struct TxContextPG {
-            id: i64,
-            pub level: i32,
-            pub contract: String,
-            pub operation_group_number: i32,
-            pub operation_number: i32,
-            pub content_number: i32,
-            pub internal_number: Option<i32>,
-        }
-        for chunk in tx_contexts.chunks(Self::INSERT_BATCH_SIZE) {
-            let num_columns = 7;
-            let v_refs = (1..(num_columns * chunk.len()) + 1)
-                .map(|i| format!("${}", i))
-                .collect::<Vec<String>>()
-                .chunks(num_columns)
-                .map(|x| x.join(", "))
+    // The contract and table that owned the bigmap at given level, ie those
+    // of its most recent alloc at or before the level (a bigmap id can be
+    // reallocated).
+    pub(crate) fn get_bigmap_owner(
+        &mut self,
+        bigmap_id: i32,
+        level: u32,
+    ) -> Result<Option<BigmapOwner>> {
+        let mut conn = self.dbconn()?;
+        let level = level_to_db(level)?;
+        let res = conn.query_opt(
+            "
+SELECT
+    owner.contract_address,
+    owner.table_name,
+    owner.alloc_level
+FROM bigmap_owners owner
+JOIN tx_contexts ctx
+  ON ctx.id = owner.tx_context_id
+WHERE owner.bigmap_id = $1
+  AND owner.alloc_level <= $2
+ORDER BY
+    ctx.level DESC,
+    ctx.operation_group_number DESC,
+    ctx.operation_number DESC,
+    ctx.content_number DESC,
+    COALESCE(ctx.internal_number, -1) DESC
+LIMIT 1
+",
+            &[&bigmap_id, &level],
+        )?;
+        res.map(|row| {
+            Ok(BigmapOwner {
+                bigmap_id,
+                contract_address: row.get(0),
+                table_name: row.get(1),
+                alloc_level: level_from_db(row.get(2))?,
+            })
+        })
+        .transpose()
+    }
+
+    pub(crate) fn save_operation_failures(
+        tx: &mut Transaction,
+        failures: &[OperationFailure],
+    ) -> Result<()> {
+        for chunk in failures.chunks(Self::INSERT_BATCH_SIZE) {
+            let num_columns = 4;
+            let v_refs = (1..(num_columns * chunk.len()) + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>()
+                .chunks(num_columns)
+                .map(|x| x.join(", "))
+                .join("), (");
+            let stmt = tx.prepare(&format!(
+                "
+    INSERT INTO operation_failures (
+        tx_context_id, status, error_kind, error_id
+    )
+    Values ({})",
+                v_refs
+            ))?;
+
+            let values: Vec<&dyn postgres::types::ToSql> = chunk
+                .iter()
+                .flat_map(|x| {
+                    [
+                        x.tx_context_id.borrow_to_sql(),
+                        x.status.borrow_to_sql(),
+                        x.error_kind.borrow_to_sql(),
+                        x.error_id.borrow_to_sql(),
+                    ]
+                })
+                .collect();
+
+            tx.query_raw(&stmt, values)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_raw_storage(
+        tx: &mut Transaction,
+        raw_storage: &[RawStorage],
+        compression: StorageCompression,
+    ) -> Result<()> {
+        for chunk in raw_storage.chunks(Self::INSERT_BATCH_SIZE) {
+            let compressed = chunk
+                .iter()
+                .map(|x| match compression {
+                    StorageCompression::None => Ok(None),
+                    StorageCompression::Gzip => Ok(Some(compression::gzip(
+                        x.storage.to_string().as_bytes(),
+                    )?)),
+                })
+                .collect::<Result<Vec<Option<Vec<u8>>>>>()?;
+            let storage: Vec<Option<&serde_json::Value>> = chunk
+                .iter()
+                .zip(&compressed)
+                .map(|(x, compressed)| match compressed {
+                    Some(_) => None,
+                    None => Some(&x.storage),
+                })
+                .collect();
+
+            let num_columns = 4;
+            let v_refs = (1..(num_columns * chunk.len()) + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>()
+                .chunks(num_columns)
+                .map(|x| x.join(", "))
+                .join("), (");
+            let stmt = tx.prepare(&format!(
+                "
+    INSERT INTO raw_storage (
+        tx_context_id, contract, storage, storage_compressed
+    )
+    Values ({})",
+                v_refs
+            ))?;
+
+            let values: Vec<&dyn postgres::types::ToSql> = chunk
+                .iter()
+                .zip(storage.iter().zip(&compressed))
+                .flat_map(|(x, (storage, compressed))| {
+                    [
+                        x.tx_context_id.borrow_to_sql(),
+                        x.contract.borrow_to_sql(),
+                        storage.borrow_to_sql(),
+                        compressed.borrow_to_sql(),
+                    ]
+                })
+                .collect();
+
+            tx.query_raw(&stmt, values)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_bigmap_keyhashes(
+        tx: &mut Transaction,
+        bigmap_keyhashes: BigmapEntries,
+    ) -> Result<()> {
+        for chunk in bigmap_keyhashes
+            .into_iter()
+            .collect::<Vec<(
+                (i32, TxContext, String),
+                (serde_json::Value, Option<serde_json::Value>),
+            )>>()
+            .chunks(Self::INSERT_BATCH_SIZE)
+        {
+            let num_columns = 5;
+            let v_refs = (1..(num_columns * chunk.len()) + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>()
+                .chunks(num_columns)
+                .map(|x| x.join(", "))
+                .join("), (");
+            let stmt = tx.prepare(&format!(
+                "
+INSERT INTO bigmap_keys (
+    tx_context_id, bigmap_id, keyhash, key, value
+)
+Values ({})",
+                v_refs
+            ))?;
+
+            let values: Vec<&dyn postgres::types::ToSql> = chunk
+                .iter()
+                .flat_map(|((bigmap_id, tx_context, keyhash), (key, value))| {
+                    [
+                        tx_context.id.borrow_to_sql(),
+                        bigmap_id.borrow_to_sql(),
+                        keyhash.borrow_to_sql(),
+                        key.borrow_to_sql(),
+                        value.borrow_to_sql(),
+                    ]
+                })
+                .collect();
+
+            tx.query_raw(&stmt, values)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_tx_contexts(
+        tx: &mut Transaction,
+        tx_contexts: &[TxContext],
+        shared_tx_contexts: bool,
+    ) -> Result<()> {
+        if shared_tx_contexts {
+            return Self::save_shared_tx_contexts(tx, tx_contexts);
+        }
+        for chunk in tx_contexts.chunks(Self::INSERT_BATCH_SIZE) {
+            let num_columns = 7;
+            let v_refs = (1..(num_columns * chunk.len()) + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>()
+                .chunks(num_columns)
+                .map(|x| x.join(", "))
                 .join("), (");
             let stmt = tx.prepare(&format!(
                 "
@@ -588,31 +2041,53 @@ VALUES ( {} )",
                 v_refs
             ))?;
 
-            let tx_contexts_pg: Vec<TxContextPG> = chunk
-                .iter()
-                .map(|tx_context| TxContextPG {
-                    id: tx_context
-                        .id
-                        .ok_or_else(|| anyhow!("Missing ID on TxContext"))
-                        .unwrap(),
-                    level: tx_context.level as i32,
-                    contract: tx_context.contract.clone(),
-                    operation_group_number: tx_context.operation_group_number
-                        as i32,
-                    operation_number: tx_context.operation_number as i32,
-                    content_number: tx_context.content_number as i32,
-                    internal_number: tx_context
-                        .internal_number
-                        .map(|n| n as i32),
-                })
-                .collect();
+            let tx_contexts_pg = TxContextPG::from_chunk(chunk)?;
+            tx.query_raw(&stmt, TxContextPG::sql_values(&tx_contexts_pg))?;
+        }
+
+        Ok(())
+    }
+
+    // With shared tx contexts, the operation coordinates are stored once in
+    // operations, regardless of how many contracts they touched.
+    fn save_shared_tx_contexts(
+        tx: &mut Transaction,
+        tx_contexts: &[TxContext],
+    ) -> Result<()> {
+        for chunk in tx_contexts.chunks(Self::INSERT_BATCH_SIZE) {
+            let tx_contexts_pg = TxContextPG::from_chunk(chunk)?;
+
+            let num_columns = 5;
+            let v_refs = (1..(num_columns * chunk.len()) + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>()
+                .chunks(num_columns)
+                .map(|x| x.join(", "))
+                .join("), (");
+            let stmt = tx.prepare(&format!(
+                "
+INSERT INTO operations(
+    level,
+    operation_group_number,
+    operation_number,
+    content_number,
+    internal_number
+)
+VALUES ( {} )
+ON CONFLICT (
+    level,
+    operation_group_number,
+    operation_number,
+    content_number,
+    coalesce(internal_number, -1)
+) DO NOTHING",
+                v_refs
+            ))?;
             let values: Vec<&dyn postgres::types::ToSql> = tx_contexts_pg
                 .iter()
                 .flat_map(|tx_context| {
                     [
-                        tx_context.id.borrow_to_sql(),
                         tx_context.level.borrow_to_sql(),
-                        tx_context.contract.borrow_to_sql(),
                         tx_context
                             .operation_group_number
                             .borrow_to_sql(),
@@ -628,8 +2103,53 @@ VALUES ( {} )",
                     ]
                 })
                 .collect();
-
             tx.query_raw(&stmt, values)?;
+
+            let column_types = [
+                "BIGINT", "INTEGER", "TEXT", "INTEGER", "INTEGER", "INTEGER",
+                "INTEGER",
+            ];
+            let v_refs = (0..(column_types.len() * chunk.len()))
+                .map(|i| {
+                    format!(
+                        "${}::{}",
+                        i + 1,
+                        column_types[i % column_types.len()]
+                    )
+                })
+                .collect::<Vec<String>>()
+                .chunks(column_types.len())
+                .map(|x| x.join(", "))
+                .join("), (");
+            let stmt = tx.prepare(&format!(
+                "
+INSERT INTO contract_tx_contexts(
+    id,
+    operation_id,
+    contract
+)
+SELECT
+    v.id,
+    op.id,
+    v.contract
+FROM (VALUES ( {} )) AS v(
+    id,
+    level,
+    contract,
+    operation_group_number,
+    operation_number,
+    content_number,
+    internal_number
+)
+JOIN operations op
+  ON  op.level = v.level
+  AND op.operation_group_number = v.operation_group_number
+  AND op.operation_number = v.operation_number
+  AND op.content_number = v.content_number
+  AND coalesce(op.internal_number, -1) = coalesce(v.internal_number, -1)",
+                v_refs
+            ))?;
+            tx.query_raw(&stmt, TxContextPG::sql_values(&tx_contexts_pg))?;
         }
 
         Ok(())
@@ -637,7 +2157,7 @@ VALUES ( {} )",
 
     pub(crate) fn save_txs(tx: &mut Transaction, txs: &[Tx]) -> Result<()> {
         for txs_chunk in txs.chunks(Self::INSERT_BATCH_SIZE) {
-            let num_columns = 12;
+            let num_columns = 15;
             let v_refs = (1..(num_columns * txs_chunk.len()) + 1)
                 .map(|i| format!("${}", i))
                 .collect::<Vec<String>>()
@@ -650,6 +2170,7 @@ INSERT INTO txs(
     tx_context_id,
 
     operation_hash,
+    status,
     source,
     destination,
     entrypoint,
@@ -661,7 +2182,10 @@ INSERT INTO txs(
 
     consumed_milligas,
     storage_size,
-    paid_storage_size_diff
+    paid_storage_size_diff,
+
+    counter,
+    branch
 )
 VALUES ( {} )",
                 v_refs
@@ -673,6 +2197,7 @@ VALUES ( {} )",
                     [
                         tx.tx_context_id.borrow_to_sql(),
                         tx.operation_hash.borrow_to_sql(),
+                        tx.status.borrow_to_sql(),
                         tx.source.borrow_to_sql(),
                         tx.destination.borrow_to_sql(),
                         tx.entrypoint.borrow_to_sql(),
@@ -684,6 +2209,8 @@ VALUES ( {} )",
                         tx.storage_size.borrow_to_sql(),
                         tx.paid_storage_size_diff
                             .borrow_to_sql(),
+                        tx.counter.borrow_to_sql(),
+                        tx.branch.borrow_to_sql(),
                     ]
                 })
                 .collect();
@@ -699,6 +2226,22 @@ VALUES ( {} )",
         contract_id: &ContractID,
         inserts: &[Insert],
     ) -> Result<()> {
+        for table_inserts in Self::group_inserts(inserts) {
+            for chunk in table_inserts.chunks(Self::INSERT_BATCH_SIZE) {
+                Self::apply_inserts_for_table(tx, contract_id, chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Groups the inserts by table (and set of columns), in the order they
+    // must be applied in: a child table's rows reference their parent row
+    // (with a foreign key, see PostgresqlGenerator::table_parent_name), so
+    // tables are ordered by their depth in the tree of parent tables the
+    // inserts refer to (parents before children), and only then by name.
+    // Table names do not tell the depth, as they may be renamed or
+    // shortened.
+    fn group_inserts(inserts: &[Insert]) -> Vec<Vec<&Insert>> {
         let mut table_grouped: HashMap<(String, Vec<String>), Vec<&Insert>> =
             HashMap::new();
         for insert in inserts {
@@ -718,16 +2261,32 @@ VALUES ( {} )",
                 .unwrap()
                 .push(insert);
         }
-        let mut keys: Vec<&(String, Vec<String>)> =
-            table_grouped.keys().collect();
-        keys.sort();
-        for k in keys {
-            let table_inserts = table_grouped.get(k).unwrap();
-            for chunk in table_inserts.chunks(Self::INSERT_BATCH_SIZE) {
-                Self::apply_inserts_for_table(tx, contract_id, chunk)?;
+        let parents: HashMap<&str, &str> = inserts
+            .iter()
+            .filter_map(|insert| {
+                insert
+                    .parent_table
+                    .as_deref()
+                    .map(|parent| (insert.table_name.as_str(), parent))
+            })
+            .collect();
+        let depth = |table: &str| {
+            let mut res = 0;
+            let mut table = table;
+            while let Some(parent) = parents.get(table) {
+                res += 1;
+                table = parent;
             }
-        }
-        Ok(())
+            res
+        };
+        let mut keys: Vec<(String, Vec<String>)> =
+            table_grouped.keys().cloned().collect();
+        keys.sort_by_cached_key(|(table, columns)| {
+            (depth(table), table.clone(), columns.clone())
+        });
+        keys.into_iter()
+            .map(|k| table_grouped.remove(&k).unwrap())
+            .collect()
     }
 
     pub(crate) fn get_config_deps(
@@ -813,10 +2372,43 @@ WHERE dest_schema IN ({})
         while let Some(row) = it.next()? {
             res.push(row.get(0));
         }
-        Ok(res
-            .into_iter()
-            .map(|x| x as u32)
-            .collect())
+        res.into_iter()
+            .map(level_from_db)
+            .collect()
+    }
+
+    // Like get_dependent_levels, but only the levels at which a bigmap of
+    // the configured contracts is a copy of given bigmap of src_contract.
+    // Dependencies recorded before the copied bigmap was tracked are
+    // included as well.
+    pub(crate) fn get_bigmap_dependent_levels(
+        &mut self,
+        config: &[ContractID],
+        src_contract: &str,
+        src_bigmap: i32,
+    ) -> Result<Vec<u32>> {
+        let dest_schemas: Vec<String> = config
+            .iter()
+            .map(|c| c.name.clone())
+            .collect();
+
+        let mut conn = self.dbconn()?;
+        conn.query(
+            "
+SELECT DISTINCT
+    level
+FROM contract_deps
+WHERE dest_schema = ANY($1)
+  AND src_contract = $2
+  AND (src_bigmap = $3 OR src_bigmap IS NULL)
+  AND is_deep_copy
+ORDER BY level
+",
+            &[&dest_schemas, &src_contract, &src_bigmap],
+        )?
+        .iter()
+        .map(|row| level_from_db(row.get(0)))
+        .collect()
     }
 
     pub(crate) fn apply_inserts_for_table(
@@ -841,6 +2433,11 @@ WHERE dest_schema IN ({})
             .map(|x| x.join(", "))
             .join("), (");
 
+        // a level's rows are deleted before it is reprocessed, rather than
+        // skipping the rows that are already there: reprocessed rows get
+        // other ids, so the children of a skipped row would refer to a row
+        // that was never inserted. the tables' unique indexes make inserting
+        // a level twice fail, instead of duplicating its rows
         let qry = format!(
             r#"
 INSERT INTO "{contract_schema}"."{table}" ( {v_names} )
@@ -912,6 +2509,22 @@ WHERE table_schema = $1
                 Self::delete_contract_schema(&mut tx, &contract)?
             }
         }
+        if Self::has_operations_table(&mut tx, &main_schema)? {
+            tx.simple_query(
+                "
+DROP VIEW  IF EXISTS txs_ordered;
+DROP VIEW  IF EXISTS tx_contexts;
+DROP TABLE IF EXISTS bigmap_keys;
+DROP TABLE IF EXISTS operation_failures;
+DROP TABLE IF EXISTS raw_storage;
+DROP TABLE IF EXISTS bigmap_owners;
+DROP TABLE IF EXISTS bigmap_meta_actions;
+DROP TABLE IF EXISTS txs;
+DROP TABLE IF EXISTS contract_tx_contexts;
+DROP TABLE IF EXISTS operations;
+",
+            )?;
+        }
         tx.simple_query(
             "
 DROP FUNCTION IF EXISTS last_context_at(INT, INT, INT, INT, INT);
@@ -921,12 +2534,19 @@ DROP FUNCTION IF EXISTS last_context_at(INT, INT);
 DROP FUNCTION IF EXISTS last_context_at(INT);
 DROP TABLE IF EXISTS bigmap_keys;
 DROP TABLE IF EXISTS contract_deps;
+DROP TABLE IF EXISTS operation_failures;
+DROP TABLE IF EXISTS raw_storage;
+DROP TABLE IF EXISTS bigmap_owners;
 DROP TABLE IF EXISTS bigmap_meta_actions;
 DROP VIEW  IF EXISTS txs_ordered;
 DROP TABLE IF EXISTS txs;
 DROP TABLE IF EXISTS tx_contexts;
 DROP TABLE IF EXISTS indexer_state;
 DROP TYPE  IF EXISTS indexer_mode;
+DROP TABLE IF EXISTS bcd_progress;
+DROP TABLE IF EXISTS failed_contracts;
+DROP TABLE IF EXISTS contract_balances;
+DROP TABLE IF EXISTS delegations;
 DROP TABLE IF EXISTS contract_levels;
 DROP TABLE IF EXISTS contracts;
 DROP TABLE IF EXISTS levels;
@@ -936,13 +2556,236 @@ DROP TABLE IF EXISTS levels;
         Ok(())
     }
 
-    pub(crate) fn mark_missing_levels_empty(
+    pub(crate) fn get_contract_id(
         &mut self,
-        contract_id: &ContractID,
-    ) -> Result<u64> {
+        name: &str,
+    ) -> Result<Option<ContractID>> {
         let mut conn = self.dbconn()?;
+        Ok(conn
+            .query_opt(
+                "SELECT name, address FROM contracts WHERE name = $1",
+                &[&name],
+            )?
+            .map(|row| ContractID {
+                name: row.get(0),
+                address: row.get(1),
+            }))
+    }
 
-        Ok(conn.execute(
+    pub(crate) fn delete_contract(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+
+        Self::delete_contract_schema(&mut tx, contract)?;
+        Self::delete_contract_rows(&mut tx, contract, self.shared_tx_contexts)?;
+        tx.execute(
+            "DELETE FROM bcd_progress WHERE contract = $1",
+            &[&contract.cid.name],
+        )?;
+        tx.execute(
+            "DELETE FROM contracts WHERE name = $1",
+            &[&contract.cid.name],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletes everything indexed for the contract (keeping its schema and
+    /// its entry in contracts), so it can be processed again. Of the levels
+    /// it was processed for, only those no other contract was processed for
+    /// are deleted. Returns the levels the contract was processed for.
+    pub(crate) fn delete_contract_data(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<Vec<u32>> {
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+
+        let levels: Vec<i32> = tx
+            .query(
+                "
+SELECT level
+FROM contract_levels
+WHERE contract = $1
+ORDER BY level",
+                &[&contract.cid.name],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        let res = levels
+            .iter()
+            .map(|level| level_from_db(*level))
+            .collect::<Result<Vec<u32>>>()?;
+
+        Self::delete_contract_rows(&mut tx, contract, self.shared_tx_contexts)?;
+        tx.execute(
+            "
+DELETE FROM levels lvl
+WHERE lvl.level = ANY($1)
+  AND NOT EXISTS (
+    SELECT 1 FROM contract_levels clvl WHERE clvl.level = lvl.level
+)",
+            &[&levels],
+        )?;
+        tx.commit()?;
+        Ok(res)
+    }
+
+    // Deletes the contract's rows from the common tables, and (by cascading
+    // from its tx contexts) from its own tables.
+    fn delete_contract_rows(
+        tx: &mut Transaction,
+        contract: &relational::Contract,
+        shared_tx_contexts: bool,
+    ) -> Result<()> {
+        tx.execute(
+            "
+DELETE FROM bigmap_keys
+WHERE tx_context_id IN (
+    SELECT id FROM tx_contexts WHERE contract = $1
+)",
+            &[&contract.cid.address],
+        )?;
+        // note: removing the tx_contexts cascades to txs,
+        // bigmap_meta_actions, bigmap_owners, operation_failures and
+        // raw_storage
+        if shared_tx_contexts {
+            tx.execute(
+                "DELETE FROM contract_tx_contexts WHERE contract = $1",
+                &[&contract.cid.address],
+            )?;
+            tx.execute(
+                "
+DELETE FROM operations op
+WHERE NOT EXISTS (
+    SELECT 1 FROM contract_tx_contexts ctx WHERE ctx.operation_id = op.id
+)",
+                &[],
+            )?;
+        } else {
+            tx.execute(
+                "DELETE FROM tx_contexts WHERE contract = $1",
+                &[&contract.cid.address],
+            )?;
+        }
+        tx.execute(
+            "DELETE FROM contract_deps WHERE dest_schema = $1",
+            &[&contract.cid.name],
+        )?;
+        tx.execute(
+            "DELETE FROM contract_levels WHERE contract = $1",
+            &[&contract.cid.name],
+        )?;
+        tx.execute(
+            "DELETE FROM contract_balances WHERE contract = $1",
+            &[&contract.cid.name],
+        )?;
+        tx.execute(
+            "DELETE FROM delegations WHERE contract = $1",
+            &[&contract.cid.name],
+        )?;
+        Ok(())
+    }
+
+    /// (table name, approximate row count, total size in bytes) of every
+    /// table in the contract's schema, including derived and bigmap tables.
+    /// Row counts are Postgres' estimates, as maintained by (auto)vacuum.
+    pub(crate) fn table_stats(
+        &mut self,
+        contract: &ContractID,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        let mut conn = self.dbconn()?;
+        Ok(conn
+            .query(
+                "
+SELECT
+    c.relname,
+    GREATEST(c.reltuples, 0)::BIGINT,
+    pg_total_relation_size(c.oid)
+FROM pg_class c
+JOIN pg_namespace ns
+  ON ns.oid = c.relnamespace
+WHERE ns.nspname = $1
+  AND c.relkind IN ('r', 'p')
+ORDER BY 3 DESC, 1
+",
+                &[&contract.name],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect())
+    }
+
+    // Records a contract that is skipped because its tables could not be
+    // derived, replacing what was recorded for it before.
+    pub(crate) fn save_failed_contract(
+        &mut self,
+        contract_id: &ContractID,
+        error: &str,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        conn.execute(
+            "
+INSERT INTO failed_contracts (name, address, error)
+VALUES ($1, $2, $3)
+ON CONFLICT (name) DO UPDATE
+SET address = EXCLUDED.address, error = EXCLUDED.error, failed_at = now()",
+            &[&contract_id.name, &contract_id.address, &error],
+        )?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get_failed_contracts(
+        &mut self,
+    ) -> Result<Vec<(ContractID, String)>> {
+        let mut conn = self.dbconn()?;
+        Ok(conn
+            .query(
+                "SELECT name, address, error FROM failed_contracts ORDER BY name",
+                &[],
+            )?
+            .iter()
+            .map(|row| {
+                (
+                    ContractID {
+                        name: row.get(0),
+                        address: row.get(1),
+                    },
+                    row.get(2),
+                )
+            })
+            .collect())
+    }
+
+    /// Forget where the better-call.dev scans of these contracts got to (eg
+    /// because they are done).
+    pub(crate) fn clear_bcd_progress(
+        &mut self,
+        contracts: &[ContractID],
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        conn.execute(
+            "DELETE FROM bcd_progress WHERE contract = ANY($1)",
+            &[&contracts
+                .iter()
+                .map(|c| &c.name)
+                .collect::<Vec<&String>>()],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn mark_missing_levels_empty(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<u64> {
+        let mut conn = self.dbconn()?;
+
+        Ok(conn.execute(
             "
 INSERT INTO contract_levels(contract, level)
 SELECT $1, q.level
@@ -967,11 +2810,54 @@ FROM (
         self.get_level_internal(None)
     }
 
+    // The highest level processed per contract, which may differ from the
+    // db's head (get_head) when some contracts are still catching up.
+    // Contracts without processed levels are left out. The head of each
+    // contract is looked up on its own (through the primary key), rather than
+    // aggregating all of the contracts' levels.
+    pub(crate) fn get_head_per_contract(
+        &mut self,
+        contracts: &[ContractID],
+    ) -> Result<HashMap<ContractID, u32>> {
+        let mut conn = self.dbconn()?;
+
+        let heads: HashMap<String, u32> = conn
+            .query(
+                "
+SELECT
+    c.name,
+    head.level
+FROM unnest($1::TEXT[]) AS c(name)
+CROSS JOIN LATERAL (
+    SELECT level
+    FROM contract_levels
+    WHERE contract = c.name
+    ORDER BY level DESC
+    LIMIT 1
+) head",
+                &[&contracts
+                    .iter()
+                    .map(|c| &c.name)
+                    .collect::<Vec<&String>>()],
+            )?
+            .iter()
+            .map(|row| Ok((row.get(0), level_from_db(row.get(1))?)))
+            .collect::<Result<HashMap<String, u32>>>()?;
+        Ok(contracts
+            .iter()
+            .filter_map(|c| {
+                heads
+                    .get(&c.name)
+                    .map(|level| (c.clone(), *level))
+            })
+            .collect())
+    }
+
     pub(crate) fn get_level(
         &mut self,
         level: u32,
     ) -> Result<Option<LevelMeta>> {
-        self.get_level_internal(Some(level as i32))
+        self.get_level_internal(Some(level_to_db(level)?))
     }
 
     fn get_level_internal(
@@ -983,7 +2869,7 @@ FROM (
         let result = conn.query_opt(
             "
 SELECT
-    level, hash, prev_hash, baked_at
+    level, hash, prev_hash, baked_at, protocol, content_hash
 FROM levels
 WHERE ($1::INTEGER IS NULL AND level = (SELECT max(level) FROM levels)) OR level = $1",
             &[&level],
@@ -998,18 +2884,34 @@ WHERE ($1::INTEGER IS NULL AND level = (SELECT max(level) FROM levels)) OR level
         let hash: Option<String> = row.get(1);
         let prev_hash: Option<String> = row.get(2);
         let baked_at: Option<DateTime<Utc>> = row.get(3);
+        let protocol: Option<String> = row.get(4);
+        let content_hash: Option<String> = row.get(5);
 
         Ok(Some(LevelMeta {
-            level: level as u32,
+            level: level_from_db(level)?,
             hash,
             prev_hash,
             baked_at,
+            protocol,
+            content_hash,
         }))
     }
 
     pub(crate) fn get_missing_levels(
         &mut self,
         contracts: &[ContractID],
+        start_levels: &HashMap<String, u32>,
+        end: u32,
+    ) -> Result<Vec<u32>> {
+        self.get_missing_levels_between(contracts, start_levels, 0, end)
+    }
+
+    // Like get_missing_levels, but only for the levels from `from` onwards
+    pub(crate) fn get_missing_levels_between(
+        &mut self,
+        contracts: &[ContractID],
+        start_levels: &HashMap<String, u32>,
+        from: u32,
         end: u32,
     ) -> Result<Vec<u32>> {
         let mut conn = self.dbconn()?;
@@ -1020,20 +2922,16 @@ WHERE ($1::INTEGER IS NULL AND level = (SELECT max(level) FROM levels)) OR level
                 "querying db to check for any missing levels of {}..",
                 contract_id.name
             );
-            let origination = self.get_origination(contract_id)?;
-            let start = origination.unwrap_or(1);
+            let start = self
+                .missing_levels_start(contract_id, start_levels)?
+                .max(from);
+            if start > end {
+                continue;
+            }
             for row in conn.query(
                 format!(
-                    "
-SELECT
-    s.i
-FROM generate_series({},{}) s(i)
-LEFT JOIN contract_levels clvl
-  ON  clvl.contract = $1
-  AND clvl.level = s.i
-WHERE clvl IS NULL
-ORDER BY 1",
-                    start, end
+                    "{} ORDER BY 1",
+                    Self::missing_levels_sql(1, start, end)
                 )
                 .as_str(),
                 &[&contract_id.name],
@@ -1043,10 +2941,164 @@ ORDER BY 1",
         }
         rows.sort_unstable();
         rows.dedup();
-        Ok(rows
+        rows.into_iter()
+            .map(level_from_db)
+            .collect()
+    }
+
+    // The holes in the levels processed for the contract: the ranges (from,
+    // to, both inclusive) of levels that are missing between the lowest and
+    // the highest level it has processed. Unlike get_missing_levels, this
+    // doesn't look beyond the levels already spanned, so it only reports
+    // levels that were skipped in history that should be complete.
+    pub(crate) fn find_level_gaps(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<Vec<(u32, u32)>> {
+        let mut conn = self.dbconn()?;
+
+        conn.query(
+            "
+WITH span AS (
+    SELECT
+        MIN(level) AS lo,
+        MAX(level) AS hi
+    FROM contract_levels
+    WHERE contract = $1
+), missing AS (
+    SELECT
+        s.i
+    FROM span, generate_series(span.lo, span.hi) s(i)
+    LEFT JOIN contract_levels clvl
+      ON  clvl.contract = $1
+      AND clvl.level = s.i
+    WHERE clvl IS NULL
+)
+SELECT
+    MIN(i),
+    MAX(i)
+FROM (
+    SELECT
+        i,
+        i - ROW_NUMBER() OVER (ORDER BY i) AS grp
+    FROM missing
+) q
+GROUP BY grp
+ORDER BY 1",
+            &[&contract_id.name],
+        )?
+        .iter()
+        .map(|row| Ok((level_from_db(row.get(0))?, level_from_db(row.get(1))?)))
+        .collect()
+    }
+
+    // Returns the lowest missing level, the highest missing level and the
+    // number of missing levels, without fetching the missing levels
+    // themselves (see missing_levels_iter for that).
+    pub(crate) fn get_missing_levels_summary(
+        &mut self,
+        contracts: &[ContractID],
+        start_levels: &HashMap<String, u32>,
+        end: u32,
+    ) -> Result<Option<(u32, u32, usize)>> {
+        if contracts.is_empty() {
+            return Ok(None);
+        }
+        let mut selects: Vec<String> = vec![];
+        for (i, contract_id) in contracts.iter().enumerate() {
+            let start = self.missing_levels_start(contract_id, start_levels)?;
+            selects.push(Self::missing_levels_sql(i + 1, start, end));
+        }
+        let names: Vec<&(dyn postgres::types::ToSql + Sync)> = contracts
+            .iter()
+            .map(|contract_id| {
+                &contract_id.name as &(dyn postgres::types::ToSql + Sync)
+            })
+            .collect();
+
+        let mut conn = self.dbconn()?;
+        let row = conn.query_one(
+            format!(
+                "
+SELECT
+    min(missing.i), max(missing.i), count(DISTINCT missing.i)
+FROM ({}) missing(i)",
+                selects.join("\nUNION ALL\n")
+            )
+            .as_str(),
+            &names,
+        )?;
+        let min: Option<i32> = row.get(0);
+        let max: Option<i32> = row.get(1);
+        let count: i64 = row.get(2);
+        match min.zip(max) {
+            Some((min, max)) => Ok(Some((
+                level_from_db(min)?,
+                level_from_db(max)?,
+                count as usize,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Streams the missing levels in chunks, from the highest missing level to
+    // the lowest, each chunk covering at most chunk_size levels.
+    pub(crate) fn missing_levels_iter(
+        &mut self,
+        contracts: &[ContractID],
+        start_levels: &HashMap<String, u32>,
+        end: u32,
+        chunk_size: u32,
+    ) -> Result<MissingLevelsIter> {
+        let mut starts: Vec<(ContractID, u32)> = vec![];
+        for contract_id in contracts {
+            starts.push((
+                contract_id.clone(),
+                self.missing_levels_start(contract_id, start_levels)?,
+            ));
+        }
+        let floor = starts
             .iter()
-            .map(|x| *x as u32)
-            .collect::<Vec<u32>>())
+            .map(|(_, start)| *start)
+            .min()
+            .unwrap_or_else(|| end.saturating_add(1));
+        Ok(MissingLevelsIter {
+            dbcli: self.clone(),
+            starts,
+            floor,
+            cursor: end,
+            chunk_size: chunk_size.max(1),
+        })
+    }
+
+    fn missing_levels_start(
+        &mut self,
+        contract_id: &ContractID,
+        start_levels: &HashMap<String, u32>,
+    ) -> Result<u32> {
+        let origination = self.get_origination(contract_id)?;
+        Ok(origination.unwrap_or(1).max(
+            start_levels
+                .get(&contract_id.name)
+                .copied()
+                .unwrap_or(1),
+        ))
+    }
+
+    fn missing_levels_sql(contract_ref: usize, start: u32, end: u32) -> String {
+        format!(
+            "
+SELECT
+    s.i
+FROM generate_series({start},{end}) s(i)
+LEFT JOIN contract_levels clvl
+  ON  clvl.contract = ${contract_ref}
+  AND clvl.level = s.i
+WHERE clvl IS NULL",
+            start = start,
+            end = end,
+            contract_ref = contract_ref,
+        )
     }
 
     pub(crate) fn get_forked_levels(&mut self) -> Result<Vec<u32>> {
@@ -1072,10 +3124,9 @@ WHERE chain_prev_hash != db_prev_hash
         )? {
             rows.push(row.get(0));
         }
-        Ok(rows
-            .iter()
-            .map(|x| *x as u32)
-            .collect::<Vec<u32>>())
+        rows.into_iter()
+            .map(level_from_db)
+            .collect::<Result<Vec<u32>>>()
     }
 
     pub(crate) fn get_indexer_mode(&mut self) -> Result<IndexerMode> {
@@ -1105,6 +3156,49 @@ set mode = $1",
         }
     }
 
+    pub(crate) fn get_chain_id(&mut self) -> Result<Option<String>> {
+        let mut conn = self.dbconn()?;
+
+        let chain_id: Option<String> = conn
+            .query_one("select chain_id from indexer_state", &[])?
+            .get(0);
+        Ok(chain_id)
+    }
+
+    pub(crate) fn set_chain_id(&mut self, chain_id: &str) -> Result<()> {
+        let mut conn = self.dbconn()?;
+
+        let updated = conn.execute(
+            "
+update indexer_state
+set chain_id = $1",
+            &[&chain_id],
+        )?;
+        if updated == 1 {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "wrong number of rows in indexer_state table. please fix manually. sorry"
+            ))
+        }
+    }
+
+    // Errors when the db was set up for another chain than the node's (eg
+    // when pointing a db bootstrapped on a testnet at a mainnet node). A db
+    // that does not know its chain yet (set up before the chain was stored)
+    // takes the node's.
+    pub(crate) fn check_chain_id(&mut self, node_chain_id: &str) -> Result<()> {
+        match self.get_chain_id()? {
+            Some(db_chain_id) if db_chain_id != node_chain_id => Err(anyhow!(
+                "the db was set up for chain {}, but the node is on chain {}",
+                db_chain_id,
+                node_chain_id
+            )),
+            Some(_) => Ok(()),
+            None => self.set_chain_id(node_chain_id),
+        }
+    }
+
     pub(crate) fn get_max_id(&mut self) -> Result<i64> {
         let mut conn = self.dbconn()?;
 
@@ -1114,11 +3208,13 @@ set mode = $1",
         Ok(max_id)
     }
 
+    // Never lowers max_id: concurrent inserters may commit their batches out
+    // of order of the ids handed out to them.
     pub(crate) fn set_max_id(tx: &mut Transaction, max_id: i64) -> Result<()> {
         let updated = tx.execute(
             "
 update indexer_state
-set max_id = $1",
+set max_id = greatest(max_id, $1)",
             &[&max_id],
         )?;
         if updated == 1 {
@@ -1153,8 +3249,8 @@ ORDER by 1",
             )?
             .iter()
             .map(|row| row.get(0))
-            .map(|lvl: i32| lvl as u32)
-            .collect();
+            .map(level_from_db)
+            .collect::<Result<Vec<u32>>>()?;
         Ok(fully_processed)
     }
 
@@ -1195,25 +3291,36 @@ order by 1",
             )?
             .iter()
             .map(|row| row.get(0))
-            .map(|lvl: i32| lvl as u32)
-            .collect();
+            .map(level_from_db)
+            .collect::<Result<Vec<u32>>>()?;
         Ok(partial_processed)
     }
 
+    // Replaces the levels, unless keep_existing is set: then levels already
+    // in the db are left in place, except for their content hash, which no
+    // longer covers everything at the level.
     pub(crate) fn save_levels(
         tx: &mut Transaction,
         levels: &[&LevelMeta],
+        keep_existing: bool,
     ) -> Result<()> {
-        Self::delete_levels(
-            tx,
-            &levels
-                .iter()
-                .map(|meta| meta.level as i32)
-                .collect::<Vec<i32>>(),
-        )?;
+        if !keep_existing {
+            Self::delete_levels(
+                tx,
+                &levels
+                    .iter()
+                    .map(|meta| level_to_db(meta.level))
+                    .collect::<Result<Vec<i32>>>()?,
+            )?;
+        }
+        let on_conflict = if keep_existing {
+            "ON CONFLICT (level) DO UPDATE SET content_hash = NULL"
+        } else {
+            ""
+        };
 
         for lvls_chunk in levels.chunks(Self::INSERT_BATCH_SIZE) {
-            let num_columns = 4;
+            let num_columns = 6;
             let v_refs = (1..(num_columns * lvls_chunk.len()) + 1)
                 .map(|i| format!("${}", i))
                 .collect::<Vec<String>>()
@@ -1223,10 +3330,11 @@ order by 1",
             let stmt = tx.prepare(&format!(
                 "
 INSERT INTO levels(
-    level, hash, prev_hash, baked_at
+    level, hash, prev_hash, baked_at, protocol, content_hash
 )
-VALUES ( {} )",
-                v_refs
+VALUES ( {} )
+{}",
+                v_refs, on_conflict
             ))?;
 
             #[allow(clippy::type_complexity)]
@@ -1235,28 +3343,43 @@ VALUES ( {} )",
                 Option<String>,
                 Option<String>,
                 Option<DateTime<Utc>>,
+                Option<String>,
+                Option<String>,
             )> = lvls_chunk
                 .iter()
                 .map(|m| {
-                    (
-                        m.level as i32,
+                    Ok((
+                        level_to_db(m.level)?,
                         m.hash.clone(),
                         m.prev_hash.clone(),
                         m.baked_at,
-                    )
+                        m.protocol.clone(),
+                        m.content_hash.clone(),
+                    ))
                 })
-                .collect();
+                .collect::<Result<_>>()?;
 
             let values: Vec<&dyn postgres::types::ToSql> = v_
                 .iter()
-                .flat_map(|(lvl, hash, prev_hash, baked_at)| {
-                    [
-                        lvl.borrow_to_sql(),
-                        hash.borrow_to_sql(),
-                        prev_hash.borrow_to_sql(),
-                        baked_at.borrow_to_sql(),
-                    ]
-                })
+                .flat_map(
+                    |(
+                        lvl,
+                        hash,
+                        prev_hash,
+                        baked_at,
+                        protocol,
+                        content_hash,
+                    )| {
+                        [
+                            lvl.borrow_to_sql(),
+                            hash.borrow_to_sql(),
+                            prev_hash.borrow_to_sql(),
+                            baked_at.borrow_to_sql(),
+                            protocol.borrow_to_sql(),
+                            content_hash.borrow_to_sql(),
+                        ]
+                    },
+                )
                 .collect();
 
             tx.query_raw(&stmt, values)?;
@@ -1306,34 +3429,77 @@ WHERE level IN ( {} )
                 .collect();
             let stmt = tx.prepare(&format!(
                 "
-DELETE FROM levels
+DELETE FROM contract_balances
 WHERE level IN ( {} )
 ",
                 v_refs
             ))?;
             tx.query_raw(&stmt, values)?;
-        }
-        Ok(())
-    }
 
-    pub(crate) fn save_contract_levels(
-        tx: &mut Transaction,
-        clvls: &[(ContractID, i32, bool)],
-    ) -> Result<()> {
-        for clvls_chunk in clvls.chunks(Self::INSERT_BATCH_SIZE) {
-            let num_columns = 3;
-            let v_refs = (1..(num_columns * clvls_chunk.len()) + 1)
-                .map(|i| format!("${}", i))
-                .collect::<Vec<String>>()
-                .chunks(num_columns)
-                .map(|x| x.join(", "))
-                .join("), (");
+            let values: Vec<&dyn postgres::types::ToSql> = lvls_chunk
+                .iter()
+                .map(|level| level.borrow_to_sql())
+                .collect();
+            let stmt = tx.prepare(&format!(
+                "
+DELETE FROM delegations
+WHERE level IN ( {} )
+",
+                v_refs
+            ))?;
+            tx.query_raw(&stmt, values)?;
+
+            let values: Vec<&dyn postgres::types::ToSql> = lvls_chunk
+                .iter()
+                .map(|level| level.borrow_to_sql())
+                .collect();
+            let stmt = tx.prepare(&format!(
+                "
+DELETE FROM levels
+WHERE level IN ( {} )
+",
+                v_refs
+            ))?;
+            tx.query_raw(&stmt, values)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_contract_levels(
+        tx: &mut Transaction,
+        clvls: &[(ContractID, i32, bool)],
+    ) -> Result<()> {
+        // a (contract, level) can only be marked once (the upsert below
+        // can't touch the same row twice in one statement), it's an
+        // origination level if any of its entries says so
+        let mut merged: Vec<(&ContractID, i32, bool)> = vec![];
+        let mut positions: HashMap<(&str, i32), usize> = HashMap::new();
+        for (contract, level, is_origination) in clvls {
+            match positions.get(&(contract.name.as_str(), *level)) {
+                Some(i) => merged[*i].2 |= is_origination,
+                None => {
+                    positions
+                        .insert((contract.name.as_str(), *level), merged.len());
+                    merged.push((contract, *level, *is_origination));
+                }
+            }
+        }
+        for clvls_chunk in merged.chunks(Self::INSERT_BATCH_SIZE) {
+            let num_columns = 3;
+            let v_refs = (1..(num_columns * clvls_chunk.len()) + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>()
+                .chunks(num_columns)
+                .map(|x| x.join(", "))
+                .join("), (");
             let stmt = tx.prepare(&format!(
                 "
 INSERT INTO contract_levels(
     contract, level, is_origination
 )
-VALUES ( {} )",
+VALUES ( {} )
+ON CONFLICT (contract, level) DO UPDATE
+SET is_origination = contract_levels.is_origination OR EXCLUDED.is_origination",
                 v_refs
             ))?;
 
@@ -1353,12 +3519,93 @@ VALUES ( {} )",
         Ok(())
     }
 
+    pub(crate) fn save_contract_balances(
+        tx: &mut Transaction,
+        balances: &[(ContractID, i32, PgNumeric)],
+    ) -> Result<()> {
+        for balances_chunk in balances.chunks(Self::INSERT_BATCH_SIZE) {
+            let num_columns = 3;
+            let v_refs = (1..(num_columns * balances_chunk.len()) + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>()
+                .chunks(num_columns)
+                .map(|x| x.join(", "))
+                .join("), (");
+            let stmt = tx.prepare(&format!(
+                "
+INSERT INTO contract_balances(
+    contract, level, balance
+)
+VALUES ( {} )",
+                v_refs
+            ))?;
+
+            let values: Vec<&dyn postgres::types::ToSql> = balances_chunk
+                .iter()
+                .flat_map(|(contract, level, balance)| {
+                    [
+                        contract.name.borrow_to_sql(),
+                        level.borrow_to_sql(),
+                        balance.borrow_to_sql(),
+                    ]
+                })
+                .collect();
+
+            tx.query_raw(&stmt, values)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_delegations(
+        tx: &mut Transaction,
+        delegations: &[(ContractID, Delegation)],
+    ) -> Result<()> {
+        for delegations_chunk in delegations.chunks(Self::INSERT_BATCH_SIZE) {
+            let num_columns = 5;
+            let v_refs = (1..(num_columns * delegations_chunk.len()) + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>()
+                .chunks(num_columns)
+                .map(|x| x.join(", "))
+                .join("), (");
+            let stmt = tx.prepare(&format!(
+                "
+INSERT INTO delegations(
+    contract, level, operation_hash, source, delegate
+)
+VALUES ( {} )",
+                v_refs
+            ))?;
+
+            let levels = delegations_chunk
+                .iter()
+                .map(|(_, d)| level_to_db(d.level))
+                .collect::<Result<Vec<i32>>>()?;
+            let values: Vec<&dyn postgres::types::ToSql> = delegations_chunk
+                .iter()
+                .zip(levels.iter())
+                .flat_map(|((contract, d), level)| {
+                    [
+                        contract.name.borrow_to_sql(),
+                        level.borrow_to_sql(),
+                        d.operation_hash.borrow_to_sql(),
+                        d.source.borrow_to_sql(),
+                        d.delegate.borrow_to_sql(),
+                    ]
+                })
+                .collect();
+
+            tx.query_raw(&stmt, values)?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn save_contract_deps(
         tx: &mut Transaction,
-        deps: &[(i32, String, ContractID, bool)],
+        deps: &[(i32, String, i32, ContractID, bool)],
     ) -> Result<()> {
         for deps_chunk in deps.chunks(Self::INSERT_BATCH_SIZE) {
-            let num_columns = 4;
+            let num_columns = 5;
             let v_refs = (1..(num_columns * deps_chunk.len()) + 1)
                 .map(|i| format!("${}", i))
                 .collect::<Vec<String>>()
@@ -1367,7 +3614,7 @@ VALUES ( {} )",
                 .join("), (");
             let stmt = tx.prepare(&format!(
                 "
-INSERT INTO contract_deps (level, src_contract, dest_schema, is_deep_copy)
+INSERT INTO contract_deps (level, src_contract, src_bigmap, dest_schema, is_deep_copy)
 VALUES ( {} )
 ON CONFLICT DO NOTHING",
                 v_refs
@@ -1375,14 +3622,17 @@ ON CONFLICT DO NOTHING",
 
             let values: Vec<&dyn postgres::types::ToSql> = deps_chunk
                 .iter()
-                .flat_map(|(level, src_addr, dest, is_deep_copy)| {
-                    [
-                        level.borrow_to_sql(),
-                        src_addr.borrow_to_sql(),
-                        dest.name.borrow_to_sql(),
-                        is_deep_copy.borrow_to_sql(),
-                    ]
-                })
+                .flat_map(
+                    |(level, src_addr, src_bigmap, dest, is_deep_copy)| {
+                        [
+                            level.borrow_to_sql(),
+                            src_addr.borrow_to_sql(),
+                            src_bigmap.borrow_to_sql(),
+                            dest.name.borrow_to_sql(),
+                            is_deep_copy.borrow_to_sql(),
+                        ]
+                    },
+                )
                 .collect();
 
             tx.query_raw(&stmt, values)?;
@@ -1390,29 +3640,238 @@ ON CONFLICT DO NOTHING",
         Ok(())
     }
 
+    // A contract can have been originated more than once at the same
+    // address (eg across a reorg), by default its first origination level is
+    // returned, or its most recent one if use_latest_origination is set.
     pub(crate) fn get_origination(
         &mut self,
         contract_id: &ContractID,
     ) -> Result<Option<u32>> {
         let mut conn = self.dbconn()?;
 
-        let result = conn.query(
-            "
+        let level: Option<i32> = conn
+            .query_one(
+                format!(
+                    "
 SELECT
-    level
+    {}(level)
 FROM contract_levels
 WHERE contract = $1
   AND is_origination = TRUE",
-            &[&contract_id.name],
-        )?;
-        if result.is_empty() {
-            Ok(None)
-        } else if result.len() == 1 {
-            let level: i32 = result[0].get(0);
-            Ok(Some(level as u32))
-        } else {
-            Err(anyhow!("Too many results for get_origination"))
+                    if self.latest_origination {
+                        "MAX"
+                    } else {
+                        "MIN"
+                    }
+                )
+                .as_str(),
+                &[&contract_id.name],
+            )?
+            .get(0);
+        level.map(level_from_db).transpose()
+    }
+}
+
+struct TxContextPG {
+    id: i64,
+    level: i32,
+    contract: String,
+    operation_group_number: i32,
+    operation_number: i32,
+    content_number: i32,
+    internal_number: Option<i32>,
+}
+
+impl TxContextPG {
+    fn from_chunk(tx_contexts: &[TxContext]) -> Result<Vec<Self>> {
+        tx_contexts
+            .iter()
+            .map(|tx_context| {
+                Ok(Self {
+                    id: tx_context
+                        .id
+                        .ok_or_else(|| anyhow!("Missing ID on TxContext"))?,
+                    level: level_to_db(tx_context.level)?,
+                    contract: tx_context.contract.clone(),
+                    operation_group_number: tx_context.operation_group_number
+                        as i32,
+                    operation_number: tx_context.operation_number as i32,
+                    content_number: tx_context.content_number as i32,
+                    internal_number: tx_context
+                        .internal_number
+                        .map(|n| n as i32),
+                })
+            })
+            .collect()
+    }
+
+    fn sql_values(tx_contexts: &[Self]) -> Vec<&dyn postgres::types::ToSql> {
+        tx_contexts
+            .iter()
+            .flat_map(|tx_context| {
+                [
+                    tx_context.id.borrow_to_sql(),
+                    tx_context.level.borrow_to_sql(),
+                    tx_context.contract.borrow_to_sql(),
+                    tx_context
+                        .operation_group_number
+                        .borrow_to_sql(),
+                    tx_context
+                        .operation_number
+                        .borrow_to_sql(),
+                    tx_context
+                        .content_number
+                        .borrow_to_sql(),
+                    tx_context
+                        .internal_number
+                        .borrow_to_sql(),
+                ]
+            })
+            .collect()
+    }
+}
+
+// Levels are stored as INTEGER (i32) in the db, so they must be converted
+// explicitly rather than silently wrapping around.
+pub(crate) fn level_to_db(level: u32) -> Result<i32> {
+    i32::try_from(level).map_err(|_| {
+        anyhow!(
+            "level {} exceeds the range of levels that can be stored in the db (max {})",
+            level,
+            i32::MAX
+        )
+    })
+}
+
+pub(crate) fn level_from_db(level: i32) -> Result<u32> {
+    u32::try_from(level)
+        .map_err(|_| anyhow!("bad level {} in the db (negative)", level))
+}
+
+// Connections are tagged with the role and the thread that uses them, eg
+// "que-pasa processor-2 ThreadId(7)". Postgres truncates application names
+// beyond 63 bytes.
+fn application_name(role: &str) -> String {
+    format!("que-pasa {} {:?}", role, std::thread::current().id())
+}
+
+const RETRY_BACKOFF_INITIAL: Duration = Duration::from_millis(500);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+// Calls f until it succeeds, up to retries times more after the first attempt
+// (or infinitely if retries is smaller than 0), for as long as the errors are
+// accepted by should_retry. Waits in between attempts, doubling the wait
+// after each attempt.
+pub(crate) fn retry_with_backoff<T, P, F>(
+    descr: &str,
+    retries: i32,
+    should_retry: P,
+    mut f: F,
+) -> Result<T>
+where
+    P: Fn(&anyhow::Error) -> bool,
+    F: FnMut() -> Result<T>,
+{
+    let mut wait = RETRY_BACKOFF_INITIAL;
+    let mut attempt = 0;
+    loop {
+        let err = match f() {
+            Ok(res) => return Ok(res),
+            Err(err) => err,
+        };
+        if (retries >= 0 && attempt >= retries) || !should_retry(&err) {
+            return Err(err);
+        }
+        attempt += 1;
+        warn!(
+            "failed to {} (attempt {}), retrying in {:?}.. err: {:?}",
+            descr, attempt, wait, err
+        );
+        std::thread::sleep(wait);
+        wait = std::cmp::min(wait * 2, RETRY_BACKOFF_MAX);
+    }
+}
+
+// Returns true for errors of connecting to the db that retrying won't resolve:
+// the credentials are refused, or the database does not exist.
+fn is_fatal_conn_err(err: &postgres::Error) -> bool {
+    match err.code() {
+        Some(code) => {
+            code.code().starts_with("28")
+                || *code == postgres::error::SqlState::INVALID_CATALOG_NAME
+        }
+        None => false,
+    }
+}
+
+// Returns true for errors that are due to losing the connection to the db
+// (eg because it restarted), rather than due to the statements executed.
+pub(crate) fn is_conn_err(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(pg_err) = cause.downcast_ref::<postgres::Error>() {
+            return pg_err.is_closed()
+                || matches!(
+                    std::error::Error::source(pg_err),
+                    Some(src) if src.is::<std::io::Error>()
+                );
+        }
+        cause.is::<std::io::Error>() || cause.is::<r2d2::Error>()
+    })
+}
+
+pub(crate) struct MissingLevelsIter {
+    dbcli: DBClient,
+    starts: Vec<(ContractID, u32)>,
+    floor: u32,
+    cursor: u32,
+    chunk_size: u32,
+}
+
+impl MissingLevelsIter {
+    fn next_chunk(&mut self, start: u32, end: u32) -> Result<Vec<u32>> {
+        let mut conn = self.dbcli.dbconn()?;
+        let mut rows: Vec<i32> = vec![];
+        for (contract_id, contract_start) in &self.starts {
+            if *contract_start > end {
+                continue;
+            }
+            for row in conn.query(
+                DBClient::missing_levels_sql(
+                    1,
+                    start.max(*contract_start),
+                    end,
+                )
+                .as_str(),
+                &[&contract_id.name],
+            )? {
+                rows.push(row.get(0));
+            }
+        }
+        rows.sort_unstable_by(|a, b| b.cmp(a));
+        rows.dedup();
+        rows.into_iter()
+            .map(level_from_db)
+            .collect()
+    }
+}
+
+impl Iterator for MissingLevelsIter {
+    type Item = Result<Vec<u32>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.cursor >= self.floor && self.cursor > 0 {
+            let end = self.cursor;
+            let start = end
+                .saturating_sub(self.chunk_size - 1)
+                .max(self.floor);
+            self.cursor = start - 1;
+
+            match self.next_chunk(start, end) {
+                Ok(chunk) if chunk.is_empty() => continue,
+                res => return Some(res),
+            }
         }
+        None
     }
 }
 
@@ -1423,30 +3882,2484 @@ pub(crate) type BigmapEntries = HashMap<
 pub(crate) type BigmapEntry =
     (String, serde_json::Value, Option<serde_json::Value>);
 
+/// Where the better-call.dev scan of a contract's levels got to, so that an
+/// interrupted scan can be resumed instead of restarted.
+pub(crate) trait BcdProgressStore {
+    fn get_bcd_last_id(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<Option<String>>;
+
+    fn set_bcd_last_id(
+        &mut self,
+        contract_id: &ContractID,
+        last_id: &str,
+    ) -> Result<()>;
+
+    // whether all given levels are processed (and committed) for the contract
+    fn levels_processed(
+        &mut self,
+        contract_id: &ContractID,
+        levels: &[u32],
+    ) -> Result<bool>;
+}
+
+impl BcdProgressStore for DBClient {
+    fn get_bcd_last_id(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<Option<String>> {
+        let mut conn = self.dbconn()?;
+        Ok(conn
+            .query_opt(
+                "SELECT last_id FROM bcd_progress WHERE contract = $1",
+                &[&contract_id.name],
+            )?
+            .map(|row| row.get(0)))
+    }
+
+    fn set_bcd_last_id(
+        &mut self,
+        contract_id: &ContractID,
+        last_id: &str,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        conn.execute(
+            "
+INSERT INTO bcd_progress (contract, last_id)
+VALUES ($1, $2)
+ON CONFLICT (contract) DO UPDATE
+SET last_id = EXCLUDED.last_id
+",
+            &[&contract_id.name, &last_id],
+        )?;
+        Ok(())
+    }
+
+    fn levels_processed(
+        &mut self,
+        contract_id: &ContractID,
+        levels: &[u32],
+    ) -> Result<bool> {
+        let levels = levels
+            .iter()
+            .map(|l| level_to_db(*l))
+            .collect::<Result<Vec<i32>>>()?;
+        let mut conn = self.dbconn()?;
+        let missing: i64 = conn
+            .query_one(
+                "
+SELECT
+    COUNT(1)
+FROM UNNEST($2::INTEGER[]) AS lvl(level)
+WHERE NOT EXISTS (
+    SELECT 1
+    FROM contract_levels clvl
+    WHERE clvl.contract = $1
+      AND clvl.level = lvl.level
+)",
+                &[&contract_id.name, &levels],
+            )?
+            .get(0);
+        Ok(missing == 0)
+    }
+}
+
+// (level, operation group, operation, content, internal) of a tx context,
+// which orders tx contexts by execution
+type TxContextOrdering = (i32, i32, i32, i32, i32);
+
+fn tx_context_ordering(row: &postgres::Row, from: usize) -> TxContextOrdering {
+    (
+        row.get(from),
+        row.get(from + 1),
+        row.get(from + 2),
+        row.get(from + 3),
+        row.get::<_, Option<i32>>(from + 4)
+            .unwrap_or(-1),
+    )
+}
+
+// Reads a tx context from the row's columns id, level,
+// operation_group_number, operation_number, content_number and
+// internal_number, starting at column from.
+fn tx_context_from_row(
+    row: &postgres::Row,
+    from: usize,
+    contract: &str,
+) -> Result<TxContext> {
+    let operation_group_number: i32 = row.get(from + 2);
+    let operation_number: i32 = row.get(from + 3);
+    let content_number: i32 = row.get(from + 4);
+    Ok(TxContext {
+        id: Some(row.get(from)),
+        contract: contract.to_string(),
+        level: level_from_db(row.get(from + 1))?,
+        operation_group_number: usize::try_from(operation_group_number)?,
+        operation_number: usize::try_from(operation_number)?,
+        content_number: usize::try_from(content_number)?,
+        internal_number: row.get(from + 5),
+    })
+}
+
+// Tezos reuses the ids of removed bigmaps, so the keys stored under an id can
+// belong to multiple incarnations of it. Only those set since the latest
+// allocation belong to the bigmap that currently has the id.
+fn keys_since_alloc(
+    keys: Vec<(TxContextOrdering, BigmapEntry)>,
+    alloc: Option<TxContextOrdering>,
+) -> Vec<BigmapEntry> {
+    keys.into_iter()
+        .filter(|(ordering, _)| match alloc {
+            Some(alloc) => *ordering >= alloc,
+            None => true,
+        })
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
 pub(crate) trait BigmapKeysGetter {
+    // the keys of the bigmap's incarnation that was live at given level
     fn get(&mut self, level: u32, bigmap_id: i32) -> Result<Vec<BigmapEntry>>;
+
+    // the table the bigmap was last allocated in (if its allocation was
+    // indexed)
+    fn get_table(&mut self, bigmap_id: i32) -> Result<Option<String>>;
 }
 
 impl BigmapKeysGetter for DBClient {
     fn get(&mut self, level: u32, bigmap_id: i32) -> Result<Vec<BigmapEntry>> {
         let mut conn = self.dbconn()?;
+        let level = level_to_db(level)?;
+        let alloc = conn
+            .query_opt(
+                "
+SELECT
+    ctx.level,
+    ctx.operation_group_number,
+    ctx.operation_number,
+    ctx.content_number,
+    ctx.internal_number
+FROM bigmap_meta_actions action
+JOIN tx_contexts ctx
+  ON ctx.id = action.tx_context_id
+WHERE action.bigmap_id = $1
+  AND action.action = 'alloc'
+  AND ctx.level <= $2
+ORDER BY
+    ctx.level DESC,
+    ctx.operation_group_number DESC,
+    ctx.operation_number DESC,
+    ctx.content_number DESC,
+    COALESCE(ctx.internal_number, -1) DESC
+LIMIT 1
+",
+                &[&bigmap_id, &level],
+            )?
+            .map(|row| tx_context_ordering(&row, 0));
         let res = conn.query(
             "
 SELECT
     keyhash,
     key,
-    value
+    value,
+    ctx.level,
+    ctx.operation_group_number,
+    ctx.operation_number,
+    ctx.content_number,
+    ctx.internal_number
 FROM bigmap_keys bigmap
 JOIN tx_contexts ctx
   ON ctx.id = bigmap.tx_context_id
 WHERE bigmap_id = $1
   AND ctx.level <= $2
 ",
-            &[&bigmap_id, &(level as i32)],
+            &[&bigmap_id, &level],
         )?;
-        Ok(res
-            .into_iter()
-            .map(|row| (row.get(0), row.get(1), row.get(2)))
-            .collect::<Vec<BigmapEntry>>())
+        Ok(keys_since_alloc(
+            res.into_iter()
+                .map(|row| {
+                    (
+                        tx_context_ordering(&row, 3),
+                        (row.get(0), row.get(1), row.get(2)),
+                    )
+                })
+                .collect(),
+            alloc,
+        ))
     }
+
+    fn get_table(&mut self, bigmap_id: i32) -> Result<Option<String>> {
+        let mut conn = self.dbconn()?;
+        let res = conn.query_opt(
+            "
+SELECT
+    action.value->>'table'
+FROM bigmap_meta_actions action
+JOIN tx_contexts ctx
+  ON ctx.id = action.tx_context_id
+WHERE action.bigmap_id = $1
+  AND action.action = 'alloc'
+ORDER BY
+    ctx.level DESC,
+    ctx.operation_group_number DESC,
+    ctx.operation_number DESC,
+    ctx.content_number DESC,
+    COALESCE(ctx.internal_number, -1) DESC
+LIMIT 1
+",
+            &[&bigmap_id],
+        )?;
+        Ok(res.and_then(|row| row.get(0)))
+    }
+}
+
+#[test]
+fn test_application_name() {
+    let name = application_name("processor-12");
+    assert!(name.starts_with("que-pasa processor-12 ThreadId("));
+    assert!(name.len() <= 63);
+    assert!(name
+        .chars()
+        .all(|c| c.is_ascii() && c != '\''));
+}
+
+#[test]
+fn test_retry_with_backoff() {
+    use std::cell::Cell;
+
+    fn conn_lost() -> anyhow::Error {
+        anyhow::Error::from(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "connection reset",
+        ))
+        .context("failed to insert")
+    }
+
+    // connection is lost for the first 2 attempts, then it is restored
+    let attempts = Cell::new(0);
+    let res = retry_with_backoff("test", 3, is_conn_err, || {
+        attempts.set(attempts.get() + 1);
+        if attempts.get() <= 2 {
+            return Err(conn_lost());
+        }
+        Ok(attempts.get())
+    });
+    assert_eq!(res.unwrap(), 3);
+
+    // retries are bounded
+    let attempts = Cell::new(0);
+    let res: Result<()> = retry_with_backoff("test", 1, is_conn_err, || {
+        attempts.set(attempts.get() + 1);
+        Err(conn_lost())
+    });
+    assert!(res.is_err());
+    assert_eq!(attempts.get(), 2);
+
+    // errors unrelated to the connection are not retried
+    let attempts = Cell::new(0);
+    let res: Result<()> = retry_with_backoff("test", 3, is_conn_err, || {
+        attempts.set(attempts.get() + 1);
+        Err(anyhow!("duplicate key value violates unique constraint"))
+    });
+    assert!(res.is_err());
+    assert_eq!(attempts.get(), 1);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_dbconn_fatal_errors() {
+    let url = match test_db_url() {
+        Some(url) => url,
+        None => return,
+    };
+    let db = "quepasa_test_dbconn_fatal_errors";
+    let mut admin = postgres::Client::connect(&url, NoTls).unwrap();
+    let drop_db = format!(r#"DROP DATABASE IF EXISTS "{}" WITH (FORCE)"#, db);
+    admin.simple_query(&drop_db).unwrap();
+    admin
+        .simple_query(&format!(r#"CREATE DATABASE "{}""#, db))
+        .unwrap();
+
+    let (base_url, _) = url.rsplit_once('/').unwrap();
+    let dbcli = DBClient::connect(
+        &format!("{}/{}", base_url, db),
+        "public",
+        Duration::from_secs(1),
+        2,
+        3,
+    )
+    .unwrap();
+    assert!(dbcli.dbconn().is_ok());
+
+    // the database is gone, retrying won't bring it back
+    admin.simple_query(&drop_db).unwrap();
+    let start = std::time::Instant::now();
+    let err = dbcli.dbconn().err().unwrap();
+    assert!(format!("{:?}", err).contains("does not exist"), "{:?}", err);
+    assert!(!is_conn_err(&err));
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn test_level_conversions() {
+    assert_eq!(level_to_db(0).unwrap(), 0);
+    assert_eq!(level_to_db(i32::MAX as u32).unwrap(), i32::MAX);
+
+    let res = level_to_db(u32::MAX - 1);
+    assert!(res.is_err());
+    assert!(res
+        .unwrap_err()
+        .to_string()
+        .contains(&(u32::MAX - 1).to_string()));
+
+    assert_eq!(level_from_db(i32::MAX).unwrap(), i32::MAX as u32);
+    assert!(level_from_db(-1).is_err());
+}
+
+#[test]
+fn test_keys_since_alloc() {
+    let key = |keyhash: &str| -> BigmapEntry {
+        (
+            keyhash.to_string(),
+            serde_json::json!({ "string": keyhash }),
+            Some(serde_json::json!({ "int": "1" })),
+        )
+    };
+    // bigmap 5 is allocated at level 1 and gets keys A and B, it's cleared
+    // and removed at level 3, after which id 5 is reused by a new allocation
+    // at level 4 (in the same operation that sets key C)
+    let keys = vec![
+        ((2, 0, 0, 0, -1), key("exprA")),
+        ((2, 1, 0, 0, 0), key("exprB")),
+        ((4, 0, 1, 0, 2), key("exprC")),
+    ];
+
+    assert_eq!(
+        vec![key("exprA"), key("exprB"), key("exprC")],
+        keys_since_alloc(keys.clone(), None)
+    );
+    assert_eq!(
+        vec![key("exprA"), key("exprB")],
+        keys_since_alloc(keys[..2].to_vec(), Some((1, 0, 0, 0, -1)))
+    );
+    assert_eq!(
+        vec![key("exprC")],
+        keys_since_alloc(keys, Some((4, 0, 1, 0, -1)))
+    );
+}
+
+// The db tests need a Postgres db to run against, set through
+// QUEPASA_TEST_DATABASE_URL (they are skipped when it is not set).
+#[cfg(test)]
+pub(crate) fn test_db_url() -> Option<String> {
+    std::env::var("QUEPASA_TEST_DATABASE_URL").ok()
+}
+
+// A client of the test db (see test_db_url), with the common tables set up
+// in a schema of the test's own. The schema (and that of the test's
+// contract, see test_contract) is dropped first in case an earlier run
+// failed halfway, and is dropped again with drop_test_db.
+#[cfg(test)]
+pub(crate) fn test_db(schema: &str) -> Option<DBClient> {
+    let url = test_db_url()?;
+    let mut dbcli =
+        DBClient::connect(&url, schema, Duration::from_secs(10), 2, 0).unwrap();
+    drop_test_db(&dbcli, schema);
+    dbcli
+        .create_common_tables(false)
+        .unwrap();
+    Some(dbcli)
+}
+
+// A new client of the test db that test_db set up, as when que-pasa is
+// started again (eg after an upgrade).
+#[cfg(test)]
+pub(crate) fn test_db_restart(schema: &str) -> DBClient {
+    let url = test_db_url().unwrap();
+    DBClient::connect(&url, schema, Duration::from_secs(10), 2, 0).unwrap()
+}
+
+#[cfg(test)]
+pub(crate) fn drop_test_db(dbcli: &DBClient, schema: &str) {
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(&format!(
+            r#"DROP SCHEMA IF EXISTS "{}" CASCADE; DROP SCHEMA IF EXISTS "{}_contract" CASCADE"#,
+            schema, schema
+        ))
+        .unwrap();
+}
+
+// A contract (with a schema named after the test's, see test_db) with a
+// storage of the given type.
+#[cfg(test)]
+pub(crate) fn test_contract(
+    schema: &str,
+    type_json: serde_json::Value,
+) -> relational::Contract {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+
+    relational::Contract {
+        cid: ContractID {
+            name: format!("{}_contract", schema),
+            address: "KT1test".to_string(),
+        },
+        storage_ast: ASTBuilder::new("storage")
+            .build_relational_ast(
+                &typing::type_ast_from_json(&type_json).unwrap(),
+            )
+            .unwrap(),
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: Default::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    }
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_find_by_operation_hash() {
+    let schema = "test_find_by_operation_hash";
+    let dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let tx_context =
+        |id: i64, contract: &str, internal: Option<i32>| TxContext {
+            id: Some(id),
+            contract: contract.to_string(),
+            level: 10,
+            operation_group_number: 1,
+            operation_number: 0,
+            content_number: 0,
+            internal_number: internal,
+        };
+    let tx = |tx_context_id: i64, operation_hash: &str| Tx {
+        tx_context_id,
+        operation_hash: operation_hash.to_string(),
+        status: "applied".to_string(),
+        source: Some("tz1source".to_string()),
+        destination: None,
+        entrypoint: None,
+        entrypoint_args: None,
+        amount: None,
+        fee: None,
+        gas_limit: None,
+        storage_limit: None,
+        consumed_milligas: None,
+        storage_size: None,
+        paid_storage_size_diff: None,
+        counter: None,
+        branch: None,
+    };
+    // operation opA calls contract a, which calls b and then a again
+    // (internally), operation opB calls b
+    let tx_contexts = vec![
+        tx_context(1, "a", None),
+        tx_context(2, "b", Some(1)),
+        tx_context(3, "a", Some(2)),
+        TxContext {
+            operation_group_number: 2,
+            ..tx_context(4, "b", None)
+        },
+    ];
+    let txs = vec![tx(3, "opA"), tx(1, "opA"), tx(4, "opB"), tx(2, "opA")];
+
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    DBClient::save_levels(
+        &mut db_tx,
+        &[&LevelMeta {
+            level: 10,
+            hash: None,
+            prev_hash: None,
+            baked_at: None,
+            protocol: None,
+            content_hash: None,
+        }],
+        false,
+    )
+    .unwrap();
+    DBClient::save_tx_contexts(&mut db_tx, &tx_contexts, false).unwrap();
+    DBClient::save_txs(&mut db_tx, &txs).unwrap();
+    db_tx.commit().unwrap();
+    drop(conn);
+
+    let found = |hash: &str| -> Vec<(i64, String, Option<i32>)> {
+        dbcli
+            .clone()
+            .find_by_operation_hash(hash)
+            .unwrap()
+            .into_iter()
+            .map(|ctx| (ctx.id.unwrap(), ctx.contract, ctx.internal_number))
+            .collect()
+    };
+    assert_eq!(
+        vec![
+            (1, "a".to_string(), None),
+            (2, "b".to_string(), Some(1)),
+            (3, "a".to_string(), Some(2)),
+        ],
+        found("opA")
+    );
+    assert_eq!(vec![(4, "b".to_string(), None)], found("opB"));
+    assert!(found("opC").is_empty());
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_long_table_names() {
+    let schema = "test_long_table_names";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    // a map nested in a map, both named beyond the identifier limit
+    let long =
+        "a_rather_long_field_name_to_push_the_identifiers_over_the_limit";
+    let type_json = serde_json::json!({
+        "prim": "map",
+        "args": [
+            { "prim": "nat" },
+            {
+                "prim": "map",
+                "args": [{ "prim": "nat" }, { "prim": "nat" }],
+                "annots": [format!("%{}_inner", long)],
+            },
+        ],
+        "annots": [format!("%{}_outer", long)],
+    });
+    let contract = test_contract(schema, type_json);
+    assert!(dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+    let (tables, _, _) = TableBuilder::tables_from_contract(&contract).unwrap();
+    let child = |parent: &str| -> String {
+        tables
+            .iter()
+            .find(|t| t.parent() == Some(parent))
+            .unwrap()
+            .name
+            .clone()
+    };
+    let outer = child("storage");
+    let inner = child(&outer);
+
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    DBClient::save_levels(
+        &mut db_tx,
+        &[&LevelMeta {
+            level: 10,
+            hash: None,
+            prev_hash: None,
+            baked_at: None,
+            protocol: None,
+            content_hash: None,
+        }],
+        false,
+    )
+    .unwrap();
+    DBClient::save_tx_contexts(
+        &mut db_tx,
+        &[TxContext {
+            id: Some(1),
+            contract: contract.cid.address.clone(),
+            level: 10,
+            operation_group_number: 0,
+            operation_number: 0,
+            content_number: 0,
+            internal_number: None,
+        }],
+        false,
+    )
+    .unwrap();
+    // every row refers to the row of the table it is nested in
+    for (id, table, parent) in &[
+        (1, "storage", None),
+        (2, outer.as_str(), Some("storage")),
+        (3, inner.as_str(), Some(outer.as_str())),
+    ] {
+        let insert = Insert {
+            table_name: table.to_string(),
+            id: *id,
+            fk_id: parent.map(|_| id - 1),
+            parent_table: parent.map(String::from),
+            columns: vec![Column {
+                name: "tx_context_id".to_string(),
+                value: Value::BigInt(1),
+            }],
+        };
+        DBClient::apply_inserts(&mut db_tx, &contract.cid, &[insert]).unwrap();
+    }
+    db_tx.commit().unwrap();
+    drop(conn);
+
+    dbcli
+        .repopulate_derived_tables(&contract)
+        .unwrap();
+    let count: i64 = dbcli
+        .dbconn()
+        .unwrap()
+        .query_one(
+            format!(
+                r#"SELECT COUNT(1) FROM "{}"."{}_live""#,
+                contract.cid.name, inner
+            )
+            .as_str(),
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(1, count);
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_create_derived_tables() {
+    let schema = "test_create_derived_tables";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let type_json = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "nat", "annots": ["%counter"] },
+            {
+                "prim": "map",
+                "args": [{ "prim": "string" }, { "prim": "nat" }],
+                "annots": ["%names"],
+            },
+        ],
+    });
+    let contract = test_contract(schema, type_json);
+    let tables = |dbcli: &DBClient| -> Vec<String> {
+        dbcli
+            .dbconn()
+            .unwrap()
+            .query(
+                "
+SELECT table_name
+FROM information_schema.tables
+WHERE table_schema = $1
+ORDER BY table_name",
+                &[&contract.cid.name],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect()
+    };
+
+    dbcli.skip_derived_tables();
+    assert!(dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+    assert_eq!(vec!["storage", "storage.names"], tables(&dbcli));
+    // not maintained for the contract
+    dbcli
+        .repopulate_derived_tables(&contract)
+        .unwrap();
+
+    assert!(dbcli
+        .create_derived_tables(&contract)
+        .unwrap());
+    assert_eq!(
+        vec![
+            "storage",
+            "storage.names",
+            "storage.names_live",
+            "storage.names_ordered",
+            "storage_live",
+            "storage_ordered",
+        ],
+        tables(&dbcli)
+    );
+    dbcli
+        .repopulate_derived_tables(&contract)
+        .unwrap();
+    assert!(!dbcli
+        .create_derived_tables(&contract)
+        .unwrap());
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_get_bigmap_owner() {
+    let schema = "test_get_bigmap_owner";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let level_meta = |level: u32| LevelMeta {
+        level,
+        hash: None,
+        prev_hash: None,
+        baked_at: None,
+        protocol: None,
+        content_hash: None,
+    };
+    let tx_context =
+        |id: i64, contract: &str, level: u32, group: usize| TxContext {
+            id: Some(id),
+            contract: contract.to_string(),
+            level,
+            operation_group_number: group,
+            operation_number: 0,
+            content_number: 0,
+            internal_number: None,
+        };
+    let action = |tx_context_id: i64, bigmap_id: i32, action: &str, value| {
+        BigmapMetaAction {
+            tx_context_id,
+            bigmap_id,
+            operation_hash: None,
+            action: action.to_string(),
+            value,
+        }
+    };
+    // bigmap 5 is allocated by contract a, and reallocated (after a's
+    // bigmap got removed) by contract b later on. bigmap 6 is only copied
+    let tx_contexts = vec![
+        tx_context(1, "a", 10, 1),
+        tx_context(2, "b", 20, 1),
+        tx_context(3, "b", 20, 2),
+    ];
+    let actions = vec![
+        action(
+            1,
+            5,
+            "alloc",
+            Some(
+                serde_json::json!({"contract_address": "a", "table": "storage.x"}),
+            ),
+        ),
+        action(2, 6, "copy", Some(serde_json::json!({"source": 5}))),
+        action(
+            3,
+            5,
+            "alloc",
+            Some(
+                serde_json::json!({"contract_address": "b", "table": "storage.y"}),
+            ),
+        ),
+    ];
+
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    DBClient::save_levels(
+        &mut db_tx,
+        &[&level_meta(10), &level_meta(20)],
+        false,
+    )
+    .unwrap();
+    DBClient::save_tx_contexts(&mut db_tx, &tx_contexts, false).unwrap();
+    DBClient::save_bigmap_meta_actions(&mut db_tx, &actions).unwrap();
+    db_tx.commit().unwrap();
+    drop(conn);
+
+    let owner = |contract: &str, table: &str, alloc_level: u32| {
+        Some(BigmapOwner {
+            bigmap_id: 5,
+            contract_address: contract.to_string(),
+            table_name: table.to_string(),
+            alloc_level,
+        })
+    };
+    let check = |dbcli: &mut DBClient| {
+        assert_eq!(None, dbcli.get_bigmap_owner(5, 9).unwrap());
+        assert_eq!(
+            owner("a", "storage.x", 10),
+            dbcli.get_bigmap_owner(5, 10).unwrap()
+        );
+        assert_eq!(
+            owner("a", "storage.x", 10),
+            dbcli.get_bigmap_owner(5, 19).unwrap()
+        );
+        assert_eq!(
+            owner("b", "storage.y", 20),
+            dbcli.get_bigmap_owner(5, 20).unwrap()
+        );
+        assert_eq!(None, dbcli.get_bigmap_owner(6, 20).unwrap());
+    };
+    check(&mut dbcli);
+
+    // dbs set up before the bigmap owners were tracked get them backfilled
+    // from the alloc actions, skipping those recorded without their table
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(
+            r#"
+DROP TABLE bigmap_owners;
+INSERT INTO bigmap_meta_actions (tx_context_id, bigmap_id, action, value)
+VALUES (2, 7, 'alloc', '{"contract_address": "b"}'), (2, 8, 'alloc', NULL);
+"#,
+        )
+        .unwrap();
+    dbcli
+        .add_missing_common_columns()
+        .unwrap();
+    check(&mut dbcli);
+    assert_eq!(None, dbcli.get_bigmap_owner(7, 20).unwrap());
+    assert_eq!(None, dbcli.get_bigmap_owner(8, 20).unwrap());
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_get_bigmap_dependent_levels() {
+    let schema = "test_get_bigmap_dependent_levels";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let contract_id = |name: &str| ContractID {
+        name: name.to_string(),
+        address: name.to_string(),
+    };
+    let save_deps =
+        |dbcli: &mut DBClient, deps: &[(i32, &str, i32, &str, bool)]| {
+            let deps: Vec<(i32, String, i32, ContractID, bool)> = deps
+                .iter()
+                .map(|(level, src, src_bigmap, dest, is_deep_copy)| {
+                    (
+                        *level,
+                        src.to_string(),
+                        *src_bigmap,
+                        contract_id(dest),
+                        *is_deep_copy,
+                    )
+                })
+                .collect();
+            let mut conn = dbcli.dbconn().unwrap();
+            let mut db_tx = conn.transaction().unwrap();
+            DBClient::save_contract_deps(&mut db_tx, &deps).unwrap();
+            db_tx.commit().unwrap();
+        };
+    // contract a copies bigmaps 1 and 2 of src at level 10 (and bigmap 1
+    // again at level 20), contract b copies bigmap 1 at level 30
+    save_deps(
+        &mut dbcli,
+        &[
+            (10, "src", 1, "a", true),
+            (10, "src", 2, "a", true),
+            (10, "src", 2, "a", true),
+            (20, "src", 1, "a", true),
+            (20, "src", 2, "a", false),
+            (30, "src", 1, "b", true),
+            (40, "other", 1, "a", true),
+        ],
+    );
+
+    let config = vec![contract_id("a")];
+    let levels = |dbcli: &mut DBClient, src: &str, bigmap: i32| {
+        dbcli
+            .get_bigmap_dependent_levels(&config, src, bigmap)
+            .unwrap()
+    };
+    assert_eq!(vec![10, 20], levels(&mut dbcli, "src", 1));
+    assert_eq!(vec![10], levels(&mut dbcli, "src", 2));
+    assert!(levels(&mut dbcli, "src", 3).is_empty());
+    assert_eq!(vec![40], levels(&mut dbcli, "other", 1));
+
+    // dbs set up before the copied bigmap was tracked get the column added,
+    // their dependencies are included for any bigmap
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(
+            "
+DROP TABLE contract_deps;
+CREATE TABLE contract_deps (
+    level INT NOT NULL,
+
+    src_contract TEXT NOT NULL,
+    dest_schema TEXT NOT NULL,
+    is_deep_copy BOOLEAN NOT NULL DEFAULT true,
+
+    PRIMARY KEY (level, src_contract, dest_schema, is_deep_copy)
+);
+INSERT INTO contract_deps (level, src_contract, dest_schema, is_deep_copy)
+VALUES (10, 'src', 'a', true);
+",
+        )
+        .unwrap();
+    dbcli
+        .add_missing_common_columns()
+        .unwrap();
+    save_deps(
+        &mut dbcli,
+        &[(20, "src", 1, "a", true), (20, "src", 2, "a", true)],
+    );
+    assert_eq!(vec![10, 20], levels(&mut dbcli, "src", 1));
+    assert_eq!(vec![10, 20], levels(&mut dbcli, "src", 2));
+    assert_eq!(vec![10], levels(&mut dbcli, "src", 3));
+
+    drop_test_db(&dbcli, schema);
+}
+
+#[test]
+fn test_get_origination_multiple() {
+    let schema = "test_get_origination_multiple";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query("INSERT INTO contracts (name, address) VALUES ('a', 'a')")
+        .unwrap();
+
+    let contract_id = ContractID {
+        name: "a".to_string(),
+        address: "a".to_string(),
+    };
+    assert_eq!(
+        None,
+        dbcli
+            .get_origination(&contract_id)
+            .unwrap()
+    );
+
+    let save_levels = |dbcli: &mut DBClient, clvls: &[(i32, bool)]| {
+        let clvls: Vec<(ContractID, i32, bool)> = clvls
+            .iter()
+            .map(|(level, is_origination)| {
+                (contract_id.clone(), *level, *is_origination)
+            })
+            .collect();
+        let mut conn = dbcli.dbconn().unwrap();
+        let mut db_tx = conn.transaction().unwrap();
+        DBClient::save_contract_levels(&mut db_tx, &clvls).unwrap();
+        db_tx.commit().unwrap();
+    };
+    // originated at level 10 and again at level 30 (eg after a reorg), the
+    // same level may be marked more than once, within and across batches
+    save_levels(&mut dbcli, &[(10, true), (20, false), (20, false)]);
+    save_levels(&mut dbcli, &[(30, false), (30, true), (10, false)]);
+
+    let origination_levels: Vec<i32> = dbcli
+        .dbconn()
+        .unwrap()
+        .query(
+            "SELECT level FROM contract_levels WHERE is_origination ORDER BY level",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(vec![10, 30], origination_levels);
+
+    assert_eq!(
+        Some(10),
+        dbcli
+            .get_origination(&contract_id)
+            .unwrap()
+    );
+    dbcli.use_latest_origination();
+    assert_eq!(
+        Some(30),
+        dbcli
+            .get_origination(&contract_id)
+            .unwrap()
+    );
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_find_level_gaps() {
+    let schema = "test_find_level_gaps";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query("INSERT INTO contracts (name, address) VALUES ('a', 'a')")
+        .unwrap();
+
+    let contract_id = ContractID {
+        name: "a".to_string(),
+        address: "a".to_string(),
+    };
+    assert_eq!(
+        Vec::<(u32, u32)>::new(),
+        dbcli
+            .find_level_gaps(&contract_id)
+            .unwrap()
+    );
+
+    let clvls: Vec<(ContractID, i32, bool)> = [10, 11, 14, 15, 17, 20]
+        .iter()
+        .map(|level| (contract_id.clone(), *level, *level == 10))
+        .collect();
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    DBClient::save_contract_levels(&mut db_tx, &clvls).unwrap();
+    db_tx.commit().unwrap();
+    drop(conn);
+
+    // the levels before 10 and after 20 are not gaps
+    assert_eq!(
+        vec![(12, 13), (16, 16), (18, 19)],
+        dbcli
+            .find_level_gaps(&contract_id)
+            .unwrap()
+    );
+
+    dbcli
+        .mark_missing_levels_empty(&contract_id)
+        .unwrap();
+    assert_eq!(
+        Vec::<(u32, u32)>::new(),
+        dbcli
+            .find_level_gaps(&contract_id)
+            .unwrap()
+    );
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_apply_inserts_no_duplicates() {
+    use crate::sql::insert::{Column, Insert, Value};
+
+    let schema = "test_apply_inserts_no_duplicates";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let type_json = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {
+                "prim": "map",
+                "args": [{ "prim": "string" }, { "prim": "nat" }],
+                "annots": ["%names"],
+            },
+            {
+                "prim": "list",
+                "args": [{ "prim": "string" }],
+                "annots": ["%tags"],
+            },
+        ],
+    });
+    let contract = test_contract(schema, type_json);
+    dbcli.skip_derived_tables();
+    dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap();
+
+    let save_level = |dbcli: &DBClient| {
+        let mut conn = dbcli.dbconn().unwrap();
+        let mut db_tx = conn.transaction().unwrap();
+        DBClient::save_levels(
+            &mut db_tx,
+            &[&LevelMeta {
+                level: 10,
+                hash: None,
+                prev_hash: None,
+                baked_at: None,
+                protocol: None,
+                content_hash: None,
+            }],
+            false,
+        )
+        .unwrap();
+        DBClient::save_tx_contexts(
+            &mut db_tx,
+            &[TxContext {
+                id: Some(1),
+                contract: contract.cid.address.clone(),
+                level: 10,
+                operation_group_number: 0,
+                operation_number: 0,
+                content_number: 0,
+                internal_number: None,
+            }],
+            false,
+        )
+        .unwrap();
+        db_tx.commit().unwrap();
+    };
+    save_level(&dbcli);
+
+    let insert = |table: &str,
+                  id: i64,
+                  fk_id: Option<i64>,
+                  columns: &[(&str, Value)]| {
+        let mut columns: Vec<Column> = columns
+            .iter()
+            .map(|(name, value)| Column {
+                name: name.to_string(),
+                value: value.clone(),
+            })
+            .collect();
+        columns.push(Column {
+            name: "tx_context_id".to_string(),
+            value: Value::BigInt(1),
+        });
+        Insert {
+            table_name: table.to_string(),
+            id,
+            fk_id,
+            parent_table: fk_id.map(|_| "storage".to_string()),
+            columns,
+        }
+    };
+    let string = |s: &str| Value::String(s.to_string());
+    let numeric = |n: i32| {
+        Value::Numeric(pg_bigdecimal::PgNumeric::new(Some(
+            pg_bigdecimal::BigDecimal::from(n),
+        )))
+    };
+    // the list holds the same element twice, at different positions
+    let inserts = |id: i64| {
+        vec![
+            insert("storage", id, None, &[]),
+            insert(
+                "storage.names",
+                id + 1,
+                Some(id),
+                &[("idx_string", string("a")), ("nat", numeric(1))],
+            ),
+            insert(
+                "storage.tags",
+                id + 2,
+                Some(id),
+                &[("string", string("x")), ("ordinal", numeric(0))],
+            ),
+            insert(
+                "storage.tags",
+                id + 3,
+                Some(id),
+                &[("string", string("x")), ("ordinal", numeric(1))],
+            ),
+        ]
+    };
+    let apply = |dbcli: &DBClient, inserts: &[Insert]| -> Result<()> {
+        let mut conn = dbcli.dbconn().unwrap();
+        let mut db_tx = conn.transaction().unwrap();
+        DBClient::apply_inserts(&mut db_tx, &contract.cid, inserts)?;
+        db_tx.commit()?;
+        Ok(())
+    };
+    let count = |dbcli: &DBClient, table: &str| -> i64 {
+        dbcli
+            .dbconn()
+            .unwrap()
+            .query_one(
+                format!(
+                    r#"SELECT COUNT(1) FROM "{}"."{}""#,
+                    contract.cid.name, table
+                )
+                .as_str(),
+                &[],
+            )
+            .unwrap()
+            .get(0)
+    };
+
+    let check_counts = |dbcli: &DBClient| {
+        assert_eq!(1, count(dbcli, "storage"));
+        assert_eq!(1, count(dbcli, "storage.names"));
+        assert_eq!(2, count(dbcli, "storage.tags"));
+    };
+
+    apply(&dbcli, &inserts(1)).unwrap();
+    check_counts(&dbcli);
+    // the same rows again, with other ids (as a concurrent processor would
+    // make), are rejected as a whole
+    assert!(apply(&dbcli, &inserts(11)).is_err());
+    check_counts(&dbcli);
+
+    // reprocessing deletes the level first
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    DBClient::delete_levels(&mut db_tx, &[10]).unwrap();
+    db_tx.commit().unwrap();
+    drop(conn);
+    assert_eq!(0, count(&dbcli, "storage"));
+    save_level(&dbcli);
+    apply(&dbcli, &inserts(11)).unwrap();
+    check_counts(&dbcli);
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_read_only() {
+    let schema = "test_read_only";
+    let dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let mut follower = DBClient::connect(
+        &test_db_url().unwrap(),
+        schema,
+        Duration::from_secs(10),
+        2,
+        0,
+    )
+    .unwrap();
+    follower.set_read_only();
+    assert!(follower.common_tables_exist().unwrap());
+    assert!(follower.get_head().unwrap().is_none());
+    // writes are rejected, also within (read-only) transactions
+    assert!(follower
+        .dbconn()
+        .unwrap()
+        .simple_query("DELETE FROM levels")
+        .is_err());
+    let mut conn = follower.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    assert!(DBClient::save_levels(
+        &mut db_tx,
+        &[&LevelMeta {
+            level: 10,
+            hash: None,
+            prev_hash: None,
+            baked_at: None,
+            protocol: None,
+            content_hash: None,
+        }],
+        false,
+    )
+    .is_err());
+    drop(db_tx);
+    drop(conn);
+
+    drop_test_db(&dbcli, schema);
+}
+
+#[test]
+fn test_save_delegations() {
+    let schema = "test_save_delegations";
+    let dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(
+            "INSERT INTO contracts (name, address) VALUES ('a', 'KT1a')",
+        )
+        .unwrap();
+
+    let contract_id = ContractID {
+        name: "a".to_string(),
+        address: "KT1a".to_string(),
+    };
+    let delegation = |level: u32, delegate: Option<&str>| {
+        (
+            contract_id.clone(),
+            Delegation {
+                level,
+                operation_hash: format!("op{}", level),
+                source: "KT1a".to_string(),
+                delegate: delegate.map(|d| d.to_string()),
+            },
+        )
+    };
+    {
+        let mut conn = dbcli.dbconn().unwrap();
+        let mut db_tx = conn.transaction().unwrap();
+        DBClient::save_delegations(
+            &mut db_tx,
+            &[delegation(10, Some("tz1b")), delegation(20, None)],
+        )
+        .unwrap();
+        db_tx.commit().unwrap();
+    }
+
+    let rows: Vec<(String, i32, String, Option<String>)> = dbcli
+        .dbconn()
+        .unwrap()
+        .query(
+            "SELECT contract, level, operation_hash, delegate FROM delegations ORDER BY level",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+        .collect();
+    assert_eq!(
+        vec![
+            (
+                "a".to_string(),
+                10,
+                "op10".to_string(),
+                Some("tz1b".to_string())
+            ),
+            ("a".to_string(), 20, "op20".to_string(), None),
+        ],
+        rows
+    );
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_raw_storage_compression() {
+    let schema = "test_raw_storage_compression";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let tx_contexts: Vec<TxContext> = (1..4)
+        .map(|id| TxContext {
+            id: Some(id),
+            contract: "KT1a".to_string(),
+            level: 10,
+            operation_group_number: id as usize,
+            operation_number: 0,
+            content_number: 0,
+            internal_number: None,
+        })
+        .collect();
+    let txs: Vec<Tx> = (1..4)
+        .map(|id| Tx {
+            tx_context_id: id,
+            operation_hash: format!("op{}", id),
+            status: "applied".to_string(),
+            source: Some("tz1source".to_string()),
+            destination: Some("KT1a".to_string()),
+            entrypoint: None,
+            entrypoint_args: None,
+            amount: None,
+            fee: None,
+            gas_limit: None,
+            storage_limit: None,
+            consumed_milligas: None,
+            storage_size: None,
+            paid_storage_size_diff: None,
+            counter: None,
+            branch: None,
+        })
+        .collect();
+    let raw_storage = |tx_context_id: i64| RawStorage {
+        tx_context_id,
+        contract: "KT1a".to_string(),
+        storage: serde_json::json!({ "int": tx_context_id.to_string() }),
+    };
+
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    DBClient::save_levels(
+        &mut db_tx,
+        &[&LevelMeta {
+            level: 10,
+            hash: None,
+            prev_hash: None,
+            baked_at: None,
+            protocol: None,
+            content_hash: None,
+        }],
+        false,
+    )
+    .unwrap();
+    DBClient::save_tx_contexts(&mut db_tx, &tx_contexts, false).unwrap();
+    DBClient::save_txs(&mut db_tx, &txs).unwrap();
+    // the 3rd call's storage is not kept
+    DBClient::save_raw_storage(
+        &mut db_tx,
+        &[raw_storage(1)],
+        StorageCompression::None,
+    )
+    .unwrap();
+    DBClient::save_raw_storage(
+        &mut db_tx,
+        &[raw_storage(2)],
+        StorageCompression::Gzip,
+    )
+    .unwrap();
+    db_tx.commit().unwrap();
+
+    let stored: Vec<(i64, bool, bool)> = conn
+        .query(
+            "
+SELECT tx_context_id, storage IS NULL, storage_compressed IS NULL
+FROM raw_storage
+ORDER BY tx_context_id",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect();
+    assert_eq!(vec![(1, false, true), (2, true, false)], stored);
+    drop(conn);
+
+    let contract_id = ContractID {
+        name: "a".to_string(),
+        address: "KT1a".to_string(),
+    };
+    let got: Vec<Option<serde_json::Value>> = dbcli
+        .get_calls(&contract_id, 0, 20, 10, 0)
+        .unwrap()
+        .into_iter()
+        .map(|call| call.raw_storage)
+        .collect();
+    assert_eq!(
+        vec![
+            Some(raw_storage(1).storage),
+            Some(raw_storage(2).storage),
+            None
+        ],
+        got
+    );
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_get_head_per_contract() {
+    let schema = "test_get_head_per_contract";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(
+            "INSERT INTO contracts (name, address) VALUES ('a', 'KT1a'), ('b', 'KT1b'), ('c', 'KT1c')",
+        )
+        .unwrap();
+
+    let cid = |name: &str| ContractID {
+        name: name.to_string(),
+        address: format!("KT1{}", name),
+    };
+    // b is still catching up, c has not been processed at all
+    {
+        let mut conn = dbcli.dbconn().unwrap();
+        let mut db_tx = conn.transaction().unwrap();
+        DBClient::save_contract_levels(
+            &mut db_tx,
+            &[
+                (cid("a"), 10, true),
+                (cid("a"), 30, false),
+                (cid("a"), 20, false),
+                (cid("b"), 10, true),
+                (cid("b"), 15, false),
+            ],
+        )
+        .unwrap();
+        db_tx.commit().unwrap();
+    }
+
+    let mut exp: HashMap<ContractID, u32> = HashMap::new();
+    exp.insert(cid("a"), 30);
+    exp.insert(cid("b"), 15);
+    assert_eq!(
+        exp,
+        dbcli
+            .get_head_per_contract(&[cid("a"), cid("b"), cid("c")])
+            .unwrap()
+    );
+    exp.remove(&cid("a"));
+    assert_eq!(
+        exp,
+        dbcli
+            .get_head_per_contract(&[cid("b")])
+            .unwrap()
+    );
+
+    drop_test_db(&dbcli, schema);
+}
+
+#[test]
+fn test_group_inserts_parents_first() {
+    let insert =
+        |table_name: &str, id: i64, parent: Option<(&str, i64)>| Insert {
+            table_name: table_name.to_string(),
+            id,
+            fk_id: parent.map(|(_, fk_id)| fk_id),
+            parent_table: parent.map(|(table, _)| table.to_string()),
+            columns: vec![Column {
+                name: "nat".to_string(),
+                value: Value::Int(1),
+            }],
+        };
+    // a list nested in a list: storage.outer's rows reference storage's,
+    // and storage.outer.inner's rows reference storage.outer's. the name of
+    // a shortened table (and of its children) does not tell its depth
+    let shortened = "storage.shortened_ffffffff";
+    let shortened_child = "storage.shortened_00000000";
+    let inserts = vec![
+        insert("storage.outer.inner", 4, Some(("storage.outer", 2))),
+        insert("storage.z", 5, Some(("storage", 1))),
+        insert("storage.outer", 2, Some(("storage", 1))),
+        insert("storage.outer.inner", 3, Some(("storage.outer", 2))),
+        insert(shortened_child, 8, Some((shortened, 7))),
+        insert("storage", 1, None),
+        insert(
+            "storage.outer.inner.deeper",
+            6,
+            Some(("storage.outer.inner", 3)),
+        ),
+        insert(shortened, 7, Some(("storage", 1))),
+    ];
+    let tables: Vec<(&str, Vec<i64>)> = DBClient::group_inserts(&inserts)
+        .iter()
+        .map(|group| {
+            (
+                group[0].table_name.as_str(),
+                group
+                    .iter()
+                    .map(|insert| insert.id)
+                    .collect(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            ("storage", vec![1]),
+            ("storage.outer", vec![2]),
+            (shortened, vec![7]),
+            ("storage.z", vec![5]),
+            ("storage.outer.inner", vec![4, 3]),
+            (shortened_child, vec![8]),
+            ("storage.outer.inner.deeper", vec![6]),
+        ],
+        tables
+    );
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_level_columns() {
+    use chrono::TimeZone;
+
+    let schema = "test_level_columns";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    // the bigmap's derived tables are derived from changes, which are
+    // selected together with the level of their tx context
+    let type_json = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {
+                "prim": "map",
+                "args": [{ "prim": "string" }, { "prim": "nat" }],
+                "annots": ["%names"],
+            },
+            {
+                "prim": "big_map",
+                "args": [{ "prim": "nat" }, { "prim": "mutez" }],
+                "annots": ["%ledger"],
+            },
+        ],
+    });
+    let contract = test_contract(schema, type_json);
+    dbcli.skip_derived_tables();
+    dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap();
+
+    let baked_at = |level: u32| Utc.timestamp(1_600_000_000 + level as i64, 0);
+    let numeric = |n: i32| {
+        Value::Numeric(pg_bigdecimal::PgNumeric::new(Some(
+            pg_bigdecimal::BigDecimal::from(n),
+        )))
+    };
+    let apply_level = |dbcli: &DBClient, level: u32, ctx_id: i64| {
+        let mut conn = dbcli.dbconn().unwrap();
+        let mut db_tx = conn.transaction().unwrap();
+        DBClient::save_levels(
+            &mut db_tx,
+            &[&LevelMeta {
+                level,
+                hash: None,
+                prev_hash: None,
+                baked_at: Some(baked_at(level)),
+                protocol: None,
+                content_hash: None,
+            }],
+            false,
+        )
+        .unwrap();
+        DBClient::save_tx_contexts(
+            &mut db_tx,
+            &[TxContext {
+                id: Some(ctx_id),
+                contract: contract.cid.address.clone(),
+                level,
+                operation_group_number: 0,
+                operation_number: 0,
+                content_number: 0,
+                internal_number: None,
+            }],
+            false,
+        )
+        .unwrap();
+        let tx_context_id = Column {
+            name: "tx_context_id".to_string(),
+            value: Value::BigInt(ctx_id),
+        };
+        let mut inserts = vec![
+            Insert {
+                table_name: "storage".to_string(),
+                id: ctx_id * 10,
+                fk_id: None,
+                parent_table: None,
+                columns: vec![tx_context_id.clone()],
+            },
+            Insert {
+                table_name: "storage.ledger".to_string(),
+                id: ctx_id * 10 + 1,
+                fk_id: None,
+                parent_table: None,
+                columns: vec![
+                    tx_context_id,
+                    Column {
+                        name: "bigmap_id".to_string(),
+                        value: Value::Int(1),
+                    },
+                    Column {
+                        name: "deleted".to_string(),
+                        value: Value::Bool(false),
+                    },
+                    Column {
+                        name: "idx_nat".to_string(),
+                        value: numeric(ctx_id as i32),
+                    },
+                    Column {
+                        name: "mutez".to_string(),
+                        value: numeric(100),
+                    },
+                ],
+            },
+        ];
+        for insert in &mut inserts {
+            insert.set_level(level as i32, Some(baked_at(level)));
+        }
+        DBClient::apply_inserts(&mut db_tx, &contract.cid, &inserts).unwrap();
+        db_tx.commit().unwrap();
+    };
+    let levels = |dbcli: &DBClient, table: &str| -> Vec<(i32, DateTime<Utc>)> {
+        dbcli
+            .dbconn()
+            .unwrap()
+            .query(
+                format!(
+                    r#"SELECT level, level_timestamp FROM "{}"."{}" ORDER BY level"#,
+                    contract.cid.name, table,
+                )
+                .as_str(),
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect()
+    };
+
+    apply_level(&dbcli, 10, 1);
+    assert_eq!(vec![(10, baked_at(10))], levels(&dbcli, "storage"));
+    assert_eq!(vec![(10, baked_at(10))], levels(&dbcli, "storage.ledger"));
+
+    // derived from scratch
+    assert!(dbcli
+        .create_derived_tables(&contract)
+        .unwrap());
+    dbcli
+        .repopulate_derived_tables(&contract)
+        .unwrap();
+    for table in &["storage_live", "storage_ordered"] {
+        assert_eq!(vec![(10, baked_at(10))], levels(&dbcli, table));
+    }
+    for table in &["storage.ledger_live", "storage.ledger_ordered"] {
+        assert_eq!(vec![(10, baked_at(10))], levels(&dbcli, table));
+    }
+
+    // derived incrementally
+    apply_level(&dbcli, 11, 2);
+    dbcli
+        .update_derived_tables_between(&contract, 11, 11)
+        .unwrap();
+    assert_eq!(vec![(11, baked_at(11))], levels(&dbcli, "storage_live"));
+    assert_eq!(
+        vec![(10, baked_at(10)), (11, baked_at(11))],
+        levels(&dbcli, "storage_ordered")
+    );
+    for table in &["storage.ledger_live", "storage.ledger_ordered"] {
+        assert_eq!(
+            vec![(10, baked_at(10)), (11, baked_at(11))],
+            levels(&dbcli, table)
+        );
+    }
+
+    // tables set up without the columns get them when the contract is set up
+    // again
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(&format!(
+            r#"ALTER TABLE "{}"."storage.names" DROP COLUMN level, DROP COLUMN level_timestamp"#,
+            contract.cid.name
+        ))
+        .unwrap();
+    // (the schema is checked again when que-pasa is started again)
+    let mut dbcli = test_db_restart(schema);
+    assert!(!dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+    let count: i64 = dbcli
+        .dbconn()
+        .unwrap()
+        .query_one(
+            "
+SELECT
+    COUNT(1)
+FROM information_schema.columns
+WHERE table_schema = $1
+  AND table_name = 'storage.names'
+  AND column_name IN ('level', 'level_timestamp')
+",
+            &[&contract.cid.name],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(2, count);
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_add_missing_ordinal_columns() {
+    let schema = "test_add_missing_ordinal_columns";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let type_json = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {
+                "prim": "list",
+                "args": [{ "prim": "string" }],
+                "annots": ["%names"],
+            },
+            { "prim": "nat", "annots": ["%n"] },
+        ],
+    });
+    let contract = test_contract(schema, type_json);
+    dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap();
+
+    let with_ordinal = |dbcli: &DBClient| -> Vec<String> {
+        let mut res: Vec<String> = dbcli
+            .dbconn()
+            .unwrap()
+            .query(
+                "
+SELECT
+    table_name::TEXT
+FROM information_schema.columns
+WHERE table_schema = $1
+  AND column_name = 'ordinal'
+",
+                &[&contract.cid.name],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        res.sort();
+        res
+    };
+    let list_tables = vec![
+        "storage.names".to_string(),
+        "storage.names_live".to_string(),
+        "storage.names_ordered".to_string(),
+    ];
+    assert_eq!(list_tables, with_ordinal(&dbcli));
+
+    // list tables set up without the column get it (as do their derived
+    // tables) when the contract is set up again
+    for table in &list_tables {
+        dbcli
+            .dbconn()
+            .unwrap()
+            .simple_query(&format!(
+                r#"ALTER TABLE "{}"."{}" DROP COLUMN ordinal"#,
+                contract.cid.name, table
+            ))
+            .unwrap();
+    }
+    assert!(with_ordinal(&dbcli).is_empty());
+    // the schema is only checked the first time per run
+    assert!(!dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+    assert!(with_ordinal(&dbcli).is_empty());
+    // (the schema is checked again when que-pasa is started again)
+    let mut dbcli = test_db_restart(schema);
+    assert!(!dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+    assert_eq!(list_tables, with_ordinal(&dbcli));
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_add_missing_metadata_tables() {
+    let schema = "test_add_missing_metadata_tables";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let type_json = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "string", "annots": ["%name"] },
+            { "prim": "bytes", "annots": ["%uri"] },
+        ],
+    });
+    let mut contract = test_contract(schema, type_json);
+    assert!(dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+
+    let contract_schema = contract.cid.name.clone();
+    let tables = |dbcli: &DBClient| -> Vec<String> {
+        let mut res: Vec<String> = dbcli
+            .dbconn()
+            .unwrap()
+            .query(
+                "SELECT table_name FROM information_schema.tables WHERE table_schema = $1",
+                &[&contract_schema],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        res.sort();
+        res
+    };
+    assert_eq!(
+        vec!["storage", "storage_live", "storage_ordered"],
+        tables(&dbcli)
+    );
+
+    contract
+        .metadata_uris
+        .insert("storage".to_string(), "uri".to_string());
+    // (the schema is checked again when que-pasa is started again)
+    let mut dbcli = test_db_restart(schema);
+    assert!(!dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+    assert_eq!(
+        vec![
+            "storage",
+            "storage.resolved_metadata",
+            "storage_live",
+            "storage_ordered"
+        ],
+        tables(&dbcli)
+    );
+    // and only once
+    assert!(!dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_check_chain_id() {
+    let schema = "test_check_chain_id";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    // a db that does not know its chain takes the node's
+    assert_eq!(None, dbcli.get_chain_id().unwrap());
+    dbcli
+        .check_chain_id("NetXnHfVqm9iesp")
+        .unwrap();
+    assert_eq!(
+        Some("NetXnHfVqm9iesp".to_string()),
+        dbcli.get_chain_id().unwrap()
+    );
+    dbcli
+        .check_chain_id("NetXnHfVqm9iesp")
+        .unwrap();
+
+    // and from then on refuses other chains
+    let err = dbcli
+        .check_chain_id("NetXdQprcVkpaWU")
+        .unwrap_err();
+    assert_eq!(
+        "the db was set up for chain NetXnHfVqm9iesp, but the node is on chain NetXdQprcVkpaWU",
+        err.to_string()
+    );
+    assert_eq!(
+        Some("NetXnHfVqm9iesp".to_string()),
+        dbcli.get_chain_id().unwrap()
+    );
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_add_missing_txs_status() {
+    let schema = "test_add_missing_txs_status";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    // a db set up before the status of txs was recorded
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(
+            "DROP VIEW txs_ordered; ALTER TABLE txs DROP COLUMN status",
+        )
+        .unwrap();
+    dbcli
+        .add_missing_common_columns()
+        .unwrap();
+
+    let columns = |table: &str| -> Vec<String> {
+        dbcli
+            .dbconn()
+            .unwrap()
+            .query(
+                "SELECT column_name::TEXT FROM information_schema.columns WHERE table_schema = $1 AND table_name = $2",
+                &[&schema, &table],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect()
+    };
+    assert!(columns("txs").contains(&"status".to_string()));
+    assert!(columns("txs_ordered").contains(&"status".to_string()));
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_add_missing_entrypoint_tables() {
+    use crate::storage_structure::relational::{ASTBuilder, RelationalAST};
+    use crate::storage_structure::typing;
+
+    let schema = "test_add_missing_entrypoint_tables";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let rel_ast =
+        |table: &str, type_json: serde_json::Value| -> RelationalAST {
+            ASTBuilder::new(table)
+                .memoryless_bigmaps()
+                .build_relational_ast(
+                    &typing::type_ast_from_json(&type_json).unwrap(),
+                )
+                .unwrap()
+        };
+    let mut contract =
+        test_contract(schema, serde_json::json!({"prim": "nat"}));
+    contract.entrypoint_asts.insert(
+        "mint".to_string(),
+        rel_ast("entry.mint", serde_json::json!({"prim": "nat"})),
+    );
+    assert!(dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+
+    let contract_schema = contract.cid.name.clone();
+    let tables = |dbcli: &DBClient| -> Vec<String> {
+        let mut res: Vec<String> = dbcli
+            .dbconn()
+            .unwrap()
+            .query(
+                "SELECT table_name FROM information_schema.tables WHERE table_schema = $1 AND table_name LIKE 'entry.%'",
+                &[&contract_schema],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        res.sort();
+        res
+    };
+    assert_eq!(vec!["entry.mint"], tables(&dbcli));
+
+    // the contract gains an entrypoint, eg by a lazy upgrade
+    contract.entrypoint_asts.insert(
+        "burn".to_string(),
+        rel_ast(
+            "entry.burn",
+            serde_json::json!({
+                "prim": "list",
+                "args": [{ "prim": "nat", "annots": ["%token_id"] }],
+            }),
+        ),
+    );
+    // (the schema is checked again when que-pasa is started again)
+    let mut dbcli = test_db_restart(schema);
+    assert!(!dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+    let mut expected: Vec<String> =
+        TableBuilder::tables_from_contract(&contract)
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|t| t.name)
+            .filter(|name| name.starts_with("entry."))
+            .collect();
+    expected.sort();
+    assert_eq!(3, expected.len());
+    assert_eq!(expected, tables(&dbcli));
+    // and only once
+    assert!(!dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap());
+
+    drop_test_db(&dbcli, schema);
+}
+
+// Needs a Postgres db, see test_db.
+#[test]
+fn test_repair_derived_tables() {
+    let schema = "test_repair_derived_tables";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let type_json = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "nat", "annots": ["%counter"] },
+            {
+                "prim": "big_map",
+                "args": [{ "prim": "string" }, { "prim": "nat" }],
+                "annots": ["%names"],
+            },
+        ],
+    });
+    let contract = test_contract(schema, type_json);
+    dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap();
+
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    let levels: Vec<LevelMeta> = (10..12)
+        .map(|level| LevelMeta {
+            level,
+            hash: None,
+            prev_hash: None,
+            baked_at: None,
+            protocol: None,
+            content_hash: None,
+        })
+        .collect();
+    DBClient::save_levels(
+        &mut db_tx,
+        &levels
+            .iter()
+            .collect::<Vec<&LevelMeta>>(),
+        false,
+    )
+    .unwrap();
+    DBClient::save_tx_contexts(
+        &mut db_tx,
+        &(1..3)
+            .map(|id| TxContext {
+                id: Some(id),
+                contract: contract.cid.address.clone(),
+                level: 9 + id as u32,
+                operation_group_number: 0,
+                operation_number: 0,
+                content_number: 0,
+                internal_number: None,
+            })
+            .collect::<Vec<TxContext>>(),
+        false,
+    )
+    .unwrap();
+    db_tx
+        .simple_query(&format!(
+            r#"
+INSERT INTO "{contract_schema}".storage (id, tx_context_id, counter)
+VALUES (1, 1, 5), (3, 2, 6);
+INSERT INTO "{contract_schema}"."storage.names" (id, tx_context_id, bigmap_id, idx_string, nat, deleted)
+VALUES (2, 1, 7, 'a', 1, false), (4, 2, 7, 'b', 2, false), (5, 2, 7, 'a', NULL, true);
+"#,
+            contract_schema = contract.cid.name,
+        ))
+        .unwrap();
+    db_tx.commit().unwrap();
+    drop(conn);
+
+    dbcli
+        .repopulate_derived_tables(&contract)
+        .unwrap();
+    assert!(dbcli
+        .repair_derived_tables(&contract)
+        .unwrap()
+        .is_empty());
+
+    let live_rows = |dbcli: &DBClient, table: &str| -> i64 {
+        dbcli
+            .dbconn()
+            .unwrap()
+            .query_one(
+                format!(
+                    r#"SELECT COUNT(*) FROM "{}"."{}_live""#,
+                    contract.cid.name, table
+                )
+                .as_str(),
+                &[],
+            )
+            .unwrap()
+            .get(0)
+    };
+    assert_eq!(1, live_rows(&dbcli, "storage"));
+    assert_eq!(1, live_rows(&dbcli, "storage.names"));
+
+    // the derived tables of storage.names are left behind, storage's are
+    // left alone
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(&format!(
+            r#"
+DELETE FROM "{contract_schema}"."storage.names_live";
+DELETE FROM "{contract_schema}"."storage.names_ordered" WHERE tx_context_id = 2;
+UPDATE "{contract_schema}".storage_live SET counter = 0;
+"#,
+            contract_schema = contract.cid.name,
+        ))
+        .unwrap();
+    assert_eq!(
+        vec!["storage.names".to_string()],
+        dbcli
+            .repair_derived_tables(&contract)
+            .unwrap()
+    );
+    assert_eq!(1, live_rows(&dbcli, "storage.names"));
+    assert!(dbcli
+        .repair_derived_tables(&contract)
+        .unwrap()
+        .is_empty());
+
+    // a row too many in a snapshot table's _live
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(&format!(
+            r#"
+INSERT INTO "{contract_schema}".storage_live (level, id, tx_context_id, counter)
+VALUES (10, 1, 1, 5);
+"#,
+            contract_schema = contract.cid.name,
+        ))
+        .unwrap();
+    assert_eq!(
+        vec!["storage".to_string()],
+        dbcli
+            .repair_derived_tables(&contract)
+            .unwrap()
+    );
+    assert_eq!(1, live_rows(&dbcli, "storage"));
+
+    // the newest tx context of a bigmap table goes by the order of the
+    // tx contexts, not by their ids: c is set in between a and b
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    DBClient::save_tx_contexts(
+        &mut db_tx,
+        &[TxContext {
+            id: Some(3),
+            contract: contract.cid.address.clone(),
+            level: 10,
+            operation_group_number: 1,
+            operation_number: 0,
+            content_number: 0,
+            internal_number: None,
+        }],
+        false,
+    )
+    .unwrap();
+    db_tx
+        .simple_query(&format!(
+            r#"
+INSERT INTO "{contract_schema}"."storage.names" (id, tx_context_id, bigmap_id, idx_string, nat, deleted)
+VALUES (6, 3, 7, 'c', 3, false);
+"#,
+            contract_schema = contract.cid.name,
+        ))
+        .unwrap();
+    db_tx.commit().unwrap();
+    drop(conn);
+    dbcli
+        .repopulate_derived_tables(&contract)
+        .unwrap();
+    dbcli
+        .dbconn()
+        .unwrap()
+        .simple_query(&format!(
+            r#"
+DELETE FROM "{contract_schema}"."storage.names_live" WHERE tx_context_id = 2;
+"#,
+            contract_schema = contract.cid.name,
+        ))
+        .unwrap();
+    assert_eq!(
+        vec!["storage.names".to_string()],
+        dbcli
+            .repair_derived_tables(&contract)
+            .unwrap()
+    );
+    assert_eq!(2, live_rows(&dbcli, "storage.names"));
+
+    drop_test_db(&dbcli, schema);
+}
+
+#[test]
+fn test_ordered_changes() {
+    // Needs a Postgres db, see test_db.
+    let schema = "test_ordered_changes";
+    let mut dbcli = match test_db(schema) {
+        Some(dbcli) => dbcli,
+        None => return,
+    };
+
+    let type_json = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "nat", "annots": ["%counter"] },
+            {
+                "prim": "map",
+                "args": [{ "prim": "string" }, { "prim": "nat" }],
+                "annots": ["%names"],
+            },
+        ],
+    });
+    let mut contract = test_contract(schema, type_json);
+    contract
+        .table_snapshots
+        .insert("storage.names".to_string(), false);
+    dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap();
+
+    let tx_contexts: Vec<TxContext> = (1..4)
+        .map(|id| TxContext {
+            id: Some(id),
+            contract: contract.cid.address.clone(),
+            level: 9 + id as u32,
+            operation_group_number: 0,
+            operation_number: 0,
+            content_number: 0,
+            internal_number: None,
+        })
+        .collect();
+    let mut conn = dbcli.dbconn().unwrap();
+    let mut db_tx = conn.transaction().unwrap();
+    let levels: Vec<LevelMeta> = (10..13)
+        .map(|level| LevelMeta {
+            level,
+            hash: None,
+            prev_hash: None,
+            baked_at: None,
+            protocol: None,
+            content_hash: None,
+        })
+        .collect();
+    DBClient::save_levels(
+        &mut db_tx,
+        &levels
+            .iter()
+            .collect::<Vec<&LevelMeta>>(),
+        false,
+    )
+    .unwrap();
+    DBClient::save_tx_contexts(&mut db_tx, &tx_contexts, false).unwrap();
+    // b changes and c is added in the second snapshot, both are removed in
+    // the third
+    db_tx
+        .simple_query(&format!(
+            r#"
+INSERT INTO "{contract_schema}".storage (id, tx_context_id, counter)
+VALUES (1, 1, 5), (4, 2, 6), (8, 3, 7);
+INSERT INTO "{contract_schema}"."storage.names" (id, tx_context_id, storage_id, idx_string, nat)
+VALUES (2, 1, 1, 'a', 1), (3, 1, 1, 'b', 2),
+       (5, 2, 4, 'a', 1), (6, 2, 4, 'b', 3), (7, 2, 4, 'c', 4),
+       (9, 3, 8, 'a', 1);
+"#,
+            contract_schema = contract.cid.name,
+        ))
+        .unwrap();
+    db_tx.commit().unwrap();
+
+    let ordered = |conn: &mut DBPooledConn| -> Vec<(i64, i64, String, bool)> {
+        conn.query(
+            format!(
+                r#"
+SELECT ordering::BIGINT, id, idx_string, deleted
+FROM "{}"."storage.names_ordered"
+ORDER BY ordering, idx_string"#,
+                contract.cid.name
+            )
+            .as_str(),
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+        .collect()
+    };
+    let exp: Vec<(i64, i64, String, bool)> = vec![
+        (1, 2, "a".to_string(), false),
+        (1, 3, "b".to_string(), false),
+        (2, 6, "b".to_string(), false),
+        (2, 7, "c".to_string(), false),
+        (3, 6, "b".to_string(), true),
+        (3, 7, "c".to_string(), true),
+    ];
+
+    // repopulated all at once
+    drop(conn);
+    dbcli
+        .repopulate_derived_tables(&contract)
+        .unwrap();
+    let mut conn = dbcli.dbconn().unwrap();
+    assert_eq!(exp, ordered(&mut conn));
+    drop(conn);
+    assert!(dbcli
+        .repair_derived_tables(&contract)
+        .unwrap()
+        .is_empty());
+
+    // updated snapshot by snapshot
+    let mut conn = dbcli.dbconn().unwrap();
+    conn.simple_query(&format!(
+        r#"
+DELETE FROM "{contract_schema}"."storage_ordered";
+DELETE FROM "{contract_schema}"."storage.names_ordered";
+"#,
+        contract_schema = contract.cid.name,
+    ))
+    .unwrap();
+    for tx_context in &tx_contexts {
+        let mut db_tx = conn.transaction().unwrap();
+        dbcli
+            .update_derived_tables(
+                &mut db_tx,
+                &contract,
+                std::slice::from_ref(tx_context),
+            )
+            .unwrap();
+        db_tx.commit().unwrap();
+    }
+    assert_eq!(exp, ordered(&mut conn));
+    let live: Vec<String> = conn
+        .query(
+            format!(
+                r#"SELECT idx_string FROM "{}"."storage.names_live""#,
+                contract.cid.name
+            )
+            .as_str(),
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(vec!["a".to_string()], live);
+    drop(conn);
+
+    // switching back to snapshots repopulates _ordered with all rows
+    contract.table_snapshots.clear();
+    // (the schema is checked again when que-pasa is started again)
+    let mut dbcli = test_db_restart(schema);
+    dbcli
+        .create_contract_schemas(&mut vec![contract.clone()])
+        .unwrap();
+    let mut conn = dbcli.dbconn().unwrap();
+    let row = conn
+        .query_one(
+            format!(
+                r#"SELECT COUNT(*) FROM "{}"."storage.names_ordered""#,
+                contract.cid.name
+            )
+            .as_str(),
+            &[],
+        )
+        .unwrap();
+    assert_eq!(6i64, row.get::<_, i64>(0));
+    assert!(conn
+        .query_opt(
+            "
+SELECT 1
+FROM information_schema.columns
+WHERE table_schema = $1
+  AND table_name = 'storage.names_ordered'
+  AND column_name = 'deleted'",
+            &[&contract.cid.name],
+        )
+        .unwrap()
+        .is_none());
+    drop(conn);
+
+    drop_test_db(&dbcli, schema);
 }
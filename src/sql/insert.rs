@@ -27,10 +27,7 @@ impl Value {
             Value::Int(i) => i.borrow_to_sql(),
             Value::BigInt(i) => i.borrow_to_sql(),
             Value::Timestamp(Some(t)) => t.borrow_to_sql(),
-            Value::Timestamp(None) => {
-                postgres::types::Timestamp::<DateTime<Utc>>::PosInfinity
-                    .borrow_to_sql()
-            }
+            Value::Timestamp(None) => &None::<DateTime<Utc>>,
             Value::Numeric(n) => n.borrow_to_sql(),
             Value::Null => "NULL".borrow_to_sql(),
         }
@@ -68,6 +65,9 @@ pub struct Insert {
     pub table_name: String,
     pub id: i64,
     pub fk_id: Option<i64>,
+    // the table of the row fk_id refers to
+    #[serde(default)]
+    pub parent_table: Option<String>,
     pub columns: Vec<Column>,
 }
 
@@ -80,18 +80,15 @@ impl Insert {
             value: Value::BigInt(self.id),
         });
         if let Some(fk_id) = self.fk_id {
-            let parent_name = PostgresqlGenerator::parent_name(
-                &self.table_name,
-            )
-            .ok_or_else(|| {
-                anyhow!(
-                    "
-                failed to get parent name from table={}",
-                    self.table_name
-                )
-            })?;
+            let parent_table =
+                self.parent_table.as_ref().ok_or_else(|| {
+                    anyhow!(
+                        "row of table={} refers to a parent row without its table",
+                        self.table_name
+                    )
+                })?;
             res.push(Column {
-                name: PostgresqlGenerator::parent_ref(&parent_name),
+                name: PostgresqlGenerator::parent_ref(parent_table),
                 value: Value::BigInt(fk_id),
             });
         }
@@ -128,6 +125,50 @@ impl Insert {
             .cloned())
     }
 
+    // Sets the level and level_timestamp columns, which every storage table
+    // has, to the level the row was indexed at and its timestamp.
+    pub fn set_level(
+        &mut self,
+        level: i32,
+        level_timestamp: Option<DateTime<Utc>>,
+    ) {
+        self.columns.retain(|column| {
+            column.name != "level" && column.name != "level_timestamp"
+        });
+        self.columns.push(Column {
+            name: "level".to_string(),
+            value: Value::Int(level),
+        });
+        self.columns.push(Column {
+            name: "level_timestamp".to_string(),
+            value: Value::Timestamp(level_timestamp),
+        });
+    }
+
+    // Shifts the ids of the row, and the ids it refers to (its parent row and
+    // its tx context).
+    pub fn offset_ids(&mut self, offset: i64) {
+        self.map_column("tx_context_id", |v| match v {
+            Value::BigInt(i) => Value::BigInt(i + offset),
+            _ => panic!(".."),
+        });
+
+        self.id += offset;
+        self.fk_id = self.fk_id.map(|fk_id| fk_id + offset);
+    }
+
+    // The highest of the ids of the row, and the ids it refers to.
+    pub fn max_id(&self) -> i64 {
+        vec![
+            self.id,
+            self.fk_id.unwrap_or(0),
+            self.get_tx_context_id().unwrap(),
+        ]
+        .into_iter()
+        .max()
+        .unwrap()
+    }
+
     pub fn map_column<F>(&mut self, col_name: &str, f: F)
     where
         F: FnOnce(&Value) -> Value,
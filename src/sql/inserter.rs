@@ -1,16 +1,19 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use pg_bigdecimal::PgNumeric;
+use sha2::{Digest, Sha256};
 use std::collections::hash_map::Entry::Vacant;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::config::ContractID;
-use crate::octez::block::{LevelMeta, Tx, TxContext};
+use crate::octez::block::{Delegation, LevelMeta, Tx, TxContext};
 use crate::sql::db;
 use crate::sql::db::DBClient;
-use crate::sql::insert;
 use crate::sql::insert::Insert;
-use crate::sql::types::BigmapMetaAction;
+use crate::sql::spill::SpilledInserts;
+use crate::sql::types::{BigmapMetaAction, OperationFailure, RawStorage};
 use crate::stats::StatsLogger;
 use crate::storage_structure::relational;
 
@@ -19,51 +22,100 @@ pub(crate) struct DBInserter {
 
     // the number of processed blocks to collect before inserting into the db
     batch_size: usize,
+    // if set, the longest time to collect processed blocks for before
+    // inserting them, even if there are fewer than batch_size
+    commit_interval: Option<Duration>,
+
+    // the number of threads draining the channel of processed blocks, each
+    // inserting its own batches
+    threads: usize,
 }
 
+// The highest id handed out to any of the inserter threads' batches.
+pub(crate) type SharedMaxId = Arc<Mutex<i64>>;
+
 pub(crate) type ProcessedBlock = Vec<ProcessedContractBlock>;
 
-impl DBInserter {
-    pub(crate) fn new(dbcli: DBClient, batch_size: usize) -> Self {
-        Self { dbcli, batch_size }
-    }
+// A processed block as it is sent to a sink, along with its level (which a
+// block without contracts, eg of a level none of the contracts are active
+// in, does not tell).
+pub(crate) type LevelBlock = (u32, ProcessedBlock);
 
-    pub(crate) fn run(
+// The levels handed out for processing, in the order they were handed out.
+pub(crate) type LevelOrder = Arc<Mutex<VecDeque<u32>>>;
+
+/// Consumes the processed blocks, either in bulk (from a channel the
+/// processors send to, see run) or one at a time (see insert_processed).
+pub(crate) trait ProcessedBlockSink {
+    fn run(
         &self,
         stats: &StatsLogger,
-        recv_ch: flume::Receiver<Box<ProcessedBlock>>,
-    ) -> Result<thread::JoinHandle<()>> {
-        let batch_size = self.batch_size;
-        let dbcli = self.dbcli.clone();
-        let stats_cl = stats.clone();
+        recv_ch: flume::Receiver<Box<LevelBlock>>,
+        level_order: Option<LevelOrder>,
+    ) -> Result<thread::JoinHandle<Result<()>>>;
+
+    fn insert_processed(
+        &mut self,
+        update_derived_tables: bool,
+        processed: ProcessedBlock,
+    ) -> Result<()>;
+
+    // Whether run must be given the order in which the levels were handed
+    // out, processed blocks arrive on its channel in any order.
+    fn needs_level_order(&self) -> bool {
+        false
+    }
+}
 
-        let thread_handle = thread::spawn(move || {
-            Self::exec(dbcli, batch_size, &stats_cl, recv_ch).unwrap();
-        });
-        Ok(thread_handle)
+impl DBInserter {
+    pub(crate) fn new(
+        dbcli: DBClient,
+        batch_size: usize,
+        threads: usize,
+    ) -> Self {
+        Self {
+            dbcli,
+            batch_size,
+            commit_interval: None,
+            threads,
+        }
+    }
+
+    /// Insert the processed blocks collected so far once this much time has
+    /// passed since the previous insert, even if there are fewer than
+    /// batch_size of them.
+    pub(crate) fn set_commit_interval(&mut self, interval: Duration) {
+        self.commit_interval = Some(interval);
     }
 
+    // Each processed block holds a single level (of all contracts) and is
+    // added to one batch as a whole, so a contract's level is always
+    // committed in one go, even when several threads insert concurrently.
     fn exec(
         mut dbcli: DBClient,
         batch_size: usize,
+        commit_interval: Option<Duration>,
         stats: &StatsLogger,
-        recv_ch: flume::Receiver<Box<ProcessedBlock>>,
+        recv_ch: flume::Receiver<Box<LevelBlock>>,
+        max_id: &SharedMaxId,
     ) -> Result<()> {
         let update_derived = false;
         #[cfg(feature = "regression_force_update_derived")]
         let update_derived = true | update_derived;
 
-        let mut batch = ProcessedBatch::new(dbcli.get_max_id()?);
-
-        let mut accum_begin = Instant::now();
-        for processed_block in recv_ch {
-            batch.add(*processed_block);
-
-            if batch.len() >= batch_size {
-                let accum_elapsed = accum_begin.elapsed();
-
+        Self::collect_batches(
+            batch_size,
+            commit_interval,
+            recv_ch,
+            max_id,
+            |batch, accum_elapsed| {
                 let insert_begin = Instant::now();
-                insert_batch(&mut dbcli, Some(stats), update_derived, &batch)?;
+                Self::insert_batch_retrying(
+                    &mut dbcli,
+                    stats,
+                    update_derived,
+                    batch,
+                )?;
                 let insert_elapsed = insert_begin.elapsed();
 
                 stats.set(
@@ -75,34 +127,159 @@ impl DBInserter {
                     "inserter",
                     "prev batch's insert time",
                     format!("{:?}", insert_elapsed),
-                )?;
+                )
+            },
+        )
+    }
+
+    // Collects the processed blocks into batches, passed on to flush (along
+    // with the time spent collecting them) once batch_size blocks are
+    // collected, or once commit_interval has passed since the previous flush
+    // (with at least one block collected), whichever comes first. What is
+    // left is flushed once the channel is closed.
+    fn collect_batches<F>(
+        batch_size: usize,
+        commit_interval: Option<Duration>,
+        recv_ch: flume::Receiver<Box<LevelBlock>>,
+        max_id: &SharedMaxId,
+        mut flush: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&ProcessedBatch, Duration) -> Result<()>,
+    {
+        let mut batch = ProcessedBatch::new(0);
+
+        let mut accum_begin = Instant::now();
+        loop {
+            let received = match commit_interval {
+                Some(interval) if batch.len() > 0 => {
+                    match recv_ch.recv_deadline(accum_begin + interval) {
+                        Ok(processed_block) => Some(processed_block),
+                        Err(flume::RecvTimeoutError::Timeout) => None,
+                        Err(flume::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                _ => match recv_ch.recv() {
+                    Ok(processed_block) => Some(processed_block),
+                    Err(flume::RecvError::Disconnected) => break,
+                },
+            };
+            let timed_out = received.is_none();
+            if let Some(received) = received {
+                let (_, processed_block) = *received;
+                batch.add_shared(processed_block, max_id)?;
+            }
+
+            if timed_out || batch.len() >= batch_size {
+                flush(&batch, accum_begin.elapsed())?;
                 batch.clear();
                 accum_begin = Instant::now();
             }
         }
-        insert_batch(&mut dbcli, Some(stats), update_derived, &batch)?;
+        flush(&batch, accum_begin.elapsed())
+    }
 
-        Ok(())
+    // A failed insert is rolled back entirely, so on losing the connection
+    // to the db the same batch is simply inserted again.
+    fn insert_batch_retrying(
+        dbcli: &mut DBClient,
+        stats: &StatsLogger,
+        update_derived: bool,
+        batch: &ProcessedBatch,
+    ) -> Result<()> {
+        let retries = dbcli.get_conn_retries();
+        db::retry_with_backoff(
+            "insert a batch of processed levels",
+            retries,
+            db::is_conn_err,
+            || insert_batch(dbcli, Some(stats), update_derived, batch),
+        )
     }
 }
 
-pub(crate) fn insert_processed(
-    dbcli: &mut DBClient,
-    update_derived_tables: bool,
-    processed: ProcessedBlock,
-) -> Result<()> {
-    let mut batch = ProcessedBatch::new(dbcli.get_max_id()?);
-    batch.add(processed);
+impl ProcessedBlockSink for DBInserter {
+    fn run(
+        &self,
+        stats: &StatsLogger,
+        recv_ch: flume::Receiver<Box<LevelBlock>>,
+        _level_order: Option<LevelOrder>,
+    ) -> Result<thread::JoinHandle<Result<()>>> {
+        let batch_size = self.batch_size;
+        let commit_interval = self.commit_interval;
+        let threads = self.threads;
+        let mut dbcli = self.dbcli.with_role("inserter");
+        let stats_cl = stats.clone();
+
+        let max_id: SharedMaxId = Arc::new(Mutex::new(dbcli.get_max_id()?));
+        let thread_handle = thread::spawn(move || {
+            if threads <= 1 {
+                return Self::exec(
+                    dbcli,
+                    batch_size,
+                    commit_interval,
+                    &stats_cl,
+                    recv_ch,
+                    &max_id,
+                );
+            }
+
+            let mut inserters = vec![];
+            for i in 0..threads {
+                let dbcli = dbcli.with_role(&format!("inserter-{}", i));
+                let stats = stats_cl.clone();
+                let recv_ch = recv_ch.clone();
+                let max_id = max_id.clone();
+                inserters.push(thread::spawn(move || {
+                    Self::exec(
+                        dbcli,
+                        batch_size,
+                        commit_interval,
+                        &stats,
+                        recv_ch,
+                        &max_id,
+                    )
+                }));
+            }
+            // (all of them are waited for, the first error is returned)
+            let mut res = Ok(());
+            for inserter in inserters {
+                let inserted = inserter
+                    .join()
+                    .map_err(|e| anyhow!("inserter thread panicked: {:?}", e));
+                if res.is_ok() {
+                    res = inserted.and_then(|inserted| inserted);
+                }
+            }
+            res
+        });
+        Ok(thread_handle)
+    }
 
-    insert_batch(dbcli, None, update_derived_tables, &batch)
+    fn insert_processed(
+        &mut self,
+        update_derived_tables: bool,
+        processed: ProcessedBlock,
+    ) -> Result<()> {
+        let mut batch = ProcessedBatch::new(self.dbcli.get_max_id()?);
+        batch.add(processed);
+
+        insert_batch(&mut self.dbcli, None, update_derived_tables, &batch)
+    }
 }
 
-fn insert_batch(
+// The number of spilled inserts to read back into memory at a time.
+pub(crate) const SPILLED_INSERTS_CHUNK_SIZE: usize = 10000;
+
+// Inserts everything of the batch, except for the contracts' data when the db
+// has no contract tables (in which case the data is produced elsewhere, eg to
+// Kafka).
+pub(crate) fn insert_batch(
     dbcli: &mut DBClient,
     stats: Option<&StatsLogger>,
     update_derived_tables: bool,
     batch: &ProcessedBatch,
 ) -> Result<()> {
+    let shared_tx_contexts = dbcli.shares_tx_contexts();
     let mut conn = dbcli.dbconn()?;
 
     let mut db_tx = conn.transaction()?;
@@ -114,27 +291,63 @@ fn insert_batch(
             .levels
             .values()
             .collect::<Vec<&LevelMeta>>(),
+        dbcli.keeps_levels(),
     )?;
     DBClient::save_contract_deps(&mut db_tx, &batch.contract_deps)?;
     DBClient::save_contract_levels(&mut db_tx, &batch.contract_levels)?;
+    DBClient::save_contract_balances(&mut db_tx, &batch.contract_balances)?;
+    DBClient::save_delegations(&mut db_tx, &batch.contract_delegations)?;
 
-    DBClient::save_tx_contexts(&mut db_tx, &batch.tx_contexts)?;
+    DBClient::save_tx_contexts(
+        &mut db_tx,
+        &batch.tx_contexts,
+        shared_tx_contexts,
+    )?;
     DBClient::save_txs(&mut db_tx, &batch.txs)?;
 
-    for (contract_id, inserts) in &batch.contract_inserts {
-        let num_rows = inserts.len();
-        if let Some(stats) = stats {
-            stats.add("inserter", "contract data rows", num_rows)?;
+    let contract_tables = dbcli.has_contract_tables();
+    if contract_tables {
+        for (contract_id, inserts) in &batch.contract_inserts {
+            let num_rows = inserts.len();
+            if let Some(stats) = stats {
+                stats.add("inserter", "contract data rows", num_rows)?;
+            }
+            DBClient::apply_inserts(&mut db_tx, contract_id, inserts)?;
+        }
+        // the spilled inserts go after the others, as they may refer to them
+        // (eg to the row of the bigmap they were copied into)
+        for (contract_id, level, spilled) in &batch.contract_spilled_inserts {
+            if let Some(stats) = stats {
+                stats.add("inserter", "contract data rows", spilled.len())?;
+            }
+            let level_timestamp = batch
+                .levels
+                .get(level)
+                .and_then(|meta| meta.baked_at);
+            spilled.for_each_chunk(
+                SPILLED_INSERTS_CHUNK_SIZE,
+                |mut chunk| {
+                    for insert in &mut chunk {
+                        insert.set_level(*level, level_timestamp);
+                    }
+                    DBClient::apply_inserts(&mut db_tx, contract_id, &chunk)
+                },
+            )?;
         }
-        DBClient::apply_inserts(&mut db_tx, contract_id, inserts)?;
     }
     DBClient::save_bigmap_keyhashes(
         &mut db_tx,
         batch.bigmap_keyhashes.clone(),
     )?;
     DBClient::save_bigmap_meta_actions(&mut db_tx, &batch.bigmap_meta_actions)?;
+    DBClient::save_operation_failures(&mut db_tx, &batch.operation_failures)?;
+    DBClient::save_raw_storage(
+        &mut db_tx,
+        &batch.raw_storage,
+        dbcli.raw_storage_compression(),
+    )?;
 
-    if update_derived_tables {
+    if update_derived_tables && contract_tables {
         for (contract_id, (contract, ctxs)) in &batch.contract_tx_contexts {
             dbcli.update_derived_tables(
                 &mut db_tx,
@@ -150,6 +363,11 @@ fn insert_batch(
 
     db_tx.commit()?;
 
+    if let Some(level_feed) = dbcli.level_feed() {
+        if let Err(e) = level_feed.publish(batch) {
+            warn!("failed to publish committed levels: {}", e);
+        }
+    }
     Ok(())
 }
 
@@ -161,15 +379,146 @@ pub(crate) struct ProcessedContractBlock {
     pub is_origination: bool,
 
     pub inserts: Vec<Insert>,
+    // inserts that were moved to disk while processing (see
+    // --bigmap-copy-chunk-size), in addition to inserts
+    pub spilled_inserts: Option<SpilledInserts>,
     pub tx_contexts: Vec<TxContext>,
     pub txs: Vec<Tx>,
     pub bigmap_contract_deps: Vec<(String, i32, bool)>,
     pub bigmap_keyhashes: db::BigmapEntries,
     pub bigmap_meta_actions: Vec<BigmapMetaAction>,
+    pub operation_failures: Vec<OperationFailure>,
+    // only set when keeping the raw storage
+    pub raw_storage: Vec<RawStorage>,
+    // only set when indexing delegations
+    pub delegations: Vec<Delegation>,
+
+    // the contract's balance at this level, only set when tracking balances
+    pub balance: Option<PgNumeric>,
+
+    // whether the ids were derived deterministically (see
+    // --deterministic-ids), they are then final and not offset into the
+    // batch's range of ids
+    pub deterministic_ids: bool,
+}
+
+/// A hash over the content processed for a level: the inserts, tx contexts
+/// and bigmap actions of all its contract blocks. Row ids are left out, as
+/// they depend on the batch the level is inserted in (rows refer to their tx
+/// context by its coordinates instead), and the content is sorted first. This
+/// way processing the same block again (eg in parallel with other blocks)
+/// yields the same hash.
+///
+/// The content is not sorted as a whole, instead each line is hashed on its
+/// own and the line hashes are summed (see DigestSum), which does not depend
+/// on their order. Inserts that were spilled to disk are thus hashed chunk by
+/// chunk, without reading them into memory all at once, and a level hashes
+/// the same whether or not its inserts were spilled.
+pub(crate) fn content_hash(block: &[ProcessedContractBlock]) -> Result<String> {
+    let mut sum = DigestSum::default();
+    for cres in block {
+        let mut ctx_coords: HashMap<i64, String> = HashMap::new();
+        for ctx in &cres.tx_contexts {
+            let id = ctx
+                .id
+                .ok_or_else(|| anyhow!("tx context without id: {:?}", ctx))?;
+            ctx_coords.insert(
+                id,
+                format!(
+                    "{}/{}/{}/{}/{}/{:?}",
+                    ctx.contract,
+                    ctx.level,
+                    ctx.operation_group_number,
+                    ctx.operation_number,
+                    ctx.content_number,
+                    ctx.internal_number
+                ),
+            );
+        }
+        let coords = |id: i64| {
+            ctx_coords
+                .get(&id)
+                .ok_or_else(|| anyhow!("unknown tx context id {}", id))
+        };
+
+        for ctx in ctx_coords.values() {
+            sum.add(&format!("ctx {}", ctx));
+        }
+        let insert_line = |insert: &Insert| -> Result<String> {
+            let mut columns: Vec<String> = vec![];
+            for col in &insert.columns {
+                if col.name == "tx_context_id" {
+                    continue;
+                }
+                columns.push(format!(
+                    "{}={}",
+                    col.name,
+                    serde_json::to_string(&col.value)?
+                ));
+            }
+            columns.sort();
+            Ok(format!(
+                "insert {} {} {} {}",
+                cres.contract.cid.name,
+                insert.table_name,
+                coords(insert.get_tx_context_id()?)?,
+                columns.join(",")
+            ))
+        };
+        for insert in &cres.inserts {
+            sum.add(&insert_line(insert)?);
+        }
+        if let Some(spilled) = &cres.spilled_inserts {
+            spilled.for_each_chunk(SPILLED_INSERTS_CHUNK_SIZE, |chunk| {
+                for insert in &chunk {
+                    sum.add(&insert_line(insert)?);
+                }
+                Ok(())
+            })?;
+        }
+        for action in &cres.bigmap_meta_actions {
+            sum.add(&format!(
+                "bigmap {} {} {} {}",
+                coords(action.tx_context_id)?,
+                action.bigmap_id,
+                action.action,
+                serde_json::to_string(&action.value)?
+            ));
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(sum.sum);
+    hasher.update(sum.count.to_be_bytes());
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// The sum (modulo 2^256) of the SHA-256 hashes of the lines added, and their
+// number. Neither depends on the order in which the lines are added.
+#[derive(Default)]
+struct DigestSum {
+    sum: [u8; 32],
+    count: u64,
+}
+
+impl DigestSum {
+    fn add(&mut self, line: &str) {
+        let digest = Sha256::digest(line.as_bytes());
+        let mut carry: u16 = 0;
+        for i in (0..32).rev() {
+            let x = self.sum[i] as u16 + digest[i] as u16 + carry;
+            self.sum[i] = x as u8;
+            carry = x >> 8;
+        }
+        self.count += 1;
+    }
 }
 
 impl ProcessedContractBlock {
     pub fn offset_ids(&mut self, offset: i64) -> i64 {
+        if self.deterministic_ids {
+            return offset;
+        }
         let max_insert_id = self.offset_inserts(offset);
         let max_tx_id = self.offset_txs(offset);
         std::cmp::max(max_insert_id, max_tx_id)
@@ -178,22 +527,11 @@ impl ProcessedContractBlock {
     fn offset_inserts(&mut self, offset: i64) -> i64 {
         let mut max = offset;
         for insert in self.inserts.iter_mut() {
-            insert.map_column("tx_context_id", |v| match v {
-                insert::Value::BigInt(i) => insert::Value::BigInt(i + offset),
-                _ => panic!(".."),
-            });
-
-            insert.id += offset;
-            insert.fk_id = insert.fk_id.map(|fk_id| fk_id + offset);
-            max = vec![
-                insert.id,
-                insert.fk_id.unwrap_or(0),
-                insert.get_tx_context_id().unwrap(),
-                max,
-            ]
-            .into_iter()
-            .max()
-            .unwrap();
+            insert.offset_ids(offset);
+            max = std::cmp::max(insert.max_id(), max);
+        }
+        if let Some(spilled) = &mut self.spilled_inserts {
+            max = std::cmp::max(spilled.offset_ids(offset), max);
         }
         max
     }
@@ -227,11 +565,21 @@ impl ProcessedContractBlock {
             max = std::cmp::max(action.tx_context_id, max);
         }
 
+        for failure in self.operation_failures.iter_mut() {
+            failure.tx_context_id += offset;
+            max = std::cmp::max(failure.tx_context_id, max);
+        }
+
+        for raw in self.raw_storage.iter_mut() {
+            raw.tx_context_id += offset;
+            max = std::cmp::max(raw.tx_context_id, max);
+        }
+
         max
     }
 }
 
-struct ProcessedBatch {
+pub(crate) struct ProcessedBatch {
     size: usize,
 
     pub levels: HashMap<i32, LevelMeta>,
@@ -239,10 +587,16 @@ struct ProcessedBatch {
     pub txs: Vec<Tx>,
     pub bigmap_keyhashes: db::BigmapEntries,
     pub bigmap_meta_actions: Vec<BigmapMetaAction>,
+    pub operation_failures: Vec<OperationFailure>,
+    pub raw_storage: Vec<RawStorage>,
 
     pub contract_levels: Vec<(ContractID, i32, bool)>,
+    pub contract_balances: Vec<(ContractID, i32, PgNumeric)>,
+    pub contract_delegations: Vec<(ContractID, Delegation)>,
     pub contract_inserts: HashMap<ContractID, Vec<Insert>>,
-    pub contract_deps: Vec<(i32, String, ContractID, bool)>,
+    // with the level they were indexed at
+    pub contract_spilled_inserts: Vec<(ContractID, i32, SpilledInserts)>,
+    pub contract_deps: Vec<(i32, String, i32, ContractID, bool)>,
     pub contract_tx_contexts:
         HashMap<ContractID, (relational::Contract, Vec<TxContext>)>,
 
@@ -259,9 +613,14 @@ impl ProcessedBatch {
             txs: vec![],
             bigmap_keyhashes: HashMap::new(),
             bigmap_meta_actions: vec![],
+            operation_failures: vec![],
+            raw_storage: vec![],
 
             contract_levels: vec![],
+            contract_balances: vec![],
+            contract_delegations: vec![],
             contract_inserts: HashMap::new(),
+            contract_spilled_inserts: vec![],
             contract_deps: vec![],
             contract_tx_contexts: HashMap::new(),
 
@@ -283,16 +642,52 @@ impl ProcessedBatch {
         self.txs.clear();
         self.bigmap_keyhashes.clear();
         self.bigmap_meta_actions.clear();
+        self.operation_failures.clear();
+        self.raw_storage.clear();
         self.contract_levels.clear();
+        self.contract_balances.clear();
+        self.contract_delegations.clear();
         self.contract_inserts.clear();
+        self.contract_spilled_inserts.clear();
         self.contract_deps.clear();
 
         self.size = 0;
     }
 
-    pub fn add(&mut self, processed_block: ProcessedBlock) {
-        for mut cres in processed_block.into_iter() {
+    pub fn add(&mut self, mut processed_block: ProcessedBlock) {
+        self.offset_ids(&mut processed_block);
+        self.add_offset(processed_block);
+    }
+
+    // Like add, but offsets the block's ids past the highest id handed out to
+    // any batch sharing max_id (eg the batches of the other inserter threads).
+    pub fn add_shared(
+        &mut self,
+        mut processed_block: ProcessedBlock,
+        max_id: &SharedMaxId,
+    ) -> Result<()> {
+        {
+            let mut max_id = max_id
+                .lock()
+                .map_err(|_| anyhow!("failed to lock max_id mutex"))?;
+            self.max_id = *max_id;
+            self.offset_ids(&mut processed_block);
+            *max_id = self.max_id;
+        }
+        self.add_offset(processed_block);
+        Ok(())
+    }
+
+    // Offsets the block's ids past those of the blocks added so far, the
+    // block is expected to be passed to add_offset next.
+    pub fn offset_ids(&mut self, processed_block: &mut ProcessedBlock) {
+        for cres in processed_block.iter_mut() {
             self.max_id = cres.offset_ids(self.max_id);
+        }
+    }
+
+    pub fn add_offset(&mut self, processed_block: ProcessedBlock) {
+        for cres in processed_block.into_iter() {
             self.add_cres(cres);
         }
         self.size += 1;
@@ -328,6 +723,18 @@ impl ProcessedBatch {
             cres.level.level as i32,
             cres.is_origination,
         ));
+        if let Some(balance) = &cres.balance {
+            self.contract_balances.push((
+                cres.contract.cid.clone(),
+                level,
+                balance.clone(),
+            ));
+        }
+        self.contract_delegations.extend(
+            cres.delegations
+                .iter()
+                .map(|d| (cres.contract.cid.clone(), d.clone())),
+        );
 
         if !self
             .contract_inserts
@@ -340,14 +747,31 @@ impl ProcessedBatch {
             .contract_inserts
             .get_mut(&cres.contract.cid)
             .unwrap();
-        inserts.extend(cres.inserts.clone());
+        inserts.extend(cres.inserts.iter().map(|insert| {
+            let mut insert = insert.clone();
+            insert.set_level(level, cres.level.baked_at);
+            insert
+        }));
+        if let Some(spilled) = &cres.spilled_inserts {
+            self.contract_spilled_inserts.push((
+                cres.contract.cid.clone(),
+                level,
+                spilled.clone(),
+            ));
+        }
 
         self.contract_deps
             .extend(
                 cres.bigmap_contract_deps
                     .iter()
                     .map(|dep| {
-                        (level, dep.0.clone(), cres.contract.cid.clone(), dep.2)
+                        (
+                            level,
+                            dep.0.clone(),
+                            dep.1,
+                            cres.contract.cid.clone(),
+                            dep.2,
+                        )
                     }),
             );
 
@@ -356,5 +780,154 @@ impl ProcessedBatch {
 
         self.bigmap_meta_actions
             .extend(cres.bigmap_meta_actions);
+
+        self.operation_failures
+            .extend(cres.operation_failures);
+
+        self.raw_storage
+            .extend(cres.raw_storage);
+    }
+}
+
+#[test]
+fn test_add_shared() {
+    use crate::test_support::test_block;
+
+    let max_id: SharedMaxId = Arc::new(Mutex::new(100));
+    let mut batch1 = ProcessedBatch::new(0);
+    let mut batch2 = ProcessedBatch::new(0);
+
+    batch1
+        .add_shared(test_block(10, &[("a", true), ("b", true)]), &max_id)
+        .unwrap();
+    batch2
+        .add_shared(test_block(11, &[("a", true)]), &max_id)
+        .unwrap();
+    batch1
+        .add_shared(test_block(12, &[("b", true)]), &max_id)
+        .unwrap();
+
+    let ids = |batch: &ProcessedBatch| -> Vec<i64> {
+        batch
+            .tx_contexts
+            .iter()
+            .map(|ctx| ctx.id.unwrap())
+            .collect()
+    };
+    assert_eq!(vec![101, 102, 104], ids(&batch1));
+    assert_eq!(vec![103], ids(&batch2));
+    assert_eq!(2, batch1.len());
+    assert_eq!(104, batch1.get_max_id());
+    assert_eq!(103, batch2.get_max_id());
+    assert_eq!(104, *max_id.lock().unwrap());
+}
+
+#[test]
+fn test_add_level_columns() {
+    use crate::sql::insert::{Column, Value};
+    use crate::test_support::test_block;
+    use chrono::{TimeZone, Utc};
+
+    let baked_at = Utc.timestamp(1_600_000_000, 0);
+    let mut block = test_block(10, &[("a", true)]);
+    block[0].level.baked_at = Some(baked_at);
+    block[0].inserts = vec![Insert {
+        table_name: "storage".to_string(),
+        id: 1,
+        fk_id: None,
+        parent_table: None,
+        columns: vec![Column {
+            name: "tx_context_id".to_string(),
+            value: Value::BigInt(1),
+        }],
+    }];
+
+    let mut batch = ProcessedBatch::new(0);
+    batch.add(block);
+    let insert = &batch.contract_inserts[&ContractID {
+        name: "a".to_string(),
+        address: "KT1a".to_string(),
+    }][0];
+    assert_eq!(
+        Some(Value::Int(10)),
+        insert
+            .get_column("level")
+            .unwrap()
+            .map(|column| column.value)
+    );
+    assert_eq!(
+        Some(Value::Timestamp(Some(baked_at))),
+        insert
+            .get_column("level_timestamp")
+            .unwrap()
+            .map(|column| column.value)
+    );
+}
+
+#[test]
+fn test_collect_batches_commit_interval() {
+    use crate::test_support::test_block;
+
+    let max_id: SharedMaxId = Arc::new(Mutex::new(0));
+    let (send_ch, recv_ch) = flume::unbounded::<Box<LevelBlock>>();
+    // the levels of each flushed batch, and the time spent collecting them
+    let (flushed_send, flushed_recv) =
+        flume::unbounded::<(Vec<i32>, Duration)>();
+    let collector = thread::spawn(move || {
+        DBInserter::collect_batches(
+            3,
+            Some(Duration::from_millis(100)),
+            recv_ch,
+            &max_id,
+            |batch, accum_elapsed| {
+                let mut levels: Vec<i32> =
+                    batch.levels.keys().cloned().collect();
+                levels.sort_unstable();
+                flushed_send.send((levels, accum_elapsed))?;
+                Ok(())
+            },
+        )
+    });
+
+    // a full batch is flushed right away
+    for level in 10..13 {
+        send_ch
+            .send(Box::new((level, test_block(level, &[("a", true)]))))
+            .unwrap();
     }
+    assert_eq!(
+        vec![10, 11, 12],
+        flushed_recv
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap()
+            .0
+    );
+
+    // a partial batch is flushed once the interval passed (since the
+    // previous flush), without new input
+    send_ch
+        .send(Box::new((13, test_block(13, &[("a", true)]))))
+        .unwrap();
+    let (levels, accum_elapsed) = flushed_recv
+        .recv_timeout(Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(vec![13], levels);
+    assert!(accum_elapsed >= Duration::from_millis(100));
+    // nothing is flushed while there is nothing to flush
+    assert!(flushed_recv
+        .recv_timeout(Duration::from_millis(300))
+        .is_err());
+
+    // what is left is flushed, at the latest once the channel closes (the
+    // interval may pass before then, leaving nothing for the last flush)
+    send_ch
+        .send(Box::new((14, test_block(14, &[("a", true)]))))
+        .unwrap();
+    drop(send_ch);
+    collector.join().unwrap().unwrap();
+    let flushed: Vec<i32> = flushed_recv
+        .try_iter()
+        .flat_map(|(levels, _)| levels)
+        .collect();
+    assert_eq!(vec![14], flushed);
 }
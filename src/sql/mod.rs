@@ -1,7 +1,9 @@
+pub mod compression;
 pub mod db;
 pub mod insert;
 pub mod inserter;
 pub mod postgresql_generator;
+pub mod spill;
 pub mod table;
 pub mod table_builder;
 pub mod types;
@@ -3,6 +3,7 @@ use askama::Template;
 use std::vec::Vec;
 
 use crate::config::{ContractID, QUEPASA_VERSION};
+use crate::metadata;
 use crate::sql::table::{Column, Table};
 use crate::storage_structure::typing::ExprTy;
 
@@ -51,6 +52,7 @@ struct CreateFunctionShortcutsTmpl<'a> {
 pub struct PostgresqlGenerator {
     main_schema: String,
     contract_id: ContractID,
+    tx_contexts_table: String,
 }
 
 impl PostgresqlGenerator {
@@ -58,15 +60,30 @@ impl PostgresqlGenerator {
         Self {
             main_schema,
             contract_id: contract_id.clone(),
+            tx_contexts_table: "tx_contexts".to_string(),
         }
     }
 
+    /// Reference the tx contexts of a db set up with shared tx contexts
+    /// (where tx_contexts is a view over operations and the contract
+    /// specific contract_tx_contexts).
+    pub(crate) fn share_tx_contexts(&mut self) -> &mut Self {
+        self.tx_contexts_table = "contract_tx_contexts".to_string();
+        self
+    }
+
     pub(crate) fn create_sql(column: &Column) -> Option<String> {
         match column.name.as_str() {
             "id" => return Some("id BIGSERIAL PRIMARY KEY".to_string()),
             "tx_context_id" => {
                 return Some("tx_context_id BIGINT NOT NULL".to_string())
             }
+            "level" => return Some("level INTEGER".to_string()),
+            "level_timestamp" => {
+                return Some(
+                    "level_timestamp TIMESTAMP WITH TIME ZONE".to_string(),
+                )
+            }
             "deleted" => {
                 return Some(
                     "deleted BOOLEAN NOT NULL DEFAULT 'false'".to_string(),
@@ -81,16 +98,25 @@ impl PostgresqlGenerator {
             ExprTy::Address => Some(Self::address(&name)),
             ExprTy::Bool => Some(Self::bool(&name)),
             ExprTy::Bytes => Some(Self::bytes(&name)),
-            ExprTy::Int | ExprTy::Nat | ExprTy::Mutez => {
-                Some(Self::numeric(&name))
+            ExprTy::Int | ExprTy::Nat | ExprTy::Mutez if column.bigint => {
+                Some(Self::bigint(&name))
             }
+            ExprTy::Int | ExprTy::Nat | ExprTy::Mutez => match column.precision
+            {
+                Some((precision, scale)) => {
+                    Some(Self::bounded_numeric(&name, precision, scale))
+                }
+                None => Some(Self::numeric(&name)),
+            },
             ExprTy::KeyHash | ExprTy::Signature | ExprTy::Contract => {
                 Some(Self::string(&name))
             }
             ExprTy::Stop => None,
+            ExprTy::Never => Some(Self::unit(&name)),
             ExprTy::String => Some(Self::string(&name)),
             ExprTy::Timestamp => Some(Self::timestamp(&name)),
             ExprTy::Unit => Some(Self::unit(&name)),
+            ExprTy::Opaque(_) => Some(Self::bytes(&name)),
             _ => panic!(
                 "unrecoverable err, cannot make sql column for type {:#?}",
                 column.column_type
@@ -118,6 +144,18 @@ impl PostgresqlGenerator {
         format!("{} NUMERIC", name)
     }
 
+    pub(crate) fn bounded_numeric(
+        name: &str,
+        precision: u32,
+        scale: u32,
+    ) -> String {
+        format!("{} NUMERIC({},{})", name, precision, scale)
+    }
+
+    pub(crate) fn bigint(name: &str) -> String {
+        format!("{} BIGINT", name)
+    }
+
     pub(crate) fn string(name: &str) -> String {
         format!("{} TEXT", name)
     }
@@ -139,7 +177,10 @@ impl PostgresqlGenerator {
     }
 
     pub(crate) fn end_table(&self) -> String {
-        include_str!("../../sql/table-footer.sql").to_string()
+        format!(
+            include_str!("../../sql/table-footer.sql"),
+            tx_contexts_table = self.tx_contexts_table,
+        )
     }
 
     pub(crate) fn create_table_functions(
@@ -318,6 +359,25 @@ impl PostgresqlGenerator {
             .collect()
     }
 
+    // The columns of the table's elements (ie all but the reference to the
+    // parent row, which differs between snapshots), and of these the columns
+    // that tell the elements apart.
+    pub(crate) fn table_sql_element_columns(
+        table: &Table,
+    ) -> (Vec<String>, Vec<String>) {
+        let parent_key = Self::parent_key(table).map(|k| Self::quote_id(&k));
+        let element = |columns: Vec<String>| -> Vec<String> {
+            columns
+                .into_iter()
+                .filter(|c| Some(c) != parent_key.as_ref())
+                .collect()
+        };
+        (
+            element(Self::table_sql_columns(table, false)),
+            element(Self::table_sql_indices(table, false)),
+        )
+    }
+
     pub(crate) fn create_index(&self, table: &Table) -> Vec<String> {
         if table.indices.is_empty() {
             return vec![];
@@ -333,6 +393,23 @@ impl PostgresqlGenerator {
             table = table.name,
             columns = Self::table_sql_indices(table, true).join(", ")
         )];
+        if !table.has_uniqueness() && table.columns.contains_key("ordinal") {
+            // the elements of a list need not be unique, their positions
+            // within the list are
+            let mut columns = vec!["tx_context_id".to_string()];
+            columns.extend(Self::parent_key(table));
+            columns.push("ordinal".to_string());
+            res.push(format!(
+                r#"CREATE UNIQUE INDEX ON "{contract_schema}"."{table}"({columns});"#,
+                contract_schema = self.contract_id.name,
+                table = table.name,
+                columns = columns
+                    .iter()
+                    .map(|c| Self::quote_id(c))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ));
+        }
         if let Some(parent) = Self::table_parent_name(table) {
             res.push(format!(
                 r#"CREATE INDEX ON "{contract_schema}"."{table}"("{parent_ref}");"#,
@@ -358,15 +435,7 @@ impl PostgresqlGenerator {
             // changes at later levels
             return None;
         }
-        Self::parent_name(&table.name)
-    }
-
-    pub(crate) fn parent_name(name: &str) -> Option<String> {
-        if name.starts_with("entry.") && name.matches('.').count() == 1 {
-            return None;
-        }
-        name.rfind('.')
-            .map(|pos| name[0..pos].to_string())
+        table.parent().map(String::from)
     }
 
     pub(crate) fn parent_ref(parent_table: &str) -> String {
@@ -400,11 +469,24 @@ impl PostgresqlGenerator {
         }).collect::<Vec<String>>()
     }
 
-    pub(crate) fn create_common_tables(main_schema: &str) -> String {
+    pub(crate) fn create_common_tables(
+        main_schema: &str,
+        shared_tx_contexts: bool,
+    ) -> String {
+        let (tx_contexts_tables, tx_contexts_table) = if shared_tx_contexts {
+            (
+                include_str!("../../sql/tx-contexts-shared.sql"),
+                "contract_tx_contexts",
+            )
+        } else {
+            (include_str!("../../sql/tx-contexts.sql"), "tx_contexts")
+        };
         format!(
             include_str!("../../sql/common-tables.sql"),
             main_schema = main_schema,
             quepasa_version = QUEPASA_VERSION,
+            tx_contexts_tables = tx_contexts_tables,
+            tx_contexts_table = tx_contexts_table,
         )
     }
 
@@ -421,9 +503,47 @@ impl PostgresqlGenerator {
         v.push(s);
         v.push(self.end_table());
         v.extend(self.create_index(table));
+        v.extend(self.create_table_comment(table));
         Ok(v.join("\n"))
     }
 
+    // Records the kind of Michelson value the table holds the elements of,
+    // and its path in the storage, for tooling to read from pg_description.
+    fn create_table_comment(&self, table: &Table) -> Option<String> {
+        let origin = table.origin()?;
+        Some(format!(
+            r#"COMMENT ON TABLE "{contract_schema}"."{table}" IS '{comment}';"#,
+            contract_schema = self.contract_id.name,
+            table = table.name,
+            comment = format!(
+                "michelson_kind={} storage_path={}",
+                origin.kind.as_str(),
+                origin.path
+            )
+            .replace('\'', "''"),
+        ))
+    }
+
+    // The table the resolved metadata of a table's metadata URI column go to
+    // (see metadata::resolve_in_background), with at most one row per row of
+    // the table.
+    pub(crate) fn create_resolved_metadata_table(&self, table: &str) -> String {
+        format!(
+            r#"
+CREATE TABLE IF NOT EXISTS "{contract_schema}"."{resolved_table}" (
+    "{parent_ref}" BIGINT PRIMARY KEY REFERENCES "{contract_schema}"."{table}"(id) ON DELETE CASCADE,
+    uri TEXT,
+    resolved BOOLEAN NOT NULL,
+    metadata TEXT,
+    error TEXT
+);"#,
+            contract_schema = self.contract_id.name,
+            resolved_table = metadata::resolved_metadata_table(table),
+            parent_ref = Self::parent_ref(table),
+            table = table,
+        )
+    }
+
     pub(crate) fn create_derived_table_definitions(
         &self,
         table: &Table,
@@ -449,13 +569,27 @@ impl PostgresqlGenerator {
         if !table.contains_snapshots() {
             ordered.drop_column("bigmap_id");
         }
+        if table.contains_ordered_changes() {
+            // marks the elements that were removed from the snapshot
+            ordered.add_column("deleted", &ExprTy::Bool);
+        }
         ordered.add_fk("id".to_string(), table.name.clone(), "id".to_string());
         ordered.id_unique = false;
 
-        Ok(vec![
+        let mut res = vec![
             self.create_table_definition(&live)?,
             self.create_table_definition(&ordered)?,
-        ])
+        ];
+        if ordered.columns.contains_key("ordinal") {
+            // list elements share their ordering (that of their tx context),
+            // within it they are ordered by their position in the list
+            res.push(format!(
+                r#"CREATE INDEX ON "{contract_schema}"."{table}"(ordering, ordinal);"#,
+                contract_schema = self.contract_id.name,
+                table = ordered.name,
+            ));
+        }
+        Ok(res)
     }
 
     /*
@@ -466,3 +600,88 @@ impl PostgresqlGenerator {
     }
     */
 }
+
+#[test]
+fn test_create_common_tables_shared_tx_contexts() {
+    let unshared = PostgresqlGenerator::create_common_tables("public", false);
+    assert!(unshared.contains("REFERENCES tx_contexts(id)"));
+    assert!(!unshared.contains("CREATE TABLE operations"));
+
+    let shared = PostgresqlGenerator::create_common_tables("public", true);
+    assert!(shared.contains("CREATE TABLE operations"));
+    assert!(shared.contains("CREATE VIEW tx_contexts"));
+    assert!(shared.contains("REFERENCES contract_tx_contexts(id)"));
+    assert!(!shared.contains("REFERENCES tx_contexts(id)"));
+
+    let mut generator = PostgresqlGenerator::new(
+        "public".to_string(),
+        &ContractID {
+            name: "test".to_string(),
+            address: "KT1".to_string(),
+        },
+    );
+    assert!(generator
+        .end_table()
+        .contains("REFERENCES tx_contexts(id)"));
+    generator.share_tx_contexts();
+    assert!(generator
+        .end_table()
+        .contains("REFERENCES contract_tx_contexts(id)"));
+}
+
+#[test]
+fn test_create_table_definition_comment() {
+    use crate::sql::table::TableKind;
+
+    let generator = PostgresqlGenerator::new(
+        "public".to_string(),
+        &ContractID {
+            name: "test".to_string(),
+            address: "KT1".to_string(),
+        },
+    );
+    let table = |name: &str| {
+        let mut t = Table::new(name.to_string());
+        t.add_index("tx_context_id", &ExprTy::Int);
+        t.add_column("id", &ExprTy::Int);
+        t
+    };
+
+    let storage = table("storage");
+    assert!(!generator
+        .create_table_definition(&storage)
+        .unwrap()
+        .contains("COMMENT ON TABLE"));
+
+    let mut operators = table("storage.operators");
+    operators.set_kind(TableKind::Set, "storage.operators".to_string());
+    assert!(generator
+        .create_table_definition(&operators)
+        .unwrap()
+        .contains(r#"COMMENT ON TABLE "test"."storage.operators" IS 'michelson_kind=set storage_path=storage.operators';"#));
+
+    // derived tables refer to the storage path of their table
+    let mut ledger = table("storage.ledger");
+    ledger.add_index("bigmap_id", &ExprTy::Int);
+    ledger.set_kind(TableKind::BigMap, "storage.ledger".to_string());
+    let derived = generator
+        .create_derived_table_definitions(&ledger)
+        .unwrap();
+    assert!(derived[0].contains(r#"COMMENT ON TABLE "test"."storage.ledger_live" IS 'michelson_kind=bigmap storage_path=storage.ledger';"#));
+    assert!(derived[1].contains(r#"COMMENT ON TABLE "test"."storage.ledger_ordered" IS 'michelson_kind=bigmap storage_path=storage.ledger';"#));
+
+    // renamed (or shortened) tables refer to the path they were generated as
+    let mut renamed = table("storage.ops");
+    renamed.set_kind(TableKind::Set, "storage.operators".to_string());
+    assert!(generator
+        .create_table_definition(&renamed)
+        .unwrap()
+        .contains(r#"COMMENT ON TABLE "test"."storage.ops" IS 'michelson_kind=set storage_path=storage.operators';"#));
+
+    let mut quoted = table("storage.it's");
+    quoted.set_kind(TableKind::Map, "storage.it's".to_string());
+    assert!(generator
+        .create_table_definition(&quoted)
+        .unwrap()
+        .contains("IS 'michelson_kind=map storage_path=storage.it''s';"));
+}
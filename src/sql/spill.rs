@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::sql::insert::Insert;
+
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// Inserts that are kept in a file rather than in memory until they are
+// applied, for contract blocks that produce too many rows to hold at once
+// (see StorageProcessor::set_bigmap_copy_chunk_size). They are written in
+// chunks while the block is processed (one json line per insert), and are
+// read back in chunks by the inserter, in the same transaction as the rest
+// of the level.
+//
+// The file is removed once the last clone is dropped.
+#[derive(Clone, Debug)]
+pub(crate) struct SpilledInserts {
+    file: Arc<SpillFile>,
+    len: usize,
+    // the highest id (of a row, or one it refers to) as written
+    max_id: i64,
+    // added to the ids when the inserts are read back, see offset_ids
+    offset: i64,
+}
+
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "failed to remove spilled inserts file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl SpilledInserts {
+    pub(crate) fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "quepasa-spill-{}-{}.jsonl",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::SeqCst)
+        ));
+        File::create(&path).with_context(|| {
+            format!("failed to create spilled inserts file {}", path.display())
+        })?;
+        Ok(Self {
+            file: Arc::new(SpillFile { path }),
+            len: 0,
+            max_id: 0,
+            offset: 0,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    // Appends a chunk of inserts, in the order given.
+    pub(crate) fn write(&mut self, inserts: &[Insert]) -> Result<()> {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&self.file.path)
+            .with_context(|| {
+                format!(
+                    "failed to open spilled inserts file {}",
+                    self.file.path.display()
+                )
+            })?;
+        let mut writer = BufWriter::new(file);
+        for insert in inserts {
+            serde_json::to_writer(&mut writer, insert)?;
+            writer.write_all(b"\n")?;
+            self.max_id = std::cmp::max(insert.max_id(), self.max_id);
+        }
+        writer.flush()?;
+        self.len += inserts.len();
+        Ok(())
+    }
+
+    // Shifts the ids of the inserts, like ProcessedContractBlock::offset_ids
+    // does for the inserts it holds in memory. The shift is applied when the
+    // inserts are read back. Returns the highest id after shifting.
+    pub(crate) fn offset_ids(&mut self, offset: i64) -> i64 {
+        self.offset += offset;
+        std::cmp::max(self.max_id + self.offset, offset)
+    }
+
+    // Reads the inserts back in chunks of (at most) chunk_size, in the order
+    // they were written.
+    pub(crate) fn for_each_chunk<F>(
+        &self,
+        chunk_size: usize,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Vec<Insert>) -> Result<()>,
+    {
+        let file = File::open(&self.file.path).with_context(|| {
+            format!(
+                "failed to open spilled inserts file {}",
+                self.file.path.display()
+            )
+        })?;
+        let mut chunk: Vec<Insert> = vec![];
+        for line in BufReader::new(file).lines() {
+            let mut insert: Insert = serde_json::from_str(&line?)?;
+            insert.offset_ids(self.offset);
+            chunk.push(insert);
+            if chunk.len() >= chunk_size {
+                f(std::mem::take(&mut chunk))?;
+            }
+        }
+        if !chunk.is_empty() {
+            f(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_spilled_inserts() {
+    use crate::sql::insert::{Column, Value};
+    use pg_bigdecimal::{BigDecimal, PgNumeric};
+
+    let insert = |id: i64| Insert {
+        table_name: "storage.ledger".to_string(),
+        id,
+        fk_id: Some(1),
+        parent_table: Some("storage".to_string()),
+        columns: vec![
+            Column {
+                name: "tx_context_id".to_string(),
+                value: Value::BigInt(2),
+            },
+            Column {
+                name: "idx_nat".to_string(),
+                value: Value::Numeric(PgNumeric::new(Some(BigDecimal::from(
+                    id,
+                )))),
+            },
+        ],
+    };
+
+    let mut spilled = SpilledInserts::new().unwrap();
+    assert_eq!(0, spilled.len());
+    spilled
+        .write(
+            &(3..6)
+                .map(insert)
+                .collect::<Vec<Insert>>(),
+        )
+        .unwrap();
+    spilled
+        .write(
+            &(6..10)
+                .map(insert)
+                .collect::<Vec<Insert>>(),
+        )
+        .unwrap();
+    assert_eq!(7, spilled.len());
+    assert_eq!(109, spilled.offset_ids(100));
+
+    let mut chunks: Vec<Vec<Insert>> = vec![];
+    spilled
+        .for_each_chunk(3, |chunk| {
+            chunks.push(chunk);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(
+        vec![3, 3, 1],
+        chunks
+            .iter()
+            .map(Vec::len)
+            .collect::<Vec<_>>()
+    );
+    let mut expected = insert(9);
+    expected.offset_ids(100);
+    assert_eq!(expected, chunks[2][0]);
+    assert_eq!(
+        102,
+        chunks[0][0]
+            .get_tx_context_id()
+            .unwrap()
+    );
+
+    // the file goes away with the last clone
+    let path = spilled.file.path.clone();
+    let clone = spilled.clone();
+    drop(spilled);
+    assert!(path.exists());
+    drop(clone);
+    assert!(!path.exists());
+}
@@ -6,6 +6,14 @@ use std::collections::HashMap;
 pub struct Column {
     pub name: String,
     pub column_type: ExprTy,
+    // whether the (int, nat or mutez) column is stored as BIGINT instead of
+    // NUMERIC
+    #[serde(default)]
+    pub bigint: bool,
+    // the (precision, scale) of the (int, nat or mutez) column when it is
+    // stored as a bounded NUMERIC
+    #[serde(default)]
+    pub precision: Option<(u32, u32)>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -18,7 +26,50 @@ pub struct Table {
     keys: Vec<String>,
     unique: bool,
     snapshots: bool,
+    // whether the _ordered table of this snapshots table holds the changes
+    // between the snapshots instead of the snapshots themselves (see
+    // TableBuilder::override_snapshots)
+    #[serde(default)]
+    ordered_changes: bool,
     pointers: bool,
+    // the storage value the table holds the elements of, if any
+    #[serde(default)]
+    origin: Option<TableOrigin>,
+    // the table this table is nested in. table names are not parsed for it,
+    // as long names are shortened (see relational::ASTBuilder)
+    #[serde(default)]
+    parent: Option<String>,
+}
+
+// The kind of Michelson value (and its path in the storage, ie the name of
+// the table it got before it was derived from, see
+// create_derived_table_definitions) a table holds the elements of. Maps and
+// sets (as opposed to lists) hold unique keys, of which maps also have
+// values.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TableOrigin {
+    pub kind: TableKind,
+    pub path: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableKind {
+    Map,
+    Set,
+    List,
+    BigMap,
+}
+
+impl TableKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            TableKind::Map => "map",
+            TableKind::Set => "set",
+            TableKind::List => "list",
+            TableKind::BigMap => "bigmap",
+        }
+    }
 }
 
 impl Table {
@@ -30,12 +81,31 @@ impl Table {
             keys: vec![],
             unique: true,
             snapshots: true,
+            ordered_changes: false,
             fk: HashMap::new(),
             id_unique: true,
             pointers: false,
+            origin: None,
+            parent: None,
         }
     }
 
+    pub(crate) fn set_kind(&mut self, kind: TableKind, path: String) {
+        self.origin = Some(TableOrigin { kind, path })
+    }
+
+    pub(crate) fn origin(&self) -> Option<&TableOrigin> {
+        self.origin.as_ref()
+    }
+
+    pub(crate) fn set_parent(&mut self, parent: &str) {
+        self.parent = Some(parent.to_string())
+    }
+
+    pub(crate) fn parent(&self) -> Option<&str> {
+        self.parent.as_deref()
+    }
+
     pub(crate) fn has_uniqueness(&self) -> bool {
         self.unique
     }
@@ -48,6 +118,14 @@ impl Table {
         self.snapshots = false
     }
 
+    pub(crate) fn set_ordered_changes(&mut self, ordered_changes: bool) {
+        self.ordered_changes = ordered_changes
+    }
+
+    pub(crate) fn contains_ordered_changes(&self) -> bool {
+        self.ordered_changes
+    }
+
     pub(crate) fn contains_snapshots(&self) -> bool {
         self.snapshots
     }
@@ -90,6 +168,8 @@ impl Table {
                     Column {
                         name,
                         column_type: ExprTy::Unit, // What will ultimately go in is a Unit
+                        bigint: false,
+                        precision: None,
                     },
                 );
             }
@@ -112,6 +192,8 @@ impl Table {
                     Column {
                         name,
                         column_type: column_type.clone(),
+                        bigint: false,
+                        precision: None,
                     },
                 );
             }
@@ -150,6 +232,8 @@ impl Table {
                     Column {
                         name,
                         column_type: column_type.clone(),
+                        bigint: false,
+                        precision: None,
                     },
                 );
             }
@@ -187,7 +271,12 @@ impl Table {
     }
 
     pub(crate) fn keywords(&self) -> Vec<String> {
-        let mut res = vec!["id".to_string(), "tx_context_id".to_string()];
+        let mut res = vec![
+            "id".to_string(),
+            "tx_context_id".to_string(),
+            "level".to_string(),
+            "level_timestamp".to_string(),
+        ];
         if !self.contains_snapshots() {
             res.push("deleted".to_string());
             res.push("bigmap_id".to_string());
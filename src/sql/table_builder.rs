@@ -1,34 +1,51 @@
-use crate::sql::table::Table;
+use crate::metadata;
+use crate::sql::table::{Table, TableKind};
 use crate::storage_structure::relational::{
-    Contract, RelationalAST, RelationalEntry,
+    self, Contract, NumericType, RelationalAST, RelationalEntry,
 };
 use crate::storage_structure::typing::ExprTy;
+use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 
 pub type TableMap = HashMap<String, Table>;
 
 pub struct TableBuilder {
     pub tables: TableMap,
+    root_table: String,
+    // renamed or shortened table name => the table path it was generated as
+    table_origins: HashMap<String, String>,
 }
 
 impl TableBuilder {
+    /// The contract's tables (parents before their children), and the names
+    /// of those without derived tables and without functions respectively.
     pub(crate) fn tables_from_contract(
         contract: &Contract,
-    ) -> (Vec<Table>, Vec<String>, Vec<String>) {
+    ) -> Result<(Vec<Table>, Vec<String>, Vec<String>)> {
         // Generate the SQL schema for this contract
-        let mut builder = TableBuilder::new("storage");
+        let mut builder = TableBuilder::new(&contract.root_table);
+        builder.table_origins = contract.table_origins.clone();
         builder.populate(&contract.storage_ast);
+        builder.check_metadata_uris(&contract.metadata_uris)?;
+        builder.override_snapshots(&contract.table_snapshots)?;
 
-        let nofunctions_tables = builder.get_functionless_table_prefixes();
+        let nofunctions_tables = builder.get_functionless_tables();
         let mut noview_tables = nofunctions_tables.clone();
-        noview_tables.push("entry.".to_string());
         let mut tables: Vec<Table> = builder.tables.into_values().collect();
 
         for (entrypoint, entrypoint_ast) in &contract.entrypoint_asts {
             let mut entrypoint_table_builder =
                 TableBuilder::new(format!("entry.{}", entrypoint).as_str());
+            entrypoint_table_builder.table_origins =
+                contract.table_origins.clone();
             entrypoint_table_builder.populate(entrypoint_ast);
 
+            noview_tables.extend(
+                entrypoint_table_builder
+                    .tables
+                    .keys()
+                    .cloned(),
+            );
             tables.append(
                 &mut entrypoint_table_builder
                     .tables
@@ -36,37 +53,231 @@ impl TableBuilder {
                     .collect(),
             );
         }
+        Self::override_column_types(&mut tables, contract)?;
+        sort_parents_first(&mut tables);
+
+        Ok((tables, noview_tables, nofunctions_tables))
+    }
+
+    /// Store the int, nat and mutez columns that the overrides say so as
+    /// BIGINT instead of NUMERIC.
+    fn override_column_types(
+        tables: &mut [Table],
+        contract: &Contract,
+    ) -> Result<()> {
+        let overrides = &contract.column_types;
+        for michelson_type in overrides.types.keys() {
+            if !["int", "nat", "mutez"].contains(&michelson_type.as_str()) {
+                return Err(anyhow!(
+                    "bad column type override of type {}: only int, nat and mutez columns can be overridden",
+                    michelson_type
+                ));
+            }
+        }
 
-        (tables, noview_tables, nofunctions_tables)
+        // (table, column) => type of the columns holding storage and
+        // entrypoint values (ie not the ids, fks, etc.)
+        let mut value_columns: HashMap<(String, String), ExprTy> =
+            HashMap::new();
+        collect_value_columns(&contract.storage_ast, &mut value_columns);
+        for entrypoint_ast in contract.entrypoint_asts.values() {
+            collect_value_columns(entrypoint_ast, &mut value_columns);
+        }
+        for (table_name, columns) in &overrides.columns {
+            for column_name in columns.keys() {
+                match value_columns
+                    .get(&(table_name.clone(), column_name.clone()))
+                {
+                    Some(ExprTy::Int) | Some(ExprTy::Nat) | Some(ExprTy::Mutez) => {}
+                    _ => {
+                        return Err(anyhow!(
+                            "bad column type override of column {} (table {}): no such int, nat or mutez column",
+                            column_name,
+                            table_name
+                        ))
+                    }
+                }
+            }
+        }
+
+        for table in tables.iter_mut() {
+            for column in table.columns.values_mut() {
+                let numeric_type = match value_columns
+                    .get(&(table.name.clone(), column.name.clone()))
+                {
+                    Some(column_type) => overrides.numeric_type(
+                        &table.name,
+                        &column.name,
+                        column_type,
+                    ),
+                    None => NumericType::Numeric,
+                };
+                column.bigint = numeric_type == NumericType::Bigint;
+                column.precision = match numeric_type {
+                    NumericType::BoundedNumeric { precision, scale } => {
+                        Some((precision, scale))
+                    }
+                    _ => None,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the metadata URI columns exist, and that the tables their
+    /// resolved off-chain metadata go to (which are not part of the tables,
+    /// see PostgresqlGenerator::create_resolved_metadata_table) do not clash
+    /// with them.
+    fn check_metadata_uris(
+        &self,
+        metadata_uris: &HashMap<String, String>,
+    ) -> Result<()> {
+        for (table_name, column_name) in metadata_uris {
+            let is_uri_column = self
+                .tables
+                .get(table_name)
+                .and_then(|t| t.columns.get(column_name))
+                .map(|c| {
+                    matches!(c.column_type, ExprTy::String | ExprTy::Bytes)
+                })
+                .unwrap_or(false);
+            if !is_uri_column {
+                return Err(anyhow!(
+                    "bad metadata uri column {} (table {}): no such string or bytes column",
+                    column_name,
+                    table_name
+                ));
+            }
+            let name = metadata::resolved_metadata_table(table_name);
+            if self.tables.contains_key(&name)
+                || name.len() > relational::MAX_IDENTIFIER_LEN
+            {
+                return Err(anyhow!(
+                    "bad metadata uri column {} (table {}): the name of its resolved metadata table ({}) clashes with another table or is too long",
+                    column_name,
+                    table_name,
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Force whether the _ordered tables of tables hold snapshots (as
+    /// opposed to changes), per table name or per table name prefix (when
+    /// ending with '*'). When multiple overrides apply to a table, the most
+    /// specific one is used. Bigmap tables always hold changes. Of the other
+    /// tables, only those of maps and sets directly in the root table that
+    /// have no tables nested in them can hold changes: their elements are
+    /// told apart by their keys alone, and no other rows refer to the rows
+    /// of a snapshot that are left out.
+    pub(crate) fn override_snapshots(
+        &mut self,
+        overrides: &HashMap<String, bool>,
+    ) -> Result<()> {
+        let matches =
+            |pattern: &str, name: &str| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            };
+        for pattern in overrides.keys() {
+            if !self
+                .tables
+                .keys()
+                .any(|name| matches(pattern, name))
+            {
+                return Err(anyhow!(
+                    "bad snapshots override {}: no such table",
+                    pattern
+                ));
+            }
+        }
+
+        let mut names: Vec<String> = self.tables.keys().cloned().collect();
+        names.sort();
+        for name in &names {
+            let snapshots = match overrides
+                .iter()
+                .filter(|(pattern, _)| matches(pattern, name))
+                .max_by_key(|(pattern, _)| pattern.len())
+            {
+                Some((_, snapshots)) => *snapshots,
+                None => continue,
+            };
+            let mut t = self.get_table(name);
+            if !t.contains_snapshots() {
+                if snapshots {
+                    // Snapshot rows are tied to the parent row of the same
+                    // tx_context, changes rows (bigmap updates) outlive it.
+                    return Err(anyhow!(
+                        "bad snapshots override of table {}: its rows are bigmap updates, which are not tied to a parent row",
+                        name
+                    ));
+                }
+                continue;
+            }
+            if !snapshots {
+                let is_map_or_set = matches!(
+                    t.origin().map(|origin| origin.kind),
+                    Some(TableKind::Map) | Some(TableKind::Set)
+                );
+                let has_keys = t.has_uniqueness()
+                    && t.indices
+                        .iter()
+                        .any(|idx| idx != "tx_context_id");
+                let has_children = self
+                    .tables
+                    .values()
+                    .any(|child| child.parent() == Some(name.as_str()));
+                if !is_map_or_set
+                    || !has_keys
+                    || t.parent() != Some(self.root_table.as_str())
+                    || has_children
+                    || t.columns.contains_key("deleted")
+                {
+                    return Err(anyhow!(
+                        "bad snapshots override of table {}: only tables of maps and sets in the root table ({}) without nested tables can hold changes",
+                        name,
+                        self.root_table
+                    ));
+                }
+            }
+            t.set_ordered_changes(!snapshots);
+            self.store_table(t);
+        }
+        Ok(())
     }
 
     pub(crate) fn new(root_table_name: &str) -> Self {
         let mut res = Self {
             tables: TableMap::new(),
+            root_table: root_table_name.to_string(),
+            table_origins: HashMap::new(),
         };
         res.touch_table(root_table_name);
         res
     }
 
-    fn get_functionless_table_prefixes(&self) -> Vec<String> {
-        let mut res: Vec<String> = vec![];
-
+    fn get_functionless_tables(&self) -> Vec<String> {
         // All child tables of changes tables cannot have view definitions defined.
         // To get _ordered or _live rows for these child tables, simply join with id
         // of parent bigmap table (on which there are _live and _ordered views defined).
-        res.extend(
-            self.tables
-                .values()
-                .filter_map(|t| {
-                    if t.contains_snapshots() {
-                        None
-                    } else {
-                        Some(format!("{}.", t.name))
-                    }
-                })
-                .collect::<Vec<String>>(),
-        );
-        res
+        let in_changes_table = |t: &Table| {
+            let mut parent = t.parent();
+            while let Some(name) = parent {
+                match self.tables.get(name) {
+                    Some(p) if !p.contains_snapshots() => return true,
+                    Some(p) => parent = p.parent(),
+                    None => return false,
+                }
+            }
+            false
+        };
+        self.tables
+            .values()
+            .filter(|t| in_changes_table(t))
+            .map(|t| t.name.clone())
+            .collect()
     }
 
     fn add_column(&mut self, rel_entry: &RelationalEntry) {
@@ -90,30 +301,54 @@ impl TableBuilder {
                 let mut t = Table::new(name.to_string());
                 t.add_index("tx_context_id", &ExprTy::Int);
                 t.add_column("id", &ExprTy::Int);
+                t.add_column("level", &ExprTy::Int);
+                t.add_column("level_timestamp", &ExprTy::Timestamp);
                 t
             }
         }
     }
 
+    // The path in the storage (or entrypoint) type that the table was
+    // generated for, which differs from its name if it was renamed or
+    // shortened.
+    fn storage_path(&self, name: &str) -> String {
+        self.table_origins
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
     fn store_table(&mut self, table: Table) {
         self.tables
             .insert(table.name.clone(), table);
     }
 
     pub(crate) fn populate(&mut self, rel_ast: &RelationalAST) {
+        let root_table = self.root_table.clone();
+        self.populate_table(&root_table, rel_ast);
+    }
+
+    // Populates the tables of the AST, which is nested in table parent.
+    fn populate_table(&mut self, parent: &str, rel_ast: &RelationalAST) {
         match rel_ast {
             RelationalAST::Pair {
                 left_ast,
                 right_ast,
             } => {
-                self.populate(left_ast);
-                self.populate(right_ast);
+                self.populate_table(parent, left_ast);
+                self.populate_table(parent, right_ast);
             }
             RelationalAST::Map {
-                key_ast, value_ast, ..
+                table,
+                key_ast,
+                value_ast,
             } => {
-                self.populate(key_ast);
-                self.populate(value_ast);
+                self.populate_table(table, key_ast);
+                self.populate_table(table, value_ast);
+                let mut t = self.get_table(table);
+                t.set_parent(parent);
+                t.set_kind(TableKind::Map, self.storage_path(table));
+                self.store_table(t);
             }
             RelationalAST::BigMap {
                 table,
@@ -121,9 +356,11 @@ impl TableBuilder {
                 value_ast,
                 has_memory,
             } => {
-                self.populate(key_ast);
-                self.populate(value_ast);
+                self.populate_table(table, key_ast);
+                self.populate_table(table, value_ast);
                 let mut t = self.get_table(table);
+                t.set_parent(parent);
+                t.set_kind(TableKind::BigMap, self.storage_path(table));
 
                 t.add_index("bigmap_id", &ExprTy::Int);
                 if *has_memory {
@@ -135,18 +372,27 @@ impl TableBuilder {
                 }
                 self.store_table(t);
             }
-            RelationalAST::Option { elem_ast } => self.populate(elem_ast),
+            RelationalAST::Option { elem_ast } => {
+                self.populate_table(parent, elem_ast)
+            }
             RelationalAST::List {
                 table,
                 elems_unique,
                 elems_ast,
             } => {
-                self.populate(elems_ast);
-                if !elems_unique {
-                    let mut t = self.get_table(table);
+                self.populate_table(table, elems_ast);
+                let mut t = self.get_table(table);
+                t.set_parent(parent);
+                if *elems_unique {
+                    t.set_kind(TableKind::Set, self.storage_path(table));
+                } else {
+                    t.set_kind(TableKind::List, self.storage_path(table));
                     t.no_uniqueness();
-                    self.store_table(t);
+                    // the position of the element in its list, the rows of
+                    // a list have no other order to go by
+                    t.add_column("ordinal", &ExprTy::Int);
                 }
+                self.store_table(t);
             }
             RelationalAST::OrEnumeration {
                 or_unfold,
@@ -165,15 +411,664 @@ impl TableBuilder {
                 }
 
                 if let Some(left_table) = left_table {
-                    self.touch_table(left_table);
-                    self.populate(left_ast);
+                    let mut t = self.get_table(left_table);
+                    t.set_parent(parent);
+                    self.store_table(t);
+                    self.populate_table(left_table, left_ast);
                 }
                 if let Some(right_table) = right_table {
-                    self.touch_table(right_table);
-                    self.populate(right_ast);
+                    let mut t = self.get_table(right_table);
+                    t.set_parent(parent);
+                    self.store_table(t);
+                    self.populate_table(right_table, right_ast);
                 }
             }
             RelationalAST::Leaf { rel_entry } => self.add_column(rel_entry),
+            RelationalAST::Packed {
+                rel_entry,
+                packed_ast,
+            } => {
+                // the bytes column is kept for values that fail to unpack
+                self.add_column(rel_entry);
+                self.populate_table(parent, packed_ast);
+            }
+        }
+    }
+}
+
+/// Orders the tables such that parents come before their children (which
+/// refer to them), and by name otherwise.
+pub(crate) fn sort_parents_first(tables: &mut [Table]) {
+    let parents: HashMap<String, String> = tables
+        .iter()
+        .filter_map(|t| {
+            t.parent()
+                .map(|parent| (t.name.clone(), parent.to_string()))
+        })
+        .collect();
+    let depth = |name: &str| {
+        let mut res = 0;
+        let mut name = name;
+        while let Some(parent) = parents.get(name) {
+            res += 1;
+            name = parent;
+        }
+        res
+    };
+    tables.sort_by_cached_key(|t| (depth(&t.name), t.name.clone()));
+}
+
+fn collect_value_columns(
+    rel_ast: &RelationalAST,
+    res: &mut HashMap<(String, String), ExprTy>,
+) {
+    match rel_ast {
+        RelationalAST::Leaf { rel_entry } => {
+            res.insert(
+                (rel_entry.table_name.clone(), rel_entry.column_name.clone()),
+                rel_entry.column_type.clone(),
+            );
+        }
+        RelationalAST::Packed {
+            rel_entry,
+            packed_ast,
+        } => {
+            res.insert(
+                (rel_entry.table_name.clone(), rel_entry.column_name.clone()),
+                rel_entry.column_type.clone(),
+            );
+            collect_value_columns(packed_ast, res);
+        }
+        RelationalAST::Option { elem_ast } => {
+            collect_value_columns(elem_ast, res)
+        }
+        RelationalAST::List { elems_ast, .. } => {
+            collect_value_columns(elems_ast, res)
+        }
+        RelationalAST::Pair {
+            left_ast,
+            right_ast,
+        }
+        | RelationalAST::OrEnumeration {
+            left_ast,
+            right_ast,
+            ..
+        } => {
+            collect_value_columns(left_ast, res);
+            collect_value_columns(right_ast, res);
+        }
+        RelationalAST::Map {
+            key_ast, value_ast, ..
         }
+        | RelationalAST::BigMap {
+            key_ast, value_ast, ..
+        } => {
+            collect_value_columns(key_ast, res);
+            collect_value_columns(value_ast, res);
+        }
+    }
+}
+
+#[test]
+fn test_override_snapshots() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+
+    let storage_definition = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {
+                "prim": "big_map",
+                "args": [{"prim": "nat"}, {"prim": "mutez"}],
+                "annots": ["%ledger"],
+            },
+            {
+                "prim": "pair",
+                "args": [
+                    {
+                        "prim": "map",
+                        "args": [{"prim": "nat"}, {"prim": "string"}],
+                        "annots": ["%names"],
+                    },
+                    {
+                        "prim": "set",
+                        "args": [{"prim": "string"}],
+                        "annots": ["%tags"],
+                    },
+                ],
+            },
+            {
+                "prim": "pair",
+                "args": [
+                    {
+                        "prim": "list",
+                        "args": [{"prim": "nat"}],
+                        "annots": ["%history"],
+                    },
+                    {
+                        "prim": "map",
+                        "args": [
+                            {"prim": "nat"},
+                            {
+                                "prim": "map",
+                                "args": [{"prim": "nat"}, {"prim": "nat"}],
+                                "annots": ["%inner"],
+                            },
+                        ],
+                        "annots": ["%outer"],
+                    },
+                ],
+            },
+        ],
+    });
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let builder = || {
+        let mut builder = TableBuilder::new("storage");
+        builder.populate(&rel_ast);
+        builder
+    };
+    let overrides = |kvs: &[(&str, bool)]| -> HashMap<String, bool> {
+        kvs.iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect()
+    };
+
+    // the most specific override applies
+    let mut b = builder();
+    assert!(b
+        .override_snapshots(&overrides(&[
+            ("storage*", true),
+            ("storage.ledger", false),
+            ("storage.names", false),
+            ("storage.tags", false),
+        ]))
+        .is_ok());
+    assert!(!b.tables["storage"].contains_ordered_changes());
+    assert!(!b.tables["storage.ledger"].contains_snapshots());
+    assert!(b.tables["storage.names"].contains_snapshots());
+    assert!(b.tables["storage.names"].contains_ordered_changes());
+    assert!(b.tables["storage.tags"].contains_ordered_changes());
+    assert!(!b.tables["storage.history"].contains_ordered_changes());
+    assert!(b
+        .tables
+        .contains_key("storage.outer.inner"));
+
+    for bad in &[
+        ("storage.nonexisting", true),
+        ("storage.ledger", true),
+        ("storage*", false),
+        ("storage.history", false),
+        ("storage.outer", false),
+        ("storage.outer.inner", false),
+    ] {
+        let mut b = builder();
+        assert!(
+            b.override_snapshots(&overrides(&[*bad]))
+                .is_err(),
+            "{:?}",
+            bad
+        );
+    }
+}
+
+#[test]
+fn test_tables_from_contract_root_table() {
+    use crate::config::ContractID;
+    use crate::storage_structure::relational::{ASTBuilder, LayoutSettings};
+    use crate::storage_structure::typing;
+
+    let storage_definition = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {
+                "prim": "nat",
+                "annots": ["%level"],
+            },
+            {
+                "prim": "big_map",
+                "args": [{"prim": "nat"}, {"prim": "mutez"}],
+                "annots": ["%ledger"],
+            },
+        ],
+    });
+    let mut layout_settings = LayoutSettings::default();
+    layout_settings
+        .root_tables
+        .insert("market".to_string(), "market".to_string());
+    let root_table = layout_settings
+        .root_table("market")
+        .unwrap();
+    assert_eq!(
+        LayoutSettings::DEFAULT_ROOT_TABLE,
+        layout_settings
+            .root_table("other")
+            .unwrap()
+    );
+
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let contract = Contract {
+        cid: ContractID {
+            name: "market".to_string(),
+            address: "KT1".to_string(),
+        },
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        storage_ast: ASTBuilder::new(&root_table)
+            .build_relational_ast(&type_ast)
+            .unwrap(),
+        root_table,
+        column_types: Default::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+    let (tables, _, _) = TableBuilder::tables_from_contract(&contract).unwrap();
+    let mut names: Vec<&str> = tables
+        .iter()
+        .map(|t| t.name.as_str())
+        .collect();
+    names.sort_unstable();
+    assert_eq!(vec!["market", "market.ledger"], names);
+
+    // reserved columns are reserved in the renamed root table as well, level
+    // being the level the row was indexed at
+    let root = tables
+        .iter()
+        .find(|t| t.name == "market")
+        .unwrap();
+    assert_eq!(ExprTy::Nat, root.columns["level_1"].column_type);
+    assert_eq!(ExprTy::Int, root.columns["level"].column_type);
+
+    for bad in &["", "entry", "market.storage"] {
+        layout_settings
+            .root_tables
+            .insert("market".to_string(), bad.to_string());
+        assert!(
+            layout_settings
+                .root_table("market")
+                .is_err(),
+            "{}",
+            bad
+        );
+    }
+}
+
+#[test]
+fn test_override_column_types() {
+    use crate::config::ContractID;
+    use crate::sql::postgresql_generator::PostgresqlGenerator;
+    use crate::storage_structure::relational::{
+        ASTBuilder, ColumnTypes, NumericType,
+    };
+    use crate::storage_structure::typing;
+
+    let storage_definition = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {
+                "prim": "nat",
+                "annots": ["%counter"],
+            },
+            {
+                "prim": "big_map",
+                "args": [{"prim": "nat"}, {"prim": "mutez"}],
+                "annots": ["%ledger"],
+            },
+        ],
+    });
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let contract = |column_types: ColumnTypes| Contract {
+        cid: ContractID {
+            name: "market".to_string(),
+            address: "KT1".to_string(),
+        },
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        storage_ast: ASTBuilder::new("storage")
+            .build_relational_ast(&type_ast)
+            .unwrap(),
+        root_table: "storage".to_string(),
+        column_types,
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+    let column_types =
+        |types: &[(&str, NumericType)],
+         columns: &[(&str, &str, NumericType)]| {
+            let mut res = ColumnTypes::default();
+            for (michelson_type, numeric_type) in types {
+                res.types
+                    .insert(michelson_type.to_string(), *numeric_type);
+            }
+            for (table, column, numeric_type) in columns {
+                res.columns
+                    .entry(table.to_string())
+                    .or_default()
+                    .insert(column.to_string(), *numeric_type);
+            }
+            res
+        };
+    let sql_types = |column_types: ColumnTypes| -> Vec<String> {
+        let (tables, _, _) =
+            TableBuilder::tables_from_contract(&contract(column_types))
+                .unwrap();
+        let mut res: Vec<String> = tables
+            .iter()
+            .flat_map(|t| {
+                t.get_columns()
+                    .into_iter()
+                    .filter_map(PostgresqlGenerator::create_sql)
+                    .map(move |sql| format!("{}: {}", t.name, sql))
+            })
+            .collect();
+        res.sort_unstable();
+        res
+    };
+
+    assert_eq!(
+        vec![
+            r#"storage.ledger: "idx_nat" NUMERIC"#,
+            r#"storage.ledger: "mutez" NUMERIC"#,
+            "storage.ledger: bigmap_id INTEGER",
+            "storage.ledger: deleted BOOLEAN NOT NULL DEFAULT 'false'",
+            "storage.ledger: id BIGSERIAL PRIMARY KEY",
+            "storage.ledger: level INTEGER",
+            "storage.ledger: level_timestamp TIMESTAMP WITH TIME ZONE",
+            "storage.ledger: tx_context_id BIGINT NOT NULL",
+            r#"storage: "counter" NUMERIC"#,
+            "storage: id BIGSERIAL PRIMARY KEY",
+            "storage: level INTEGER",
+            "storage: level_timestamp TIMESTAMP WITH TIME ZONE",
+            "storage: tx_context_id BIGINT NOT NULL",
+        ],
+        sql_types(ColumnTypes::default())
+    );
+    // per column overrides take precedence over per type overrides
+    assert_eq!(
+        vec![
+            r#"storage.ledger: "idx_nat" NUMERIC"#,
+            r#"storage.ledger: "mutez" BIGINT"#,
+            "storage.ledger: bigmap_id INTEGER",
+            "storage.ledger: deleted BOOLEAN NOT NULL DEFAULT 'false'",
+            "storage.ledger: id BIGSERIAL PRIMARY KEY",
+            "storage.ledger: level INTEGER",
+            "storage.ledger: level_timestamp TIMESTAMP WITH TIME ZONE",
+            "storage.ledger: tx_context_id BIGINT NOT NULL",
+            r#"storage: "counter" BIGINT"#,
+            "storage: id BIGSERIAL PRIMARY KEY",
+            "storage: level INTEGER",
+            "storage: level_timestamp TIMESTAMP WITH TIME ZONE",
+            "storage: tx_context_id BIGINT NOT NULL",
+        ],
+        sql_types(column_types(
+            &[("nat", NumericType::Bigint), ("int", NumericType::Bigint)],
+            &[
+                ("storage.ledger", "idx_nat", NumericType::Numeric),
+                ("storage.ledger", "mutez", NumericType::Bigint),
+            ]
+        ))
+    );
+
+    let mutez = NumericType::BoundedNumeric {
+        precision: 20,
+        scale: 0,
+    };
+    assert_eq!(
+        vec![
+            r#"storage.ledger: "idx_nat" NUMERIC"#,
+            r#"storage.ledger: "mutez" NUMERIC(20,0)"#,
+            "storage.ledger: bigmap_id INTEGER",
+            "storage.ledger: deleted BOOLEAN NOT NULL DEFAULT 'false'",
+            "storage.ledger: id BIGSERIAL PRIMARY KEY",
+            "storage.ledger: level INTEGER",
+            "storage.ledger: level_timestamp TIMESTAMP WITH TIME ZONE",
+            "storage.ledger: tx_context_id BIGINT NOT NULL",
+            r#"storage: "counter" NUMERIC"#,
+            "storage: id BIGSERIAL PRIMARY KEY",
+            "storage: level INTEGER",
+            "storage: level_timestamp TIMESTAMP WITH TIME ZONE",
+            "storage: tx_context_id BIGINT NOT NULL",
+        ],
+        sql_types(column_types(&[("mutez", mutez)], &[]))
+    );
+
+    for bad in &[
+        column_types(&[("string", NumericType::Bigint)], &[]),
+        column_types(&[], &[("storage.other", "mutez", NumericType::Bigint)]),
+        column_types(&[], &[("storage.ledger", "other", NumericType::Bigint)]),
+        column_types(&[], &[("storage.ledger", "id", NumericType::Bigint)]),
+    ] {
+        assert!(
+            TableBuilder::tables_from_contract(&contract(bad.clone())).is_err(),
+            "{:?}",
+            bad
+        );
+    }
+}
+
+#[test]
+fn test_long_names() {
+    use crate::config::ContractID;
+    use crate::sql::postgresql_generator::PostgresqlGenerator;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use std::collections::HashSet;
+
+    // maps nested in maps, with names that only differ past the 63 bytes
+    // Postgres would keep of them
+    fn nested(depth: usize) -> serde_json::Value {
+        let long =
+            "a_rather_long_field_name_to_push_the_identifiers_over_the_limit";
+        if depth == 0 {
+            return serde_json::json!({
+                "prim": "pair",
+                "args": [
+                    { "prim": "nat", "annots": [format!("%{}_x", long)] },
+                    { "prim": "nat", "annots": [format!("%{}_y", long)] },
+                ],
+            });
+        }
+        serde_json::json!({
+            "prim": "pair",
+            "args": [
+                {
+                    "prim": "map",
+                    "args": [
+                        { "prim": "nat", "annots": [format!("%{}_key", long)] },
+                        nested(depth - 1),
+                    ],
+                    "annots": [format!("%{}_{}_left", long, depth)],
+                },
+                {
+                    "prim": "map",
+                    "args": [{ "prim": "nat" }, nested(depth - 1)],
+                    "annots": [format!("%{}_{}_right", long, depth)],
+                },
+            ],
+        })
+    }
+    let type_ast = typing::type_ast_from_json(&nested(3)).unwrap();
+    let mut ast_builder = ASTBuilder::new("storage");
+    let rel_ast = ast_builder
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let mut builder = TableBuilder::new("storage");
+    builder.table_origins = ast_builder.table_origins().clone();
+    builder.populate(&rel_ast);
+    assert_eq!(15, builder.tables.len());
+
+    // shortened tables keep the storage path they were generated for
+    let mut shortened = 0;
+    for table in builder.tables.values() {
+        let path = match table.origin() {
+            Some(origin) => &origin.path,
+            None => continue,
+        };
+        if *path != table.name {
+            assert!(path.len() > 63, "{}", path);
+            assert!(path.ends_with("_left") || path.ends_with("_right"));
+            shortened += 1;
+        }
+    }
+    assert!(shortened > 0);
+
+    // the identifiers derived from the (shortened) table names (eg the
+    // _at_deref function) must fit, and stay distinct
+    let mut identifiers: HashSet<String> = HashSet::new();
+    for table in builder.tables.values() {
+        for suffix in &["", "_live", "_ordered", "_at", "_at_deref"] {
+            let identifier = format!("{}{}", table.name, suffix);
+            assert!(identifier.len() <= 63, "{}", identifier);
+            assert!(identifiers.insert(identifier));
+        }
+
+        let mut columns: HashSet<&str> = HashSet::new();
+        for column in table.get_columns() {
+            assert!(column.name.len() <= 63, "{}", column.name);
+            assert!(columns.insert(&column.name[..]));
+        }
+    }
+
+    // the children of shortened tables refer to these, rather than to the
+    // table their shortened names seem to be nested in: every map holds two
+    // maps, except for those nested deepest
+    let generator = PostgresqlGenerator::new(
+        "main".to_string(),
+        &ContractID {
+            name: "c".to_string(),
+            address: "KT1c".to_string(),
+        },
+    );
+    let children = |name: &str| -> usize {
+        builder
+            .tables
+            .values()
+            .filter(|t| t.parent() == Some(name))
+            .count()
+    };
+    assert_eq!(2, children("storage"));
+    for table in builder.tables.values() {
+        assert!([0, 2].contains(&children(&table.name)), "{}", table.name);
+        let parent = match table.parent() {
+            Some(parent) => parent,
+            None => {
+                assert_eq!("storage", table.name);
+                continue;
+            }
+        };
+        assert!(builder.tables.contains_key(parent), "{}", parent);
+        assert!(generator
+            .create_table_definition(table)
+            .unwrap()
+            .contains(&format!(r#"REFERENCES "c"."{}"(id)"#, parent)));
+    }
+
+    let mut tables: Vec<Table> = builder
+        .tables
+        .values()
+        .cloned()
+        .collect();
+    sort_parents_first(&mut tables);
+    for (i, table) in tables.iter().enumerate() {
+        if let Some(parent) = table.parent() {
+            assert!(tables[..i]
+                .iter()
+                .any(|t| t.name == parent));
+        }
+    }
+
+    // names that fit are kept, even when the identifiers derived from them
+    // do not (as they were before long names were shortened)
+    let name = "b".repeat(63 - "storage.".len());
+    let type_ast = typing::type_ast_from_json(&serde_json::json!({
+        "prim": "map",
+        "args": [{ "prim": "nat" }, { "prim": "nat" }],
+        "annots": [format!("%{}", name)],
+    }))
+    .unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let mut builder = TableBuilder::new("storage");
+    builder.populate(&rel_ast);
+    assert!(builder
+        .tables
+        .contains_key(&format!("storage.{}", name)));
+}
+
+#[test]
+fn test_tables_from_contract_metadata_uris() {
+    use crate::config::ContractID;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+
+    let type_ast = typing::type_ast_from_json(&serde_json::json!({
+        "prim": "big_map",
+        "args": [
+            { "prim": "nat" },
+            {
+                "prim": "pair",
+                "args": [
+                    { "prim": "nat", "annots": ["%token_id"] },
+                    {
+                        "prim": "map",
+                        "args": [{ "prim": "string" }, { "prim": "bytes" }],
+                        "annots": ["%token_info"],
+                    },
+                ],
+            },
+        ],
+        "annots": ["%token_metadata"],
+    }))
+    .unwrap();
+    let contract = |table: &str, column: &str| {
+        let mut metadata_uris: HashMap<String, String> = HashMap::new();
+        metadata_uris.insert(table.to_string(), column.to_string());
+        Contract {
+            cid: ContractID {
+                name: "nft".to_string(),
+                address: "KT1".to_string(),
+            },
+            level_floor: None,
+            table_snapshots: HashMap::new(),
+            table_origins: HashMap::new(),
+            storage_ast: ASTBuilder::new("storage")
+                .build_relational_ast(&type_ast)
+                .unwrap(),
+            root_table: "storage".to_string(),
+            column_types: Default::default(),
+            bigmap_filter: Default::default(),
+            metadata_uris,
+            entrypoint_asts: HashMap::new(),
+        }
+    };
+
+    let (tables, _, _) = TableBuilder::tables_from_contract(&contract(
+        "storage.token_metadata.token_info",
+        "bytes",
+    ))
+    .unwrap();
+    assert!(!tables
+        .iter()
+        .any(|t| t.name.ends_with(".resolved_metadata")));
+
+    for (table, column) in &[
+        ("storage.token_metadata.token_info", "idx_string_1"),
+        ("storage.token_metadata", "token_id"),
+        ("storage.other", "bytes"),
+    ] {
+        assert!(
+            TableBuilder::tables_from_contract(&contract(table, column))
+                .is_err(),
+            "{}.{}",
+            table,
+            column
+        );
     }
 }
@@ -1,9 +1,59 @@
+use pg_bigdecimal::PgNumeric;
 use serde_json;
 
-#[derive(Clone, Debug)]
+use crate::octez::block::TxContext;
+
+#[derive(Clone, Debug, Serialize)]
 pub(crate) struct BigmapMetaAction {
     pub tx_context_id: i64,
     pub bigmap_id: i32,
+    pub operation_hash: Option<String>,
     pub action: String,
     pub value: Option<serde_json::Value>,
 }
+
+// The contract (and its table) a bigmap was allocated in.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct BigmapOwner {
+    pub bigmap_id: i32,
+    pub contract_address: String,
+    pub table_name: String,
+    pub alloc_level: u32,
+}
+
+// A call to a contract that did not get applied, with one of the errors it
+// failed with (if any, backtracked operations have none).
+#[derive(Clone, Debug)]
+pub(crate) struct OperationFailure {
+    pub tx_context_id: i64,
+    pub status: String,
+    pub error_kind: Option<String>,
+    pub error_id: Option<String>,
+}
+
+// The storage of a contract after a call (or its origination), as the node
+// returned it (ie before it was parsed).
+#[derive(Clone, Debug)]
+pub(crate) struct RawStorage {
+    pub tx_context_id: i64,
+    pub contract: String,
+    pub storage: serde_json::Value,
+}
+
+// A call to a contract, as recorded in the txs table.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CallRecord {
+    pub tx_context: TxContext,
+    pub operation_hash: String,
+    pub status: String,
+    pub entrypoint: Option<String>,
+    pub source: String,
+    pub amount: Option<PgNumeric>,
+    // the id of the call's row in the contract's "entry.<entrypoint>" table
+    // (None if the contract has no such table)
+    pub entrypoint_row_id: Option<i64>,
+    // the contract's storage after the call, if it was kept (see
+    // --keep-raw-storage)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_storage: Option<serde_json::Value>,
+}
@@ -1,15 +1,24 @@
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub(crate) struct StatsLogger {
     interval: Duration,
+    // when set, prefixes the reports (eg with the network's name, when
+    // indexing multiple networks from one process)
+    namespace: Option<String>,
 
     stats: Arc<Mutex<HashMap<String, Stats>>>,
+    // the slowest processed contract blocks, over the whole run (ie not
+    // reset along with the stats)
+    slowest: Arc<Mutex<SlowestBlocks>>,
+    // the processed levels, from which the levels/sec rate and the remaining
+    // time (when a target is set) are reported
+    progress: Arc<Mutex<Progress>>,
 
     is_cancelled: Arc<AtomicBool>,
 }
@@ -18,13 +27,116 @@ impl StatsLogger {
     pub(crate) fn new(interval: Duration) -> Self {
         Self {
             interval,
+            namespace: None,
 
             stats: Arc::new(Mutex::new(HashMap::new())),
+            slowest: Arc::new(Mutex::new(SlowestBlocks::new(0))),
+            progress: Arc::new(Mutex::new(Progress::new())),
 
             is_cancelled: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    pub(crate) fn set_namespace(&mut self, namespace: &str) {
+        self.namespace = Some(namespace.to_string());
+    }
+
+    // Tracks the n slowest processed contract blocks (none when n is 0).
+    pub(crate) fn track_slowest(&self, n: usize) -> Result<()> {
+        *self
+            .slowest
+            .lock()
+            .map_err(|_| anyhow!("failed to lock slowest mutex"))? =
+            SlowestBlocks::new(n);
+        Ok(())
+    }
+
+    pub(crate) fn add_block_time(&self, block: SlowBlock) -> Result<()> {
+        self.slowest
+            .lock()
+            .map_err(|_| anyhow!("failed to lock slowest mutex"))?
+            .add(block);
+        Ok(())
+    }
+
+    pub(crate) fn log_slowest(&self) -> Result<()> {
+        let slowest = self
+            .slowest
+            .lock()
+            .map_err(|_| anyhow!("failed to lock slowest mutex"))?;
+        if slowest.blocks.is_empty() {
+            return Ok(());
+        }
+        let lines = slowest
+            .blocks
+            .iter()
+            .map(|b| {
+                format!(
+                    "\n\t{:?}\tlevel {}, contract {} ({} txs)",
+                    b.duration, b.level, b.contract, b.tx_count
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("");
+        let header = match &self.namespace {
+            Some(namespace) => format!(
+                "{}: {} slowest processed blocks",
+                namespace,
+                slowest.blocks.len()
+            ),
+            None => {
+                format!("{} slowest processed blocks", slowest.blocks.len())
+            }
+        };
+        info!("\n=============\n{}{}\n", header, lines);
+        Ok(())
+    }
+
+    pub(crate) fn add_levels_processed(&self, n: usize) -> Result<()> {
+        self.progress
+            .lock()
+            .map_err(|_| anyhow!("failed to lock progress mutex"))?
+            .add(n);
+        Ok(())
+    }
+
+    // Sets the number of levels that remain to be processed, from which on
+    // the remaining time is reported (until cleared with None).
+    pub(crate) fn set_levels_target(&self, n: Option<usize>) -> Result<()> {
+        self.progress
+            .lock()
+            .map_err(|_| anyhow!("failed to lock progress mutex"))?
+            .set_target(n);
+        if n.is_none() {
+            self.unset("progress", "eta")?;
+        }
+        Ok(())
+    }
+
+    fn update_progress(&self) -> Result<()> {
+        let eta = {
+            let mut progress = self
+                .progress
+                .lock()
+                .map_err(|_| anyhow!("failed to lock progress mutex"))?;
+            progress.sample(Instant::now());
+            progress.eta()
+        };
+        if let Some((remaining, rate, eta)) = eta {
+            self.set(
+                "progress",
+                "eta",
+                format!(
+                    "{} levels, ~{} remaining at {:.0} levels/s",
+                    remaining,
+                    format_duration(eta),
+                    rate
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn add(
         &self,
         report: &str,
@@ -131,8 +243,9 @@ impl StatsLogger {
         while !self.cancelled() {
             thread::park_timeout(self.interval);
 
+            self.update_progress()?;
             let stats = self.drain_stats()?;
-            Self::print_report(&self.interval, stats);
+            Self::print_report(&self.interval, &self.namespace, stats);
         }
         Ok(())
     }
@@ -167,7 +280,11 @@ impl StatsLogger {
             .load(Ordering::Relaxed)
     }
 
-    fn print_report(at_interval: &Duration, stats: HashMap<String, Stats>) {
+    fn print_report(
+        at_interval: &Duration,
+        namespace: &Option<String>,
+        stats: HashMap<String, Stats>,
+    ) {
         let mut stats_ordered: Vec<(String, Stats)> =
             stats.into_iter().collect();
         stats_ordered.sort_by_key(|(section_name, _)| section_name.clone());
@@ -178,7 +295,133 @@ impl StatsLogger {
             .collect::<Vec<String>>()
             .join("\n");
 
-        info!("\n=============\n{:?} report\n{}\n", at_interval, sections);
+        let header = match namespace {
+            Some(namespace) => {
+                format!("{}: {:?} report", namespace, at_interval)
+            }
+            None => format!("{:?} report", at_interval),
+        };
+        info!("\n=============\n{}\n{}\n", header, sections);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SlowBlock {
+    pub level: u32,
+    pub contract: String,
+    pub tx_count: usize,
+    pub duration: Duration,
+}
+
+// The slowest blocks added so far (at most capacity), slowest first.
+struct SlowestBlocks {
+    capacity: usize,
+    blocks: Vec<SlowBlock>,
+}
+
+impl SlowestBlocks {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: vec![],
+        }
+    }
+
+    fn add(&mut self, block: SlowBlock) {
+        if self.blocks.len() >= self.capacity {
+            match self.blocks.last() {
+                Some(last) if last.duration < block.duration => {}
+                _ => return,
+            }
+        }
+        let pos = self
+            .blocks
+            .iter()
+            .position(|b| b.duration < block.duration)
+            .unwrap_or(self.blocks.len());
+        self.blocks.insert(pos, block);
+        self.blocks.truncate(self.capacity);
+    }
+}
+
+// Tracks the processed levels, sampled once per report, and derives a
+// levels/sec rate from the last ROLLING_WINDOW samples.
+struct Progress {
+    processed: usize,
+    // the value of processed at which the target is reached
+    target: Option<usize>,
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl Progress {
+    const ROLLING_WINDOW: usize = 10;
+
+    fn new() -> Self {
+        Self {
+            processed: 0,
+            target: None,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, n: usize) {
+        self.processed += n;
+    }
+
+    fn set_target(&mut self, n: Option<usize>) {
+        self.target = n.map(|n| self.processed + n);
+    }
+
+    fn sample(&mut self, at: Instant) {
+        self.samples
+            .push_back((at, self.processed));
+        if self.samples.len() > Self::ROLLING_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    // The levels/sec over the sampled window, None until there are 2
+    // samples.
+    fn rate(&self) -> Option<f64> {
+        let (first_at, first) = self.samples.front()?;
+        let (last_at, last) = self.samples.back()?;
+        let secs = last_at
+            .duration_since(*first_at)
+            .as_secs_f64();
+        if secs <= 0.0 {
+            return None;
+        }
+        Some((last - first) as f64 / secs)
+    }
+
+    // The remaining levels, the rate and the expected time until the target
+    // is reached (None when no target is set, or nothing is being processed).
+    fn eta(&self) -> Option<(usize, f64, Duration)> {
+        let remaining = self
+            .target?
+            .saturating_sub(self.processed);
+        let rate = self.rate()?;
+        if rate <= 0.0 {
+            return None;
+        }
+        Some((
+            remaining,
+            rate,
+            Duration::from_secs_f64(remaining as f64 / rate),
+        ))
+    }
+}
+
+// Formats a duration coarsely, eg 3h12m, 12m5s or 40s.
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (h, m, s) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if h > 0 {
+        format!("{}h{}m", h, m)
+    } else if m > 0 {
+        format!("{}m{}s", m, s)
+    } else {
+        format!("{}s", s)
     }
 }
 
@@ -261,3 +504,73 @@ impl Stats {
         )
     }
 }
+
+#[test]
+fn test_slowest_blocks() {
+    let block = |level: u32, millis: u64| SlowBlock {
+        level,
+        contract: "c".to_string(),
+        tx_count: 1,
+        duration: Duration::from_millis(millis),
+    };
+    let levels = |slowest: &SlowestBlocks| -> Vec<u32> {
+        slowest
+            .blocks
+            .iter()
+            .map(|b| b.level)
+            .collect()
+    };
+
+    let mut slowest = SlowestBlocks::new(3);
+    for (level, millis) in &[(1, 20), (2, 50), (3, 10), (4, 30), (5, 5)] {
+        slowest.add(block(*level, *millis));
+    }
+    assert_eq!(vec![2, 4, 1], levels(&slowest));
+    slowest.add(block(6, 40));
+    assert_eq!(vec![2, 6, 4], levels(&slowest));
+
+    let mut disabled = SlowestBlocks::new(0);
+    disabled.add(block(1, 20));
+    assert!(disabled.blocks.is_empty());
+}
+
+#[test]
+fn test_progress_eta() {
+    let start = Instant::now();
+    let mut progress = Progress::new();
+    progress.sample(start);
+    progress.add(100);
+    progress.sample(start + Duration::from_secs(1));
+    // no eta without a target
+    assert_eq!(None, progress.eta());
+
+    progress.set_target(Some(1000));
+    progress.add(200);
+    progress.sample(start + Duration::from_secs(2));
+    let (remaining, rate, eta) = progress.eta().unwrap();
+    assert_eq!(800, remaining);
+    assert!((rate - 150.0).abs() < 1e-6);
+    assert_eq!(Duration::from_secs_f64(800.0 / 150.0), eta);
+
+    // the rate is taken over the last ROLLING_WINDOW samples only
+    for i in 3..(3 + Progress::ROLLING_WINDOW as u64) {
+        progress.add(10);
+        progress.sample(start + Duration::from_secs(i));
+    }
+    let (remaining, rate, _) = progress.eta().unwrap();
+    assert_eq!(800 - 10 * Progress::ROLLING_WINDOW, remaining);
+    assert!((rate - 10.0).abs() < 1e-6);
+
+    progress.set_target(None);
+    assert_eq!(None, progress.eta());
+}
+
+#[test]
+fn test_format_duration() {
+    assert_eq!("40s", format_duration(Duration::from_secs(40)));
+    assert_eq!("12m5s", format_duration(Duration::from_secs(12 * 60 + 5)));
+    assert_eq!(
+        "3h12m",
+        format_duration(Duration::from_secs(3 * 3600 + 12 * 60 + 59))
+    );
+}
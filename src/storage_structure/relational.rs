@@ -3,7 +3,9 @@ use crate::storage_structure::typing::{Ele, ExprTy};
 
 use crate::config::ContractID;
 use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
@@ -12,6 +14,18 @@ use pretty_assertions::assert_eq;
 pub(crate) struct Contract {
     pub cid: ContractID,
     pub level_floor: Option<u32>,
+    // table name (or prefix, when ending with '*') => whether the table's
+    // _ordered table holds snapshots (otherwise: changes)
+    pub table_snapshots: HashMap<String, bool>,
+    // renamed or shortened table name => the table path it was generated as
+    pub table_origins: HashMap<String, String>,
+    // name of the table the storage_ast is rooted at
+    pub root_table: String,
+    pub column_types: ColumnTypes,
+    pub bigmap_filter: BigmapFilter,
+    // table => column holding the URIs of off-chain metadata to resolve, see
+    // metadata::MetadataResolver
+    pub metadata_uris: HashMap<String, String>,
 
     pub storage_ast: RelationalAST,
     pub entrypoint_asts: HashMap<String, RelationalAST>,
@@ -19,6 +33,25 @@ pub(crate) struct Contract {
 
 pub type Indexes = HashMap<String, u32>;
 
+// Postgres truncates identifiers beyond this many bytes
+pub(crate) const MAX_IDENTIFIER_LEN: usize = 63;
+// Table names that are too long are shortened to this many bytes, leaving room
+// for the longest suffix of the functions and derived tables generated per
+// table. Names that do fit are kept as they are, the tables of existing dbs
+// were created with them
+const MAX_TABLE_NAME_LEN: usize = MAX_IDENTIFIER_LEN - "_at_deref".len();
+
+// Shortens the name to max_len bytes, keeping it distinct from other
+// shortened names by ending it on a hash of the full name.
+fn shorten_identifier(name: &str, max_len: usize) -> String {
+    let hash = hex::encode(&Sha256::digest(name.as_bytes())[..4]);
+    let mut end = max_len - hash.len() - 1;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}_{}", &name[..end], hash)
+}
+
 fn get_column_name(expr: &ExprTy) -> &str {
     match expr {
         ExprTy::Address => "address",
@@ -34,6 +67,8 @@ fn get_column_name(expr: &ExprTy) -> &str {
         ExprTy::Timestamp => "timestamp",
         ExprTy::Unit => "unit",
         ExprTy::Stop => "stop",
+        ExprTy::Never => "never",
+        ExprTy::Opaque(prim) => prim.as_str(),
         ExprTy::Pair(..)
         | ExprTy::Map(..)
         | ExprTy::BigMap(..)
@@ -92,7 +127,7 @@ impl Context {
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Debug)]
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
 pub enum RelationalAST {
     Option {
         elem_ast: Box<RelationalAST>,
@@ -127,6 +162,13 @@ pub enum RelationalAST {
     Leaf {
         rel_entry: RelationalEntry,
     },
+    // A bytes column holding PACKed values of a known type, which are
+    // unpacked into the columns of packed_ast. The bytes are only stored (in
+    // rel_entry's column) when they cannot be unpacked.
+    Packed {
+        rel_entry: RelationalEntry,
+        packed_ast: Box<RelationalAST>,
+    },
 }
 
 impl RelationalAST {
@@ -140,7 +182,7 @@ impl RelationalAST {
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Debug)]
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
 pub struct RelationalEntry {
     pub table_name: String,
     pub column_name: String,
@@ -149,6 +191,190 @@ pub struct RelationalEntry {
     pub is_index: bool,
 }
 
+/// User supplied overrides for generated table and column names. Tables are
+/// referred to by their generated path (eg "storage.noname_1"), columns by
+/// their generated name within such a table path.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct RenameMap {
+    #[serde(default)]
+    pub tables: HashMap<String, String>,
+    #[serde(default)]
+    pub columns: HashMap<String, HashMap<String, String>>,
+}
+
+/// User supplied overrides of the sql type of int, nat and mutez columns,
+/// which are (unbounded) NUMERIC by default. Overrides per column (referred to by their
+/// table and column name in the db) take precedence over overrides per type.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct ColumnTypes {
+    // "int", "nat" or "mutez" => sql type
+    #[serde(default)]
+    pub types: HashMap<String, NumericType>,
+    // table => column => sql type
+    #[serde(default)]
+    pub columns: HashMap<String, HashMap<String, NumericType>>,
+}
+
+// Written as "numeric", "bigint", or "numeric(precision)" or
+// "numeric(precision, scale)" for a bounded NUMERIC.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum NumericType {
+    Numeric,
+    // values that do not fit in an i64 fail the processing of the contract
+    Bigint,
+    // values with more digits than precision - scale fail the processing of
+    // the contract
+    BoundedNumeric { precision: u32, scale: u32 },
+}
+
+impl TryFrom<String> for NumericType {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        match s.as_str() {
+            "numeric" => return Ok(Self::Numeric),
+            "bigint" => return Ok(Self::Bigint),
+            _ => {}
+        }
+        let bad = || {
+            anyhow!(
+                "bad numeric type {:?}: expected numeric, bigint, numeric(precision) or numeric(precision, scale)",
+                s
+            )
+        };
+        let args: Vec<u32> = s
+            .strip_prefix("numeric(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or_else(bad)?
+            .split(',')
+            .map(|arg| arg.trim().parse::<u32>())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| bad())?;
+        let (precision, scale) = match args[..] {
+            [precision] => (precision, 0),
+            [precision, scale] => (precision, scale),
+            _ => return Err(bad()),
+        };
+        // the bounds Postgres puts on them
+        if precision == 0 || precision > 1000 || scale > precision {
+            return Err(anyhow!(
+                "bad numeric type {:?}: the precision must be between 1 and 1000, and the scale cannot exceed it",
+                s
+            ));
+        }
+        Ok(Self::BoundedNumeric { precision, scale })
+    }
+}
+
+impl From<NumericType> for String {
+    fn from(numeric_type: NumericType) -> Self {
+        match numeric_type {
+            NumericType::Numeric => "numeric".to_string(),
+            NumericType::Bigint => "bigint".to_string(),
+            NumericType::BoundedNumeric { precision, scale } => {
+                format!("numeric({},{})", precision, scale)
+            }
+        }
+    }
+}
+
+impl ColumnTypes {
+    pub(crate) fn numeric_type(
+        &self,
+        table: &str,
+        column: &str,
+        column_type: &ExprTy,
+    ) -> NumericType {
+        match column_type {
+            ExprTy::Int | ExprTy::Nat | ExprTy::Mutez => {}
+            _ => return NumericType::Numeric,
+        }
+        self.columns
+            .get(table)
+            .and_then(|columns| columns.get(column))
+            .or_else(|| {
+                self.types
+                    .get(get_column_name(column_type))
+            })
+            .copied()
+            .unwrap_or(NumericType::Numeric)
+    }
+}
+
+/// User supplied selection of the bigmaps to index, referred to by their
+/// table in the db. With include set, only the included bigmaps are indexed,
+/// otherwise all but the excluded ones. The tables of bigmaps that are not
+/// indexed are still created, but remain empty.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct BigmapFilter {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl BigmapFilter {
+    pub(crate) fn is_indexed(&self, table: &str) -> bool {
+        // the selection is of the storage's bigmaps, the bigmaps passed to
+        // entrypoints are always indexed
+        if table.starts_with("entry.") {
+            return true;
+        }
+        if !self.include.is_empty() {
+            return self.include.iter().any(|t| t == table);
+        }
+        !self.exclude.iter().any(|t| t == table)
+    }
+}
+
+/// Settings that affect the table layout derived from contracts.
+#[derive(Clone, Default, Debug)]
+pub struct LayoutSettings {
+    // contract name => rename map
+    pub rename_maps: HashMap<String, RenameMap>,
+    // contract name => overrides of which _ordered tables hold snapshots
+    pub table_snapshots: HashMap<String, HashMap<String, bool>>,
+    // contract name => name of the storage's root table (when not "storage")
+    pub root_tables: HashMap<String, String>,
+    // contract name => overrides of the sql types of numeric columns
+    pub column_types: HashMap<String, ColumnTypes>,
+    // contract name => table => bytes column => michelson type of the values
+    // packed in the column
+    pub packed_columns:
+        HashMap<String, HashMap<String, HashMap<String, serde_json::Value>>>,
+    // contract name => table => columns to leave out
+    pub excluded_columns: HashMap<String, HashMap<String, Vec<String>>>,
+    // contract name => selection of the bigmaps to index
+    pub bigmap_filters: HashMap<String, BigmapFilter>,
+    // contract name => table => column holding off-chain metadata URIs
+    pub metadata_uris: HashMap<String, HashMap<String, String>>,
+    pub flatten_ors: bool,
+}
+
+impl LayoutSettings {
+    pub const DEFAULT_ROOT_TABLE: &'static str = "storage";
+
+    pub(crate) fn root_table(&self, contract_name: &str) -> Result<String> {
+        let root_table = match self.root_tables.get(contract_name) {
+            Some(root_table) => root_table.clone(),
+            None => return Ok(Self::DEFAULT_ROOT_TABLE.to_string()),
+        };
+        // entrypoint tables are rooted at "entry.<entrypoint>"
+        if root_table.is_empty()
+            || root_table.contains('.')
+            || root_table == "entry"
+        {
+            return Err(anyhow!(
+                "bad root table name '{}' for contract {}: name is invalid or clashes with the entrypoint tables",
+                root_table,
+                contract_name
+            ));
+        }
+        Ok(root_table)
+    }
+}
+
 pub struct ASTBuilder {
     root_table: String,
 
@@ -156,6 +382,17 @@ pub struct ASTBuilder {
     column_names: HashMap<(String, String), u32>,
 
     bigmaps_retain: bool,
+    flatten_ors: bool,
+
+    rename_map: RenameMap,
+    // renamed table path => generated table path
+    table_origins: HashMap<String, String>,
+
+    // table => column => the type of the values packed in the (bytes) column
+    packed_columns: HashMap<String, HashMap<String, Ele>>,
+
+    // table => columns of which the values are skipped
+    excluded_columns: HashMap<String, Vec<String>>,
 }
 
 lazy_static! {
@@ -167,6 +404,7 @@ lazy_static! {
     ];
     static ref RESERVED_BIGMAP: Vec<String> =
         vec!["bigmap_id".to_string(), "deleted".to_string()];
+    static ref RESERVED_LIST: Vec<String> = vec!["ordinal".to_string()];
 }
 
 impl ASTBuilder {
@@ -178,6 +416,14 @@ impl ASTBuilder {
             column_names: HashMap::new(),
 
             bigmaps_retain: true,
+            flatten_ors: false,
+
+            rename_map: RenameMap::default(),
+            table_origins: HashMap::new(),
+
+            packed_columns: HashMap::new(),
+
+            excluded_columns: HashMap::new(),
         };
         for column_name in RESERVED.iter() {
             res.column_names
@@ -191,7 +437,56 @@ impl ASTBuilder {
         self
     }
 
-    fn start_table(&mut self, ctx: &Context, ele: &Ele) -> Context {
+    /// Store ors of which all variants are simple values in a single table,
+    /// with a column per variant (next to the column holding which variant
+    /// is set), instead of a child table per variant.
+    pub(crate) fn flatten_ors(&mut self) -> &mut Self {
+        self.flatten_ors = true;
+        self
+    }
+
+    pub(crate) fn with_rename_map(
+        &mut self,
+        rename_map: RenameMap,
+    ) -> &mut Self {
+        self.rename_map = rename_map;
+        self
+    }
+
+    /// Unpack the values of these bytes columns (table => column => type)
+    /// into columns of their own, see RelationalAST::Packed.
+    pub(crate) fn with_packed_columns(
+        &mut self,
+        packed_columns: HashMap<String, HashMap<String, Ele>>,
+    ) -> &mut Self {
+        self.packed_columns = packed_columns;
+        self
+    }
+
+    /// Skip the values of these columns (table => columns), as is done for
+    /// lambdas: no column is created for them.
+    pub(crate) fn with_excluded_columns(
+        &mut self,
+        excluded_columns: HashMap<String, Vec<String>>,
+    ) -> &mut Self {
+        self.excluded_columns = excluded_columns;
+        self
+    }
+
+    /// The renamed and shortened tables of the ASTs built so far, mapped to
+    /// the table paths they were generated as.
+    pub(crate) fn table_origins(&self) -> &HashMap<String, String> {
+        &self.table_origins
+    }
+
+    fn generated_table_name(&self, table_name: &str) -> String {
+        self.table_origins
+            .get(table_name)
+            .cloned()
+            .unwrap_or_else(|| table_name.to_string())
+    }
+
+    fn start_table(&mut self, ctx: &Context, ele: &Ele) -> Result<Context> {
         let name = match &ele.name {
             Some(s) => s.clone(),
             None => "noname".to_string(),
@@ -214,7 +509,7 @@ impl ASTBuilder {
         self.table_names
             .insert(full_name.clone(), c);
 
-        let name = if c == 0 {
+        let mut name = if c == 0 {
             name
         } else {
             self.table_names
@@ -222,8 +517,51 @@ impl ASTBuilder {
             format!("{}_{}", name, c)
         };
 
+        let generated =
+            format!("{}.{}", self.generated_table_name(&ctx.table_name), name);
+        if let Some(rename) = self
+            .rename_map
+            .tables
+            .get(&generated)
+            .cloned()
+        {
+            let renamed_full_name = ctx.start_table(&rename).table_name;
+            if rename.is_empty()
+                || rename.contains('.')
+                || self
+                    .table_names
+                    .contains_key(&renamed_full_name)
+            {
+                return Err(anyhow!(
+                    "bad rename of table {} to {}: name is invalid or clashes with another table",
+                    generated,
+                    rename
+                ));
+            }
+            self.table_names
+                .insert(renamed_full_name.clone(), 0);
+            self.table_origins
+                .insert(renamed_full_name, generated);
+            name = rename;
+        }
+
         let parent_table = &ctx.table_name;
-        let ctx = ctx.start_table(&name);
+        let mut ctx = ctx.start_table(&name);
+        if ctx.table_name.len() > MAX_IDENTIFIER_LEN {
+            let shortened =
+                shorten_identifier(&ctx.table_name, MAX_TABLE_NAME_LEN);
+            warn!(
+                "table name {} exceeds {} bytes, shortened to {}",
+                ctx.table_name, MAX_IDENTIFIER_LEN, shortened
+            );
+            // rename maps keep referring to the table by its full name
+            let generated = self.generated_table_name(&ctx.table_name);
+            self.table_names
+                .insert(shortened.clone(), 0);
+            self.table_origins
+                .insert(shortened.clone(), generated);
+            ctx.table_name = shortened;
+        }
 
         self.column_names.insert(
             (
@@ -236,7 +574,7 @@ impl ASTBuilder {
             self.column_names
                 .insert((ctx.table_name.clone(), column_name.clone()), 0);
         }
-        ctx
+        Ok(ctx)
     }
 
     fn column_name(
@@ -244,7 +582,7 @@ impl ASTBuilder {
         ctx: &Context,
         ele: &Ele,
         is_index: bool,
-    ) -> String {
+    ) -> Result<String> {
         let name = match &ele.name {
             Some(x) => x.clone(),
             None => get_column_name(&ele.expr_type).to_string(),
@@ -270,13 +608,51 @@ impl ASTBuilder {
         }
         self.column_names
             .insert((table.clone(), name.clone()), c);
-        if c == 0 {
+        let name = if c == 0 {
             name
         } else {
             let postfixed = format!("{}_{}", name, c);
             self.column_names
-                .insert((table, postfixed.clone()), 0);
+                .insert((table.clone(), postfixed.clone()), 0);
             postfixed
+        };
+
+        let rename = self
+            .rename_map
+            .columns
+            .get(&self.generated_table_name(&table))
+            .and_then(|columns| columns.get(&name))
+            .cloned();
+        match rename {
+            Some(rename) => {
+                if rename.is_empty()
+                    || RESERVED_BIGMAP.contains(&rename)
+                    || self
+                        .column_names
+                        .contains_key(&(table.clone(), rename.clone()))
+                {
+                    return Err(anyhow!(
+                        "bad rename of column {} (table {}) to {}: name is invalid or clashes with another column",
+                        name,
+                        table,
+                        rename
+                    ));
+                }
+                self.column_names
+                    .insert((table, rename.clone()), 0);
+                Ok(rename)
+            }
+            None if name.len() > MAX_IDENTIFIER_LEN => {
+                let shortened = shorten_identifier(&name, MAX_IDENTIFIER_LEN);
+                warn!(
+                    "column name {} (table {}) exceeds {} bytes, shortened to {}",
+                    name, table, MAX_IDENTIFIER_LEN, shortened
+                );
+                self.column_names
+                    .insert((table, shortened.clone()), 0);
+                Ok(shortened)
+            }
+            None => Ok(name),
         }
     }
 
@@ -311,10 +687,16 @@ impl ASTBuilder {
                 })
             }
             ExprTy::List(elems_unique, elems_type) => {
-                let ctx = &self.start_table(ctx, ele);
+                let ctx = &self.start_table(ctx, ele)?;
                 let elems_ast = match elems_unique {
                     true => self.build_index(ctx, &elems_type)?,
                     false => {
+                        for column_name in RESERVED_LIST.iter() {
+                            self.column_names.insert(
+                                (ctx.table_name.clone(), column_name.clone()),
+                                0,
+                            );
+                        }
                         self.build_relational_ast_internal(ctx, &elems_type)?
                     }
                 };
@@ -325,7 +707,7 @@ impl ASTBuilder {
                 })
             }
             ExprTy::BigMap(key_type, value_type) => {
-                let ctx = &self.start_table(ctx, ele);
+                let ctx = &self.start_table(ctx, ele)?;
 
                 for column_name in RESERVED_BIGMAP.iter() {
                     self.column_names.insert(
@@ -345,7 +727,7 @@ impl ASTBuilder {
                 })
             }
             ExprTy::Map(key_type, value_type) => {
-                let ctx = &self.start_table(ctx, ele);
+                let ctx = &self.start_table(ctx, ele)?;
                 let key_ast = self.build_index(ctx, &key_type)?;
                 let value_ast =
                     self.build_relational_ast_internal(ctx, &value_type)?;
@@ -373,15 +755,45 @@ impl ASTBuilder {
                     .build_enumeration_or(ctx, ele, &name, false)?
                     .0)
             }
-            _ => Ok(RelationalAST::Leaf {
-                rel_entry: RelationalEntry {
+            _ => {
+                let mut rel_entry = RelationalEntry {
                     table_name: ctx.table_name.clone(),
-                    column_name: self.column_name(ctx, ele, false),
+                    column_name: self.column_name(ctx, ele, false)?,
                     column_type: ele.expr_type.clone(),
                     value: None,
                     is_index: false,
-                },
-            }),
+                };
+                if matches!(
+                    self.excluded_columns.get(&rel_entry.table_name),
+                    Some(columns) if columns.contains(&rel_entry.column_name)
+                ) {
+                    rel_entry.column_type = ExprTy::Stop;
+                    return Ok(RelationalAST::Leaf { rel_entry });
+                }
+                let packed_type = match ele.expr_type {
+                    ExprTy::Bytes => self
+                        .packed_columns
+                        .get(&rel_entry.table_name)
+                        .and_then(|columns| columns.get(&rel_entry.column_name))
+                        .cloned(),
+                    _ => None,
+                };
+                match packed_type {
+                    Some(packed_type) => {
+                        let packed_ast = self.build_relational_ast_internal(
+                            &ctx.next_with_prefix(
+                                rel_entry.column_name.clone(),
+                            ),
+                            &packed_type,
+                        )?;
+                        Ok(RelationalAST::Packed {
+                            rel_entry,
+                            packed_ast: Box::new(packed_ast),
+                        })
+                    }
+                    None => Ok(RelationalAST::Leaf { rel_entry }),
+                }
+            }
         }
     }
 
@@ -398,18 +810,20 @@ impl ASTBuilder {
                 ctx,
                 &ele_set_annot(ele, Some(column_name.to_string())),
                 false,
-            ),
+            )?,
             column_type: ExprTy::String,
             is_index,
             value: None,
         };
 
+        let flatten = self.flatten_ors && !is_index && is_leaf_only_or(ele);
         self.build_enumeration_or_internal(
             ctx,
             ele,
             column_name,
             is_index,
             Some(rel_entry),
+            flatten,
         )
     }
 
@@ -420,6 +834,7 @@ impl ASTBuilder {
         column_name: &str,
         is_index: bool,
         or_unfold: Option<RelationalEntry>,
+        flatten: bool,
     ) -> Result<(RelationalAST, String)> {
         match &ele.expr_type {
             ExprTy::OrEnumeration(left_type, right_type) => {
@@ -430,6 +845,7 @@ impl ASTBuilder {
                         column_name,
                         false,
                         None,
+                        flatten,
                     )?;
                 let (right_ast, right_table) = self
                     .build_enumeration_or_internal(
@@ -438,21 +854,26 @@ impl ASTBuilder {
                         column_name,
                         false,
                         None,
+                        flatten,
                     )?;
+                // A flattened variant lives in the same table as the or
+                // itself, but (unlike unit variants) still has a value to
+                // process.
+                let branch_table = |table: String, ast: &RelationalAST| {
+                    if table != ctx.table_name
+                        || (flatten && !is_unit_leaf(ast))
+                    {
+                        Some(table)
+                    } else {
+                        None
+                    }
+                };
                 Ok((
                     RelationalAST::OrEnumeration {
                         or_unfold,
-                        left_table: if left_table != ctx.table_name {
-                            Some(left_table)
-                        } else {
-                            None
-                        },
+                        left_table: branch_table(left_table, &left_ast),
                         left_ast: Box::new(left_ast),
-                        right_table: if right_table != ctx.table_name {
-                            Some(right_table)
-                        } else {
-                            None
-                        },
+                        right_table: branch_table(right_table, &right_ast),
                         right_ast: Box::new(right_ast),
                     },
                     ctx.table_name.clone(),
@@ -466,7 +887,7 @@ impl ASTBuilder {
                             ctx,
                             &ele_set_annot(ele, Some(column_name.to_string())),
                             false,
-                        ),
+                        )?,
                         column_type: ele.expr_type.clone(),
                         value: ele.name.clone(),
                         is_index,
@@ -488,8 +909,31 @@ impl ASTBuilder {
                     }
                 };
 
+                if flatten {
+                    let column = self.column_name(
+                        ctx,
+                        &ele_set_annot(
+                            ele,
+                            Some(format!("{}_{}", column_name, name)),
+                        ),
+                        false,
+                    )?;
+                    return Ok((
+                        RelationalAST::Leaf {
+                            rel_entry: RelationalEntry {
+                                table_name: ctx.table_name.clone(),
+                                column_name: column,
+                                column_type: ele.expr_type.clone(),
+                                value: Some(name),
+                                is_index: false,
+                            },
+                        },
+                        ctx.table_name.clone(),
+                    ));
+                }
+
                 let ctx =
-                    self.start_table(ctx, &ele_set_annot(ele, Some(name)));
+                    self.start_table(ctx, &ele_set_annot(ele, Some(name)))?;
                 let ele = &ele_set_annot(ele, Some(ctx.table_leaf_name()));
                 Ok((
                     if is_index {
@@ -545,10 +989,10 @@ impl ASTBuilder {
             | ExprTy::List { .. } => {
                 Err(anyhow!("unexpected input type to index: ele={:#?}", ele))
             }
-            ExprTy::Stop => Ok(RelationalAST::Leaf {
+            ExprTy::Stop | ExprTy::Never => Ok(RelationalAST::Leaf {
                 rel_entry: RelationalEntry {
                     table_name: ctx.table_name.clone(),
-                    column_name: self.column_name(ctx, ele, true),
+                    column_name: self.column_name(ctx, ele, true)?,
                     column_type: ele.expr_type.clone(),
                     value: None,
                     is_index: false,
@@ -557,7 +1001,7 @@ impl ASTBuilder {
             _ => Ok(RelationalAST::Leaf {
                 rel_entry: RelationalEntry {
                     table_name: ctx.table_name.clone(),
-                    column_name: self.column_name(ctx, ele, true),
+                    column_name: self.column_name(ctx, ele, true)?,
                     column_type: ele.expr_type.clone(),
                     value: None,
                     is_index: true,
@@ -567,6 +1011,145 @@ impl ASTBuilder {
     }
 }
 
+// Whether all variants of the or are simple values (ie no variant requires a
+// table of its own).
+fn is_leaf_only_or(ele: &Ele) -> bool {
+    match &ele.expr_type {
+        ExprTy::OrEnumeration(left, right) => {
+            is_leaf_only_or(left) && is_leaf_only_or(right)
+        }
+        ExprTy::Pair(..)
+        | ExprTy::Map(..)
+        | ExprTy::BigMap(..)
+        | ExprTy::List(..)
+        | ExprTy::Option(..) => false,
+        _ => true,
+    }
+}
+
+/// The (table, column) of the packed bytes columns in the AST.
+pub(crate) fn packed_columns(rel_ast: &RelationalAST) -> Vec<(String, String)> {
+    match rel_ast {
+        RelationalAST::Packed { rel_entry, .. } => {
+            vec![(rel_entry.table_name.clone(), rel_entry.column_name.clone())]
+        }
+        RelationalAST::Option { elem_ast } => packed_columns(elem_ast),
+        RelationalAST::List { elems_ast, .. } => packed_columns(elems_ast),
+        RelationalAST::Pair {
+            left_ast: l,
+            right_ast: r,
+        }
+        | RelationalAST::OrEnumeration {
+            left_ast: l,
+            right_ast: r,
+            ..
+        }
+        | RelationalAST::Map {
+            key_ast: l,
+            value_ast: r,
+            ..
+        }
+        | RelationalAST::BigMap {
+            key_ast: l,
+            value_ast: r,
+            ..
+        } => {
+            let mut res = packed_columns(l);
+            res.extend(packed_columns(r));
+            res
+        }
+        RelationalAST::Leaf { .. } => vec![],
+    }
+}
+
+/// The (table, column) of the leaves in the AST of which the values are
+/// skipped: lambdas and excluded columns.
+pub(crate) fn skipped_columns(
+    rel_ast: &RelationalAST,
+) -> Vec<(String, String)> {
+    match rel_ast {
+        RelationalAST::Leaf { rel_entry }
+            if rel_entry.column_type == ExprTy::Stop =>
+        {
+            vec![(rel_entry.table_name.clone(), rel_entry.column_name.clone())]
+        }
+        RelationalAST::Leaf { .. } => vec![],
+        RelationalAST::Option { elem_ast } => skipped_columns(elem_ast),
+        RelationalAST::List { elems_ast, .. } => skipped_columns(elems_ast),
+        RelationalAST::Packed { packed_ast, .. } => skipped_columns(packed_ast),
+        RelationalAST::Pair {
+            left_ast: l,
+            right_ast: r,
+        }
+        | RelationalAST::OrEnumeration {
+            left_ast: l,
+            right_ast: r,
+            ..
+        }
+        | RelationalAST::Map {
+            key_ast: l,
+            value_ast: r,
+            ..
+        }
+        | RelationalAST::BigMap {
+            key_ast: l,
+            value_ast: r,
+            ..
+        } => {
+            let mut res = skipped_columns(l);
+            res.extend(skipped_columns(r));
+            res
+        }
+    }
+}
+
+/// The tables of the bigmaps in the AST.
+pub(crate) fn bigmap_tables(rel_ast: &RelationalAST) -> Vec<String> {
+    match rel_ast {
+        RelationalAST::BigMap {
+            table,
+            key_ast,
+            value_ast,
+            ..
+        } => {
+            let mut res = vec![table.clone()];
+            res.extend(bigmap_tables(key_ast));
+            res.extend(bigmap_tables(value_ast));
+            res
+        }
+        RelationalAST::Option { elem_ast } => bigmap_tables(elem_ast),
+        RelationalAST::List { elems_ast, .. } => bigmap_tables(elems_ast),
+        RelationalAST::Pair {
+            left_ast: l,
+            right_ast: r,
+        }
+        | RelationalAST::OrEnumeration {
+            left_ast: l,
+            right_ast: r,
+            ..
+        }
+        | RelationalAST::Map {
+            key_ast: l,
+            value_ast: r,
+            ..
+        } => {
+            let mut res = bigmap_tables(l);
+            res.extend(bigmap_tables(r));
+            res
+        }
+        RelationalAST::Leaf { .. } | RelationalAST::Packed { .. } => vec![],
+    }
+}
+
+fn is_unit_leaf(rel_ast: &RelationalAST) -> bool {
+    match rel_ast {
+        RelationalAST::Leaf { rel_entry } => {
+            rel_entry.column_type == ExprTy::Unit
+        }
+        _ => false,
+    }
+}
+
 fn ele_with_annot(ele: &Ele, annot: Option<String>) -> Ele {
     match &ele.name {
         Some(_) => ele.clone(),
@@ -1420,3 +2003,279 @@ fn test_relational_ast_builder() {
         assert_eq!(tc.exp.unwrap(), got.unwrap());
     }
 }
+
+#[test]
+fn test_relational_ast_builder_rename_map() {
+    let ele = Ele {
+        name: None,
+        expr_type: ExprTy::Pair(
+            Box::new(Ele {
+                name: None,
+                expr_type: ExprTy::Map(
+                    Box::new(Ele {
+                        name: None,
+                        expr_type: ExprTy::Nat,
+                    }),
+                    Box::new(Ele {
+                        name: None,
+                        expr_type: ExprTy::Mutez,
+                    }),
+                ),
+            }),
+            Box::new(Ele {
+                name: Some("admin".to_string()),
+                expr_type: ExprTy::Address,
+            }),
+        ),
+    };
+    fn rename_map(
+        tables: &[(&str, &str)],
+        columns: &[(&str, &str, &str)],
+    ) -> RenameMap {
+        let mut res = RenameMap::default();
+        for (from, to) in tables {
+            res.tables
+                .insert(from.to_string(), to.to_string());
+        }
+        for (table, from, to) in columns {
+            res.columns
+                .entry(table.to_string())
+                .or_insert_with(HashMap::new)
+                .insert(from.to_string(), to.to_string());
+        }
+        res
+    }
+
+    let got = ASTBuilder::new("storage")
+        .with_rename_map(rename_map(
+            &[("storage.noname", "balances")],
+            &[
+                ("storage.noname", "idx_nat", "idx_token_id"),
+                ("storage.noname", "mutez", "balance"),
+                ("storage", "admin", "administrator"),
+            ],
+        ))
+        .build_relational_ast(&ele)
+        .unwrap();
+    assert_eq!(
+        RelationalAST::Pair {
+            left_ast: Box::new(RelationalAST::Map {
+                table: "storage.balances".to_string(),
+                key_ast: Box::new(RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage.balances".to_string(),
+                        column_name: "idx_token_id".to_string(),
+                        column_type: ExprTy::Nat,
+                        value: None,
+                        is_index: true,
+                    }
+                }),
+                value_ast: Box::new(RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage.balances".to_string(),
+                        column_name: "balance".to_string(),
+                        column_type: ExprTy::Mutez,
+                        value: None,
+                        is_index: false,
+                    }
+                }),
+            }),
+            right_ast: Box::new(RelationalAST::Leaf {
+                rel_entry: RelationalEntry {
+                    table_name: "storage".to_string(),
+                    column_name: "administrator".to_string(),
+                    column_type: ExprTy::Address,
+                    value: None,
+                    is_index: false,
+                }
+            }),
+        },
+        got
+    );
+
+    // renames must not clash with reserved columns
+    assert!(ASTBuilder::new("storage")
+        .with_rename_map(rename_map(
+            &[],
+            &[("storage", "admin", "tx_context_id")]
+        ))
+        .build_relational_ast(&ele)
+        .is_err());
+    // renames must not clash with each other
+    assert!(ASTBuilder::new("storage")
+        .with_rename_map(rename_map(
+            &[],
+            &[
+                ("storage.noname", "idx_nat", "x"),
+                ("storage.noname", "mutez", "x")
+            ]
+        ))
+        .build_relational_ast(&ele)
+        .is_err());
+    assert!(ASTBuilder::new("storage")
+        .with_rename_map(rename_map(&[("storage.noname", "a.b")], &[]))
+        .build_relational_ast(&ele)
+        .is_err());
+}
+
+#[test]
+fn test_relational_ast_builder_flatten_ors() {
+    fn variant(name: &str, expr_type: ExprTy) -> Ele {
+        Ele {
+            name: Some(name.to_string()),
+            expr_type,
+        }
+    }
+    fn or(l: Ele, r: Ele) -> Ele {
+        Ele {
+            name: None,
+            expr_type: ExprTy::OrEnumeration(Box::new(l), Box::new(r)),
+        }
+    }
+    fn leaf(
+        column_name: &str,
+        column_type: ExprTy,
+        value: Option<&str>,
+    ) -> RelationalAST {
+        RelationalAST::Leaf {
+            rel_entry: RelationalEntry {
+                table_name: "storage".to_string(),
+                column_name: column_name.to_string(),
+                column_type,
+                value: value.map(|s| s.to_string()),
+                is_index: false,
+            },
+        }
+    }
+
+    let mut ele = or(
+        or(variant("a", ExprTy::Nat), variant("b", ExprTy::String)),
+        or(variant("c", ExprTy::Unit), variant("d", ExprTy::Int)),
+    );
+    ele.name = Some("action".to_string());
+
+    let storage = Some("storage".to_string());
+    let exp = RelationalAST::OrEnumeration {
+        or_unfold: Some(RelationalEntry {
+            table_name: "storage".to_string(),
+            column_name: "action".to_string(),
+            column_type: ExprTy::String,
+            value: None,
+            is_index: false,
+        }),
+        left_table: storage.clone(),
+        left_ast: Box::new(RelationalAST::OrEnumeration {
+            or_unfold: None,
+            left_table: storage.clone(),
+            left_ast: Box::new(leaf("action_a", ExprTy::Nat, Some("a"))),
+            right_table: storage.clone(),
+            right_ast: Box::new(leaf("action_b", ExprTy::String, Some("b"))),
+        }),
+        right_table: storage.clone(),
+        right_ast: Box::new(RelationalAST::OrEnumeration {
+            or_unfold: None,
+            left_table: None,
+            left_ast: Box::new(leaf("action_1", ExprTy::Unit, Some("c"))),
+            right_table: storage,
+            right_ast: Box::new(leaf("action_d", ExprTy::Int, Some("d"))),
+        }),
+    };
+    let got = ASTBuilder::new("storage")
+        .flatten_ors()
+        .build_relational_ast(&ele)
+        .unwrap();
+    assert_eq!(exp, got);
+
+    // ors with a variant that's not a simple value are not flattened
+    let ele = or(
+        variant("a", ExprTy::Nat),
+        variant(
+            "b",
+            ExprTy::Pair(
+                Box::new(variant("x", ExprTy::Nat)),
+                Box::new(variant("y", ExprTy::Nat)),
+            ),
+        ),
+    );
+    let got = ASTBuilder::new("storage")
+        .flatten_ors()
+        .build_relational_ast(&ele)
+        .unwrap();
+    let exp = ASTBuilder::new("storage")
+        .build_relational_ast(&ele)
+        .unwrap();
+    assert_eq!(exp, got);
+}
+
+#[test]
+fn test_relational_ast_builder_excluded_columns() {
+    use serde_json::json;
+
+    let type_ast = crate::storage_structure::typing::type_ast_from_json(&json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "address", "annots": ["%owner"] },
+            {
+                "prim": "big_map",
+                "args": [
+                    { "prim": "address" },
+                    {
+                        "prim": "pair",
+                        "args": [
+                            { "prim": "nat", "annots": ["%balance"] },
+                            {
+                                "prim": "pair",
+                                "annots": ["%info"],
+                                "args": [
+                                    { "prim": "string", "annots": ["%name"] },
+                                    { "prim": "bytes", "annots": ["%blob"] },
+                                ],
+                            },
+                        ],
+                    },
+                ],
+                "annots": ["%ledger"],
+            },
+        ],
+    }))
+    .unwrap();
+    let mut excluded_columns = HashMap::new();
+    excluded_columns
+        .insert("storage.ledger".to_string(), vec!["info_blob".to_string()]);
+    let got = ASTBuilder::new("storage")
+        .with_excluded_columns(excluded_columns)
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    assert_eq!(
+        vec![("storage.ledger".to_string(), "info_blob".to_string())],
+        skipped_columns(&got)
+    );
+
+    // the other columns are named as they are without the exclusion
+    let all = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    assert!(skipped_columns(&all).is_empty());
+    let tables = |ast: &RelationalAST| {
+        let mut builder =
+            crate::sql::table_builder::TableBuilder::new("storage");
+        builder.populate(ast);
+        builder.tables
+    };
+    let (got_tables, all_tables) = (tables(&got), tables(&all));
+    let ledger_columns =
+        |tables: &crate::sql::table_builder::TableMap| -> Vec<String> {
+            let mut columns: Vec<String> = tables["storage.ledger"]
+                .get_columns()
+                .iter()
+                .filter(|column| column.column_type != ExprTy::Stop)
+                .map(|column| column.name.clone())
+                .collect();
+            columns.sort();
+            columns
+        };
+    let mut exp = ledger_columns(&all_tables);
+    exp.retain(|column| column != "info_blob");
+    assert_eq!(exp, ledger_columns(&got_tables));
+    assert!(ledger_columns(&got_tables).contains(&"info_name".to_string()));
+}
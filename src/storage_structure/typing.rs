@@ -13,9 +13,14 @@ pub enum ExprTy {
     Signature,
     Contract,
     Stop,
+    // never has no values, its columns are placeholders that are always NULL
+    Never,
     String,
     Timestamp,
     Unit,
+    // Opaque values are stored as-is (raw bytes or string), without any
+    // further interpretation. Carries the name of the Michelson prim.
+    Opaque(String),
     BigMap(Box<Ele>, Box<Ele>),
     List(bool, Box<Ele>),
     Map(Box<Ele>, Box<Ele>),
@@ -132,9 +137,10 @@ pub(crate) fn type_ast_from_json(json: &serde_json::Value) -> Result<Ele> {
                     }
                     2 => Ok(complex_expr!(ExprTy::Pair, annot, args)),
                     _ => {
-                        let mut args_cloned = args
-                            .ok_or_else(|| anyhow!("Args was none!"))
-                            .unwrap();
+                        // pair a b c is pair a (pair b c), the same right
+                        // fold as is applied to Pair values
+                        let mut args_cloned =
+                            args.ok_or_else(|| anyhow!("Args was none!"))?;
                         args_cloned.reverse();
                         let unfolded =
                             parser::lexer_unfold_many_pair(&mut args_cloned);
@@ -157,27 +163,41 @@ pub(crate) fn type_ast_from_json(json: &serde_json::Value) -> Result<Ele> {
                 })
             }
             "string" => Ok(simple_expr!(ExprTy::String, annot)),
-            "chain_id" | "bls12_381_g1" | "bls12_381_g2" | "bls12_381_fr" => {
-                Ok(simple_expr!(
-                    ExprTy::String,
-                    annot.or_else(|| Some(
-                        prim.to_ascii_lowercase()
-                            .as_str()
-                            .to_string()
-                    ))
+            "chain_id" => Ok(simple_expr!(
+                ExprTy::String,
+                annot.or_else(|| Some(
+                    prim.to_ascii_lowercase()
+                        .as_str()
+                        .to_string()
                 ))
-            }
+            )),
             "timestamp" => Ok(simple_expr!(ExprTy::Timestamp, annot)),
             "unit" => Ok(simple_expr!(ExprTy::Unit, annot)),
-            // - ignoring constants, as far as we can see now there's no reason
-            // to index these
-            // - ignoring tickets and sapling_state because it's not clear to
-            // us right now how this info would be used exactly
+            // - ignoring global constants that were not expanded (see
+            // expand_global_constants)
+            // - ignoring tickets because it's not clear to us right now how
+            // this info would be used exactly
             // - ignoring lambdas because they're a pandoras box. probably are
             // impossible to index in a meaningful way
-            "constant" | "never" | "ticket" | "sapling_state" | "lambda" => {
+            // - ignoring operations, they only occur in lambdas (and are not
+            // storable anyway)
+            "constant" | "ticket" | "lambda" | "operation" => {
                 Ok(simple_expr!(ExprTy::Stop, annot))
             }
+            "never" => Ok(simple_expr!(ExprTy::Never, annot)),
+            // sapling data is shielded, we only keep the raw value around.
+            // bls12_381 curve points and scalars are kept as raw bytes too
+            "sapling_state"
+            | "sapling_transaction"
+            | "bls12_381_g1"
+            | "bls12_381_g2"
+            | "bls12_381_fr" => {
+                let prim = prim.to_ascii_lowercase();
+                Ok(simple_expr!(
+                    ExprTy::Opaque(prim),
+                    annot.or_else(|| Some(prim.clone()))
+                ))
+            }
             "contract" | "signature" => {
                 Ok(simple_expr!(ExprTy::KeyHash, annot))
             }
@@ -191,3 +211,265 @@ pub(crate) fn type_ast_from_json(json: &serde_json::Value) -> Result<Ele> {
         Err(anyhow!("Wrong JS {}", json.to_string()))
     }
 }
+
+/// Replaces the references to global constants (`constant "<hash>"`) in a
+/// type definition with their expansion, as returned by get_constant (eg
+/// NodeClient::get_global_constant). Expansions may refer to global constants
+/// themselves, these are expanded as well. The annotations of a reference
+/// are kept on its expansion.
+pub(crate) fn expand_global_constants<F>(
+    json: &serde_json::Value,
+    get_constant: &mut F,
+) -> Result<serde_json::Value>
+where
+    F: FnMut(&str) -> Result<serde_json::Value>,
+{
+    match json {
+        serde_json::Value::Array(elems) => Ok(serde_json::Value::Array(
+            elems
+                .iter()
+                .map(|elem| expand_global_constants(elem, get_constant))
+                .collect::<Result<Vec<serde_json::Value>>>()?,
+        )),
+        serde_json::Value::Object(fields) if json["prim"] == "constant" => {
+            let hash = json["args"][0]["string"]
+                .as_str()
+                .ok_or_else(|| {
+                    anyhow!("malformed global constant reference: {}", json)
+                })?;
+            let mut expanded =
+                expand_global_constants(&get_constant(hash)?, get_constant)?;
+            if let (Some(annots), serde_json::Value::Object(expanded)) =
+                (fields.get("annots"), &mut expanded)
+            {
+                expanded.insert("annots".to_string(), annots.clone());
+            }
+            Ok(expanded)
+        }
+        serde_json::Value::Object(fields) => Ok(serde_json::Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| {
+                    Ok((k.clone(), expand_global_constants(v, get_constant)?))
+                })
+                .collect::<Result<serde_json::Map<String, serde_json::Value>>>(
+                )?,
+        )),
+        _ => Ok(json.clone()),
+    }
+}
+
+/// The size in bytes of opaque values of the given prim, for those that have
+/// a fixed size.
+pub(crate) fn opaque_size(prim: &str) -> Option<usize> {
+    match prim {
+        "bls12_381_g1" => Some(96),
+        "bls12_381_g2" => Some(192),
+        "bls12_381_fr" => Some(32),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_type_ast_from_json_sapling() {
+    let storage_definition: serde_json::Value = serde_json::from_str(
+        r#"{
+            "prim": "pair",
+            "args": [
+                { "prim": "sapling_state", "args": [ { "int": "8" } ] },
+                { "prim": "sapling_transaction", "args": [ { "int": "8" } ], "annots": [ "%tx" ] }
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    let got = type_ast_from_json(&storage_definition).unwrap();
+    assert_eq!(
+        Ele {
+            name: None,
+            expr_type: ExprTy::Pair(
+                Box::new(Ele {
+                    name: Some("sapling_state".to_string()),
+                    expr_type: ExprTy::Opaque("sapling_state".to_string()),
+                }),
+                Box::new(Ele {
+                    name: Some("tx".to_string()),
+                    expr_type: ExprTy::Opaque(
+                        "sapling_transaction".to_string()
+                    ),
+                }),
+            ),
+        },
+        got
+    );
+}
+
+#[test]
+fn test_type_ast_from_json_bls12_381() {
+    for prim in &["bls12_381_g1", "bls12_381_g2", "bls12_381_fr"] {
+        let got =
+            type_ast_from_json(&serde_json::json!({ "prim": prim })).unwrap();
+        assert_eq!(
+            Ele {
+                name: Some(prim.to_string()),
+                expr_type: ExprTy::Opaque(prim.to_string()),
+            },
+            got
+        );
+
+        let got = type_ast_from_json(
+            &serde_json::json!({ "prim": prim, "annots": ["%point"] }),
+        )
+        .unwrap();
+        assert_eq!(
+            Ele {
+                name: Some("point".to_string()),
+                expr_type: ExprTy::Opaque(prim.to_string()),
+            },
+            got
+        );
+        assert!(opaque_size(prim).is_some());
+    }
+}
+
+#[test]
+fn test_type_ast_from_json_nary_pair() {
+    use crate::storage_structure::relational::{ASTBuilder, RelationalAST};
+
+    let storage_definition = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "nat", "annots": ["%amount"] },
+            { "prim": "string", "annots": ["%name"] },
+            { "prim": "address", "annots": ["%owner"] },
+        ]
+    });
+    let got = type_ast_from_json(&storage_definition).unwrap();
+    let simple = |name: &str, expr_type: ExprTy| Ele {
+        name: Some(name.to_string()),
+        expr_type,
+    };
+    assert_eq!(
+        Ele {
+            name: None,
+            expr_type: ExprTy::Pair(
+                Box::new(simple("amount", ExprTy::Nat)),
+                Box::new(Ele {
+                    name: None,
+                    expr_type: ExprTy::Pair(
+                        Box::new(simple("name", ExprTy::String)),
+                        Box::new(simple("owner", ExprTy::Address)),
+                    ),
+                }),
+            ),
+        },
+        got
+    );
+
+    fn leaves(rel_ast: &RelationalAST) -> Vec<(String, ExprTy)> {
+        match rel_ast {
+            RelationalAST::Pair {
+                left_ast,
+                right_ast,
+            } => {
+                let mut res = leaves(left_ast);
+                res.extend(leaves(right_ast));
+                res
+            }
+            RelationalAST::Leaf { rel_entry } => vec![(
+                rel_entry.column_name.clone(),
+                rel_entry.column_type.clone(),
+            )],
+            _ => panic!("unexpected rel_ast {:?}", rel_ast),
+        }
+    }
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&got)
+        .unwrap();
+    assert_eq!(
+        vec![
+            ("amount".to_string(), ExprTy::Nat),
+            ("name".to_string(), ExprTy::String),
+            ("owner".to_string(), ExprTy::Address),
+        ],
+        leaves(&rel_ast)
+    );
+}
+
+#[test]
+fn test_type_ast_from_json_never() {
+    use crate::storage_structure::relational::{ASTBuilder, RelationalAST};
+
+    let parameter_definition = serde_json::json!({
+        "prim": "or",
+        "args": [
+            { "prim": "never", "annots": ["%nothing"] },
+            { "prim": "nat", "annots": ["%amount"] },
+        ]
+    });
+    let got = type_ast_from_json(&parameter_definition).unwrap();
+    assert_eq!(
+        Ele {
+            name: None,
+            expr_type: ExprTy::OrEnumeration(
+                Box::new(Ele {
+                    name: Some("nothing".to_string()),
+                    expr_type: ExprTy::Never,
+                }),
+                Box::new(Ele {
+                    name: Some("amount".to_string()),
+                    expr_type: ExprTy::Nat,
+                }),
+            ),
+        },
+        got
+    );
+
+    let rel_ast = ASTBuilder::new("entry")
+        .build_relational_ast(&got)
+        .unwrap();
+    assert!(matches!(rel_ast, RelationalAST::OrEnumeration { .. }));
+}
+
+#[test]
+fn test_type_ast_from_json_operation() {
+    use crate::storage_structure::relational::ASTBuilder;
+
+    let got = type_ast_from_json(&serde_json::json!({
+        "prim": "operation",
+        "annots": ["%op"],
+    }))
+    .unwrap();
+    assert_eq!(
+        Ele {
+            name: Some("op".to_string()),
+            expr_type: ExprTy::Stop,
+        },
+        got
+    );
+
+    // eg. the parameter of an entrypoint that takes a lambda producing
+    // operations, next to a plain list of operations
+    let parameter_definition = serde_json::json!({
+        "prim": "pair",
+        "args": [
+            {
+                "prim": "lambda",
+                "args": [
+                    { "prim": "unit" },
+                    { "prim": "list", "args": [ { "prim": "operation" } ] },
+                ],
+                "annots": ["%action"],
+            },
+            {
+                "prim": "list",
+                "args": [ { "prim": "operation" } ],
+                "annots": ["%ops"],
+            },
+        ]
+    });
+    let got = type_ast_from_json(&parameter_definition).unwrap();
+    ASTBuilder::new("entry")
+        .build_relational_ast(&got)
+        .unwrap();
+}
@@ -8,7 +8,8 @@ use crate::octez::block::{
     BigMapDiff, Block, LazyStorageDiff, TxContext, Update, Updates::*,
 };
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
 pub(crate) enum Op {
     Alloc {
         bigmap: i32,
@@ -374,6 +375,115 @@ impl IntraBlockBigmapDiffsProcessor {
             .copied()
             .collect::<Vec<i32>>()
     }
+
+    // The state computed for the block, for attaching to bug reports about
+    // bigmap copies (see --replay-bigmap-diffs): per tx context (in order)
+    // its bigmap ops as found in the block, and the bigmaps it owns along
+    // with their normalized diffs and the bigmaps these were copied from, as
+    // processing the block sees them.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let mut tx_contexts: Vec<&TxContext> =
+            self.tx_bigmap_ops.keys().collect();
+        tx_contexts.sort();
+
+        let mut res: Vec<serde_json::Value> = vec![];
+        for tx_context in tx_contexts {
+            let mut owned = self.get_tx_context_owned_bigmaps(tx_context);
+            owned.sort_unstable();
+            let mut owned_bigmaps: Vec<serde_json::Value> = vec![];
+            for bigmap in owned {
+                let (deps, ops) =
+                    self.normalized_diffs(bigmap, tx_context, bigmap >= 0);
+                owned_bigmaps.push(serde_json::json!({
+                    "bigmap": bigmap,
+                    "deps": deps
+                        .iter()
+                        .map(|(source, at)| serde_json::json!({
+                            "bigmap": source,
+                            "tx_context": at,
+                        }))
+                        .collect::<Vec<serde_json::Value>>(),
+                    "normalized_ops": ops,
+                }));
+            }
+            res.push(serde_json::json!({
+                "tx_context": tx_context,
+                "ops": self.tx_bigmap_ops[tx_context],
+                "owned_bigmaps": owned_bigmaps,
+            }));
+        }
+        serde_json::Value::Array(res)
+    }
+}
+
+#[test]
+fn test_to_json() {
+    let tx_context = |internal: Option<i32>| TxContext {
+        id: Some(1),
+        level: 10,
+        operation_group_number: 0,
+        operation_number: 0,
+        content_number: 0,
+        internal_number: internal,
+        contract: "KT1a".to_string(),
+    };
+    let update = |bigmap: i32| Op::Update {
+        bigmap,
+        keyhash: "expru".to_string(),
+        key: serde_json::json!({ "int": "1" }),
+        value: None,
+    };
+    let diffs = IntraBlockBigmapDiffsProcessor::from_testlist(&[
+        (tx_context(None), vec![update(5)]),
+        (
+            tx_context(Some(0)),
+            vec![
+                Op::Copy {
+                    bigmap: 7,
+                    source: 5,
+                },
+                update(7),
+            ],
+        ),
+    ]);
+    let json = diffs.to_json();
+
+    let replayed = json.as_array().unwrap();
+    assert_eq!(2, replayed.len());
+    assert_eq!(
+        serde_json::Value::Null,
+        replayed[0]["tx_context"]["internal_number"]
+    );
+    assert_eq!(
+        serde_json::json!([{
+            "op": "update",
+            "bigmap": 5,
+            "keyhash": "expru",
+            "key": { "int": "1" },
+            "value": null,
+        }]),
+        replayed[0]["ops"]
+    );
+    assert_eq!(
+        serde_json::json!({ "op": "copy", "bigmap": 7, "source": 5 }),
+        replayed[1]["ops"][0]
+    );
+    let owned = replayed[1]["owned_bigmaps"]
+        .as_array()
+        .unwrap();
+    assert_eq!(1, owned.len());
+    assert_eq!(7, owned[0]["bigmap"]);
+    // the keys the source had before are taken from the dependency
+    assert_eq!(5, owned[0]["deps"][0]["bigmap"]);
+    assert_eq!(
+        vec![7],
+        owned[0]["normalized_ops"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|op| op["bigmap"].as_i64().unwrap())
+            .collect::<Vec<i64>>()
+    );
 }
 
 #[test]
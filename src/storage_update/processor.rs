@@ -1,24 +1,29 @@
 use crate::debug;
 use crate::octez::block;
 use crate::octez::block::{Tx, TxContext};
-use crate::octez::node::StorageGetter;
+use crate::octez::node::{ContextPrunedError, StorageGetter};
 use crate::sql::db;
 use crate::sql::insert;
 use crate::sql::insert::{Column, Insert, InsertKey, Inserts};
-use crate::sql::types::BigmapMetaAction;
+use crate::sql::spill::SpilledInserts;
+use crate::sql::types::{BigmapMetaAction, OperationFailure, RawStorage};
 use crate::stats::StatsLogger;
 use crate::storage_structure::relational::{
-    Contract, RelationalAST, RelationalEntry,
+    BigmapFilter, ColumnTypes, Contract, NumericType, RelationalAST,
+    RelationalEntry,
 };
-use crate::storage_structure::typing::ExprTy;
+use crate::storage_structure::typing::{self, ExprTy};
 use crate::storage_update::bigmap;
 use crate::storage_update::bigmap::IntraBlockBigmapDiffsProcessor;
+use crate::storage_value::address_cache::{AddressCache, SharedAddressCache};
+use crate::storage_value::packed;
 use crate::storage_value::parser;
 use anyhow::{anyhow, Context, Result};
 use num::ToPrimitive;
 use pg_bigdecimal::{BigDecimal, PgNumeric};
 use serde_json::json;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
@@ -39,6 +44,8 @@ pub struct ProcessStorageContext {
     pub last_table: String,
     pub id: i64,
     pub fk_id: Option<i64>,
+    // the table of the row fk_id refers to
+    pub parent_table: Option<String>,
 }
 impl ProcessStorageContext {
     pub fn new(id: i64, root_table: String) -> ProcessStorageContext {
@@ -46,6 +53,7 @@ impl ProcessStorageContext {
             id,
             last_table: root_table,
             fk_id: None,
+            parent_table: None,
         }
     }
     pub fn with_id(&self, id: i64) -> Self {
@@ -53,9 +61,10 @@ impl ProcessStorageContext {
         c.id = id;
         c
     }
-    pub fn with_fk_id(&self, fk_id: i64) -> Self {
+    pub fn with_fk_id(&self, parent_table: &str, fk_id: i64) -> Self {
         let mut c = self.clone();
         c.fk_id = Some(fk_id);
+        c.parent_table = Some(parent_table.to_string());
         c
     }
     pub fn with_last_table(&self, last_table: String) -> Self {
@@ -67,13 +76,33 @@ impl ProcessStorageContext {
 
 pub(crate) type TxContextMap = HashMap<TxContext, Tx>;
 
+// Deterministic ids are in [2^62, 2^63), far out of reach of the counted
+// ids, so both can be mixed in the same db.
+const DETERMINISTIC_ID_BIT: u64 = 1 << 62;
+
 pub struct IdGenerator {
     id: i64,
+
+    // when set, ids are derived from what they identify instead of counted,
+    // so that reprocessing a level yields the same ids (see deterministic_id)
+    deterministic: bool,
+    // the number of rows handed out per (tx context id, table) so far
+    ordinals: HashMap<(i64, String), u64>,
+    handed_out: HashSet<i64>,
 }
 
 impl IdGenerator {
     pub(crate) fn new(initial_value: i64) -> Self {
-        Self { id: initial_value }
+        Self {
+            id: initial_value,
+            deterministic: false,
+            ordinals: HashMap::new(),
+            handed_out: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
     }
 
     pub(crate) fn get_id(&mut self) -> i64 {
@@ -81,6 +110,67 @@ impl IdGenerator {
         self.id += 1;
         old_id
     }
+
+    // The id of a tx context, in deterministic mode derived from its
+    // coordinates.
+    pub(crate) fn tx_context_id(&mut self, tx_context: &TxContext) -> i64 {
+        if !self.deterministic {
+            return self.get_id();
+        }
+        self.deterministic_id(&format!(
+            "ctx/{}/{}/{}/{}/{}/{:?}",
+            tx_context.contract,
+            tx_context.level,
+            tx_context.operation_group_number,
+            tx_context.operation_number,
+            tx_context.content_number,
+            tx_context.internal_number
+        ))
+    }
+
+    // The id of the next row in table under a tx context, in deterministic
+    // mode derived from (tx_context_id, table, ordinal), where ordinal is the
+    // number of rows handed out for the tx context in table before.
+    pub(crate) fn row_id(&mut self, tx_context_id: i64, table: &str) -> i64 {
+        if !self.deterministic {
+            return self.get_id();
+        }
+        let ordinal = self
+            .ordinals
+            .entry((tx_context_id, table.to_string()))
+            .or_insert(0);
+        let key = format!("row/{}/{}/{}", tx_context_id, table, ordinal);
+        *ordinal += 1;
+        self.deterministic_id(&key)
+    }
+
+    // Hashes key into the range of deterministic ids (62 bits). Should the
+    // id collide with one handed out before, key is rehashed with a salt
+    // until it does not. As ids are handed out in processing order, which is
+    // the same every time a block is processed, this is reproducible too.
+    // Collisions with ids of other blocks (expected only after some 2^31
+    // ids) are not detected here, they fail the insert on the tables'
+    // primary keys instead.
+    fn deterministic_id(&mut self, key: &str) -> i64 {
+        let mut salt = 0;
+        loop {
+            let mut hasher = Sha256::new();
+            hasher.update(key.as_bytes());
+            if salt > 0 {
+                hasher.update(format!("#{}", salt).as_bytes());
+            }
+            let digest = hasher.finalize();
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&digest[..8]);
+            let hash = u64::from_be_bytes(bytes);
+            let id = ((hash & (DETERMINISTIC_ID_BIT - 1))
+                | DETERMINISTIC_ID_BIT) as i64;
+            if self.handed_out.insert(id) {
+                return id;
+            }
+            salt += 1;
+        }
+    }
 }
 
 type BigMapMap = std::collections::HashMap<i32, (i64, RelationalAST)>;
@@ -91,15 +181,33 @@ where
     BigmapKeys: db::BigmapKeysGetter,
 {
     bigmap_map: BigMapMap,
+    // the contract's bigmap definitions by table
+    bigmap_asts: HashMap<String, RelationalAST>,
+    // the contract's overrides of the sql types of numeric columns
+    column_types: ColumnTypes,
+    // the contract's selection of the bigmaps to index, and the bigmaps in
+    // the storage that are not indexed because of it
+    bigmap_filter: BigmapFilter,
+    excluded_bigmaps: HashSet<i32>,
     bigmap_keyhashes: db::BigmapEntries,
     bigmap_meta_actions: Vec<BigmapMetaAction>,
+    operation_failures: Vec<OperationFailure>,
+    raw_storage: Vec<RawStorage>,
     bigmap_contract_deps: HashMap<(String, i32, bool), ()>,
     id_generator: IdGenerator,
     inserts: Inserts,
+    // inserts that were moved to disk, see set_bigmap_copy_chunk_size
+    spilled_inserts: Option<SpilledInserts>,
     tx_contexts: TxContextMap,
     node_cli: NodeCli,
     bigmap_keys: BigmapKeys,
 
+    record_all_calls: bool,
+    index_failures: bool,
+    keep_raw_storage: bool,
+    empty_string_as_null: bool,
+    bigmap_copy_chunk_size: Option<usize>,
+    address_cache: Option<SharedAddressCache>,
     stats: Option<StatsLogger>,
 }
 
@@ -115,15 +223,28 @@ where
     ) -> Self {
         Self {
             bigmap_map: BigMapMap::new(),
+            bigmap_asts: HashMap::new(),
+            column_types: ColumnTypes::default(),
+            bigmap_filter: BigmapFilter::default(),
+            excluded_bigmaps: HashSet::new(),
             inserts: Inserts::new(),
+            spilled_inserts: None,
             tx_contexts: HashMap::new(),
             bigmap_keyhashes: HashMap::new(),
             bigmap_meta_actions: vec![],
+            operation_failures: vec![],
+            raw_storage: vec![],
             bigmap_contract_deps: HashMap::new(),
             id_generator: IdGenerator::new(initial_id),
             node_cli,
             bigmap_keys,
 
+            record_all_calls: false,
+            index_failures: false,
+            keep_raw_storage: false,
+            empty_string_as_null: false,
+            bigmap_copy_chunk_size: None,
+            address_cache: None,
             stats: None,
         }
     }
@@ -132,6 +253,58 @@ where
         self.stats = Some(l);
     }
 
+    // When set, every call to the contract is recorded (in tx_contexts and
+    // txs), including calls that did not get applied or did not update the
+    // storage.
+    pub(crate) fn set_record_all_calls(&mut self, record_all_calls: bool) {
+        self.record_all_calls = record_all_calls;
+    }
+
+    // When set, every call to the contract that did not get applied is
+    // recorded (in tx_contexts and txs), together with the errors it failed
+    // with.
+    pub(crate) fn set_index_failures(&mut self, index_failures: bool) {
+        self.index_failures = index_failures;
+    }
+
+    // When set, the storage after every call (and at origination) is kept
+    // as the node returned it, see drain_raw_storage.
+    pub(crate) fn set_keep_raw_storage(&mut self, keep_raw_storage: bool) {
+        self.keep_raw_storage = keep_raw_storage;
+    }
+
+    // When set, ids are derived from the tx contexts and tables they are
+    // handed out for rather than counted, see IdGenerator::row_id.
+    pub(crate) fn set_deterministic_ids(&mut self, deterministic_ids: bool) {
+        self.id_generator
+            .set_deterministic(deterministic_ids);
+    }
+
+    // When set, empty strings are stored as NULL (rather than as empty
+    // strings), so they can't be told apart from absent values.
+    pub(crate) fn set_empty_string_as_null(
+        &mut self,
+        empty_string_as_null: bool,
+    ) {
+        self.empty_string_as_null = empty_string_as_null;
+    }
+
+    // When set, the rows of a bigmap copy are moved to disk every this many
+    // copied keys (see drain_spilled_inserts), rather than kept in memory
+    // until the block is done.
+    pub(crate) fn set_bigmap_copy_chunk_size(
+        &mut self,
+        chunk_size: Option<usize>,
+    ) {
+        self.bigmap_copy_chunk_size = chunk_size.filter(|n| *n > 0);
+    }
+
+    // Addresses that are stored as bytes are decoded through the cache, when
+    // set.
+    pub(crate) fn set_address_cache(&mut self, cache: SharedAddressCache) {
+        self.address_cache = Some(cache);
+    }
+
     fn add_bigmap_keyhash(
         &mut self,
         tx_context: TxContext,
@@ -157,45 +330,116 @@ where
         self.bigmap_map.clear();
         self.bigmap_keyhashes.clear();
         self.bigmap_meta_actions.clear();
+        self.bigmap_asts.clear();
+        self.excluded_bigmaps.clear();
+        self.bigmap_filter = contract.bigmap_filter.clone();
+        collect_bigmap_asts(&contract.storage_ast, &mut self.bigmap_asts);
+        let bigmap_filter = &self.bigmap_filter;
+        self.bigmap_asts
+            .retain(|table, _| bigmap_filter.is_indexed(table));
+        self.column_types = contract.column_types.clone();
 
-        let storages: Vec<(TxContext, Option<(String, parser::Value)>, parser::Value)> =
-            block.map_tx_contexts(|tx_context, tx, is_origination, op_res| {
-                if tx_context.contract != contract.cid.address {
-                    return Ok(None);
-                }
+        let record_all_calls = self.record_all_calls;
+        let index_failures = self.index_failures;
+        let keep_raw_storage = self.keep_raw_storage;
+        let map_fn = |tx_context: TxContext,
+                      tx: Tx,
+                      is_origination: bool,
+                      op_res: &block::OperationResult| {
+            if tx_context.contract != contract.cid.address {
+                return Ok(None);
+            }
 
-                let param_parsed: Option<(String, parser::Value)> = if let Some(entrypoint) = &tx.entrypoint {
-                    if let Some(v) = &tx.entrypoint_args {
-                        Some((entrypoint.clone(), parser::parse_lexed(v)?))
-                    } else {
-                        warn!("should not have None args to non None entrypoint?");
-                        None
-                    }
+            if index_failures && op_res.status != "applied" {
+                let tx_context = self.tx_context(tx_context, tx);
+                self.operation_failures
+                    .extend(operation_failures(tx_context.id.unwrap(), op_res));
+                return Ok(None);
+            }
+
+            if record_all_calls
+                && (op_res.status != "applied"
+                    || (!is_origination && op_res.storage.is_none()))
+            {
+                // nothing to process, only keeping the call's metadata
+                self.tx_context(tx_context, tx);
+                return Ok(None);
+            }
+
+            let param_parsed: Option<(String, parser::Value)> = if let Some(
+                entrypoint,
+            ) =
+                &tx.entrypoint
+            {
+                if let Some(v) = &tx.entrypoint_args {
+                    let v = parser::parse_lexed(v).with_context(|| {
+                            format!(
+                                "process_block: failed to parse parameters of entrypoint {} (tx_context={:?})",
+                                entrypoint, tx_context
+                            )
+                        })?;
+                    Some((entrypoint.clone(), v))
                 } else {
+                    warn!("should not have None args to non None entrypoint?");
                     None
-                };
+                }
+            } else {
+                None
+            };
 
-                if is_origination {
-                    let storage = parser::parse_json(
-                        &self.node_cli.get_contract_storage(
-                            &contract.cid.address,
-                            tx_context.level,
-                        )?,
-                    )?;
-                    Ok(Some((self.tx_context(tx_context, tx), param_parsed, storage)))
-                } else if let Some(storage) = &op_res.storage {
-                    Ok(Some((
-                        self.tx_context(tx_context, tx),
-                        param_parsed,
-                        parser::parse_lexed(storage)?,
-                    )))
-                } else {
-                    Err(anyhow!(
-                        "bad contract call: no storage update. tx_context={:#?}",
-                        tx_context
-                    ))
+            if is_origination {
+                let raw = self
+                    .node_cli
+                    .get_contract_storage(&contract.cid.address, tx_context.level)
+                    .map_err(|e| {
+                        if !ContextPrunedError::caused(&e) {
+                            return e;
+                        }
+                        e.context(format!(
+                            "process_block: the node no longer has the context of level {} to get the originated storage of contract {} from. index the contract with an archive node, or give it a start_level above this level",
+                            tx_context.level, contract.cid.name
+                        ))
+                    })?;
+                let storage = parser::parse_json(&raw).with_context(|| {
+                        format!(
+                            "process_block: failed to parse originated storage (tx_context={:?})",
+                            tx_context
+                        )
+                    })?;
+                let tx_context = self.tx_context(tx_context, tx);
+                if keep_raw_storage {
+                    self.add_raw_storage(&tx_context, raw);
+                }
+                Ok(Some((tx_context, param_parsed, storage)))
+            } else if let Some(raw) = &op_res.storage {
+                let storage =
+                    parser::parse_lexed(raw).with_context(|| {
+                        format!(
+                            "process_block: failed to parse storage (tx_context={:?})",
+                            tx_context
+                        )
+                    })?;
+                let tx_context = self.tx_context(tx_context, tx);
+                if keep_raw_storage {
+                    self.add_raw_storage(&tx_context, raw.clone());
                 }
-            })?;
+                Ok(Some((tx_context, param_parsed, storage)))
+            } else {
+                Err(anyhow!(
+                    "bad contract call: no storage update. tx_context={:#?}",
+                    tx_context
+                ))
+            }
+        };
+        let storages: Vec<(
+            TxContext,
+            Option<(String, parser::Value)>,
+            parser::Value,
+        )> = if record_all_calls || index_failures {
+            block.map_all_tx_contexts(map_fn)?
+        } else {
+            block.map_tx_contexts(map_fn)?
+        };
 
         for (tx_context, param_parsed, parsed_storage) in &storages {
             if let Some((entrypoint, param_v)) = param_parsed {
@@ -232,7 +476,7 @@ where
                 }
             }
 
-            self.process_michelson_value(parsed_storage, &contract.storage_ast, tx_context, "storage")
+            self.process_michelson_value(parsed_storage, &contract.storage_ast, tx_context, &contract.root_table)
                 .with_context(|| {
                     format!(
                         "process_block: process storage value failed (tx_context={:?})",
@@ -260,6 +504,18 @@ where
                 }
                 if self.bigmap_map.contains_key(&bigmap) {
                     for (src_bigmap, src_context) in deps {
+                        if self
+                            .excluded_bigmaps
+                            .contains(&src_bigmap)
+                        {
+                            // its keys were never stored, so there is
+                            // nothing to copy from
+                            warn!(
+                                "bigmap {} is copied from bigmap {}, which is not indexed (see the contract's bigmaps setting): its keys are not copied (tx_context={:?})",
+                                bigmap, src_bigmap, tx_context
+                            );
+                            continue;
+                        }
                         let is_deep_copy = bigmap >= 0;
                         self.bigmap_contract_deps.insert(
                             (
@@ -278,7 +534,6 @@ where
                 }
             }
         }
-
         Ok(())
     }
 
@@ -299,6 +554,28 @@ where
             .collect()
     }
 
+    pub(crate) fn drain_operation_failures(&mut self) -> Vec<OperationFailure> {
+        self.operation_failures
+            .drain(..)
+            .collect()
+    }
+
+    pub(crate) fn drain_raw_storage(&mut self) -> Vec<RawStorage> {
+        self.raw_storage.drain(..).collect()
+    }
+
+    fn add_raw_storage(
+        &mut self,
+        tx_context: &TxContext,
+        storage: serde_json::Value,
+    ) {
+        self.raw_storage.push(RawStorage {
+            tx_context_id: tx_context.id.unwrap(),
+            contract: tx_context.contract.clone(),
+            storage,
+        });
+    }
+
     pub(crate) fn drain_txs(&mut self) -> (Vec<TxContext>, Vec<Tx>) {
         self.tx_contexts.drain().unzip()
     }
@@ -307,11 +584,65 @@ where
         self.inserts.drain().collect()
     }
 
+    pub(crate) fn drain_spilled_inserts(&mut self) -> Option<SpilledInserts> {
+        self.spilled_inserts.take()
+    }
+
+    // Moves the inserts to disk, ordered by table and id so that processing
+    // the same block again spills the same file.
+    fn spill_inserts(&mut self, inserts: Inserts) -> Result<()> {
+        if inserts.is_empty() {
+            return Ok(());
+        }
+        let mut inserts: Vec<Insert> = inserts.into_values().collect();
+        inserts
+            .sort_by(|a, b| (&a.table_name, a.id).cmp(&(&b.table_name, b.id)));
+        if self.spilled_inserts.is_none() {
+            self.spilled_inserts = Some(SpilledInserts::new()?);
+        }
+        self.spilled_inserts
+            .as_mut()
+            .unwrap()
+            .write(&inserts)?;
+        if let Some(stats) = &self.stats {
+            stats.add(
+                "processor",
+                "bigmap copy rows spilled to disk",
+                inserts.len(),
+            )?;
+        }
+        Ok(())
+    }
+
+    // With a chunk size, the rows of the copy are collected apart from those
+    // of the rest of the block, and are spilled to disk once per chunk of
+    // keys.
     fn process_bigmap_copy(
         &mut self,
         ctx: &TxContext,
         src_bigmap: i32,
         dest_bigmap: i32,
+    ) -> Result<()> {
+        let chunk_size = self.bigmap_copy_chunk_size;
+        let block_inserts =
+            chunk_size.map(|_| std::mem::take(&mut self.inserts));
+        let res =
+            self.copy_bigmap_keys(ctx, src_bigmap, dest_bigmap, chunk_size);
+        if let Some(block_inserts) = block_inserts {
+            let copied = std::mem::replace(&mut self.inserts, block_inserts);
+            res?;
+            self.spill_inserts(copied)?;
+            return Ok(());
+        }
+        res
+    }
+
+    fn copy_bigmap_keys(
+        &mut self,
+        ctx: &TxContext,
+        src_bigmap: i32,
+        dest_bigmap: i32,
+        chunk_size: Option<usize>,
     ) -> Result<()> {
         let at_level = ctx.level - 1;
         let entries = self
@@ -321,6 +652,12 @@ where
         let num_entries = entries.len();
 
         for (i, (keyhash, key, value)) in entries.into_iter().enumerate() {
+            if let Some(chunk_size) = chunk_size {
+                if i > 0 && i % chunk_size == 0 {
+                    let copied = std::mem::take(&mut self.inserts);
+                    self.spill_inserts(copied)?;
+                }
+            }
             //let value = self
             //    .node_cli
             //    .get_bigmap_value(at_level, src_bigmap, &keyhash)?;
@@ -373,7 +710,9 @@ where
         {
             result.clone()
         } else {
-            let id = self.id_generator.get_id();
+            let id = self
+                .id_generator
+                .tx_context_id(&tx_context);
             tx_context.id = Some(id);
             tx.tx_context_id = id;
             self.tx_contexts
@@ -464,7 +803,13 @@ where
             }
             _ => must_match_rel!(rel_ast, RelationalAST::Leaf { rel_entry }, {
                 let mut res = parent_entry.clone();
-                res.value = Some(rel_entry.column_name.clone());
+                // flattened variants carry their variant name as value
+                res.value = Some(
+                    rel_entry
+                        .value
+                        .clone()
+                        .unwrap_or_else(|| rel_entry.column_name.clone()),
+                );
                 Ok(res)
             }),
         }
@@ -475,6 +820,16 @@ where
         op: &bigmap::Op,
         tx_context: &TxContext,
     ) -> Result<()> {
+        if self
+            .excluded_bigmaps
+            .contains(&op.get_bigmap_id())
+        {
+            return Ok(());
+        }
+        let operation_hash = self
+            .tx_contexts
+            .get(tx_context)
+            .map(|tx| tx.operation_hash.clone());
         match op {
             bigmap::Op::Update {
                 bigmap,
@@ -499,59 +854,8 @@ where
                         // ))
                     }
                 };
-                must_match_rel!(
-                    rel_ast,
-                    RelationalAST::BigMap {
-                        table,
-                        key_ast,
-                        value_ast,
-                        ..
-                    },
-                    {
-                        self.add_bigmap_keyhash(
-                            tx_context.clone(),
-                            *bigmap,
-                            keyhash.clone(),
-                            key.clone(),
-                            value.clone(),
-                        );
-
-                        let ctx = &ProcessStorageContext::new(
-                            self.id_generator.get_id(),
-                            table.clone(),
-                        );
-                        self.process_michelson_value_internal(
-                            ctx,
-                            &parser::parse_lexed(key)?,
-                            &key_ast,
-                            tx_context,
-                        )?;
-                        match &value {
-                            None => self.sql_add_cell(
-                                ctx,
-                                &table,
-                                "deleted",
-                                insert::Value::Bool(true),
-                                tx_context,
-                            ),
-                            Some(val) => {
-                                self.process_michelson_value_internal(
-                                    ctx,
-                                    &parser::parse_lexed(val)?,
-                                    &value_ast,
-                                    tx_context,
-                                )?;
-                            }
-                        };
-                        self.sql_add_cell(
-                            ctx,
-                            &table,
-                            "bigmap_id",
-                            insert::Value::Int(*bigmap),
-                            tx_context,
-                        );
-                        Ok(())
-                    }
+                self.process_bigmap_update(
+                    &rel_ast, *bigmap, keyhash, key, value, tx_context,
                 )
             }
             bigmap::Op::Alloc { bigmap } => {
@@ -569,6 +873,7 @@ where
                         .push(BigmapMetaAction {
                             tx_context_id: tx_context.id.unwrap(),
                             bigmap_id: *bigmap,
+                            operation_hash,
 
                             action: "alloc".to_string(),
                             value: Some(json!({
@@ -594,7 +899,8 @@ where
                     RelationalAST::BigMap { table, .. },
                     {
                         let ctx = &ProcessStorageContext::new(
-                            self.id_generator.get_id(),
+                            self.id_generator
+                                .row_id(tx_context.id.unwrap(), &table),
                             table.clone(),
                         );
                         self.sql_add_cell(
@@ -611,6 +917,7 @@ where
                     .push(BigmapMetaAction {
                         tx_context_id: tx_context.id.unwrap(),
                         bigmap_id: *bigmap,
+                        operation_hash,
 
                         action: "copy".to_string(),
                         value: Some(json!({ "source": source })),
@@ -622,13 +929,155 @@ where
                     .push(BigmapMetaAction {
                         tx_context_id: tx_context.id.unwrap(),
                         bigmap_id: *bigmap,
+                        operation_hash,
 
                         action: "clear".to_string(),
                         value: None,
                     });
+                self.process_bigmap_clear(tx_context, *bigmap)
+            }
+        }
+    }
+
+    fn process_bigmap_update(
+        &mut self,
+        rel_ast: &RelationalAST,
+        bigmap: i32,
+        keyhash: &str,
+        key: &serde_json::Value,
+        value: &Option<serde_json::Value>,
+        tx_context: &TxContext,
+    ) -> Result<()> {
+        must_match_rel!(
+            rel_ast,
+            RelationalAST::BigMap {
+                table,
+                key_ast,
+                value_ast,
+                ..
+            },
+            {
+                self.add_bigmap_keyhash(
+                    tx_context.clone(),
+                    bigmap,
+                    keyhash.to_string(),
+                    key.clone(),
+                    value.clone(),
+                );
+
+                let ctx = &ProcessStorageContext::new(
+                    self.id_generator
+                        .row_id(tx_context.id.unwrap(), table),
+                    table.clone(),
+                );
+                self.process_michelson_value_internal(
+                    ctx,
+                    &parser::parse_lexed(key)?,
+                    key_ast,
+                    tx_context,
+                )?;
+                match value {
+                    None => self.sql_add_cell(
+                        ctx,
+                        table,
+                        "deleted",
+                        insert::Value::Bool(true),
+                        tx_context,
+                    ),
+                    Some(val) => {
+                        self.process_michelson_value_internal(
+                            ctx,
+                            &parser::parse_lexed(val)?,
+                            value_ast,
+                            tx_context,
+                        )?;
+                    }
+                };
+                self.sql_add_cell(
+                    ctx,
+                    table,
+                    "bigmap_id",
+                    insert::Value::Int(bigmap),
+                    tx_context,
+                );
                 Ok(())
             }
+        )
+    }
+
+    // The bigmap's definition, also for bigmaps that are no longer part of
+    // the storage (eg because it was removed).
+    fn get_bigmap_ast(&mut self, bigmap: i32) -> Result<Option<RelationalAST>> {
+        if let Some((_fk, rel_ast)) = self.bigmap_map.get(&bigmap) {
+            return Ok(Some(rel_ast.clone()));
+        }
+        let allocated_in_block = self
+            .bigmap_meta_actions
+            .iter()
+            .find(|action| {
+                action.bigmap_id == bigmap && action.action == "alloc"
+            })
+            .and_then(|action| action.value.as_ref())
+            .and_then(|value| value["table"].as_str())
+            .map(String::from);
+        let table = match allocated_in_block {
+            Some(table) => Some(table),
+            None => self.bigmap_keys.get_table(bigmap)?,
+        };
+        Ok(table.and_then(|table| self.bigmap_asts.get(&table).cloned()))
+    }
+
+    // Expands a clear into a delete of every key the bigmap had.
+    fn process_bigmap_clear(
+        &mut self,
+        tx_context: &TxContext,
+        bigmap: i32,
+    ) -> Result<()> {
+        if bigmap < 0 {
+            return Ok(());
+        }
+        let rel_ast = match self.get_bigmap_ast(bigmap)? {
+            Some(
+                rel_ast @ RelationalAST::BigMap {
+                    has_memory: true, ..
+                },
+            ) => rel_ast,
+            _ => return Ok(()),
+        };
+
+        // keys known up to the previous level, and keys set earlier in this
+        // block (these are not in the db yet)
+        let mut keys: HashMap<String, serde_json::Value> = self
+            .bigmap_keys
+            .get(tx_context.level.saturating_sub(1), bigmap)?
+            .into_iter()
+            .map(|(keyhash, key, _)| (keyhash, key))
+            .collect();
+        for ((bigmap_id, _, keyhash), (key, _)) in &self.bigmap_keyhashes {
+            if *bigmap_id == bigmap {
+                keys.insert(keyhash.clone(), key.clone());
+            }
+        }
+
+        let mut keys: Vec<(String, serde_json::Value)> =
+            keys.into_iter().collect();
+        keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (keyhash, key) in keys {
+            // keys that were (re)set by this same tx_context are left as is,
+            // keys set again by later tx_contexts of this block come after
+            // the delete in the ordering
+            if self.bigmap_keyhashes.contains_key(&(
+                bigmap,
+                tx_context.clone(),
+                keyhash.clone(),
+            )) {
+                continue;
+            }
+            self.process_bigmap_update(
+                &rel_ast, bigmap, &keyhash, &key, &None, tx_context,
+            )?;
         }
+        Ok(())
     }
 
     /// Walks simultaneously through the table definition and the actual values it finds, and attempts
@@ -641,7 +1090,8 @@ where
         root_table_name: &str,
     ) -> Result<()> {
         let ctx = &ProcessStorageContext::new(
-            self.id_generator.get_id(),
+            self.id_generator
+                .row_id(tx_context.id.unwrap(), root_table_name),
             root_table_name.to_string(),
         );
         self.process_michelson_value_internal(ctx, value, rel_ast, tx_context)?;
@@ -658,15 +1108,50 @@ where
             if ctx.last_table != table_name {
                 self.sql_touch_insert(ctx, &ctx.last_table, tx_context);
 
+                let id = self
+                    .id_generator
+                    .row_id(tx_context.id.unwrap(), &table_name);
                 return ctx
                     .with_last_table(table_name)
-                    .with_fk_id(ctx.id)
-                    .with_id(self.id_generator.get_id());
+                    .with_fk_id(&ctx.last_table, ctx.id)
+                    .with_id(id);
             }
         }
         ctx.clone()
     }
 
+    // The rows of an unpacked value are collected apart from the others, and
+    // are only added to them when the whole value matched the type the
+    // settings said it would have.
+    fn process_unpacked_value(
+        &mut self,
+        ctx: &ProcessStorageContext,
+        unpacked: &parser::Value,
+        packed_ast: &RelationalAST,
+        tx_context: &TxContext,
+    ) -> Result<()> {
+        let inserts = std::mem::take(&mut self.inserts);
+        let res = self.process_michelson_value_internal(
+            ctx, unpacked, packed_ast, tx_context,
+        );
+        let unpacked_inserts = std::mem::replace(&mut self.inserts, inserts);
+        res?;
+        for (key, insert) in unpacked_inserts {
+            match self.inserts.get_mut(&key) {
+                Some(existing) => existing.columns.extend(
+                    insert
+                        .columns
+                        .into_iter()
+                        .filter(|c| c.name != "tx_context_id"),
+                ),
+                None => {
+                    self.inserts.insert(key, insert);
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn process_michelson_value_internal(
         &mut self,
         ctx: &ProcessStorageContext,
@@ -681,6 +1166,7 @@ where
             debug::pp_depth(3, v),
             debug::pp_depth(4, rel_ast)
         );
+        trace!("value (decoded): {}", debug::pp_human(3, value, rel_ast));
         match rel_ast {
             RelationalAST::Leaf { rel_entry } => {
                 if let ExprTy::Stop = rel_entry.column_type {
@@ -699,12 +1185,15 @@ where
                         ctx,
                         &rel_entry.table_name,
                         &rel_entry.column_name,
-                        insert::Value::String(value),
+                        string_value(value, self.empty_string_as_null),
                         tx_context,
                     );
                 }
             }
             RelationalAST::Option { elem_ast } => {
+                // Some(x) is parsed as x, so for nested options Some(None)
+                // arrives here as None as well: either way the elements'
+                // columns are left NULL.
                 if *v != parser::Value::None {
                     self.process_michelson_value_internal(
                         ctx, v, elem_ast, tx_context,
@@ -714,6 +1203,46 @@ where
                 }
                 return Ok(());
             }
+            RelationalAST::Packed {
+                rel_entry,
+                packed_ast,
+            } => {
+                let hex = match value {
+                    parser::Value::Bytes(hex) => hex,
+                    _ => {
+                        return Err(anyhow!(
+                            "failed to match {:#?} with {:#?}",
+                            value,
+                            rel_ast
+                        ))
+                    }
+                };
+                match packed::unpack(hex)
+                    .and_then(|json| parser::parse_json(&json))
+                    .and_then(|unpacked| {
+                        self.process_unpacked_value(
+                            ctx, &unpacked, packed_ast, tx_context,
+                        )
+                    }) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => {
+                        // not what the settings said it would be, keep the
+                        // bytes as they are
+                        warn!(
+                            "failed to unpack column {} (table {}), storing the raw bytes: {:?}",
+                            rel_entry.column_name, rel_entry.table_name, e
+                        );
+                        self.sql_add_cell(
+                            ctx,
+                            &rel_entry.table_name,
+                            &rel_entry.column_name,
+                            insert::Value::String(hex.clone()),
+                            tx_context,
+                        );
+                        return Ok(());
+                    }
+                }
+            }
             _ => {}
         };
 
@@ -804,14 +1333,32 @@ where
             }
             parser::Value::List(l) => must_match_rel!(
                 rel_ast,
-                RelationalAST::List { elems_ast, .. },
+                RelationalAST::List {
+                    table,
+                    elems_ast,
+                    elems_unique
+                },
                 {
                     let mut ctx: ProcessStorageContext = ctx.clone();
-                    for element in l {
+                    for (ordinal, element) in l.iter().enumerate() {
+                        if !elems_unique {
+                            self.sql_add_cell(
+                                &ctx,
+                                table,
+                                "ordinal",
+                                insert::Value::Numeric(PgNumeric::new(Some(
+                                    BigDecimal::from(ordinal as u64),
+                                ))),
+                                tx_context,
+                            );
+                        }
                         self.process_michelson_value_internal(
                             &ctx, element, elems_ast, tx_context,
                         )?;
-                        ctx = ctx.with_id(self.id_generator.get_id());
+                        let id = self
+                            .id_generator
+                            .row_id(tx_context.id.unwrap(), &ctx.last_table);
+                        ctx = ctx.with_id(id);
                     }
                     Ok(())
                 }
@@ -822,7 +1369,10 @@ where
                     self.process_michelson_value_internal(
                         &ctx, element, rel_ast, tx_context,
                     )?;
-                    ctx = ctx.with_id(self.id_generator.get_id());
+                    let id = self
+                        .id_generator
+                        .row_id(tx_context.id.unwrap(), &ctx.last_table);
+                    ctx = ctx.with_id(id);
                 }
                 Ok(())
             }))
@@ -835,7 +1385,10 @@ where
                         self.process_michelson_value_internal(
                             &ctx, element, rel_ast, tx_context,
                         )?;
-                        ctx = ctx.with_id(self.id_generator.get_id());
+                        let id = self
+                            .id_generator
+                            .row_id(tx_context.id.unwrap(), &ctx.last_table);
+                        ctx = ctx.with_id(id);
                     }
                     Ok(())
                 }
@@ -880,7 +1433,10 @@ where
                         &rel_entry.table_name,
                         &rel_entry.column_name,
                         match &rel_entry.value {
-                            Some(s) => insert::Value::String(s.clone()),
+                            Some(s) => string_value(
+                                s.clone(),
+                                self.empty_string_as_null,
+                            ),
                             None => insert::Value::Null,
                         },
                         tx_context,
@@ -914,7 +1470,22 @@ where
                         let v = Self::storage2sql_value(
                             &rel_entry.column_type,
                             value,
-                        )?;
+                            &SqlValueOptions {
+                                address_cache: self.address_cache.as_ref(),
+                                numeric_type: self.column_types.numeric_type(
+                                    &rel_entry.table_name,
+                                    &rel_entry.column_name,
+                                    &rel_entry.column_type,
+                                ),
+                                empty_string_as_null: self.empty_string_as_null,
+                            },
+                        )
+                        .with_context(|| {
+                            format!(
+                                "failed to process column {} (table {})",
+                                rel_entry.column_name, rel_entry.table_name
+                            )
+                        })?;
                         self.sql_add_cell(
                             ctx,
                             &rel_entry.table_name,
@@ -938,10 +1509,20 @@ where
     fn storage2sql_value(
         t: &ExprTy,
         v: &parser::Value,
+        options: &SqlValueOptions,
     ) -> Result<insert::Value> {
         debug!("t: {:#?}, v: {:#?}", t, v);
         match t {
-            ExprTy::Bytes | ExprTy::KeyHash | ExprTy::String => match v {
+            ExprTy::String => match v {
+                parser::Value::String(s) => {
+                    Ok(string_value(s.clone(), options.empty_string_as_null))
+                }
+                parser::Value::Bytes(s) => Ok(insert::Value::String(s.clone())),
+                _ => Err(anyhow!(
+                    "storage2sql_value: failed to match type with value"
+                )),
+            },
+            ExprTy::Bytes | ExprTy::KeyHash => match v {
                 parser::Value::Bytes(s) | parser::Value::String(s) => {
                     Ok(insert::Value::String(s.clone()))
                 }
@@ -955,7 +1536,10 @@ where
                     parser::Value::Bytes(bs) =>
                     // sometimes we get bytes where we expected an address.
                     {
-                        Ok(insert::Value::String(parser::decode_address(bs)?))
+                        Ok(insert::Value::String(decode_address(
+                            bs,
+                            options.address_cache,
+                        )?))
                     }
                     parser::Value::Address(addr)
                     | parser::Value::String(addr) => {
@@ -975,6 +1559,8 @@ where
                     ))
                 }
             }
+            // never has no values, so its column always stays NULL
+            ExprTy::Never => Ok(insert::Value::Null),
             ExprTy::Unit => match v {
                 parser::Value::Unit => Ok(insert::Value::Null),
                 _ => Err(anyhow!(
@@ -984,9 +1570,60 @@ where
             ExprTy::Int | ExprTy::Nat | ExprTy::Mutez => match v {
                 parser::Value::Int(i)
                 | parser::Value::Mutez(i)
-                | parser::Value::Nat(i) => Ok(insert::Value::Numeric(
-                    PgNumeric::new(Some(BigDecimal::new(i.clone(), 0))),
+                | parser::Value::Nat(i) => match options.numeric_type {
+                    NumericType::Bigint => {
+                        i.to_i64().map(insert::Value::BigInt).ok_or_else(
+                            || anyhow!(
+                                "storage2sql_value: value {} exceeds the range of the column's BIGINT type (override the column's type to numeric)",
+                                i
+                            ),
+                        )
+                    }
+                    NumericType::BoundedNumeric { precision, scale } => {
+                        let digits = i.magnitude().to_string().len() as u32;
+                        if digits + scale > precision {
+                            return Err(anyhow!(
+                                "storage2sql_value: value {} exceeds the precision of the column's NUMERIC({},{}) type (override the column's type to numeric)",
+                                i, precision, scale
+                            ));
+                        }
+                        Ok(insert::Value::Numeric(PgNumeric::new(Some(
+                            BigDecimal::new(i.clone(), 0),
+                        ))))
+                    }
+                    NumericType::Numeric => Ok(insert::Value::Numeric(
+                        PgNumeric::new(Some(BigDecimal::new(i.clone(), 0))),
+                    )),
+                },
+                _ => Err(anyhow!(
+                    "storage2sql_value: failed to match type with value"
                 )),
+            },
+            ExprTy::Opaque(prim) => match v {
+                parser::Value::Bytes(s) | parser::Value::String(s) => {
+                    if let Some(size) = typing::opaque_size(prim) {
+                        if s.len() != size * 2 {
+                            return Err(anyhow!(
+                                "storage2sql_value: malformed {} value (expected {} bytes, got {} hex characters): {}",
+                                prim, size, s.len(), s
+                            ));
+                        }
+                    }
+                    Ok(insert::Value::String(s.clone()))
+                }
+                parser::Value::Int(_) | parser::Value::Nat(_)
+                    if typing::opaque_size(prim).is_some() =>
+                {
+                    Err(anyhow!(
+                        "storage2sql_value: expected bytes for {} value, got {:?}",
+                        prim,
+                        v
+                    ))
+                }
+                // eg a sapling_state in storage is referred to by its id
+                parser::Value::Int(i) | parser::Value::Nat(i) => {
+                    Ok(insert::Value::String(i.to_string()))
+                }
                 _ => Err(anyhow!(
                     "storage2sql_value: failed to match type with value"
                 )),
@@ -1003,6 +1640,12 @@ where
         fk: i64,
         rel_ast: RelationalAST,
     ) {
+        if let RelationalAST::BigMap { table, .. } = &rel_ast {
+            if !self.bigmap_filter.is_indexed(table) {
+                self.excluded_bigmaps.insert(bigmap_id);
+                return;
+            }
+        }
         self.bigmap_map
             .insert(bigmap_id, (fk, rel_ast));
     }
@@ -1020,6 +1663,7 @@ where
                     table_name: table_name.to_string(),
                     id: ctx.id,
                     fk_id: ctx.fk_id,
+                    parent_table: ctx.parent_table.clone(),
                     columns: vec![Column {
                         name: "tx_context_id".to_string(),
                         value: insert::Value::BigInt(tx_context.id.unwrap()),
@@ -1060,6 +1704,7 @@ where
                 table_name: table_name.to_string(),
                 id: ctx.id,
                 fk_id: ctx.fk_id,
+                parent_table: ctx.parent_table.clone(),
                 columns: insert.columns,
             },
         );
@@ -1093,9 +1738,122 @@ where
     }
 }
 
-#[test]
-fn test_process_michelson_value() {
-    use num::BigInt;
+// How storage2sql_value turns storage values into column values.
+struct SqlValueOptions<'a> {
+    // addresses stored as bytes are decoded through the cache, when given
+    address_cache: Option<&'a SharedAddressCache>,
+    // the sql type of the column, when it holds an int, nat or mutez
+    numeric_type: NumericType,
+    // whether empty strings are stored as NULL (see set_empty_string_as_null)
+    empty_string_as_null: bool,
+}
+
+impl Default for SqlValueOptions<'_> {
+    fn default() -> Self {
+        Self {
+            address_cache: None,
+            numeric_type: NumericType::Numeric,
+            empty_string_as_null: false,
+        }
+    }
+}
+
+// Stores a string as is, or as NULL when it is empty and empty strings are
+// to be stored as NULL.
+fn string_value(s: String, empty_string_as_null: bool) -> insert::Value {
+    if empty_string_as_null && s.is_empty() {
+        insert::Value::Null
+    } else {
+        insert::Value::String(s)
+    }
+}
+
+// Decodes an address stored as bytes, through the cache when given.
+fn decode_address(
+    hex: &str,
+    address_cache: Option<&SharedAddressCache>,
+) -> Result<String> {
+    match address_cache {
+        Some(cache) => AddressCache::decode_shared(cache, hex),
+        None => parser::decode_address(hex),
+    }
+}
+
+// One failure per error the operation failed with, or a single failure
+// without error when it has none (eg when it was backtracked).
+fn operation_failures(
+    tx_context_id: i64,
+    op_res: &block::OperationResult,
+) -> Vec<OperationFailure> {
+    if op_res.errors.is_empty() {
+        return vec![OperationFailure {
+            tx_context_id,
+            status: op_res.status.clone(),
+            error_kind: None,
+            error_id: None,
+        }];
+    }
+    op_res
+        .errors
+        .iter()
+        .map(|err| OperationFailure {
+            tx_context_id,
+            status: op_res.status.clone(),
+            error_kind: Some(err.kind.clone()),
+            error_id: Some(err.id.clone()),
+        })
+        .collect()
+}
+
+fn collect_bigmap_asts(
+    rel_ast: &RelationalAST,
+    res: &mut HashMap<String, RelationalAST>,
+) {
+    match rel_ast {
+        RelationalAST::BigMap {
+            table,
+            key_ast,
+            value_ast,
+            ..
+        } => {
+            res.insert(table.clone(), rel_ast.clone());
+            collect_bigmap_asts(key_ast, res);
+            collect_bigmap_asts(value_ast, res);
+        }
+        RelationalAST::Map {
+            key_ast, value_ast, ..
+        } => {
+            collect_bigmap_asts(key_ast, res);
+            collect_bigmap_asts(value_ast, res);
+        }
+        RelationalAST::Pair {
+            left_ast,
+            right_ast,
+        }
+        | RelationalAST::OrEnumeration {
+            left_ast,
+            right_ast,
+            ..
+        } => {
+            collect_bigmap_asts(left_ast, res);
+            collect_bigmap_asts(right_ast, res);
+        }
+        RelationalAST::Option { elem_ast } => {
+            collect_bigmap_asts(elem_ast, res)
+        }
+        RelationalAST::List { elems_ast, .. } => {
+            collect_bigmap_asts(elems_ast, res)
+        }
+        RelationalAST::Packed { packed_ast, .. } => {
+            collect_bigmap_asts(packed_ast, res)
+        }
+        RelationalAST::Leaf { .. } => {}
+    }
+}
+
+#[test]
+fn test_process_michelson_value() {
+    use num::BigInt;
 
     fn numeric(i: i32) -> insert::Value {
         insert::Value::Numeric(PgNumeric::new(Some(BigDecimal::from(i))))
@@ -1134,6 +1892,7 @@ fn test_process_michelson_value() {
                 table_name: "storage".to_string(),
                 id: 1,
                 fk_id: None,
+                parent_table: None,
                 columns: vec![
                     Column {
                         name: "tx_context_id".to_string(),
@@ -1174,6 +1933,7 @@ fn test_process_michelson_value() {
                 table_name: "storage".to_string(),
                 id: 1,
                 fk_id: None,
+                parent_table: None,
                 columns: vec![Column {
                     name: "tx_context_id".to_string(),
                     value: insert::Value::BigInt(32),
@@ -1211,6 +1971,7 @@ fn test_process_michelson_value() {
                 table_name: "storage".to_string(),
                 id: 1,
                 fk_id: None,
+                parent_table: None,
                 columns: vec![Column {
                     name: "tx_context_id".to_string(),
                     value: insert::Value::BigInt(32),
@@ -1250,6 +2011,7 @@ fn test_process_michelson_value() {
                     table_name: "storage".to_string(),
                     id: 1,
                     fk_id: None,
+                    parent_table: None,
                     columns: vec![Column {
                         name: "tx_context_id".to_string(),
                         value: insert::Value::BigInt(32),
@@ -1259,6 +2021,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_set".to_string(),
                     id: 2,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1274,6 +2037,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_set".to_string(),
                     id: 3,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1323,6 +2087,7 @@ fn test_process_michelson_value() {
                     table_name: "storage".to_string(),
                     id: 1,
                     fk_id: None,
+                    parent_table: None,
                     columns: vec![Column {
                         name: "tx_context_id".to_string(),
                         value: insert::Value::BigInt(32),
@@ -1332,6 +2097,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_set".to_string(),
                     id: 2,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1347,6 +2113,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_set".to_string(),
                     id: 3,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1362,6 +2129,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_set".to_string(),
                     id: 4,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1422,6 +2190,7 @@ fn test_process_michelson_value() {
                     table_name: "storage".to_string(),
                     id: 1,
                     fk_id: None,
+                    parent_table: None,
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1437,6 +2206,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_set".to_string(),
                     id: 2,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1452,6 +2222,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_set".to_string(),
                     id: 3,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1495,6 +2266,7 @@ fn test_process_michelson_value() {
                 table_name: "storage".to_string(),
                 id: 1,
                 fk_id: None,
+                parent_table: None,
                 columns: vec![Column {
                     name: "tx_context_id".to_string(),
                     value: insert::Value::BigInt(32),
@@ -1540,6 +2312,7 @@ fn test_process_michelson_value() {
                 table_name: "storage".to_string(),
                 id: 1,
                 fk_id: None,
+                parent_table: None,
                 columns: vec![Column {
                     name: "tx_context_id".to_string(),
                     value: insert::Value::BigInt(32),
@@ -1596,6 +2369,7 @@ fn test_process_michelson_value() {
                     table_name: "storage".to_string(),
                     id: 1,
                     fk_id: None,
+                    parent_table: None,
                     columns: vec![Column {
                         name: "tx_context_id".to_string(),
                         value: insert::Value::BigInt(32),
@@ -1605,6 +2379,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_bigmap".to_string(),
                     id: 2,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1626,6 +2401,7 @@ fn test_process_michelson_value() {
                     table_name: "storage.the_bigmap".to_string(),
                     id: 3,
                     fk_id: Some(1),
+                    parent_table: Some("storage".to_string()),
                     columns: vec![
                         Column {
                             name: "tx_context_id".to_string(),
@@ -1692,6 +2468,488 @@ fn test_process_michelson_value() {
     }
 }
 
+#[test]
+fn test_process_michelson_value_nested_option() {
+    let rel_ast = RelationalAST::Option {
+        elem_ast: Box::new(RelationalAST::Option {
+            elem_ast: Box::new(RelationalAST::Leaf {
+                rel_entry: RelationalEntry {
+                    table_name: "storage".to_string(),
+                    column_name: "amount".to_string(),
+                    column_type: ExprTy::Nat,
+                    value: None,
+                    is_index: false,
+                },
+            }),
+        }),
+    };
+    let tx_context = TxContext {
+        id: Some(32),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+    let tx_context_column = Column {
+        name: "tx_context_id".to_string(),
+        value: insert::Value::BigInt(32),
+    };
+
+    let cases: Vec<(&str, serde_json::Value, Vec<Column>)> = vec![
+        (
+            "None",
+            json!({"prim": "None"}),
+            vec![tx_context_column.clone()],
+        ),
+        (
+            "Some(None)",
+            json!({"prim": "Some", "args": [{"prim": "None"}]}),
+            vec![tx_context_column.clone()],
+        ),
+        (
+            "Some(Some(5))",
+            json!({"prim": "Some", "args": [
+                {"prim": "Some", "args": [{"int": "5"}]}
+            ]}),
+            vec![
+                tx_context_column.clone(),
+                Column {
+                    name: "amount".to_string(),
+                    value: insert::Value::Numeric(PgNumeric::new(Some(
+                        BigDecimal::from(5),
+                    ))),
+                },
+            ],
+        ),
+    ];
+    for (name, value, exp_columns) in cases {
+        let mut processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        processor
+            .process_michelson_value_test(
+                &parser::parse_lexed(&value).unwrap(),
+                &rel_ast,
+                &tx_context,
+            )
+            .unwrap();
+
+        let got: Vec<Insert> = processor
+            .drain_inserts()
+            .into_values()
+            .collect();
+        assert_eq!(
+            vec![Insert {
+                table_name: "storage".to_string(),
+                id: 1,
+                fk_id: None,
+                parent_table: None,
+                columns: exp_columns,
+            }],
+            got,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_process_michelson_value_list_ordinals() {
+    use crate::storage_structure::relational::ASTBuilder;
+
+    let type_ast = typing::type_ast_from_json(&json!({
+        "prim": "list",
+        "args": [{"prim": "string"}],
+        "annots": ["%names"],
+    }))
+    .unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(32),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+    let value = parser::parse_lexed(&json!([
+        {"string": "b"},
+        {"string": "a"},
+        {"string": "b"},
+    ]))
+    .unwrap();
+
+    // (ordinal, value) of the list's rows, processed with ids starting at
+    // initial_id
+    let process = |initial_id: i64| -> Vec<(insert::Value, insert::Value)> {
+        let mut processor = StorageProcessor::new(
+            initial_id,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        processor
+            .process_michelson_value_test(&value, &rel_ast, &tx_context)
+            .unwrap();
+        let mut res: Vec<(insert::Value, insert::Value)> = processor
+            .drain_inserts()
+            .into_values()
+            .filter(|insert| insert.table_name == "storage.names")
+            .map(|insert| {
+                let get = |column: &str| {
+                    insert
+                        .get_column(column)
+                        .unwrap()
+                        .unwrap()
+                        .value
+                        .clone()
+                };
+                (get("ordinal"), get("string"))
+            })
+            .collect();
+        res.sort();
+        res
+    };
+
+    let ordinal = |i: u64| {
+        insert::Value::Numeric(PgNumeric::new(Some(BigDecimal::from(i))))
+    };
+    let string = |s: &str| insert::Value::String(s.to_string());
+    let exp = vec![
+        (ordinal(0), string("b")),
+        (ordinal(1), string("a")),
+        (ordinal(2), string("b")),
+    ];
+    assert_eq!(exp, process(1));
+    assert_eq!(exp, process(1000));
+}
+
+#[test]
+fn test_process_michelson_value_flattened_or() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing::Ele;
+
+    fn variant(name: &str, expr_type: ExprTy) -> Ele {
+        Ele {
+            name: Some(name.to_string()),
+            expr_type,
+        }
+    }
+    fn or(name: Option<&str>, l: Ele, r: Ele) -> Ele {
+        Ele {
+            name: name.map(|s| s.to_string()),
+            expr_type: ExprTy::OrEnumeration(Box::new(l), Box::new(r)),
+        }
+    }
+    let rel_ast = ASTBuilder::new("storage")
+        .flatten_ors()
+        .build_relational_ast(&or(
+            Some("action"),
+            or(
+                None,
+                variant("a", ExprTy::Nat),
+                variant("b", ExprTy::String),
+            ),
+            or(None, variant("c", ExprTy::Unit), variant("d", ExprTy::Int)),
+        ))
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(1),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+
+    let tests: Vec<(parser::Value, Vec<Column>)> = vec![
+        (
+            parser::Value::Left(Box::new(parser::Value::Right(Box::new(
+                parser::Value::String("x".to_string()),
+            )))),
+            vec![
+                Column {
+                    name: "tx_context_id".to_string(),
+                    value: insert::Value::BigInt(1),
+                },
+                Column {
+                    name: "action".to_string(),
+                    value: insert::Value::String("b".to_string()),
+                },
+                Column {
+                    name: "action_b".to_string(),
+                    value: insert::Value::String("x".to_string()),
+                },
+            ],
+        ),
+        (
+            parser::Value::Right(Box::new(parser::Value::Left(Box::new(
+                parser::Value::Unit,
+            )))),
+            vec![
+                Column {
+                    name: "tx_context_id".to_string(),
+                    value: insert::Value::BigInt(1),
+                },
+                Column {
+                    name: "action".to_string(),
+                    value: insert::Value::String("c".to_string()),
+                },
+            ],
+        ),
+    ];
+    for (value, exp_columns) in tests {
+        let mut processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        processor
+            .process_michelson_value_test(&value, &rel_ast, &tx_context)
+            .unwrap();
+        let got: Vec<Insert> = processor
+            .drain_inserts()
+            .into_values()
+            .collect();
+        assert_eq!(
+            vec![Insert {
+                table_name: "storage".to_string(),
+                id: 1,
+                fk_id: None,
+                parent_table: None,
+                columns: exp_columns,
+            }],
+            got
+        );
+    }
+}
+
+#[test]
+fn test_process_michelson_value_packed() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use num::BigInt;
+
+    let type_ast = typing::type_ast_from_json(&json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "bytes", "annots": ["%meta"] },
+            { "prim": "nat", "annots": ["%count"] },
+        ],
+    }))
+    .unwrap();
+    let packed_type = typing::type_ast_from_json(&json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "address", "annots": ["%owner"] },
+            { "prim": "nat", "annots": ["%amount"] },
+        ],
+    }))
+    .unwrap();
+    let mut packed_columns = HashMap::new();
+    packed_columns.insert(
+        "storage".to_string(),
+        vec![("meta".to_string(), packed_type)]
+            .into_iter()
+            .collect(),
+    );
+    let rel_ast = ASTBuilder::new("storage")
+        .with_packed_columns(packed_columns)
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(1),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+    let count = || Column {
+        name: "count".to_string(),
+        value: insert::Value::Numeric(PgNumeric::new(Some(BigDecimal::from(
+            7,
+        )))),
+    };
+
+    let tests: Vec<(&str, Vec<Column>)> = vec![
+        (
+            // Pair "tz1VSUr8wwNhLAzempoch5d6hLRiTh8Cjcjb" 42
+            "0507070a0000001600006b82198cb179e8306c1bedd08f12dc863f328886002a",
+            vec![
+                Column {
+                    name: "tx_context_id".to_string(),
+                    value: insert::Value::BigInt(1),
+                },
+                count(),
+                Column {
+                    name: "meta_amount".to_string(),
+                    value: insert::Value::Numeric(PgNumeric::new(Some(
+                        BigDecimal::from(42),
+                    ))),
+                },
+                Column {
+                    name: "meta_owner".to_string(),
+                    value: insert::Value::String(
+                        "tz1VSUr8wwNhLAzempoch5d6hLRiTh8Cjcjb".to_string(),
+                    ),
+                },
+            ],
+        ),
+        (
+            // Pair "tz1VSUr8wwNhLAzempoch5d6hLRiTh8Cjcjb" "ab", the amount
+            // is not a nat: falls back to the raw bytes, without the owner
+            "0507070a0000001600006b82198cb179e8306c1bedd08f12dc863f32888601000000026162",
+            vec![
+                Column {
+                    name: "tx_context_id".to_string(),
+                    value: insert::Value::BigInt(1),
+                },
+                count(),
+                Column {
+                    name: "meta".to_string(),
+                    value: insert::Value::String(
+                        "0507070a0000001600006b82198cb179e8306c1bedd08f12dc863f32888601000000026162".to_string(),
+                    ),
+                },
+            ],
+        ),
+        (
+            // not packed, falls back to the raw bytes
+            "deadbeef",
+            vec![
+                Column {
+                    name: "tx_context_id".to_string(),
+                    value: insert::Value::BigInt(1),
+                },
+                count(),
+                Column {
+                    name: "meta".to_string(),
+                    value: insert::Value::String("deadbeef".to_string()),
+                },
+            ],
+        ),
+    ];
+    for (bytes, exp_columns) in tests {
+        let mut processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        let value = parser::Value::Pair(
+            Box::new(parser::Value::Bytes(bytes.to_string())),
+            Box::new(parser::Value::Int(BigInt::from(7))),
+        );
+        processor
+            .process_michelson_value_test(&value, &rel_ast, &tx_context)
+            .unwrap();
+        let got: Vec<Insert> = processor
+            .drain_inserts()
+            .into_values()
+            .collect();
+        assert_eq!(
+            vec![Insert {
+                table_name: "storage".to_string(),
+                id: 1,
+                fk_id: None,
+                parent_table: None,
+                columns: exp_columns,
+            }],
+            got
+        );
+    }
+}
+
+#[test]
+fn test_process_michelson_value_excluded_columns() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use num::BigInt;
+
+    let type_ast = typing::type_ast_from_json(&json!({
+        "prim": "pair",
+        "args": [
+            { "prim": "nat", "annots": ["%count"] },
+            {
+                "prim": "pair",
+                "annots": ["%info"],
+                "args": [
+                    { "prim": "string", "annots": ["%name"] },
+                    { "prim": "bytes", "annots": ["%blob"] },
+                ],
+            },
+        ],
+    }))
+    .unwrap();
+    let mut excluded_columns = HashMap::new();
+    excluded_columns
+        .insert("storage".to_string(), vec!["info_blob".to_string()]);
+    let rel_ast = ASTBuilder::new("storage")
+        .with_excluded_columns(excluded_columns)
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(1),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+
+    let mut processor = StorageProcessor::new(
+        1,
+        DummyStorageGetter {},
+        DummyBigmapKeysGetter {},
+    );
+    let value = parser::Value::Pair(
+        Box::new(parser::Value::Int(BigInt::from(7))),
+        Box::new(parser::Value::Pair(
+            Box::new(parser::Value::String("x".to_string())),
+            Box::new(parser::Value::Bytes("deadbeef".to_string())),
+        )),
+    );
+    processor
+        .process_michelson_value_test(&value, &rel_ast, &tx_context)
+        .unwrap();
+    let got: Vec<Insert> = processor
+        .drain_inserts()
+        .into_values()
+        .collect();
+    assert_eq!(
+        vec![Insert {
+            table_name: "storage".to_string(),
+            id: 1,
+            fk_id: None,
+            parent_table: None,
+            columns: vec![
+                Column {
+                    name: "tx_context_id".to_string(),
+                    value: insert::Value::BigInt(1),
+                },
+                Column {
+                    name: "info_name".to_string(),
+                    value: insert::Value::String("x".to_string()),
+                },
+                Column {
+                    name: "count".to_string(),
+                    value: insert::Value::Numeric(PgNumeric::new(Some(
+                        BigDecimal::from(7)
+                    ))),
+                },
+            ],
+        }],
+        got
+    );
+}
+
 #[test]
 fn test_process_block() {
     // this tests the generated table structures against known good ones.
@@ -1773,12 +3031,21 @@ fn test_process_block() {
                     .filter(|idx| idx != &"id")
                     .cloned()
                     .collect();
+                // list elements are ordered by their position in the list
+                // (the other columns are their values)
+                if tables[&insert.table_name]
+                    .columns
+                    .contains_key("ordinal")
+                {
+                    sort_on.push("ordinal".to_string());
+                }
                 sort_on.extend(
                     tables[&insert.table_name]
                         .columns
                         .keys()
                         .filter(|col| {
                             col != &"id"
+                                && col != &"ordinal"
                                 && !tables[&insert.table_name]
                                     .indices
                                     .iter()
@@ -1854,6 +3121,12 @@ fn test_process_block() {
                         },
                         storage_ast: rel_ast.clone(),
                         level_floor: None,
+                        table_snapshots: HashMap::new(),
+                        table_origins: HashMap::new(),
+                        root_table: "storage".to_string(),
+                        column_types: ColumnTypes::default(),
+                        bigmap_filter: Default::default(),
+                        metadata_uris: HashMap::new(),
                         entrypoint_asts: HashMap::new(),
                     },
                 )
@@ -1896,38 +3169,1565 @@ fn test_process_block() {
     assert_eq!(expected, results);
 }
 
-#[cfg(test)]
-struct DummyStorageGetter {}
-#[cfg(test)]
-impl crate::octez::node::StorageGetter for DummyStorageGetter {
-    fn get_contract_storage(
-        &self,
-        _contract_id: &str,
-        _level: u32,
-    ) -> Result<serde_json::Value> {
-        Err(anyhow!("dummy storage getter was not expected to be called in test_block tests"))
+#[test]
+fn test_process_block_root_table() {
+    use crate::octez::block::Block;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use std::str::FromStr;
+
+    let address = "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V";
+    let script_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.script",
+        address
+    )))
+    .unwrap();
+    let storage_definition = script_json["code"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["prim"] == "storage")
+        .unwrap()["args"][0]
+        .clone();
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: "market".to_string(),
+            address: address.to_string(),
+        },
+        storage_ast: ASTBuilder::new("market")
+            .build_relational_ast(&type_ast)
+            .unwrap(),
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "market".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+
+    let block: Block = serde_json::from_str(&debug::load_test(&format!(
+        "test/{}.level-11.json",
+        address
+    )))
+    .unwrap();
+    let diffs = IntraBlockBigmapDiffsProcessor::from_block(&block).unwrap();
+    let mut storage_processor = StorageProcessor::new(
+        1,
+        DummyStorageGetter {},
+        DummyBigmapKeysGetter {},
+    );
+    storage_processor
+        .process_block(&block, &diffs, &contract)
+        .unwrap();
+
+    let inserts = storage_processor.drain_inserts();
+    let mut tables: Vec<&str> = inserts
+        .values()
+        .map(|insert| insert.table_name.as_str())
+        .collect();
+    tables.sort_unstable();
+    tables.dedup();
+    assert!(tables.contains(&"market"));
+    assert!(
+        tables
+            .iter()
+            .all(|table| *table == "market" || table.starts_with("market.")),
+        "{:?}",
+        tables
+    );
+    // the rows of the root table get ids of their own table
+    let root_ids: Vec<i64> = inserts
+        .values()
+        .filter(|insert| insert.table_name == "market")
+        .map(|insert| insert.id)
+        .collect();
+    assert!(inserts
+        .values()
+        .filter(|insert| insert.table_name != "market")
+        .all(|insert| matches!(insert.fk_id, Some(fk_id) if root_ids.contains(&fk_id))));
+}
+
+#[test]
+fn test_process_block_record_all_calls() {
+    use crate::octez::block::Block;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use std::str::FromStr;
+
+    let contract = "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V";
+    let script_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.script",
+        contract
+    )))
+    .unwrap();
+    let storage_definition = script_json["code"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["prim"] == "storage")
+        .unwrap()["args"][0]
+        .clone();
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: contract.to_string(),
+            address: contract.to_string(),
+        },
+        storage_ast: rel_ast,
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+
+    // turn the contract call of this level into a failed call
+    let mut block_json = serde_json::Value::from_str(&debug::load_test(
+        &format!("test/{}.level-11.json", contract.cid.address),
+    ))
+    .unwrap();
+    for operation_group in block_json["operations"]
+        .as_array_mut()
+        .unwrap()
+    {
+        for operation in operation_group.as_array_mut().unwrap() {
+            for content in operation["contents"]
+                .as_array_mut()
+                .unwrap()
+            {
+                if content["destination"] == contract.cid.address.as_str() {
+                    let op_res = &mut content["metadata"]["operation_result"];
+                    op_res["status"] = json!("failed");
+                    op_res["storage"] = serde_json::Value::Null;
+                }
+            }
+        }
     }
+    let block: Block = serde_json::from_value(block_json).unwrap();
+    let diffs = IntraBlockBigmapDiffsProcessor::from_block(&block).unwrap();
 
-    fn get_bigmap_value(
-        &self,
-        _level: u32,
-        _bigmap_id: i32,
-        _keyhash: &str,
-    ) -> Result<Option<serde_json::Value>> {
-        Err(anyhow!("dummy storage getter was not expected to be called in test_block tests"))
+    for record_all_calls in &[false, true] {
+        let mut storage_processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        storage_processor.set_record_all_calls(*record_all_calls);
+        storage_processor
+            .process_block(&block, &diffs, &contract)
+            .unwrap();
+
+        assert!(storage_processor
+            .drain_inserts()
+            .is_empty());
+        let (tx_contexts, txs) = storage_processor.drain_txs();
+        if *record_all_calls {
+            assert_eq!(1, tx_contexts.len());
+            assert_eq!(
+                vec!["failed".to_string()],
+                txs.iter()
+                    .map(|tx| tx.status.clone())
+                    .collect::<Vec<String>>()
+            );
+        } else {
+            assert!(tx_contexts.is_empty());
+        }
     }
 }
 
-#[cfg(test)]
-struct DummyBigmapKeysGetter {}
-#[cfg(test)]
-impl crate::sql::db::BigmapKeysGetter for DummyBigmapKeysGetter {
-    fn get(
-        &mut self,
-        _level: u32,
-        _bigmap_id: i32,
-    ) -> Result<Vec<(String, serde_json::Value, Option<serde_json::Value>)>>
+#[test]
+fn test_process_block_context_pruned() {
+    use crate::octez::block::Block;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+
+    struct PrunedStorageGetter {}
+    impl StorageGetter for PrunedStorageGetter {
+        fn get_contract_storage(
+            &self,
+            contract_id: &str,
+            level: u32,
+        ) -> Result<serde_json::Value> {
+            Err(anyhow!(ContextPrunedError)).with_context(|| {
+                format!(
+                    "failed to get storage for contract='{}', level={}",
+                    contract_id, level
+                )
+            })
+        }
+
+        fn get_bigmap_value(
+            &self,
+            _level: u32,
+            _bigmap_id: i32,
+            _keyhash: &str,
+        ) -> Result<Option<serde_json::Value>> {
+            Ok(None)
+        }
+    }
+
+    // originated in the block
+    let address = "KT18ymrGAUCF6WUsjhKPpM28xGNKNoZHM3Sy";
+    let type_ast =
+        typing::type_ast_from_json(&json!({ "prim": "nat" })).unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: "originated".to_string(),
+            address: address.to_string(),
+        },
+        storage_ast: ASTBuilder::new("storage")
+            .build_relational_ast(&type_ast)
+            .unwrap(),
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+    let block: Block = serde_json::from_str(&debug::load_test(
+        "test/KT1GT5sQWfK4f8x1DqqEfKvKoZg4sZciio7k.level-50503.json",
+    ))
+    .unwrap();
+    let diffs = IntraBlockBigmapDiffsProcessor::from_block(&block).unwrap();
+    let mut storage_processor = StorageProcessor::new(
+        1,
+        PrunedStorageGetter {},
+        DummyBigmapKeysGetter {},
+    );
+    let err = storage_processor
+        .process_block(&block, &diffs, &contract)
+        .unwrap_err();
+    assert!(ContextPrunedError::caused(&err), "{:?}", err);
+    assert!(
+        format!("{:?}", err)
+            .contains("index the contract with an archive node"),
+        "{:?}",
+        err
+    );
+}
+
+#[test]
+fn test_process_block_unknown_fields() {
+    use crate::octez::block::Block;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use std::str::FromStr;
+
+    let contract = "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V";
+    let script_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.script",
+        contract
+    )))
+    .unwrap();
+    let storage_definition = script_json["code"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["prim"] == "storage")
+        .unwrap()["args"][0]
+        .clone();
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: contract.to_string(),
+            address: contract.to_string(),
+        },
+        storage_ast: rel_ast,
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+
+    let block_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.level-11.json",
+        contract.cid.address
+    )))
+    .unwrap();
+
+    // simulate a future protocol: new fields all over the place, and
+    // operations of a kind we don't know (without the usual metadata)
+    let mut future_json = block_json.clone();
+    future_json["some_future_field"] = json!({"nested": [1, 2, 3]});
+    future_json["header"]["some_future_field"] = json!("abc");
+    for operation_group in future_json["operations"]
+        .as_array_mut()
+        .unwrap()
+    {
+        for operation in operation_group.as_array_mut().unwrap() {
+            operation["some_future_field"] = json!(42);
+            let contents = operation["contents"]
+                .as_array_mut()
+                .unwrap();
+            for content in contents.iter_mut() {
+                content["some_future_field"] = json!(null);
+                let metadata = &mut content["metadata"];
+                metadata["some_future_field"] = json!([]);
+                if let Some(op_res) = metadata.get_mut("operation_result") {
+                    op_res["some_future_field"] = json!({});
+                }
+                if let Some(internal_ops) = metadata
+                    .get_mut("internal_operation_results")
+                    .and_then(|ops| ops.as_array_mut())
+                {
+                    internal_ops.push(json!({
+                        "kind": "some_future_internal_kind",
+                        "some_future_field": true,
+                    }));
+                }
+            }
+            contents.push(json!({
+                "kind": "some_future_kind",
+                "some_future_field": "xyz",
+            }));
+        }
+    }
+
+    let block: Block = serde_json::from_value(block_json).unwrap();
+    let future_block: Block = serde_json::from_value(future_json).unwrap();
+    assert_eq!(block.active_contracts(), future_block.active_contracts());
+
+    let mut results: Vec<(Inserts, Vec<TxContext>)> = vec![];
+    for block in &[block, future_block] {
+        let diffs = IntraBlockBigmapDiffsProcessor::from_block(block).unwrap();
+        let mut storage_processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        storage_processor
+            .process_block(block, &diffs, &contract)
+            .unwrap();
+        let inserts = storage_processor.drain_inserts();
+        let (tx_contexts, _) = storage_processor.drain_txs();
+        assert!(!inserts.is_empty());
+        results.push((inserts, tx_contexts));
+    }
+    assert_eq!(results[0], results[1]);
+}
+
+#[test]
+fn test_process_block_keep_raw_storage() {
+    use crate::octez::block::Block;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use std::str::FromStr;
+
+    let contract = "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V";
+    let script_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.script",
+        contract
+    )))
+    .unwrap();
+    let storage_definition = script_json["code"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["prim"] == "storage")
+        .unwrap()["args"][0]
+        .clone();
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: contract.to_string(),
+            address: contract.to_string(),
+        },
+        storage_ast: rel_ast,
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+
+    let block_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.level-11.json",
+        contract.cid.address
+    )))
+    .unwrap();
+    let mut exp_storage: Vec<serde_json::Value> = vec![];
+    for operation_group in block_json["operations"]
+        .as_array()
+        .unwrap()
+    {
+        for operation in operation_group.as_array().unwrap() {
+            for content in operation["contents"]
+                .as_array()
+                .unwrap()
+            {
+                if content["destination"] == contract.cid.address.as_str() {
+                    exp_storage.push(
+                        content["metadata"]["operation_result"]["storage"]
+                            .clone(),
+                    );
+                }
+            }
+        }
+    }
+    assert!(!exp_storage.is_empty());
+    let block: Block = serde_json::from_value(block_json).unwrap();
+    let diffs = IntraBlockBigmapDiffsProcessor::from_block(&block).unwrap();
+
+    for keep_raw_storage in &[false, true] {
+        let mut storage_processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        storage_processor.set_keep_raw_storage(*keep_raw_storage);
+        storage_processor
+            .process_block(&block, &diffs, &contract)
+            .unwrap();
+
+        let raw_storage = storage_processor.drain_raw_storage();
+        if !*keep_raw_storage {
+            assert!(raw_storage.is_empty());
+            continue;
+        }
+        let (tx_contexts, _) = storage_processor.drain_txs();
+        let mut ctx_ids: Vec<i64> = tx_contexts
+            .iter()
+            .map(|ctx| ctx.id.unwrap())
+            .collect();
+        ctx_ids.sort_unstable();
+        assert_eq!(
+            ctx_ids,
+            raw_storage
+                .iter()
+                .map(|raw| raw.tx_context_id)
+                .collect::<Vec<i64>>()
+        );
+        assert_eq!(
+            exp_storage,
+            raw_storage
+                .iter()
+                .map(|raw| raw.storage.clone())
+                .collect::<Vec<serde_json::Value>>()
+        );
+        assert!(raw_storage
+            .iter()
+            .all(|raw| raw.contract == contract.cid.address));
+    }
+}
+
+#[test]
+fn test_process_block_deterministic_ids() {
+    use crate::octez::block::Block;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use std::str::FromStr;
+
+    let contract = "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V";
+    let script_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.script",
+        contract
+    )))
+    .unwrap();
+    let storage_definition = script_json["code"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["prim"] == "storage")
+        .unwrap()["args"][0]
+        .clone();
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: contract.to_string(),
+            address: contract.to_string(),
+        },
+        storage_ast: rel_ast,
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+    let block: Block = serde_json::from_str(&debug::load_test(&format!(
+        "test/{}.level-11.json",
+        contract.cid.address
+    )))
+    .unwrap();
+    let diffs = IntraBlockBigmapDiffsProcessor::from_block(&block).unwrap();
+
+    // the processors of parallel runs start counting at different ids
+    let process = |initial_id: i64,
+                   deterministic: bool|
+     -> (Vec<(i64, TxContext)>, Vec<Insert>) {
+        let mut storage_processor = StorageProcessor::new(
+            initial_id,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        storage_processor.set_deterministic_ids(deterministic);
+        storage_processor
+            .process_block(&block, &diffs, &contract)
+            .unwrap();
+        let (tx_contexts, _) = storage_processor.drain_txs();
+        let mut tx_contexts: Vec<(i64, TxContext)> = tx_contexts
+            .into_iter()
+            .map(|ctx| (ctx.id.unwrap(), ctx))
+            .collect();
+        tx_contexts.sort_by_key(|(id, _)| *id);
+        let mut inserts: Vec<Insert> = storage_processor
+            .drain_inserts()
+            .into_values()
+            .collect();
+        inserts
+            .sort_by(|a, b| (&a.table_name, a.id).cmp(&(&b.table_name, b.id)));
+        (tx_contexts, inserts)
+    };
+
+    let (tx_contexts, inserts) = process(1, true);
+    assert!(!tx_contexts.is_empty());
+    assert!(!inserts.is_empty());
+    assert_eq!((tx_contexts.clone(), inserts.clone()), process(1000, true));
+    assert_ne!(inserts, process(1000, false).1);
+
+    let deterministic_range = (1i64 << 62)..=i64::MAX;
+    let mut ids: Vec<i64> = tx_contexts
+        .iter()
+        .map(|(id, _)| *id)
+        .collect();
+    ids.extend(inserts.iter().map(|insert| insert.id));
+    assert!(ids
+        .iter()
+        .all(|id| deterministic_range.contains(id)));
+    let num_ids = ids.len();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(num_ids, ids.len());
+    // every row refers to its parent row and tx context by their ids
+    for insert in &inserts {
+        if let Some(fk_id) = insert.fk_id {
+            assert!(ids.contains(&fk_id), "{:?}", insert);
+        }
+        let tx_context_id = insert.get_tx_context_id().unwrap();
+        assert!(tx_contexts
+            .iter()
+            .any(|(id, _)| *id == tx_context_id));
+    }
+}
+
+#[test]
+fn test_process_block_index_failures() {
+    use crate::octez::block::Block;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use std::str::FromStr;
+
+    let contract = "KT1KfogLfUjFuQFDkBWww8TMbGijd3oMjK3V";
+    let script_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.script",
+        contract
+    )))
+    .unwrap();
+    let storage_definition = script_json["code"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["prim"] == "storage")
+        .unwrap()["args"][0]
+        .clone();
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: contract.to_string(),
+            address: contract.to_string(),
+        },
+        storage_ast: rel_ast,
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+
+    // level 11, with the contract call turned into a failed transfer
+    let block: Block = serde_json::from_str(&debug::load_test(&format!(
+        "test/{}.failed-level-11.json",
+        contract.cid.address
+    )))
+    .unwrap();
+    let diffs = IntraBlockBigmapDiffsProcessor::from_block(&block).unwrap();
+
+    for index_failures in &[false, true] {
+        let mut storage_processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        storage_processor.set_index_failures(*index_failures);
+        storage_processor
+            .process_block(&block, &diffs, &contract)
+            .unwrap();
+
+        assert!(storage_processor
+            .drain_inserts()
+            .is_empty());
+        let (tx_contexts, txs) = storage_processor.drain_txs();
+        let failures = storage_processor.drain_operation_failures();
+        if !*index_failures {
+            assert!(tx_contexts.is_empty());
+            assert!(failures.is_empty());
+            continue;
+        }
+        assert_eq!(1, tx_contexts.len());
+        assert_eq!("failed", txs[0].status);
+        assert_eq!(
+            vec![
+                (
+                    tx_contexts[0].id.unwrap(),
+                    "failed".to_string(),
+                    Some("temporary".to_string()),
+                    Some(
+                        "proto.011-PtHangz2.michelson_v1.runtime_error"
+                            .to_string()
+                    ),
+                ),
+                (
+                    tx_contexts[0].id.unwrap(),
+                    "failed".to_string(),
+                    Some("temporary".to_string()),
+                    Some(
+                        "proto.011-PtHangz2.michelson_v1.script_rejected"
+                            .to_string()
+                    ),
+                ),
+            ],
+            failures
+                .into_iter()
+                .map(|f| (f.tx_context_id, f.status, f.error_kind, f.error_id))
+                .collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn test_content_hash_deterministic() {
+    use crate::octez::block::{Block, LevelMeta};
+    use crate::sql::inserter::{content_hash, ProcessedContractBlock};
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing;
+    use std::str::FromStr;
+
+    let contract = "KT1GT5sQWfK4f8x1DqqEfKvKoZg4sZciio7k";
+    let level = 50503;
+    let script_json = serde_json::Value::from_str(&debug::load_test(&format!(
+        "test/{}.script",
+        contract
+    )))
+    .unwrap();
+    let storage_definition = script_json["code"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["prim"] == "storage")
+        .unwrap()["args"][0]
+        .clone();
+    let type_ast = typing::type_ast_from_json(&storage_definition).unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let contract = Contract {
+        cid: crate::config::ContractID {
+            name: contract.to_string(),
+            address: contract.to_string(),
+        },
+        storage_ast: rel_ast,
+        level_floor: None,
+        table_snapshots: HashMap::new(),
+        table_origins: HashMap::new(),
+        root_table: "storage".to_string(),
+        column_types: ColumnTypes::default(),
+        bigmap_filter: Default::default(),
+        metadata_uris: HashMap::new(),
+        entrypoint_asts: HashMap::new(),
+    };
+    let block: Block = serde_json::from_str(&debug::load_test(&format!(
+        "test/{}.level-{}.json",
+        contract.cid.address, level
+    )))
+    .unwrap();
+    let diffs = IntraBlockBigmapDiffsProcessor::from_block(&block).unwrap();
+
+    // processes the block with ids starting at initial_id, as happens when
+    // processing it in parallel with other blocks
+    let process = |initial_id: i64| -> ProcessedContractBlock {
+        let mut storage_processor = StorageProcessor::new(
+            initial_id,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        storage_processor
+            .process_block(&block, &diffs, &contract)
+            .unwrap();
+        let (tx_contexts, txs) = storage_processor.drain_txs();
+        ProcessedContractBlock {
+            level: LevelMeta {
+                level,
+                hash: None,
+                prev_hash: None,
+                baked_at: None,
+                protocol: None,
+                content_hash: None,
+            },
+            contract: contract.clone(),
+            is_origination: false,
+            inserts: storage_processor
+                .drain_inserts()
+                .into_values()
+                .collect(),
+            spilled_inserts: None,
+            tx_contexts,
+            txs,
+            bigmap_contract_deps: vec![],
+            bigmap_keyhashes: HashMap::new(),
+            bigmap_meta_actions: storage_processor.drain_bigmap_meta_actions(),
+            operation_failures: vec![],
+            raw_storage: vec![],
+            delegations: vec![],
+            balance: None,
+            deterministic_ids: false,
+        }
+    };
+
+    let first = process(1);
+    assert!(!first.inserts.is_empty());
+    let mut second = process(1000);
+    second.inserts.reverse();
+    second.tx_contexts.reverse();
+    assert_eq!(
+        content_hash(std::slice::from_ref(&first)).unwrap(),
+        content_hash(&[second]).unwrap()
+    );
+
+    // spilled inserts hash the same as inserts kept in memory, whatever the
+    // order they were spilled in
+    let mut spilled = first.clone();
+    let mut spilled_inserts: Vec<insert::Insert> = spilled.inserts.split_off(1);
+    assert!(!spilled_inserts.is_empty());
+    spilled_inserts.reverse();
+    let mut spill = SpilledInserts::new().unwrap();
+    spill.write(&spilled_inserts).unwrap();
+    spilled.spilled_inserts = Some(spill);
+    assert_eq!(
+        content_hash(std::slice::from_ref(&first)).unwrap(),
+        content_hash(&[spilled]).unwrap()
+    );
+
+    let mut changed = first.clone();
+    changed.inserts[0]
+        .columns
+        .push(insert::Column {
+            name: "extra".to_string(),
+            value: insert::Value::Null,
+        });
+    assert_ne!(
+        content_hash(&[first]).unwrap(),
+        content_hash(&[changed]).unwrap()
+    );
+}
+
+#[test]
+fn test_process_bigmap_clear() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing::Ele;
+
+    struct TestBigmapKeysGetter {}
+    impl crate::sql::db::BigmapKeysGetter for TestBigmapKeysGetter {
+        fn get(
+            &mut self,
+            level: u32,
+            bigmap_id: i32,
+        ) -> Result<Vec<db::BigmapEntry>> {
+            assert_eq!((9, 5), (level, bigmap_id));
+            Ok(vec![
+                (
+                    "exprA".to_string(),
+                    json!({"int": "1"}),
+                    Some(json!({"string": "a"})),
+                ),
+                ("exprA".to_string(), json!({"int": "1"}), None),
+                (
+                    "exprB".to_string(),
+                    json!({"int": "2"}),
+                    Some(json!({"string": "b"})),
+                ),
+            ])
+        }
+
+        fn get_table(&mut self, bigmap_id: i32) -> Result<Option<String>> {
+            assert_eq!(5, bigmap_id);
+            Ok(Some("storage.noname".to_string()))
+        }
+    }
+
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&Ele {
+            name: None,
+            expr_type: ExprTy::BigMap(
+                Box::new(Ele {
+                    name: None,
+                    expr_type: ExprTy::Nat,
+                }),
+                Box::new(Ele {
+                    name: None,
+                    expr_type: ExprTy::String,
+                }),
+            ),
+        })
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(1),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+    let deleted = |id: i64, idx: i32| Insert {
+        table_name: "storage.noname".to_string(),
+        id,
+        fk_id: None,
+        parent_table: None,
+        columns: vec![
+            Column {
+                name: "tx_context_id".to_string(),
+                value: insert::Value::BigInt(1),
+            },
+            Column {
+                name: "idx_nat".to_string(),
+                value: insert::Value::Numeric(PgNumeric::new(Some(
+                    BigDecimal::from(idx),
+                ))),
+            },
+            Column {
+                name: "deleted".to_string(),
+                value: insert::Value::Bool(true),
+            },
+            Column {
+                name: "bigmap_id".to_string(),
+                value: insert::Value::Int(5),
+            },
+        ],
+    };
+
+    // the cleared bigmap is still part of the storage, and when it no longer
+    // is (eg it was replaced by a newly allocated bigmap)
+    for in_storage in &[true, false] {
+        let mut processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            TestBigmapKeysGetter {},
+        );
+        if *in_storage {
+            processor
+                .process_michelson_value_test(
+                    &parser::Value::Int(5.into()),
+                    &rel_ast,
+                    &tx_context,
+                )
+                .unwrap();
+            processor.drain_inserts();
+        } else {
+            collect_bigmap_asts(&rel_ast, &mut processor.bigmap_asts);
+        }
+        processor.tx_contexts.insert(
+            tx_context.clone(),
+            Tx {
+                tx_context_id: 1,
+                operation_hash: "opHash".to_string(),
+                status: "applied".to_string(),
+                source: None,
+                destination: None,
+                entrypoint: None,
+                entrypoint_args: None,
+                amount: None,
+                fee: None,
+                gas_limit: None,
+                storage_limit: None,
+                consumed_milligas: None,
+                storage_size: None,
+                paid_storage_size_diff: None,
+                counter: None,
+                branch: None,
+            },
+        );
+        let first_id = processor.id_generator.id;
+
+        processor
+            .process_bigmap_op(&bigmap::Op::Clear { bigmap: 5 }, &tx_context)
+            .unwrap();
+
+        let mut got: Vec<Insert> = processor
+            .drain_inserts()
+            .into_values()
+            .collect();
+        got.sort_by_key(|insert| insert.id);
+        assert_eq!(
+            vec![deleted(first_id, 1), deleted(first_id + 1, 2)],
+            got,
+            "in_storage={}",
+            in_storage,
+        );
+        assert_eq!(
+            vec![("clear".to_string(), Some("opHash".to_string()))],
+            processor
+                .drain_bigmap_meta_actions()
+                .into_iter()
+                .map(|action| (action.action, action.operation_hash))
+                .collect::<Vec<(String, Option<String>)>>(),
+        );
+    }
+}
+
+#[cfg(test)]
+struct DummyStorageGetter {}
+#[cfg(test)]
+impl crate::octez::node::StorageGetter for DummyStorageGetter {
+    fn get_contract_storage(
+        &self,
+        _contract_id: &str,
+        _level: u32,
+    ) -> Result<serde_json::Value> {
+        Err(anyhow!("dummy storage getter was not expected to be called in test_block tests"))
+    }
+
+    fn get_bigmap_value(
+        &self,
+        _level: u32,
+        _bigmap_id: i32,
+        _keyhash: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        Err(anyhow!("dummy storage getter was not expected to be called in test_block tests"))
+    }
+}
+
+#[cfg(test)]
+struct DummyBigmapKeysGetter {}
+#[cfg(test)]
+impl crate::sql::db::BigmapKeysGetter for DummyBigmapKeysGetter {
+    fn get(
+        &mut self,
+        _level: u32,
+        _bigmap_id: i32,
+    ) -> Result<Vec<(String, serde_json::Value, Option<serde_json::Value>)>>
     {
         Ok(vec![])
     }
+
+    fn get_table(&mut self, _bigmap_id: i32) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_process_bigmap_filter() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing::Ele;
+
+    let bigmap_type = |name: &str| Ele {
+        name: Some(name.to_string()),
+        expr_type: ExprTy::BigMap(
+            Box::new(Ele {
+                name: None,
+                expr_type: ExprTy::Nat,
+            }),
+            Box::new(Ele {
+                name: None,
+                expr_type: ExprTy::String,
+            }),
+        ),
+    };
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&Ele {
+            name: None,
+            expr_type: ExprTy::Pair(
+                Box::new(bigmap_type("ledger")),
+                Box::new(bigmap_type("operators")),
+            ),
+        })
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(1),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+    let update = |bigmap: i32| bigmap::Op::Update {
+        bigmap,
+        keyhash: "exprA".to_string(),
+        key: json!({"int": "1"}),
+        value: Some(json!({"string": "a"})),
+    };
+
+    let indexed_tables = |bigmap_filter: BigmapFilter| -> Vec<String> {
+        let mut processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        processor.bigmap_filter = bigmap_filter;
+        processor
+            .process_michelson_value_test(
+                &parser::Value::Pair(
+                    Box::new(parser::Value::Int(5.into())),
+                    Box::new(parser::Value::Int(6.into())),
+                ),
+                &rel_ast,
+                &tx_context,
+            )
+            .unwrap();
+        processor.drain_inserts();
+        for op in &[
+            bigmap::Op::Alloc { bigmap: 5 },
+            bigmap::Op::Alloc { bigmap: 6 },
+            update(5),
+            update(6),
+            bigmap::Op::Clear { bigmap: 6 },
+        ] {
+            processor
+                .process_bigmap_op(op, &tx_context)
+                .unwrap();
+        }
+        let mut tables: Vec<String> = processor
+            .drain_inserts()
+            .into_values()
+            .map(|insert| insert.table_name)
+            .collect();
+        tables.sort();
+        tables.dedup();
+        tables
+    };
+
+    assert_eq!(
+        vec!["storage.ledger", "storage.operators"],
+        indexed_tables(BigmapFilter::default())
+    );
+    assert_eq!(
+        vec!["storage.ledger"],
+        indexed_tables(BigmapFilter {
+            include: vec![],
+            exclude: vec!["storage.operators".to_string()],
+        })
+    );
+    assert_eq!(
+        vec!["storage.operators"],
+        indexed_tables(BigmapFilter {
+            include: vec!["storage.operators".to_string()],
+            exclude: vec![],
+        })
+    );
+}
+
+#[test]
+fn test_process_bigmap_copy_spilled() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing::Ele;
+
+    const NUM_KEYS: usize = 2500;
+
+    struct LargeBigmapKeysGetter {}
+    impl crate::sql::db::BigmapKeysGetter for LargeBigmapKeysGetter {
+        fn get(
+            &mut self,
+            level: u32,
+            bigmap_id: i32,
+        ) -> Result<Vec<db::BigmapEntry>> {
+            assert_eq!((9, 5), (level, bigmap_id));
+            Ok((0..NUM_KEYS)
+                .map(|i| {
+                    (
+                        format!("expr{}", i),
+                        json!({ "int": i.to_string() }),
+                        // removed keys are not copied
+                        if i % 10 == 9 {
+                            None
+                        } else {
+                            Some(json!({ "string": format!("v{}", i) }))
+                        },
+                    )
+                })
+                .collect())
+        }
+
+        fn get_table(&mut self, _bigmap_id: i32) -> Result<Option<String>> {
+            Ok(None)
+        }
+    }
+
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&Ele {
+            name: None,
+            expr_type: ExprTy::BigMap(
+                Box::new(Ele {
+                    name: None,
+                    expr_type: ExprTy::Nat,
+                }),
+                Box::new(Ele {
+                    name: None,
+                    expr_type: ExprTy::String,
+                }),
+            ),
+        })
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(1),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+
+    let copy = |chunk_size: Option<usize>| {
+        let mut processor = StorageProcessor::new(
+            2,
+            DummyStorageGetter {},
+            LargeBigmapKeysGetter {},
+        );
+        processor.set_bigmap_copy_chunk_size(chunk_size);
+        processor
+            .process_michelson_value_test(
+                &parser::Value::Int(7.into()),
+                &rel_ast,
+                &tx_context,
+            )
+            .unwrap();
+        processor
+            .process_bigmap_copy(&tx_context, 5, 7)
+            .unwrap();
+        let mut inserts: Vec<Insert> = processor
+            .drain_inserts()
+            .into_values()
+            .collect();
+        inserts.sort_by_key(|insert| insert.id);
+        (inserts, processor.drain_spilled_inserts())
+    };
+
+    let (in_memory, spilled) = copy(None);
+    assert!(spilled.is_none());
+    let copied: Vec<Insert> = in_memory
+        .iter()
+        .filter(|insert| insert.table_name == "storage.noname")
+        .cloned()
+        .collect();
+    assert_eq!(NUM_KEYS - NUM_KEYS / 10, copied.len());
+
+    // with chunking, only the rows from before the copy stay in memory
+    let (kept, spilled) = copy(Some(100));
+    let spilled = spilled.unwrap();
+    assert_eq!(in_memory.len() - copied.len(), kept.len());
+    assert_eq!(copied.len(), spilled.len());
+    let mut chunks = 0;
+    let mut read_back: Vec<Insert> = vec![];
+    spilled
+        .for_each_chunk(100, |chunk| {
+            chunks += 1;
+            read_back.extend(chunk);
+            Ok(())
+        })
+        .unwrap();
+    read_back.sort_by_key(|insert| insert.id);
+    assert_eq!(copied, read_back);
+    assert_eq!(copied.len() / 100 + 1, chunks);
+}
+
+#[test]
+fn test_empty_string_as_null() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing::Ele;
+    type Processor =
+        StorageProcessor<DummyStorageGetter, DummyBigmapKeysGetter>;
+
+    let empty = parser::Value::String("".to_string());
+    let non_empty = parser::Value::String("a".to_string());
+    for empty_string_as_null in &[false, true] {
+        let exp_empty = if *empty_string_as_null {
+            insert::Value::Null
+        } else {
+            insert::Value::String("".to_string())
+        };
+        assert_eq!(
+            exp_empty,
+            Processor::storage2sql_value(
+                &ExprTy::String,
+                &empty,
+                &SqlValueOptions {
+                    empty_string_as_null: *empty_string_as_null,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            insert::Value::String("a".to_string()),
+            Processor::storage2sql_value(
+                &ExprTy::String,
+                &non_empty,
+                &SqlValueOptions {
+                    empty_string_as_null: *empty_string_as_null,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        );
+        // only strings are affected, not eg empty bytes (also not when
+        // found where a string was expected)
+        for t in &[ExprTy::Bytes, ExprTy::String] {
+            assert_eq!(
+                insert::Value::String("".to_string()),
+                Processor::storage2sql_value(
+                    t,
+                    &parser::Value::Bytes("".to_string()),
+                    &SqlValueOptions {
+                        empty_string_as_null: *empty_string_as_null,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+            );
+        }
+    }
+
+    let string_type = |name: &str| Ele {
+        name: Some(name.to_string()),
+        expr_type: ExprTy::String,
+    };
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&Ele {
+            name: None,
+            expr_type: ExprTy::Pair(
+                Box::new(string_type("a")),
+                Box::new(string_type("b")),
+            ),
+        })
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(1),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+    for empty_string_as_null in &[false, true] {
+        let mut processor = StorageProcessor::new(
+            1,
+            DummyStorageGetter {},
+            DummyBigmapKeysGetter {},
+        );
+        processor.set_empty_string_as_null(*empty_string_as_null);
+        processor
+            .process_michelson_value_test(
+                &parser::Value::Pair(
+                    Box::new(empty.clone()),
+                    Box::new(non_empty.clone()),
+                ),
+                &rel_ast,
+                &tx_context,
+            )
+            .unwrap();
+        let inserts: Vec<Insert> = processor
+            .drain_inserts()
+            .into_values()
+            .collect();
+        assert_eq!(1, inserts.len());
+        let column = |name: &str| {
+            inserts[0]
+                .columns
+                .iter()
+                .find(|col| col.name == name)
+                .unwrap()
+                .value
+                .clone()
+        };
+        assert_eq!(
+            if *empty_string_as_null {
+                insert::Value::Null
+            } else {
+                insert::Value::String("".to_string())
+            },
+            column("a")
+        );
+        assert_eq!(insert::Value::String("a".to_string()), column("b"));
+    }
+}
+
+#[test]
+fn test_storage2sql_value_bigint() {
+    type Processor =
+        StorageProcessor<DummyStorageGetter, DummyBigmapKeysGetter>;
+
+    let max = parser::Value::Nat(i64::MAX.into());
+    let min = parser::Value::Int(i64::MIN.into());
+    let beyond_max = parser::Value::Nat(num::BigInt::from(i64::MAX) + 1);
+    let beyond_min = parser::Value::Int(num::BigInt::from(i64::MIN) - 1);
+
+    assert_eq!(
+        insert::Value::BigInt(i64::MAX),
+        Processor::storage2sql_value(
+            &ExprTy::Nat,
+            &max,
+            &SqlValueOptions {
+                numeric_type: NumericType::Bigint,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        insert::Value::BigInt(i64::MIN),
+        Processor::storage2sql_value(
+            &ExprTy::Int,
+            &min,
+            &SqlValueOptions {
+                numeric_type: NumericType::Bigint,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        insert::Value::BigInt(42),
+        Processor::storage2sql_value(
+            &ExprTy::Mutez,
+            &parser::Value::Mutez(42.into()),
+            &SqlValueOptions {
+                numeric_type: NumericType::Bigint,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    );
+    assert!(Processor::storage2sql_value(
+        &ExprTy::Nat,
+        &beyond_max,
+        &SqlValueOptions {
+            numeric_type: NumericType::Bigint,
+            ..Default::default()
+        },
+    )
+    .is_err());
+    assert!(Processor::storage2sql_value(
+        &ExprTy::Int,
+        &beyond_min,
+        &SqlValueOptions {
+            numeric_type: NumericType::Bigint,
+            ..Default::default()
+        },
+    )
+    .is_err());
+
+    // without the override these are stored as numeric
+    assert_eq!(
+        insert::Value::Numeric(PgNumeric::new(Some(BigDecimal::new(
+            num::BigInt::from(i64::MAX) + 1,
+            0
+        )))),
+        Processor::storage2sql_value(
+            &ExprTy::Nat,
+            &beyond_max,
+            &SqlValueOptions::default(),
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn test_storage2sql_value_bounded_numeric() {
+    type Processor =
+        StorageProcessor<DummyStorageGetter, DummyBigmapKeysGetter>;
+
+    let mutez = NumericType::BoundedNumeric {
+        precision: 20,
+        scale: 0,
+    };
+    let max: num::BigInt = num::pow(num::BigInt::from(10), 20) - 1;
+    assert_eq!(
+        insert::Value::Numeric(PgNumeric::new(Some(BigDecimal::new(
+            max.clone(),
+            0
+        )))),
+        Processor::storage2sql_value(
+            &ExprTy::Mutez,
+            &parser::Value::Mutez(max.clone()),
+            &SqlValueOptions {
+                numeric_type: mutez,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    );
+    assert!(Processor::storage2sql_value(
+        &ExprTy::Mutez,
+        &parser::Value::Mutez(max + 1),
+        &SqlValueOptions {
+            numeric_type: mutez,
+            ..Default::default()
+        },
+    )
+    .is_err());
+
+    // the sign does not count as a digit, the scale takes digits away
+    let bounded = |precision: u32, scale: u32, i: i64| {
+        Processor::storage2sql_value(
+            &ExprTy::Int,
+            &parser::Value::Int(i.into()),
+            &SqlValueOptions {
+                numeric_type: NumericType::BoundedNumeric { precision, scale },
+                ..Default::default()
+            },
+        )
+    };
+    assert!(bounded(2, 0, -99).is_ok());
+    assert!(bounded(2, 0, -100).is_err());
+    assert!(bounded(4, 2, 99).is_ok());
+    assert!(bounded(4, 2, 100).is_err());
+}
+
+#[test]
+fn test_storage2sql_value_bls12_381() {
+    type Processor =
+        StorageProcessor<DummyStorageGetter, DummyBigmapKeysGetter>;
+
+    let fr = ExprTy::Opaque("bls12_381_fr".to_string());
+    let hex = "01".repeat(32);
+    assert_eq!(
+        insert::Value::String(hex.clone()),
+        Processor::storage2sql_value(
+            &fr,
+            &parser::Value::Bytes(hex),
+            &SqlValueOptions::default(),
+        )
+        .unwrap()
+    );
+    for bad in &[
+        parser::Value::Bytes("01".repeat(31)),
+        parser::Value::Bytes("01".repeat(33)),
+        parser::Value::Int(1.into()),
+    ] {
+        assert!(
+            Processor::storage2sql_value(
+                &fr,
+                bad,
+                &SqlValueOptions::default(),
+            )
+            .is_err(),
+            "{:?}",
+            bad
+        );
+    }
+
+    let g2 = ExprTy::Opaque("bls12_381_g2".to_string());
+    assert!(Processor::storage2sql_value(
+        &g2,
+        &parser::Value::Bytes("00".repeat(192)),
+        &SqlValueOptions::default(),
+    )
+    .is_ok());
+}
+
+#[test]
+fn test_process_michelson_value_map_of_bigmaps() {
+    use crate::storage_structure::relational::ASTBuilder;
+
+    let type_ast = typing::type_ast_from_json(&json!({
+        "prim": "map",
+        "args": [
+            {"prim": "string"},
+            {"prim": "big_map", "args": [{"prim": "nat"}, {"prim": "nat"}]},
+        ],
+        "annots": ["%balances"],
+    }))
+    .unwrap();
+    let rel_ast = ASTBuilder::new("storage")
+        .build_relational_ast(&type_ast)
+        .unwrap();
+    let tx_context = TxContext {
+        id: Some(32),
+        level: 10,
+        contract: "test".to_string(),
+        operation_group_number: 1,
+        operation_number: 2,
+        content_number: 3,
+        internal_number: None,
+    };
+    let value = parser::parse_lexed(&json!([
+        {"prim": "Elt", "args": [{"string": "a"}, {"int": "5"}]},
+        {"prim": "Elt", "args": [{"string": "b"}, {"int": "6"}]},
+    ]))
+    .unwrap();
+
+    let mut processor = StorageProcessor::new(
+        1,
+        DummyStorageGetter {},
+        DummyBigmapKeysGetter {},
+    );
+    processor
+        .process_michelson_value_test(&value, &rel_ast, &tx_context)
+        .unwrap();
+
+    // the bigmap of every map element is tracked with an id of its own,
+    // neither is overwritten by the other
+    assert_eq!(
+        2,
+        processor
+            .drain_inserts()
+            .values()
+            .filter(|insert| insert.table_name == "storage.balances")
+            .count()
+    );
+    assert_eq!(2, processor.bigmap_map.len());
+    assert_ne!(processor.bigmap_map[&5].0, processor.bigmap_map[&6].0);
+    for bigmap in &[5, 6] {
+        assert!(matches!(
+            processor.bigmap_map[bigmap].1,
+            RelationalAST::BigMap { .. }
+        ));
+    }
 }
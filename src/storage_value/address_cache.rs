@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Result};
+use linked_hash_map::LinkedHashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::storage_value::parser::decode_address;
+
+pub(crate) type SharedAddressCache = Arc<Mutex<AddressCache>>;
+
+// Caches decoded addresses by their raw hex, evicting the least recently
+// used address once the capacity is reached. Addresses (eg of a ledger's
+// owners) recur often, and decoding them (base58check encoding, which hashes
+// the address twice) is relatively costly.
+pub(crate) struct AddressCache {
+    entries: LinkedHashMap<String, String>,
+    capacity: usize,
+
+    hits: u64,
+    lookups: u64,
+}
+
+impl AddressCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: LinkedHashMap::new(),
+            capacity,
+            hits: 0,
+            lookups: 0,
+        }
+    }
+
+    pub(crate) fn shared(capacity: usize) -> SharedAddressCache {
+        Arc::new(Mutex::new(Self::new(capacity)))
+    }
+
+    /// Decodes the address through the shared cache. The mutex is not held
+    /// while decoding, so that the processing of other contracts is not held
+    /// up by it on a miss.
+    pub(crate) fn decode_shared(
+        cache: &SharedAddressCache,
+        hex: &str,
+    ) -> Result<String> {
+        let lock = || {
+            cache
+                .lock()
+                .map_err(|_| anyhow!("failed to lock address_cache mutex"))
+        };
+        if let Some(address) = lock()?.get(hex) {
+            return Ok(address);
+        }
+        let address = decode_address(hex)?;
+        lock()?.insert(hex, &address);
+        Ok(address)
+    }
+
+    fn get(&mut self, hex: &str) -> Option<String> {
+        self.lookups += 1;
+        let address = self.entries.get_refresh(hex)?.clone();
+        self.hits += 1;
+        Some(address)
+    }
+
+    fn insert(&mut self, hex: &str, address: &str) {
+        // another thread may have decoded (and inserted) it meanwhile
+        if self.capacity == 0 || self.entries.contains_key(hex) {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries
+            .insert(hex.to_string(), address.to_string());
+    }
+
+    // The percentage of lookups that were served from the cache.
+    pub(crate) fn hit_rate(&self) -> Option<f64> {
+        if self.lookups == 0 {
+            return None;
+        }
+        Some(self.hits as f64 / self.lookups as f64 * 100.0)
+    }
+}
+
+#[test]
+fn test_address_cache() {
+    let tz1 = "00006b82198cb179e8306c1bedd08f12dc863f328886";
+    let kt1 = "01d62a20fd2574884476f3da2f1a41bb8cc289f8cc00";
+    let kt1_callback = "016e4943f7a23ab9cbe56f48ff72f6c27e8956762400626f72726f775f63616c6c6261636b";
+    let kt1_no_callback = "016e4943f7a23ab9cbe56f48ff72f6c27e8956762400";
+
+    let cache = AddressCache::shared(2);
+    let decode = |hex: &str| AddressCache::decode_shared(&cache, hex);
+    assert_eq!(None, cache.lock().unwrap().hit_rate());
+    for hex in &[tz1, kt1, tz1, kt1, kt1_callback, kt1_no_callback, tz1] {
+        assert_eq!(decode_address(hex).unwrap(), decode(hex).unwrap());
+    }
+    // tz1 and kt1 hit twice, the last tz1 was evicted before
+    assert_eq!(Some(2.0 / 7.0 * 100.0), cache.lock().unwrap().hit_rate());
+    assert_eq!(2, cache.lock().unwrap().entries.len());
+    assert_ne!(
+        decode(kt1_callback).unwrap(),
+        decode(kt1_no_callback).unwrap()
+    );
+
+    let cache = AddressCache::shared(0);
+    let decode = |hex: &str| AddressCache::decode_shared(&cache, hex);
+    assert_eq!(decode_address(tz1).unwrap(), decode(tz1).unwrap());
+    assert_eq!(decode_address(tz1).unwrap(), decode(tz1).unwrap());
+    assert_eq!(Some(0.0), cache.lock().unwrap().hit_rate());
+}
+
+#[test]
+fn test_address_cache_shared() {
+    let tz1 = "00006b82198cb179e8306c1bedd08f12dc863f328886";
+    let kt1 = "01d62a20fd2574884476f3da2f1a41bb8cc289f8cc00";
+
+    let cache = AddressCache::shared(2);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                for hex in &[tz1, kt1, tz1, kt1] {
+                    assert_eq!(
+                        decode_address(hex).unwrap(),
+                        AddressCache::decode_shared(&cache, hex).unwrap()
+                    );
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let cache = cache.lock().unwrap();
+    assert_eq!(2, cache.entries.len());
+    assert_eq!(16, cache.lookups);
+    assert!(cache.hits >= 8);
+}
+
+#[test]
+fn test_address_cache_invalid() {
+    let cache = AddressCache::shared(2);
+    assert!(AddressCache::decode_shared(
+        &cache,
+        "05006b82198cb179e8306c1bedd08f12dc863f328886"
+    )
+    .is_err());
+    assert_eq!(0, cache.lock().unwrap().entries.len());
+}
@@ -1 +1,3 @@
+pub mod address_cache;
+pub mod packed;
 pub mod parser;
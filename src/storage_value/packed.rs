@@ -0,0 +1,393 @@
+use anyhow::{anyhow, Context, Result};
+use num::{BigInt, Zero};
+
+// The primitives by their tag in the binary encoding of Micheline.
+const PRIMS: &[&str] = &[
+    "parameter",
+    "storage",
+    "code",
+    "False",
+    "Elt",
+    "Left",
+    "None",
+    "Pair",
+    "Right",
+    "Some",
+    "True",
+    "Unit",
+    "PACK",
+    "UNPACK",
+    "BLAKE2B",
+    "SHA256",
+    "SHA512",
+    "ABS",
+    "ADD",
+    "AMOUNT",
+    "AND",
+    "BALANCE",
+    "CAR",
+    "CDR",
+    "CHECK_SIGNATURE",
+    "COMPARE",
+    "CONCAT",
+    "CONS",
+    "CREATE_ACCOUNT",
+    "CREATE_CONTRACT",
+    "IMPLICIT_ACCOUNT",
+    "DIP",
+    "DROP",
+    "DUP",
+    "EDIV",
+    "EMPTY_MAP",
+    "EMPTY_SET",
+    "EQ",
+    "EXEC",
+    "FAILWITH",
+    "GE",
+    "GET",
+    "GT",
+    "HASH_KEY",
+    "IF",
+    "IF_CONS",
+    "IF_LEFT",
+    "IF_NONE",
+    "INT",
+    "LAMBDA",
+    "LE",
+    "LEFT",
+    "LOOP",
+    "LSL",
+    "LSR",
+    "LT",
+    "MAP",
+    "MEM",
+    "MUL",
+    "NEG",
+    "NEQ",
+    "NIL",
+    "NONE",
+    "NOT",
+    "NOW",
+    "OR",
+    "PAIR",
+    "PUSH",
+    "RIGHT",
+    "SIZE",
+    "SOME",
+    "SOURCE",
+    "SENDER",
+    "SELF",
+    "STEPS_TO_QUOTA",
+    "SUB",
+    "SWAP",
+    "TRANSFER_TOKENS",
+    "SET_DELEGATE",
+    "UNIT",
+    "UPDATE",
+    "XOR",
+    "ITER",
+    "LOOP_LEFT",
+    "ADDRESS",
+    "CONTRACT",
+    "ISNAT",
+    "CAST",
+    "RENAME",
+    "bool",
+    "contract",
+    "int",
+    "key",
+    "key_hash",
+    "lambda",
+    "list",
+    "map",
+    "big_map",
+    "nat",
+    "option",
+    "or",
+    "pair",
+    "set",
+    "signature",
+    "string",
+    "bytes",
+    "mutez",
+    "timestamp",
+    "unit",
+    "operation",
+    "address",
+    "SLICE",
+    "DIG",
+    "DUG",
+    "EMPTY_BIG_MAP",
+    "APPLY",
+    "chain_id",
+    "CHAIN_ID",
+    "LEVEL",
+    "SELF_ADDRESS",
+    "never",
+    "NEVER",
+    "UNPAIR",
+    "VOTING_POWER",
+    "TOTAL_VOTING_POWER",
+    "KECCAK",
+    "SHA3",
+    "PAIRING_CHECK",
+    "bls12_381_g1",
+    "bls12_381_g2",
+    "bls12_381_fr",
+    "sapling_state",
+    "sapling_transaction_deprecated",
+    "SAPLING_EMPTY_STATE",
+    "SAPLING_VERIFY_UPDATE",
+    "ticket",
+    "TICKET_DEPRECATED",
+    "READ_TICKET",
+    "SPLIT_TICKET",
+    "JOIN_TICKETS",
+    "GET_AND_UPDATE",
+    "chest",
+    "chest_key",
+    "OPEN_CHEST",
+    "VIEW",
+    "view",
+    "constant",
+    "SUB_MUTEZ",
+    "tx_rollup_l2_address",
+    "MIN_BLOCK_TIME",
+    "sapling_transaction",
+    "EMIT",
+    "Lambda_rec",
+    "LAMBDA_REC",
+    "TICKET",
+    "BYTES",
+    "NAT",
+];
+
+// How deep values can be nested, values nested deeper are rejected rather
+// than recursed into.
+const MAX_DEPTH: usize = 256;
+
+/// Decodes PACKed bytes (hex encoded, starting with the 0x05 prefix) into
+/// the Micheline json the node would have returned for the value.
+pub(crate) fn unpack(hex: &str) -> Result<serde_json::Value> {
+    let bytes = hex::decode(hex.trim_start_matches("0x"))
+        .with_context(|| anyhow!("packed bytes are not hex: {}", hex))?;
+    match bytes.first() {
+        Some(0x05) => {}
+        _ => {
+            return Err(anyhow!("packed bytes do not start with 0x05: {}", hex))
+        }
+    }
+    let mut reader = Reader {
+        bytes: &bytes[1..],
+        pos: 0,
+        depth: 0,
+    };
+    let res = reader
+        .node()
+        .with_context(|| anyhow!("failed to unpack bytes: {}", hex))?;
+    if reader.pos != reader.bytes.len() {
+        return Err(anyhow!(
+            "failed to unpack bytes: {} trailing bytes after the value: {}",
+            reader.bytes.len() - reader.pos,
+            hex
+        ));
+    }
+    Ok(res)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // how many nodes the node being read is nested in
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        if self.pos + n > self.bytes.len() {
+            return Err(anyhow!("unexpected end of the packed bytes"));
+        }
+        let res = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(res)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn length(&mut self) -> Result<usize> {
+        let bs = self.take(4)?;
+        Ok(u32::from_be_bytes([bs[0], bs[1], bs[2], bs[3]]) as usize)
+    }
+
+    fn node(&mut self) -> Result<serde_json::Value> {
+        if self.depth >= MAX_DEPTH {
+            return Err(anyhow!(
+                "packed value is nested more than {} deep",
+                MAX_DEPTH
+            ));
+        }
+        self.depth += 1;
+        let res = self.node_inner();
+        self.depth -= 1;
+        res
+    }
+
+    fn node_inner(&mut self) -> Result<serde_json::Value> {
+        let tag = self.byte()?;
+        match tag {
+            0x00 => {
+                Ok(serde_json::json!({ "int": self.zarith()?.to_string() }))
+            }
+            0x01 => {
+                let n = self.length()?;
+                let s = std::str::from_utf8(self.take(n)?)
+                    .with_context(|| anyhow!("packed string is not utf8"))?
+                    .to_string();
+                Ok(serde_json::json!({ "string": s }))
+            }
+            0x02 => Ok(serde_json::Value::Array(self.node_seq()?)),
+            0x03..=0x08 => {
+                let prim = self.prim()?;
+                let num_args = ((tag - 0x03) / 2) as usize;
+                let mut args = vec![];
+                for _ in 0..num_args {
+                    args.push(self.node()?);
+                }
+                let annots = if (tag - 0x03) % 2 == 1 {
+                    self.annots()?
+                } else {
+                    vec![]
+                };
+                Ok(Self::prim_json(prim, args, annots))
+            }
+            0x09 => {
+                let prim = self.prim()?;
+                let args = self.node_seq()?;
+                let annots = self.annots()?;
+                Ok(Self::prim_json(prim, args, annots))
+            }
+            0x0a => {
+                let n = self.length()?;
+                Ok(serde_json::json!({ "bytes": hex::encode(self.take(n)?) }))
+            }
+            _ => Err(anyhow!("unknown packed node tag 0x{:02x}", tag)),
+        }
+    }
+
+    // A sequence of nodes (the elements of a sequence, or the arguments of
+    // a primitive), prefixed with its length in bytes.
+    fn node_seq(&mut self) -> Result<Vec<serde_json::Value>> {
+        let n = self.length()?;
+        let end = self.pos + n;
+        let mut res = vec![];
+        while self.pos < end {
+            res.push(self.node()?);
+        }
+        if self.pos != end {
+            return Err(anyhow!("packed sequence overruns its length"));
+        }
+        Ok(res)
+    }
+
+    fn prim(&mut self) -> Result<&'static str> {
+        let tag = self.byte()?;
+        PRIMS
+            .get(tag as usize)
+            .copied()
+            .ok_or_else(|| anyhow!("unknown packed primitive tag {}", tag))
+    }
+
+    fn annots(&mut self) -> Result<Vec<String>> {
+        let n = self.length()?;
+        let s = std::str::from_utf8(self.take(n)?)
+            .with_context(|| anyhow!("packed annotations are not utf8"))?;
+        Ok(s.split_whitespace()
+            .map(|annot| annot.to_string())
+            .collect())
+    }
+
+    // A signed, variable length integer: the first byte holds the sign
+    // (0x40) and 6 bits, each following byte 7 more bits, as long as the
+    // previous byte has its high bit (0x80) set.
+    fn zarith(&mut self) -> Result<BigInt> {
+        let first = self.byte()?;
+        let negative = first & 0x40 != 0;
+        let mut res = BigInt::from(first & 0x3f);
+        let mut shift = 6;
+        let mut more = first & 0x80 != 0;
+        while more {
+            let b = self.byte()?;
+            res += BigInt::from(b & 0x7f) << shift;
+            shift += 7;
+            more = b & 0x80 != 0;
+        }
+        if negative && !res.is_zero() {
+            res = -res;
+        }
+        Ok(res)
+    }
+
+    fn prim_json(
+        prim: &str,
+        args: Vec<serde_json::Value>,
+        annots: Vec<String>,
+    ) -> serde_json::Value {
+        let mut res = serde_json::Map::new();
+        res.insert("prim".to_string(), serde_json::json!(prim));
+        if !args.is_empty() {
+            res.insert("args".to_string(), serde_json::Value::Array(args));
+        }
+        if !annots.is_empty() {
+            res.insert("annots".to_string(), serde_json::json!(annots));
+        }
+        serde_json::Value::Object(res)
+    }
+}
+
+#[test]
+fn test_unpack() {
+    // Pair "tz1VSUr8wwNhLAzempoch5d6hLRiTh8Cjcjb" 42, with the address packed
+    // as (typed) bytes
+    assert_eq!(
+        serde_json::json!({
+            "prim": "Pair",
+            "args": [
+                { "bytes": "00006b82198cb179e8306c1bedd08f12dc863f328886" },
+                { "int": "42" },
+            ],
+        }),
+        unpack(
+            "0507070a0000001600006b82198cb179e8306c1bedd08f12dc863f328886002a"
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        serde_json::json!([
+            { "string": "ab" },
+            { "int": "-1000" },
+            { "int": "64" },
+            { "prim": "Some", "args": [{ "prim": "Unit" }] },
+            { "prim": "None" },
+        ]),
+        unpack("0502000000130100000002616200e80f0080010509030b0306").unwrap()
+    );
+
+    // nested too deep: Some (Some (... Unit))
+    let nested = |depth: usize| format!("05{}030b", "0509".repeat(depth));
+    assert!(unpack(&nested(MAX_DEPTH - 1)).is_ok());
+    assert!(unpack(&nested(MAX_DEPTH)).is_err());
+
+    for bad in &[
+        // no 0x05 prefix
+        "07070001",
+        // truncated
+        "050707000100",
+        // trailing bytes
+        "0500010000",
+        "not hex",
+    ] {
+        assert!(unpack(bad).is_err(), "{}", bad);
+    }
+}
@@ -5,8 +5,12 @@ use serde_json::json;
 use std::str::from_utf8;
 use std::str::FromStr;
 
+use crate::debug;
 use crate::sql::insert;
 
+// how deep to print the Micheline node that failed to parse
+const ERR_NODE_DEPTH: usize = 4;
+
 #[derive(
     Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
 )]
@@ -104,14 +108,44 @@ pub(crate) fn parse_json(storage_json: &serde_json::Value) -> Result<Value> {
         .with_context(|| "failed to parse storage json into Value")
 }
 
+// Decodes an address, including its entrypoint (as tz1..%entrypoint) when
+// the bytes hold one.
 pub(crate) fn decode_address(hex: &str) -> Result<String> {
-    let addr_hex = &hex[0..44];
-    let callback_hex = &hex[44..];
-    let mut res = decode_bs58_address(addr_hex)?;
-    if !callback_hex.is_empty() {
-        res += format!("%{}", from_utf8(&hex::decode(callback_hex)?)?).as_str();
+    let (address, entrypoint) = decode_address_prefix(hex)?;
+    Ok(match entrypoint {
+        Some(entrypoint) => format!("{}%{}", address, entrypoint),
+        None => address,
+    })
+}
+
+// Decodes the address in the first 22 bytes, and returns it together with
+// the entrypoint name that follows it (if any), as found in eg the values of
+// `contract %ep` types.
+pub(crate) fn decode_address_prefix(
+    hex: &str,
+) -> Result<(String, Option<String>)> {
+    if hex.len() < 44 || !hex.is_char_boundary(44) {
+        return Err(anyhow!(
+            "expected at least 22 bytes for an address, got {} (which has len={})",
+            hex,
+            hex.len()
+        ));
+    }
+    let (addr_hex, entrypoint_hex) = hex.split_at(44);
+    let address = decode_bs58_address(addr_hex)?;
+    if entrypoint_hex.is_empty() {
+        return Ok((address, None));
     }
-    Ok(res)
+    let entrypoint_bytes = hex::decode(entrypoint_hex)?;
+    let entrypoint = from_utf8(&entrypoint_bytes)?;
+    if !entrypoint.is_ascii() {
+        return Err(anyhow!(
+            "entrypoint of address {} is not ascii: {}",
+            address,
+            entrypoint
+        ));
+    }
+    Ok((address, Some(entrypoint.to_string())))
 }
 
 fn decode_bs58_address(hex: &str) -> Result<String> {
@@ -155,14 +189,21 @@ fn lex(json: &serde_json::Value) -> serde_json::Value {
     }
 }
 
+fn bad_node(json: &serde_json::Value) -> String {
+    format!(
+        "failed to parse Micheline node: {}",
+        debug::pp_depth(ERR_NODE_DEPTH, json)
+    )
+}
+
 /// Goes through the actual stored data and builds up a structure which can be used in combination with the node
 /// data to stash it in the database.
 pub(crate) fn parse_lexed(json: &serde_json::Value) -> Result<Value> {
     if let serde_json::Value::Array(a) = json {
         return Ok(Value::List(
             a.iter()
-                .map(|x| parse_lexed(x).unwrap())
-                .collect(),
+                .map(parse_lexed)
+                .collect::<Result<Vec<Value>>>()?,
         ));
     }
     let args: Vec<serde_json::Value> = match &json["args"] {
@@ -175,7 +216,11 @@ pub(crate) fn parse_lexed(json: &serde_json::Value) -> Result<Value> {
         match prim.as_str() {
             "ELT" => {
                 if args.len() != 2 {
-                    panic!("Elt with array length of {}", args.len());
+                    return Err(anyhow!(
+                        "Elt with array length of {}",
+                        args.len()
+                    ))
+                    .with_context(|| bad_node(json));
                 }
                 return Ok(Value::Elt(
                     Box::new(parse_lexed(&args[0])?),
@@ -183,6 +228,10 @@ pub(crate) fn parse_lexed(json: &serde_json::Value) -> Result<Value> {
                 ));
             }
             "FALSE" => return Ok(Value::Bool(false)),
+            "LEFT" | "RIGHT" if args.is_empty() => {
+                return Err(anyhow!("{} without an argument", s))
+                    .with_context(|| bad_node(json));
+            }
             "LEFT" => return Ok(Value::Left(Box::new(parse_lexed(&args[0])?))),
             "NONE" => return Ok(Value::None),
             "RIGHT" => {
@@ -226,7 +275,8 @@ pub(crate) fn parse_lexed(json: &serde_json::Value) -> Result<Value> {
 
     let keys: Vec<String> = json
         .as_object()
-        .unwrap()
+        .ok_or_else(|| anyhow!("expected an object"))
+        .with_context(|| bad_node(json))?
         .iter()
         .map(|(a, _)| String::from(a))
         .collect();
@@ -236,21 +286,27 @@ pub(crate) fn parse_lexed(json: &serde_json::Value) -> Result<Value> {
         let s = String::from(
             json[key]
                 .as_str()
-                .ok_or_else(|| anyhow!("Key {} not found", key))?,
+                .ok_or_else(|| anyhow!("Key {} not found", key))
+                .with_context(|| bad_node(json))?,
         );
         return match key.as_str() {
             "address" => Ok(Value::Address(s)),
             "bytes" => Ok(Value::Bytes(s)),
-            "int" => Ok(Value::Int(bigint(&s)?)),
-            "mutez" => Ok(Value::Mutez(bigint(&s)?)),
-            "nat" => Ok(Value::Nat(bigint(&s)?)),
+            "int" => {
+                Ok(Value::Int(bigint(&s).with_context(|| bad_node(json))?))
+            }
+            "mutez" => {
+                Ok(Value::Mutez(bigint(&s).with_context(|| bad_node(json))?))
+            }
+            "nat" => {
+                Ok(Value::Nat(bigint(&s).with_context(|| bad_node(json))?))
+            }
             "string" => Ok(Value::String(s)),
             //"timestamp" => Ok(Value::Timestamp(s)),
             "unit" => Ok(Value::Unit),
             "prim" => Ok(prim(&s)),
-            _ => {
-                panic!("Couldn't match {} in {}", key, json);
-            }
+            _ => Err(anyhow!("Couldn't match {}", key))
+                .with_context(|| bad_node(json)),
         };
     }
 
@@ -285,29 +341,19 @@ fn bigint(source: &str) -> Result<BigInt> {
     Ok(BigInt::from_str(source)?)
 }
 
+// The earliest timestamp Postgres can store (4713 BC) in Unix time. Chrono
+// reaches further back, and less far ahead than Postgres, so beyond this
+// bound timestamp_opt decides what is in range.
+const MIN_PG_UNIX_TIMESTAMP: i64 = -210_866_803_200;
+
 pub(crate) fn parse_date(value: &Value) -> Result<insert::Value> {
     match value {
-        Value::Int(s) => {
-            let ts: i64 = s
-                .to_i64()
-                .ok_or_else(|| anyhow!("Num conversion failed"))?;
-            match Utc.timestamp_opt(ts, 0) {
-                LocalResult::Single(t) => Ok(insert::Value::Timestamp(Some(t))),
-                LocalResult::None => Ok(insert::Value::Timestamp(None)),
-                LocalResult::Ambiguous(_, _) => {
-                    Err(anyhow!("Can't parse {:?}", value))
-                }
-            }
-        }
+        Value::Int(s) => Ok(timestamp_from_unix(s.to_i64(), value)),
         Value::String(s) => {
-            let fixedoffset = if s.chars().all(|c| c.is_numeric()) {
-                chrono::DateTime::parse_from_str(
-                    format!("{}+0000", s).as_str(),
-                    "%s%z",
-                )?
-            } else {
-                chrono::DateTime::parse_from_rfc3339(s.as_str())?
-            };
+            if s.chars().all(|c| c.is_numeric()) {
+                return Ok(timestamp_from_unix(s.parse::<i64>().ok(), value));
+            }
+            let fixedoffset = chrono::DateTime::parse_from_rfc3339(s.as_str())?;
             Ok(insert::Value::Timestamp(Some(
                 fixedoffset.with_timezone(&Utc),
             )))
@@ -316,6 +362,24 @@ pub(crate) fn parse_date(value: &Value) -> Result<insert::Value> {
     }
 }
 
+// Contracts may store any int as a timestamp, those that cannot be stored as
+// one are stored as NULL instead.
+fn timestamp_from_unix(ts: Option<i64>, value: &Value) -> insert::Value {
+    match ts
+        .filter(|ts| *ts >= MIN_PG_UNIX_TIMESTAMP)
+        .map(|ts| Utc.timestamp_opt(ts, 0))
+    {
+        Some(LocalResult::Single(t)) => insert::Value::Timestamp(Some(t)),
+        _ => {
+            warn!(
+                "timestamp {:?} is out of range, storing NULL instead",
+                value
+            );
+            insert::Value::Timestamp(None)
+        }
+    }
+}
+
 fn prim(s: &str) -> Value {
     match s {
         "False" => Value::Bool(true),
@@ -346,3 +410,104 @@ fn test_decode() {
         assert_eq!(to, decode_address(from).unwrap().as_str());
     }
 }
+
+#[test]
+fn test_decode_address_prefix() {
+    assert_eq!(
+        ("KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(), None),
+        decode_address_prefix("01d62a20fd2574884476f3da2f1a41bb8cc289f8cc00")
+            .unwrap()
+    );
+    assert_eq!(
+        (
+            "KT1JdufSdfg3WyxWJcCRNsBFV9V3x9TQBkJ2".to_string(),
+            Some("borrow_callback".to_string())
+        ),
+        decode_address_prefix(
+            "016e4943f7a23ab9cbe56f48ff72f6c27e8956762400626f72726f775f63616c6c6261636b"
+        )
+        .unwrap()
+    );
+
+    // too short to hold an address
+    assert!(decode_address_prefix("00006b82198cb179e830").is_err());
+    assert!(decode_address("00006b82198cb179e830").is_err());
+    // the entrypoint is not ascii
+    assert!(decode_address_prefix(
+        "00006b82198cb179e8306c1bedd08f12dc863f328886c3a9"
+    )
+    .is_err());
+}
+
+#[test]
+fn test_parse_error_contains_bad_node() {
+    let storage = json!({
+        "prim": "Pair",
+        "args": [
+            {"int": "1"},
+            {
+                "prim": "Pair",
+                "args": [{"string": "foo"}, {"int": "not_a_number"}],
+            },
+        ],
+    });
+    let err = parse_json(&storage).unwrap_err();
+    let err_str = format!("{:?}", err);
+    assert!(
+        err_str.contains("failed to parse Micheline node"),
+        "{}",
+        err_str
+    );
+    assert!(err_str.contains("not_a_number"), "{}", err_str);
+
+    let storage = json!({
+        "prim": "Elt",
+        "args": [{"int": "1"}],
+    });
+    let err_str = format!("{:?}", parse_json(&storage).unwrap_err());
+    assert!(
+        err_str.contains("Elt with array length of 1"),
+        "{}",
+        err_str
+    );
+    assert!(err_str.contains("\"1\""), "{}", err_str);
+}
+
+#[test]
+fn test_parse_date_out_of_range() {
+    let ts = |s: &str| Value::Int(BigInt::from_str(s).unwrap());
+    let date = |s: &str| {
+        insert::Value::Timestamp(Some(
+            chrono::DateTime::parse_from_rfc3339(s)
+                .unwrap()
+                .with_timezone(&Utc),
+        ))
+    };
+
+    assert_eq!(date("1969-12-31T23:59:59Z"), parse_date(&ts("-1")).unwrap());
+    assert_eq!(date("1970-01-01T00:00:00Z"), parse_date(&ts("0")).unwrap());
+    assert_eq!(
+        date("9999-12-31T23:59:59Z"),
+        parse_date(&ts("253402300799")).unwrap()
+    );
+    assert_eq!(
+        date("9999-12-31T23:59:59Z"),
+        parse_date(&Value::String("253402300799".to_string())).unwrap()
+    );
+
+    // beyond what chrono or postgres can represent
+    for out_of_range in &[
+        ts("-210866803201"),
+        ts("-9223372036854775808"),
+        ts("9223372036854775807"),
+        ts("99999999999999999999999"),
+        Value::String("99999999999999999999999".to_string()),
+    ] {
+        assert_eq!(
+            insert::Value::Timestamp(None),
+            parse_date(out_of_range).unwrap(),
+            "{:?}",
+            out_of_range
+        );
+    }
+}
@@ -0,0 +1,80 @@
+// Helpers shared by the tests of several modules.
+
+use std::collections::HashMap;
+
+use crate::config::ContractID;
+use crate::octez::block::{LevelMeta, TxContext};
+use crate::sql::inserter::{ProcessedBlock, ProcessedContractBlock};
+use crate::storage_structure::relational::{
+    Contract, RelationalAST, RelationalEntry,
+};
+use crate::storage_structure::typing::ExprTy;
+
+// A processed block of the given level with a (minimal) block per contract,
+// of which the active ones have a tx context at the level.
+pub(crate) fn test_block(
+    level: u32,
+    contracts: &[(&str, bool)],
+) -> ProcessedBlock {
+    contracts
+        .iter()
+        .map(|(name, active)| ProcessedContractBlock {
+            level: LevelMeta {
+                level,
+                hash: Some(format!("hash{}", level)),
+                prev_hash: None,
+                baked_at: None,
+                protocol: None,
+                content_hash: None,
+            },
+            contract: Contract {
+                cid: ContractID {
+                    name: name.to_string(),
+                    address: format!("KT1{}", name),
+                },
+                level_floor: None,
+                table_snapshots: HashMap::new(),
+                table_origins: HashMap::new(),
+                root_table: "storage".to_string(),
+                column_types: Default::default(),
+                bigmap_filter: Default::default(),
+                metadata_uris: HashMap::new(),
+                storage_ast: RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "nat".to_string(),
+                        column_type: ExprTy::Nat,
+                        value: None,
+                        is_index: false,
+                    },
+                },
+                entrypoint_asts: HashMap::new(),
+            },
+            is_origination: false,
+            inserts: vec![],
+            spilled_inserts: None,
+            tx_contexts: if *active {
+                vec![TxContext {
+                    id: Some(1),
+                    contract: format!("KT1{}", name),
+                    level,
+                    operation_group_number: 0,
+                    operation_number: 0,
+                    content_number: 0,
+                    internal_number: None,
+                }]
+            } else {
+                vec![]
+            },
+            txs: vec![],
+            bigmap_contract_deps: vec![],
+            bigmap_keyhashes: HashMap::new(),
+            bigmap_meta_actions: vec![],
+            operation_failures: vec![],
+            raw_storage: vec![],
+            delegations: vec![],
+            balance: None,
+            deterministic_ids: false,
+        })
+        .collect()
+}